@@ -0,0 +1,9 @@
+CREATE TABLE password_history (
+	uuid                CHAR(36) NOT NULL PRIMARY KEY,
+	user_uuid           CHAR(36) NOT NULL,
+	password_hash       BLOB NOT NULL,
+	salt                BLOB NOT NULL,
+	password_iterations INTEGER NOT NULL,
+	created_at          DATETIME NOT NULL,
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
@@ -0,0 +1,8 @@
+ALTER TABLE devices
+DROP COLUMN push_registration_error;
+
+ALTER TABLE devices
+DROP COLUMN push_registration_date;
+
+ALTER TABLE devices
+DROP COLUMN push_registration_success;
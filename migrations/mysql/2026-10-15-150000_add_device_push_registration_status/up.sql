@@ -0,0 +1,8 @@
+ALTER TABLE devices
+ADD COLUMN push_registration_success BOOLEAN;
+
+ALTER TABLE devices
+ADD COLUMN push_registration_date DATETIME;
+
+ALTER TABLE devices
+ADD COLUMN push_registration_error TEXT;
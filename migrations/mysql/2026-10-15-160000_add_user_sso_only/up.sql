@@ -0,0 +1,2 @@
+ALTER TABLE users
+ADD COLUMN sso_only BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,5 @@
+ALTER TABLE users
+ADD COLUMN attachment_limit BIGINT;
+
+ALTER TABLE users
+ADD COLUMN send_limit BIGINT;
@@ -0,0 +1,2 @@
+ALTER TABLE auth_requests
+DROP COLUMN challenge_nonce;
@@ -0,0 +1,8 @@
+CREATE TABLE login_locations (
+	uuid       CHAR(36) NOT NULL PRIMARY KEY,
+	user_uuid  CHAR(36) NOT NULL,
+	network    VARCHAR(64) NOT NULL,
+	country    VARCHAR(8),
+	last_seen  DATETIME NOT NULL,
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
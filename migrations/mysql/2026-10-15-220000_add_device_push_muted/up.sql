@@ -0,0 +1,2 @@
+ALTER TABLE devices
+ADD COLUMN push_muted INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,8 @@
+CREATE TABLE key_history (
+	uuid        CHAR(36) NOT NULL PRIMARY KEY,
+	user_uuid   CHAR(36) NOT NULL,
+	version     INTEGER NOT NULL,
+	change_type INTEGER NOT NULL,
+	changed_at  DATETIME NOT NULL,
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE users_organizations
+DROP COLUMN profile_attributes;
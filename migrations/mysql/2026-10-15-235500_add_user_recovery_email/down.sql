@@ -0,0 +1,8 @@
+ALTER TABLE users
+DROP COLUMN recovery_email_new_token;
+
+ALTER TABLE users
+DROP COLUMN recovery_email_new;
+
+ALTER TABLE users
+DROP COLUMN recovery_email;
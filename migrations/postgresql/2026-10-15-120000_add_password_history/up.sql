@@ -0,0 +1,9 @@
+CREATE TABLE password_history (
+	uuid                CHAR(36) NOT NULL PRIMARY KEY,
+	user_uuid           CHAR(36) NOT NULL,
+	password_hash       BYTEA NOT NULL,
+	salt                BYTEA NOT NULL,
+	password_iterations INTEGER NOT NULL,
+	created_at          TIMESTAMP NOT NULL,
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
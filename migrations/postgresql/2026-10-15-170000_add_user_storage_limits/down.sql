@@ -0,0 +1,5 @@
+ALTER TABLE users
+DROP COLUMN send_limit;
+
+ALTER TABLE users
+DROP COLUMN attachment_limit;
@@ -0,0 +1,8 @@
+CREATE TABLE revoked_sessions (
+	uuid               CHAR(36) NOT NULL PRIMARY KEY,
+	user_uuid          CHAR(36) NOT NULL,
+	device_uuid        CHAR(36),
+	except_device_uuid CHAR(36),
+	revoked_at         TIMESTAMP NOT NULL,
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
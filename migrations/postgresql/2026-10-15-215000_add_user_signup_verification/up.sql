@@ -0,0 +1,2 @@
+ALTER TABLE users
+ADD COLUMN signup_verification BOOLEAN NOT NULL DEFAULT FALSE;
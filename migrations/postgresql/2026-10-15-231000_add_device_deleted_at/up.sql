@@ -0,0 +1,2 @@
+ALTER TABLE devices
+ADD COLUMN deleted_at TIMESTAMP;
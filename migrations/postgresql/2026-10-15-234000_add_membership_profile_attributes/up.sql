@@ -0,0 +1,2 @@
+ALTER TABLE users_organizations
+ADD COLUMN profile_attributes TEXT;
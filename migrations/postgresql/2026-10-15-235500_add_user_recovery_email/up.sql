@@ -0,0 +1,8 @@
+ALTER TABLE users
+ADD COLUMN recovery_email TEXT;
+
+ALTER TABLE users
+ADD COLUMN recovery_email_new TEXT;
+
+ALTER TABLE users
+ADD COLUMN recovery_email_new_token TEXT;
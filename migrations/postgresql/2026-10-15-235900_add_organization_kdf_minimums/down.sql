@@ -0,0 +1,11 @@
+ALTER TABLE organizations
+DROP COLUMN kdf_min_argon2_parallelism;
+
+ALTER TABLE organizations
+DROP COLUMN kdf_min_argon2_memory;
+
+ALTER TABLE organizations
+DROP COLUMN kdf_min_argon2_iterations;
+
+ALTER TABLE organizations
+DROP COLUMN kdf_min_pbkdf2_iterations;
@@ -0,0 +1,9 @@
+CREATE TABLE password_history (
+	uuid                TEXT NOT NULL PRIMARY KEY,
+	user_uuid           TEXT NOT NULL,
+	password_hash       BLOB NOT NULL,
+	salt                BLOB NOT NULL,
+	password_iterations INTEGER NOT NULL,
+	created_at          DATETIME NOT NULL,
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
@@ -0,0 +1,5 @@
+ALTER TABLE users
+ADD COLUMN attachment_limit INTEGER;
+
+ALTER TABLE users
+ADD COLUMN send_limit INTEGER;
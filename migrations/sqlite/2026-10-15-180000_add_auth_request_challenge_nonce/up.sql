@@ -0,0 +1,2 @@
+ALTER TABLE auth_requests
+ADD COLUMN challenge_nonce TEXT;
@@ -0,0 +1,8 @@
+CREATE TABLE login_locations (
+	uuid       TEXT NOT NULL PRIMARY KEY,
+	user_uuid  TEXT NOT NULL,
+	network    TEXT NOT NULL,
+	country    TEXT,
+	last_seen  DATETIME NOT NULL,
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
@@ -0,0 +1,8 @@
+ALTER TABLE devices
+DROP COLUMN encrypted_user_key;
+
+ALTER TABLE devices
+DROP COLUMN approval_public_key;
+
+ALTER TABLE devices
+DROP COLUMN pending_approval;
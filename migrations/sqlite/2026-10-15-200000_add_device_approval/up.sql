@@ -0,0 +1,8 @@
+ALTER TABLE devices
+ADD COLUMN pending_approval BOOLEAN NOT NULL DEFAULT 0;
+
+ALTER TABLE devices
+ADD COLUMN approval_public_key TEXT;
+
+ALTER TABLE devices
+ADD COLUMN encrypted_user_key TEXT;
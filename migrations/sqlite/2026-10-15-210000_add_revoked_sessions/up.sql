@@ -0,0 +1,8 @@
+CREATE TABLE revoked_sessions (
+	uuid               TEXT NOT NULL PRIMARY KEY,
+	user_uuid          TEXT NOT NULL,
+	device_uuid        TEXT,
+	except_device_uuid TEXT,
+	revoked_at         DATETIME NOT NULL,
+	FOREIGN KEY(user_uuid) REFERENCES users(uuid)
+);
@@ -0,0 +1,8 @@
+ALTER TABLE sends
+DROP COLUMN key_version;
+
+ALTER TABLE ciphers
+DROP COLUMN key_version;
+
+ALTER TABLE users
+DROP COLUMN key_version;
@@ -0,0 +1,8 @@
+ALTER TABLE users
+ADD COLUMN key_version INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE ciphers
+ADD COLUMN key_version INTEGER;
+
+ALTER TABLE sends
+ADD COLUMN key_version INTEGER;
@@ -0,0 +1,11 @@
+ALTER TABLE organizations
+ADD COLUMN kdf_min_pbkdf2_iterations INTEGER;
+
+ALTER TABLE organizations
+ADD COLUMN kdf_min_argon2_iterations INTEGER;
+
+ALTER TABLE organizations
+ADD COLUMN kdf_min_argon2_memory INTEGER;
+
+ALTER TABLE organizations
+ADD COLUMN kdf_min_argon2_parallelism INTEGER;
@@ -0,0 +1,2 @@
+ALTER TABLE devices
+ADD COLUMN session_public_key TEXT;
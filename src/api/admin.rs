@@ -2,7 +2,7 @@ use once_cell::sync::Lazy;
 use reqwest::Method;
 use serde::de::DeserializeOwned;
 use serde_json::Value;
-use std::env;
+use std::{collections::HashMap, env};
 
 use rocket::serde::json::Json;
 use rocket::{
@@ -16,10 +16,10 @@ use rocket::{
 use crate::{
     api::{
         core::{log_event, two_factor},
-        unregister_push_device, ApiResult, EmptyResult, JsonResult, Notify,
+        unregister_push_device, ApiResult, EmptyResult, JsonResult, Notify, UpdateType,
     },
     auth::{decode_admin, encode_jwt, generate_admin_claims, ClientIp, Secure},
-    config::ConfigBuilder,
+    config::{ConfigBuilder, MAX_FILESIZE_KB},
     db::{backup_database, get_sql_server_version, models::*, DbConn, DbConnType},
     error::{Error, MapResult},
     http_client::make_http_request,
@@ -38,6 +38,9 @@ pub fn routes() -> Vec<Route> {
 
     routes![
         get_users_json,
+        get_users_kdf_audit,
+        get_users_pending_approval,
+        get_signup_policy,
         get_user_json,
         get_user_by_mail_json,
         post_admin_login,
@@ -48,8 +51,14 @@ pub fn routes() -> Vec<Route> {
         delete_user,
         delete_sso_user,
         deauth_user,
+        clear_user_push_tokens,
         disable_user,
         enable_user,
+        approve_user,
+        reject_user,
+        set_user_sso_only,
+        set_user_storage_limits,
+        merge_user,
         remove_2fa,
         update_membership_type,
         update_revision_users,
@@ -282,6 +291,8 @@ fn admin_page_login() -> ApiResult<Html<String>> {
 #[serde(rename_all = "camelCase")]
 struct InviteData {
     email: String,
+    // Residency region of the invited account, the default region is used when not set
+    region: Option<String>,
 }
 
 async fn get_user_or_404(user_id: &UserId, conn: &mut DbConn) -> ApiResult<User> {
@@ -300,6 +311,12 @@ async fn invite_user(data: Json<InviteData>, _token: AdminToken, mut conn: DbCon
     }
 
     let mut user = User::new(data.email, None);
+    if let Some(region) = data.region.filter(|r| !r.is_empty()) {
+        if !crate::config::is_region_in_list(&region, &CONFIG.residency_regions()) {
+            err_code!(format!("Unknown residency region {region}"), Status::BadRequest.code)
+        }
+        user.region = Some(region);
+    }
 
     async fn _generate_invite(user: &User, conn: &mut DbConn) -> EmptyResult {
         if CONFIG.mail_enabled() {
@@ -342,6 +359,10 @@ async fn get_users_json(_token: AdminToken, mut conn: DbConn) -> Json<Value> {
     for (u, _) in users {
         let mut usr = u.to_json(&mut conn).await;
         usr["userEnabled"] = json!(u.enabled);
+        usr["pendingApproval"] = json!(u.pending_approval);
+        usr["attachmentLimit"] = json!(u.attachment_limit);
+        usr["sendLimit"] = json!(u.send_limit);
+        usr["region"] = json!(u.region);
         usr["createdAt"] = json!(format_naive_datetime_local(&u.created_at, DT_FMT));
         usr["lastActive"] = match u.last_active(&mut conn).await {
             Some(dt) => json!(format_naive_datetime_local(&dt, DT_FMT)),
@@ -353,6 +374,129 @@ async fn get_users_json(_token: AdminToken, mut conn: DbConn) -> Json<Value> {
     Json(Value::Array(users_json))
 }
 
+const KDF_AUDIT_DEFAULT_PAGE_SIZE: i64 = 100;
+const KDF_AUDIT_MAX_PAGE_SIZE: i64 = 1000;
+
+#[get("/users/kdf-audit?<page>&<page_size>")]
+async fn get_users_kdf_audit(
+    page: Option<i64>,
+    page_size: Option<i64>,
+    _token: AdminToken,
+    mut conn: DbConn,
+) -> Json<Value> {
+    let page = page.unwrap_or(1).max(1);
+    let page_size = page_size.unwrap_or(KDF_AUDIT_DEFAULT_PAGE_SIZE).clamp(1, KDF_AUDIT_MAX_PAGE_SIZE);
+    let thresholds = KdfThresholds::from_config();
+
+    // Load one extra user to know if there is a next page, without having to count all users
+    let mut users = User::find_weak_kdf(&thresholds, page_size + 1, (page - 1) * page_size, &mut conn).await;
+    let has_more = users.len() as i64 > page_size;
+    users.truncate(page_size as usize);
+
+    let users_json: Vec<Value> = users
+        .iter()
+        .map(|u| {
+            json!({
+                "id": u.uuid,
+                "email": u.email,
+                "name": u.name,
+                "userEnabled": u.enabled,
+                "kdf": u.client_kdf_type,
+                "kdfIterations": u.client_kdf_iter,
+                "kdfMemory": u.client_kdf_memory,
+                "kdfParallelism": u.client_kdf_parallelism,
+            })
+        })
+        .collect();
+
+    Json(json!({
+        "data": users_json,
+        "page": page,
+        "pageSize": page_size,
+        "hasMore": has_more,
+        "thresholds": {
+            "pbkdf2Iterations": thresholds.pbkdf2_iterations,
+            "argon2Iterations": thresholds.argon2_iterations,
+            "argon2Memory": thresholds.argon2_memory,
+            "argon2Parallelism": thresholds.argon2_parallelism,
+        },
+    }))
+}
+
+#[get("/users/pending-approval")]
+async fn get_users_pending_approval(_token: AdminToken, mut conn: DbConn) -> Json<Value> {
+    let users = User::find_pending_approval(&mut conn).await;
+    let users_json: Vec<Value> = users
+        .iter()
+        .map(|u| {
+            json!({
+                "id": u.uuid,
+                "email": u.email,
+                "name": u.name,
+                "emailVerified": u.verified_at.is_some(),
+                "createdAt": format_naive_datetime_local(&u.created_at, DT_FMT),
+            })
+        })
+        .collect();
+
+    Json(Value::Array(users_json))
+}
+
+/// The settings which decide who can register, as they are checked by `register` and `_register_verification_email`
+struct SignupPolicy {
+    signups_allowed: bool,
+    domains_whitelist: String,
+    signups_verify: bool,
+    mail_enabled: bool,
+    requires_approval: bool,
+    invitations_allowed: bool,
+    sso_only: bool,
+    node_region: String,
+}
+
+impl SignupPolicy {
+    fn from_config() -> Self {
+        Self {
+            signups_allowed: CONFIG.signups_allowed(),
+            domains_whitelist: CONFIG.signups_domains_whitelist(),
+            signups_verify: CONFIG.signups_verify(),
+            mail_enabled: CONFIG.mail_enabled(),
+            requires_approval: CONFIG.registration_requires_approval(),
+            invitations_allowed: CONFIG.invitations_allowed(),
+            sso_only: CONFIG.sso_enabled() && CONFIG.sso_only(),
+            node_region: CONFIG.residency_node_region(),
+        }
+    }
+
+    fn to_json(&self, pending_approval_count: usize) -> Value {
+        let allowed_domains: Vec<&str> =
+            self.domains_whitelist.split(',').map(str::trim).filter(|d| !d.is_empty()).collect();
+        // The domain whitelist overrides `SIGNUPS_ALLOWED`, see `Config::is_signup_allowed`
+        let open = self.signups_allowed && allowed_domains.is_empty();
+
+        json!({
+            "signupsAllowed": self.signups_allowed,
+            "openSignups": open,
+            "allowedDomains": allowed_domains,
+            // Everyone who isn't covered by an open signup or the allowed domains needs an invitation
+            "invitationRequired": !open,
+            "invitationsAllowed": self.invitations_allowed,
+            // Without mail the verification can't be sent, so it isn't enforced
+            "verificationRequired": self.signups_verify && self.mail_enabled,
+            "approvalRequired": self.requires_approval,
+            "passwordSignupDisabled": self.sso_only,
+            "residencyRegion": (!self.node_region.is_empty()).then_some(&self.node_region),
+            "pendingApprovalCount": pending_approval_count,
+        })
+    }
+}
+
+#[get("/signup-policy")]
+async fn get_signup_policy(_token: AdminToken, mut conn: DbConn) -> Json<Value> {
+    let pending_approval_count = User::find_pending_approval(&mut conn).await.len();
+    Json(SignupPolicy::from_config().to_json(pending_approval_count))
+}
+
 #[get("/users/overview")]
 async fn users_overview(_token: AdminToken, mut conn: DbConn) -> ApiResult<Html<String>> {
     let users = User::get_all(&mut conn).await;
@@ -363,6 +507,8 @@ async fn users_overview(_token: AdminToken, mut conn: DbConn) -> ApiResult<Html<
         usr["attachment_count"] = json!(Attachment::count_by_user(&u.uuid, &mut conn).await);
         usr["attachment_size"] = json!(get_display_size(Attachment::size_by_user(&u.uuid, &mut conn).await));
         usr["user_enabled"] = json!(u.enabled);
+        usr["pending_approval"] = json!(u.pending_approval);
+        usr["sso_only"] = json!(u.sso_only);
         usr["created_at"] = json!(format_naive_datetime_local(&u.created_at, DT_FMT));
         usr["last_active"] = match u.last_active(&mut conn).await {
             Some(dt) => json!(format_naive_datetime_local(&dt, DT_FMT)),
@@ -383,6 +529,7 @@ async fn get_user_by_mail_json(mail: &str, _token: AdminToken, mut conn: DbConn)
     if let Some(u) = User::find_by_mail(mail, &mut conn).await {
         let mut usr = u.to_json(&mut conn).await;
         usr["userEnabled"] = json!(u.enabled);
+        usr["pendingApproval"] = json!(u.pending_approval);
         usr["createdAt"] = json!(format_naive_datetime_local(&u.created_at, DT_FMT));
         Ok(Json(usr))
     } else {
@@ -395,6 +542,9 @@ async fn get_user_json(user_id: UserId, _token: AdminToken, mut conn: DbConn) ->
     let u = get_user_or_404(&user_id, &mut conn).await?;
     let mut usr = u.to_json(&mut conn).await;
     usr["userEnabled"] = json!(u.enabled);
+    usr["pendingApproval"] = json!(u.pending_approval);
+    usr["attachmentLimit"] = json!(u.attachment_limit);
+    usr["sendLimit"] = json!(u.send_limit);
     usr["createdAt"] = json!(format_naive_datetime_local(&u.created_at, DT_FMT));
     Ok(Json(usr))
 }
@@ -465,28 +615,355 @@ async fn deauth_user(user_id: UserId, _token: AdminToken, mut conn: DbConn, nt:
     user.save(&mut conn).await
 }
 
+/// Forces all devices of a user to register again for push notifications, for example after switching to another push relay.
+#[post("/users/<user_id>/clear-push-tokens", format = "application/json")]
+async fn clear_user_push_tokens(user_id: UserId, _token: AdminToken, mut conn: DbConn) -> EmptyResult {
+    let user = get_user_or_404(&user_id, &mut conn).await?;
+
+    let devices = Device::find_push_devices_by_user(&user.uuid, &mut conn).await;
+    let count = devices.len();
+    for mut device in devices {
+        let push_id = device.clear_push_token();
+        if let Err(e) = unregister_push_device(&push_id).await {
+            error!("Unable to unregister device {} from Bitwarden server: {e}", device.uuid);
+        }
+        device.save(&mut conn).await?;
+    }
+
+    info!("Admin cleared the push tokens of {count} device(s) of user {} ({})", user.uuid, user.email);
+    Ok(())
+}
+
 #[post("/users/<user_id>/disable", format = "application/json")]
 async fn disable_user(user_id: UserId, _token: AdminToken, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
     let mut user = get_user_or_404(&user_id, &mut conn).await?;
+
+    // Send the logout before removing the devices, else the push devices can't be notified anymore
+    nt.send_logout(&user, None, &mut conn).await;
+
+    if CONFIG.push_enabled() && CONFIG.disable_user_unregister_push() {
+        for device in Device::find_push_devices_by_user(&user.uuid, &mut conn).await {
+            match unregister_push_device(&device.push_uuid).await {
+                Ok(r) => r,
+                Err(e) => error!("Unable to unregister devices from Bitwarden server: {e}"),
+            };
+        }
+    }
+
     Device::delete_all_by_user(&user.uuid, &mut conn).await?;
     user.reset_security_stamp();
     user.enabled = false;
 
-    let save_result = user.save(&mut conn).await;
-
-    nt.send_logout(&user, None, &mut conn).await;
-
-    save_result
+    user.save(&mut conn).await
 }
 
 #[post("/users/<user_id>/enable", format = "application/json")]
 async fn enable_user(user_id: UserId, _token: AdminToken, mut conn: DbConn) -> EmptyResult {
     let mut user = get_user_or_404(&user_id, &mut conn).await?;
     user.enabled = true;
+    // Make sure no stamp exception from before the user was disabled can be used anymore
+    user.reset_stamp_exception();
 
     user.save(&mut conn).await
 }
 
+#[post("/users/<user_id>/approve", format = "application/json")]
+async fn approve_user(user_id: UserId, _token: AdminToken, mut conn: DbConn) -> EmptyResult {
+    let mut user = get_user_or_404(&user_id, &mut conn).await?;
+    if !user.pending_approval {
+        err_code!("User is not awaiting approval", Status::BadRequest.code);
+    }
+
+    user.pending_approval = false;
+    user.save(&mut conn).await?;
+
+    if CONFIG.mail_enabled() {
+        if let Err(e) = mail::send_registration_approved(&user.email).await {
+            error!("Error sending registration approved email: {e:#?}");
+        }
+    }
+    Ok(())
+}
+
+#[post("/users/<user_id>/reject", format = "application/json")]
+async fn reject_user(user_id: UserId, _token: AdminToken, mut conn: DbConn) -> EmptyResult {
+    let user = get_user_or_404(&user_id, &mut conn).await?;
+    if !user.pending_approval {
+        err_code!("User is not awaiting approval", Status::BadRequest.code);
+    }
+
+    // A user awaiting approval was never able to log in, so there is nothing else to clean up
+    user.delete(&mut conn).await
+}
+
+#[derive(Debug, Deserialize)]
+struct SsoOnlyData {
+    sso_only: bool,
+}
+
+#[post("/users/<user_id>/sso-only", format = "application/json", data = "<data>")]
+async fn set_user_sso_only(
+    user_id: UserId,
+    data: Json<SsoOnlyData>,
+    _token: AdminToken,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> EmptyResult {
+    let sso_only = data.into_inner().sso_only;
+    let mut user = get_user_or_404(&user_id, &mut conn).await?;
+
+    if sso_only {
+        if !CONFIG.sso_enabled() {
+            err!("SSO is not enabled")
+        }
+        if !matches!(SsoUser::find_by_mail(&user.email, &conn).await, Some((_, Some(_)))) {
+            err!("The user has not signed in with SSO yet")
+        }
+    }
+
+    if user.sso_only == sso_only {
+        return Ok(());
+    }
+
+    info!("Admin changed SSO-only for user {} ({}) to {sso_only}", user.uuid, user.email);
+    user.sso_only = sso_only;
+    user.save(&mut conn).await?;
+
+    // Let the clients refresh the profile
+    nt.send_user_update(UpdateType::SyncSettings, &user, &None, &mut conn).await;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct StorageLimitsData {
+    // Limits in KB, `None` means the global limit is used
+    attachment_limit: Option<i64>,
+    send_limit: Option<i64>,
+}
+
+#[post("/users/<user_id>/limits", format = "application/json", data = "<data>")]
+async fn set_user_storage_limits(
+    user_id: UserId,
+    data: Json<StorageLimitsData>,
+    _token: AdminToken,
+    mut conn: DbConn,
+) -> EmptyResult {
+    let data = data.into_inner();
+    for (name, limit) in [("attachment_limit", data.attachment_limit), ("send_limit", data.send_limit)] {
+        if limit.is_some_and(|l| !(0..=MAX_FILESIZE_KB).contains(&l)) {
+            err!(format!("`{name}` is out of bounds"))
+        }
+    }
+
+    let mut user = get_user_or_404(&user_id, &mut conn).await?;
+    info!(
+        "Admin changed the storage limits of user {} ({}): attachments {:?} -> {:?} KB, sends {:?} -> {:?} KB",
+        user.uuid, user.email, user.attachment_limit, data.attachment_limit, user.send_limit, data.send_limit
+    );
+
+    user.attachment_limit = data.attachment_limit;
+    user.send_limit = data.send_limit;
+    user.save(&mut conn).await
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MergeUserData {
+    source_id: UserId,
+    #[serde(default)]
+    dry_run: bool,
+    // Needs to match the email of the source user, the merge can't be undone
+    confirm_email: Option<String>,
+    // The items of the source user, re-encrypted for the target user. Not needed when both users have the same keys.
+    #[serde(default)]
+    ciphers: Vec<MergeCipherData>,
+    #[serde(default)]
+    folders: Vec<MergeFolderData>,
+    #[serde(default)]
+    sends: Vec<MergeSendData>,
+    #[serde(default)]
+    memberships: Vec<MergeMembershipData>,
+}
+
+// The encrypted fields of a cipher, in the format they are stored in
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MergeCipherData {
+    id: CipherId,
+    key: Option<String>,
+    name: String,
+    notes: Option<String>,
+    fields: Option<String>,
+    data: String,
+    password_history: Option<String>,
+    #[serde(default)]
+    attachments: Vec<MergeAttachmentData>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MergeAttachmentData {
+    id: AttachmentId,
+    key: Option<String>,
+    file_name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MergeFolderData {
+    id: FolderId,
+    name: String,
+}
+
+// Everything else of a send is encrypted with the send key
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MergeSendData {
+    id: SendId,
+    key: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MergeMembershipData {
+    organization_id: OrganizationId,
+    key: String,
+}
+
+// Moves everything of the source user over to `user_id` and deletes the source user.
+// With `dryRun` nothing is changed, only the number of items which would move is returned.
+#[post("/users/<user_id>/merge", format = "application/json", data = "<data>")]
+async fn merge_user(
+    user_id: UserId,
+    data: Json<MergeUserData>,
+    _token: AdminToken,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
+    let data = data.into_inner();
+    if data.source_id == user_id {
+        err!("Can't merge a user into itself")
+    }
+    let target = get_user_or_404(&user_id, &mut conn).await?;
+    let source = get_user_or_404(&data.source_id, &mut conn).await?;
+
+    let keys_compatible = source.has_same_keys(&target);
+    let target_orgs: Vec<OrganizationId> =
+        Membership::find_any_state_by_user(&target.uuid, &mut conn).await.into_iter().map(|m| m.org_uuid).collect();
+    let (memberships, skipped_memberships): (Vec<Membership>, Vec<Membership>) =
+        Membership::find_any_state_by_user(&source.uuid, &mut conn)
+            .await
+            .into_iter()
+            .partition(|m| !target_orgs.contains(&m.org_uuid));
+
+    let report = json!({
+        "sourceId": source.uuid,
+        "targetId": target.uuid,
+        "keysCompatible": keys_compatible,
+        "ciphers": Cipher::count_owned_by_user(&source.uuid, &mut conn).await,
+        "folders": Folder::find_by_user(&source.uuid, &mut conn).await.len(),
+        "sends": Send::find_by_user(&source.uuid, &mut conn).await.len(),
+        "memberships": memberships.len(),
+        "skippedMemberships": skipped_memberships.len(),
+        "dryRun": data.dry_run,
+    });
+    if data.dry_run {
+        return Ok(Json(report));
+    }
+
+    if data.confirm_email.as_deref().map(str::to_lowercase).as_deref() != Some(source.email.as_str()) {
+        err!("The confirmation email doesn't match the email of the source user")
+    }
+    let reencrypted = if keys_compatible {
+        ReencryptedItems::default()
+    } else {
+        reencrypted_items(&source, data, memberships, &mut conn).await?
+    };
+
+    info!("Admin merged user {} ({}) into user {} ({})", source.uuid, source.email, target.uuid, target.email);
+    source.merge_into(&target, reencrypted, &mut conn).await?;
+
+    User::update_uuid_revision(&target.uuid, &mut conn).await;
+    nt.send_user_update(UpdateType::SyncVault, &target, &None, &mut conn).await;
+    Ok(Json(report))
+}
+
+/// Applies the re-encrypted fields to the items of `source`. Every item which is moved needs to be re-encrypted,
+/// otherwise the target user can't decrypt it.
+async fn reencrypted_items(
+    source: &User,
+    data: MergeUserData,
+    memberships: Vec<Membership>,
+    conn: &mut DbConn,
+) -> ApiResult<ReencryptedItems> {
+    let mut items = ReencryptedItems::default();
+
+    let mut ciphers: HashMap<CipherId, MergeCipherData> = data.ciphers.into_iter().map(|c| (c.id.clone(), c)).collect();
+    for mut cipher in Cipher::find_owned_by_user(&source.uuid, conn).await {
+        let Some(cipher_data) = ciphers.remove(&cipher.uuid) else {
+            err!(format!("Cipher {} wasn't re-encrypted for the target user", cipher.uuid))
+        };
+
+        let mut attachments: HashMap<AttachmentId, MergeAttachmentData> =
+            cipher_data.attachments.into_iter().map(|a| (a.id.clone(), a)).collect();
+        for mut attachment in Attachment::find_by_cipher(&cipher.uuid, conn).await {
+            let Some(attachment_data) = attachments.remove(&attachment.id) else {
+                err!(format!("Attachment {} wasn't re-encrypted for the target user", attachment.id))
+            };
+            attachment.akey = attachment_data.key;
+            attachment.file_name = attachment_data.file_name;
+            items.attachments.push(attachment);
+        }
+        if !attachments.is_empty() {
+            err!(format!("Cipher {} doesn't have all of the re-encrypted attachments", cipher.uuid))
+        }
+
+        cipher.key = cipher_data.key;
+        cipher.name = cipher_data.name;
+        cipher.notes = cipher_data.notes;
+        cipher.fields = cipher_data.fields;
+        cipher.data = cipher_data.data;
+        cipher.password_history = cipher_data.password_history;
+        items.ciphers.push(cipher);
+    }
+
+    let mut folders: HashMap<FolderId, String> = data.folders.into_iter().map(|f| (f.id, f.name)).collect();
+    for mut folder in Folder::find_by_user(&source.uuid, conn).await {
+        let Some(name) = folders.remove(&folder.uuid) else {
+            err!(format!("Folder {} wasn't re-encrypted for the target user", folder.uuid))
+        };
+        folder.name = name;
+        items.folders.push(folder);
+    }
+
+    let mut sends: HashMap<SendId, String> = data.sends.into_iter().map(|s| (s.id, s.key)).collect();
+    for mut send in Send::find_by_user(&source.uuid, conn).await {
+        let Some(key) = sends.remove(&send.uuid) else {
+            err!(format!("Send {} wasn't re-encrypted for the target user", send.uuid))
+        };
+        send.akey = key;
+        items.sends.push(send);
+    }
+
+    // Invited members don't have the organization key yet. The account recovery key contains the key of the source
+    // user, so it's removed and the target has to enroll again.
+    let mut org_keys: HashMap<OrganizationId, String> =
+        data.memberships.into_iter().map(|m| (m.organization_id, m.key)).collect();
+    for mut member in memberships.into_iter().filter(|m| !m.akey.is_empty()) {
+        let Some(key) = org_keys.remove(&member.org_uuid) else {
+            err!(format!("The key of organization {} wasn't re-encrypted for the target user", member.org_uuid))
+        };
+        member.akey = key;
+        member.reset_password_key = None;
+        items.memberships.push(member);
+    }
+
+    if !ciphers.is_empty() || !folders.is_empty() || !sends.is_empty() || !org_keys.is_empty() {
+        err!("Some of the re-encrypted items don't belong to the source user")
+    }
+    Ok(items)
+}
+
 #[post("/users/<user_id>/remove-2fa", format = "application/json")]
 async fn remove_2fa(user_id: UserId, token: AdminToken, mut conn: DbConn) -> EmptyResult {
     let mut user = get_user_or_404(&user_id, &mut conn).await?;
@@ -851,3 +1328,48 @@ impl<'r> FromRequest<'r> for AdminToken {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signup_policy_reflects_config() {
+        let mut policy = SignupPolicy {
+            signups_allowed: true,
+            domains_whitelist: String::new(),
+            signups_verify: true,
+            mail_enabled: false,
+            requires_approval: true,
+            invitations_allowed: true,
+            sso_only: false,
+            node_region: String::new(),
+        };
+        let json = policy.to_json(2);
+        assert_eq!(json["openSignups"], true);
+        assert_eq!(json["invitationRequired"], false);
+        assert_eq!(json["allowedDomains"], json!([]));
+        assert_eq!(json["verificationRequired"], false);
+        assert_eq!(json["approvalRequired"], true);
+        assert_eq!(json["residencyRegion"], Value::Null);
+        assert_eq!(json["pendingApprovalCount"], 2);
+
+        // The domain whitelist restricts signups even when they are allowed
+        policy.domains_whitelist = String::from("example.com, example.org");
+        policy.mail_enabled = true;
+        policy.node_region = String::from("eu");
+        let json = policy.to_json(0);
+        assert_eq!(json["signupsAllowed"], true);
+        assert_eq!(json["openSignups"], false);
+        assert_eq!(json["invitationRequired"], true);
+        assert_eq!(json["allowedDomains"], json!(["example.com", "example.org"]));
+        assert_eq!(json["verificationRequired"], true);
+        assert_eq!(json["residencyRegion"], "eu");
+
+        policy.signups_allowed = false;
+        policy.domains_whitelist = String::new();
+        let json = policy.to_json(0);
+        assert_eq!(json["openSignups"], false);
+        assert_eq!(json["invitationRequired"], true);
+    }
+}
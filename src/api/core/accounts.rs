@@ -1,21 +1,37 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use crate::db::DbPool;
-use chrono::Utc;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use once_cell::sync::Lazy;
 use rocket::serde::json::Json;
 use serde_json::Value;
 
 use crate::{
     api::{
-        core::{accept_org_invite, log_user_event, two_factor::email},
-        master_password_policy, register_push_device, unregister_push_device, AnonymousNotify, ApiResult, EmptyResult,
-        JsonResult, Notify, PasswordOrOtpData, UpdateType,
+        admin::FAKE_ADMIN_UUID,
+        core::{
+            accept_org_invite, accept_user_invitations, enforce_verified_email_for_api_keys,
+            enforce_verified_email_for_sharing,
+            folders::FolderData,
+            log_user_event, log_user_event_by, share_cipher_by_uuid,
+            two_factor::{email, protected_actions::validate_protected_action_otp, twofactor_status},
+            CipherData, ShareCipherData,
+        },
+        master_password_policy, register_push_device, unregister_push_device, with_error_delay, AnonymousNotify,
+        ApiResult, EmptyResult, JsonResult, Notify, PasswordOrOtpData, UpdateType,
+    },
+    auth::{
+        decode_delete, decode_invite, decode_login, decode_register_verify_allow_expired, decode_verify_email,
+        AuthRequestOrigin, ClientHeaders, ClientIp, Headers, InviteJwtClaims, RegisterVerifyClaims,
+        RevokedSessionHeaders, JWT_LEEWAY_SECONDS,
     },
-    auth::{decode_delete, decode_invite, decode_verify_email, ClientHeaders, Headers},
     crypto,
     db::{models::*, DbConn},
     mail,
-    util::{format_date, NumberOrString},
+    util::{format_date, NumberOrString, Tagged},
     CONFIG,
 };
 
@@ -27,19 +43,41 @@ use rocket::{
 pub fn routes() -> Vec<rocket::Route> {
     routes![
         register,
+        register_verification_email,
+        register_finish,
+        post_register_verify_token,
         profile,
         put_profile,
         post_profile,
         put_avatar,
         get_public_keys,
+        get_storage,
+        post_keys_backup,
+        get_attestation,
+        post_attestation_verify,
         post_keys,
+        get_key_history,
         post_password,
         post_set_password,
         post_kdf,
+        post_kdf_upgrade,
         post_rotatekey,
+        get_rotatekey_preview,
+        get_rotatekey_expected_items,
+        post_integrity_scan,
+        get_sync_estimate,
+        get_account_limits,
+        get_security_overview,
         post_sstamp,
+        post_transfer_to_organization,
+        get_pending_invites,
+        post_accept_all_pending_invites,
         post_email_token,
+        post_email_validate,
         post_email,
+        post_recovery_email_token,
+        post_recovery_email,
+        delete_recovery_email,
         post_verify_email,
         post_verify_email_token,
         post_delete_recover,
@@ -50,19 +88,34 @@ pub fn routes() -> Vec<rocket::Route> {
         password_hint,
         prelogin,
         verify_password,
+        get_user_master_password_policy,
+        get_user_policies,
         api_key,
         rotate_api_key,
+        put_api_key_client_cert,
         get_known_device,
+        post_known_device_confirm,
+        get_devices_export,
+        post_devices_import,
         get_all_devices,
         get_device,
+        get_device_approval,
+        put_device_approval_key,
+        put_device_approval,
+        delete_device,
+        post_delete_device,
+        post_deactivate_device,
+        get_revoked_sessions,
         post_device_token,
         put_device_token,
         put_clear_device_token,
         post_clear_device_token,
+        put_device_push_preferences,
         post_auth_request,
         get_auth_request,
         put_auth_request,
         get_auth_request_response,
+        post_auth_request_refresh_code,
         get_auth_requests,
         get_auth_requests_pending,
     ]
@@ -104,6 +157,9 @@ pub struct RegisterData {
     accept_emergency_access_invite_token: Option<String>,
     #[serde(alias = "token")]
     org_invite_token: Option<String>,
+
+    // Vaultwarden specific, an encrypted folder the client wants to start the account with
+    initial_folder: Option<FolderData>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -114,7 +170,8 @@ pub struct SetPasswordData {
 
     key: String,
     keys: Option<KeysData>,
-    master_password_hash: String,
+    // Absent when an SSO user sets up a passwordless account, see `ALLOW_PASSWORDLESS_REGISTRATION`
+    master_password_hash: Option<String>,
     master_password_hint: Option<String>,
     org_identifier: Option<String>,
 }
@@ -126,6 +183,51 @@ struct KeysData {
     public_key: String,
 }
 
+/// Only an SSO user which sets up its account after the first SSO login can leave out the master password,
+/// and only when `ALLOW_PASSWORDLESS_REGISTRATION` is enabled. The SSO identity linked to the account proves the login.
+async fn check_passwordless_setup(user: &User, allowed: bool, conn: &DbConn) -> EmptyResult {
+    if !allowed {
+        err!("Setting up an account requires a master password")
+    }
+    match SsoUser::find_by_mail(&user.email, conn).await {
+        Some((sso_account, Some(_))) if sso_account.uuid == user.uuid => Ok(()),
+        _ => err!("Only accounts which signed in with SSO can be set up without a master password"),
+    }
+}
+
+/// The residency region of a new account: the one of its invitation, else the one it was created with, else the default.
+fn registration_region(
+    invite_region: Option<String>,
+    user_region: Option<String>,
+    default_region: &str,
+    regions: &str,
+) -> ApiResult<Option<String>> {
+    if regions.is_empty() {
+        return Ok(None);
+    }
+    match invite_region.or(user_region).or_else(|| Some(default_region.to_string()).filter(|r| !r.is_empty())) {
+        Some(region) if !crate::config::is_region_in_list(&region, regions) => {
+            err!(format!("Unknown residency region {region}"))
+        }
+        region => Ok(region),
+    }
+}
+
+/// The user key is protected by SSO or device keys instead of the master password.
+/// Without a password hash the account can only sign in with SSO, and needs a one-time code instead of the password to verify.
+fn set_passwordless(user: &mut User, key: String) {
+    user.akey = key;
+    user.sso_only = true;
+}
+
+/// Some clients generate the keypair after the registration, unless required the keys can be omitted.
+fn check_registration_keys(keys: Option<&KeysData>, required: bool) -> EmptyResult {
+    if required && keys.is_none() {
+        err!("Registration requires the account keys, `keys.encryptedPrivateKey` and `keys.publicKey` are missing")
+    }
+    Ok(())
+}
+
 /// Trims whitespace from password hints, and converts blank password hints to `None`.
 fn clean_password_hint(password_hint: &Option<String>) -> Option<String> {
     match password_hint {
@@ -157,17 +259,155 @@ async fn is_email_2fa_required(member_id: Option<MembershipId>, conn: &mut DbCon
 }
 
 #[post("/accounts/register", data = "<data>")]
-async fn register(data: Json<RegisterData>, conn: DbConn) -> JsonResult {
-    _register(data, false, conn).await
+async fn register(data: Json<RegisterData>, client_headers: ClientHeaders, conn: DbConn, nt: Notify<'_>) -> JsonResult {
+    with_error_delay(_register(data, false, client_headers, conn, nt)).await
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisterVerificationData {
+    email: String,
+    name: Option<String>,
+    // receiveMarketingEmails: bool,
+}
+
+#[derive(rocket::Responder)]
+pub enum RegisterVerificationResponse {
+    NoContent(()),
+    Token(Json<String>),
+}
+
+#[post("/accounts/register/send-verification-email", data = "<data>")]
+async fn register_verification_email(
+    data: Json<RegisterVerificationData>,
+    conn: DbConn,
+) -> ApiResult<RegisterVerificationResponse> {
+    _register_verification_email(data, conn).await
+}
+
+pub async fn _register_verification_email(
+    data: Json<RegisterVerificationData>,
+    mut conn: DbConn,
+) -> ApiResult<RegisterVerificationResponse> {
+    let data = data.into_inner();
+
+    // the registration can only continue if signup is allowed or there exists an invitation
+    if !(CONFIG.is_signup_allowed(&data.email)
+        || (!CONFIG.mail_enabled() && Invitation::find_by_mail(&data.email, &mut conn).await.is_some()))
+    {
+        err!("Registration not allowed or user already exists")
+    }
+
+    let should_send_mail = CONFIG.mail_enabled() && CONFIG.signups_verify();
+
+    let token_claims =
+        crate::auth::generate_register_verify_claims(data.email.clone(), data.name.clone(), should_send_mail);
+    let token = crate::auth::encode_jwt(&token_claims);
+
+    if should_send_mail {
+        let user = User::find_by_mail(&data.email, &mut conn).await;
+        if user.filter(|u| u.private_key.is_some()).is_some() {
+            // There is still a timing side channel here in that the code
+            // paths that send mail take noticeably longer than ones that
+            // don't. Add a randomized sleep to mitigate this somewhat.
+            tokio::time::sleep(crate::util::randomized_delay(1_000, 100)).await;
+        } else {
+            mail::send_register_verify_email(&data.email, &token).await?;
+        }
+
+        Ok(RegisterVerificationResponse::NoContent(()))
+    } else {
+        // If email verification is not required, return the token directly
+        // the clients will use this token to finish the registration
+        Ok(RegisterVerificationResponse::Token(Json(token)))
+    }
+}
+
+#[post("/accounts/register/finish", data = "<data>")]
+async fn register_finish(
+    data: Json<RegisterData>,
+    client_headers: ClientHeaders,
+    conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
+    with_error_delay(_register(data, true, client_headers, conn, nt)).await
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RegisterVerifyTokenData {
+    email_verification_token: String,
+    email: Option<String>,
+}
+
+// Lets a client check the token from the verification email before asking for a password.
+// This never looks up if an account exists for the email, that is only checked when finishing the registration.
+#[post("/accounts/register/verify-token", data = "<data>")]
+fn post_register_verify_token(data: Json<RegisterVerifyTokenData>) -> Json<Value> {
+    let data = data.into_inner();
+    let claims = decode_register_verify_allow_expired(&data.email_verification_token).ok();
+    Json(register_verify_token_status(claims, data.email.as_deref(), Utc::now().timestamp()))
+}
+
+fn register_verify_token_status(claims: Option<RegisterVerifyClaims>, email: Option<&str>, now: i64) -> Value {
+    let claims = claims.filter(|c| email.is_none_or(|email| c.sub == email.to_lowercase()));
+    let expired = claims.as_ref().is_some_and(|c| c.exp + (JWT_LEEWAY_SECONDS as i64) < now);
+
+    match claims {
+        Some(claims) if !expired => json!({
+            "valid": true,
+            "expired": false,
+            "email": claims.sub,
+            "name": claims.name,
+            "verified": claims.verified,
+            "object": "registerVerifyToken",
+        }),
+        _ => json!({
+            "valid": false,
+            "expired": expired,
+            "email": null,
+            "name": null,
+            "verified": false,
+            "object": "registerVerifyToken",
+        }),
+    }
+}
+
+/// Checks the decoded email verification claims against the provided registration data.
+/// The register/finish call doesn't contain the name of the user, so it is taken from the claims if available.
+/// Returns whether the email address has been verified.
+fn apply_register_verify_claims(data: &mut RegisterData, claims: RegisterVerifyClaims) -> ApiResult<bool> {
+    if claims.sub != data.email {
+        err!("Email verification token does not match email");
+    }
+
+    if claims.name.is_some() {
+        data.name = claims.name;
+    }
+    Ok(claims.verified)
 }
 
-pub async fn _register(data: Json<RegisterData>, email_verification: bool, mut conn: DbConn) -> JsonResult {
+pub async fn _register(
+    data: Json<RegisterData>,
+    email_verification: bool,
+    client_headers: ClientHeaders,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
     let mut data: RegisterData = data.into_inner();
     let email = data.email.to_lowercase();
 
+    crate::ratelimit::check_limit_signup(&client_headers.ip.ip, &email)?;
+    check_registration_keys(data.keys.as_ref(), CONFIG.signups_require_keys())?;
+    if let Some(folder) = &data.initial_folder {
+        folder.validate()?;
+    }
+
     let mut email_verified = false;
 
     let mut pending_emergency_access = None;
+    let mut emergency_access_to_provision = None;
+    let mut invite_claims = None;
 
     // First, validate the provided verification tokens
     if email_verification {
@@ -181,15 +421,7 @@ pub async fn _register(data: Json<RegisterData>, email_verification: bool, mut c
             // Normal user registration, when email verification is required
             (Some(email_verification_token), None, None, None, None) => {
                 let claims = crate::auth::decode_register_verify(email_verification_token)?;
-                if claims.sub != data.email {
-                    err!("Email verification token does not match email");
-                }
-
-                // During this call we don't get the name, so extract it from the claims
-                if claims.name.is_some() {
-                    data.name = claims.name;
-                }
-                email_verified = claims.verified;
+                email_verified = apply_register_verify_claims(&mut data, claims)?;
             }
             // Emergency access registration
             (None, Some(accept_emergency_access_id), Some(accept_emergency_access_invite_token), None, None) => {
@@ -206,6 +438,18 @@ pub async fn _register(data: Json<RegisterData>, email_verification: bool, mut c
                     err!("Claim emer_id does not match accept_emergency_access_id")
                 }
 
+                // Only provision invites which are still open and were sent by the grantor named in the claims
+                if let Some(emergency_access) =
+                    EmergencyAccess::find_by_uuid_and_grantee_email(accept_emergency_access_id, &email, &mut conn).await
+                {
+                    let grantor = User::find_by_uuid(&emergency_access.grantor_uuid, &mut conn).await;
+                    if emergency_access.status == EmergencyAccessStatus::Invited as i32
+                        && grantor.is_some_and(|g| g.name == claims.grantor_name && g.email == claims.grantor_email)
+                    {
+                        emergency_access_to_provision = Some(emergency_access);
+                    }
+                }
+
                 pending_emergency_access = Some((accept_emergency_access_id, claims));
                 email_verified = true;
             }
@@ -236,6 +480,7 @@ pub async fn _register(data: Json<RegisterData>, email_verification: bool, mut c
         if name.len() > 50 {
             err!("The field Name must be a string with a maximum length of 50.");
         }
+        crate::ratelimit::check_limit_signup_name(name)?;
     }
 
     // Check against the password hint setting here so if it fails, the user
@@ -245,7 +490,20 @@ pub async fn _register(data: Json<RegisterData>, email_verification: bool, mut c
 
     let mut user = match User::find_by_mail(&email, &mut conn).await {
         Some(user) => {
-            if !user.password_hash.is_empty() {
+            // Passwordless accounts don't have a password hash either, but are already registered
+            if !user.password_hash.is_empty() || user.sso_only {
+                if let Some(token) =
+                    data.org_invite_token.as_deref().filter(|_| CONFIG.invite_existing_user_auto_accept())
+                {
+                    return accept_invite_for_existing_user(
+                        &user,
+                        token,
+                        data.organization_user_id.as_ref(),
+                        &client_headers,
+                        &mut conn,
+                    )
+                    .await;
+                }
                 err!("Registration not allowed or user already exists")
             }
 
@@ -254,12 +512,13 @@ pub async fn _register(data: Json<RegisterData>, email_verification: bool, mut c
                 if claims.email == email {
                     // Verify the email address when signing up via a valid invite token
                     email_verified = true;
+                    invite_claims = Some(claims);
                     user
                 } else {
                     err!("Registration email does not match invite email")
                 }
             } else if Invitation::take(&email, &mut conn).await {
-                Membership::accept_user_invitations(&user.uuid, &mut conn).await?;
+                accept_user_invitations(&user.uuid, &mut conn).await?;
                 user
             } else if CONFIG.is_signup_allowed(&email)
                 || (CONFIG.emergency_access_allowed()
@@ -274,11 +533,13 @@ pub async fn _register(data: Json<RegisterData>, email_verification: bool, mut c
             // Order is important here; the invitation check must come first
             // because the vaultwarden admin can invite anyone, regardless
             // of other signup restrictions.
-            if Invitation::take(&email, &mut conn).await
-                || CONFIG.is_signup_allowed(&email)
-                || pending_emergency_access.is_some()
-            {
+            if Invitation::take(&email, &mut conn).await || pending_emergency_access.is_some() {
                 User::new(email.clone(), None)
+            } else if CONFIG.is_signup_allowed(&email) {
+                // Only open signups need to be approved, invited users are vouched for by whoever invited them
+                let mut user = User::new(email.clone(), None);
+                user.pending_approval = CONFIG.registration_requires_approval();
+                user
             } else {
                 err!("Registration not allowed or user already exists")
             }
@@ -288,8 +549,25 @@ pub async fn _register(data: Json<RegisterData>, email_verification: bool, mut c
     // Make sure we don't leave a lingering invitation.
     Invitation::take(&email, &mut conn).await;
 
+    user.region = registration_region(
+        invite_claims.as_ref().and_then(|c| c.region.clone()),
+        user.region.take(),
+        &CONFIG.residency_default_region(),
+        &CONFIG.residency_regions(),
+    )?;
+    if !CONFIG.serves_region(user.region.as_deref()) {
+        err!("Registration not allowed or user already exists", "The account belongs to another residency region")
+    }
+
     set_kdf_data(&mut user, data.kdf)?;
 
+    // Invited users already have their memberships, new users without an invite have none
+    let mut invited_orgs = Vec::new();
+    for membership in Membership::find_any_state_by_user(&user.uuid, &mut conn).await {
+        invited_orgs.extend(Organization::find_by_uuid(&membership.org_uuid, &mut conn).await);
+    }
+    check_org_kdf_minimums(&user, &invited_orgs)?;
+
     user.set_password(&data.master_password_hash, Some(data.key), true, None);
     user.password_hint = password_hint;
 
@@ -308,13 +586,22 @@ pub async fn _register(data: Json<RegisterData>, email_verification: bool, mut c
     }
 
     if CONFIG.mail_enabled() {
+        if user.pending_approval {
+            if let Err(e) = mail::send_registration_pending_approval(&user.email).await {
+                error!("Error sending pending approval email: {e:#?}");
+            }
+        }
+
         if CONFIG.signups_verify() && !email_verified {
             if let Err(e) = mail::send_welcome_must_verify(&user.email, &user.uuid).await {
                 error!("Error sending welcome email: {e:#?}");
             }
             user.last_verifying_at = Some(user.created_at);
-        } else if let Err(e) = mail::send_welcome(&user.email).await {
-            error!("Error sending welcome email: {e:#?}");
+            user.signup_verification = true;
+        } else if !user.pending_approval {
+            if let Err(e) = mail::send_welcome(&user.email).await {
+                error!("Error sending welcome email: {e:#?}");
+            }
         }
 
         if email_verified && is_email_2fa_required(data.organization_user_id, &mut conn).await {
@@ -324,21 +611,170 @@ pub async fn _register(data: Json<RegisterData>, email_verification: bool, mut c
 
     user.save(&mut conn).await?;
 
+    if let Some(mut folder) = initial_folder(&user, data.initial_folder) {
+        folder.save(&mut conn).await?;
+    }
+
+    if let Some(claims) = invite_claims {
+        if CONFIG.invite_registration_notify() && CONFIG.mail_enabled() {
+            notify_invite_registration(&user, &claims, &mut conn).await;
+        }
+    }
+
+    // When registering via an emergency access invite, accept it right away if the grantee keys are present,
+    // so that the grantor can confirm it without the grantee having to accept it separately after logging in.
+    // Without keys the grantor can't confirm anyway, so the invite is left for the regular accept call.
+    if let Some(emergency_access) = emergency_access_to_provision {
+        if user.public_key.is_some() {
+            let emergency_access_id = emergency_access.uuid.clone();
+            if let Err(e) = accept_pending_emergency_access_invite(&user, emergency_access, &mut conn).await {
+                error!("Error accepting emergency access {emergency_access_id} during registration: {e:#?}");
+            }
+        }
+    }
+
     // accept any open emergency access invitations
     if !CONFIG.mail_enabled() && CONFIG.emergency_access_allowed() {
         for mut emergency_invite in EmergencyAccess::find_all_invited_by_grantee_email(&user.email, &mut conn).await {
-            emergency_invite.accept_invite(&user.uuid, &user.email, &mut conn).await.ok();
+            if emergency_invite.accept_invite(&user.uuid, &user.email, &mut conn).await.is_err() {
+                continue;
+            }
+
+            // The grantor did not take any action, so log the event on the grantor's account as done by the new user
+            info!(
+                "Emergency access {} of user {} automatically accepted by new user {}",
+                emergency_invite.uuid, emergency_invite.grantor_uuid, user.uuid
+            );
+            log_user_event_by(
+                EventType::UserEmergencyAccessAutoAccepted as i32,
+                &emergency_invite.grantor_uuid,
+                &user.uuid,
+                client_headers.device_type,
+                &client_headers.ip.ip,
+                &mut conn,
+                &nt,
+            )
+            .await;
+
+            if CONFIG.emergency_access_auto_accept_notify() {
+                if let Some(grantor) = User::find_by_uuid(&emergency_invite.grantor_uuid, &mut conn).await {
+                    nt.send_user_update(UpdateType::SyncSettings, &grantor, &None, &mut conn).await;
+                }
+            }
+        }
+    }
+
+    Ok(Json(json!({
+      "object": "register",
+      "captchaBypassToken": captcha_bypass_token(&user.email),
+    })))
+}
+
+/// The folder is encrypted with the new user key, it was already validated before the user got created
+fn initial_folder(user: &User, data: Option<FolderData>) -> Option<Folder> {
+    data.map(|folder| Folder::new(user.uuid.clone(), folder.name))
+}
+
+/// Best effort, a failure to notify the organization admins doesn't fail the registration
+async fn notify_invite_registration(user: &User, claims: &InviteJwtClaims, conn: &mut DbConn) {
+    let Some((org, admins)) = invite_registration_admins(user, claims, conn).await else {
+        return;
+    };
+
+    let joined_at = Utc::now().naive_utc();
+    for admin_user in admins {
+        if let Err(e) = mail::send_invite_registered(&admin_user.email, &user.email, &org.name, &joined_at).await {
+            error!("Error sending invite registration email to {}: {e:#?}", admin_user.email);
+        }
+    }
+}
+
+/// The organization the new member was invited to, together with the users which get notified about the registration
+async fn invite_registration_admins(
+    user: &User,
+    claims: &InviteJwtClaims,
+    conn: &mut DbConn,
+) -> Option<(Organization, Vec<User>)> {
+    // Invites sent from the admin panel are not linked to an organization
+    if *claims.member_id == FAKE_ADMIN_UUID {
+        return None;
+    }
+    let member = Membership::find_by_uuid_and_org(&claims.member_id, &claims.org_id, conn)
+        .await
+        .filter(|m| m.user_uuid == user.uuid)?;
+    let org = Organization::find_by_uuid(&member.org_uuid, conn).await?;
+
+    let mut admins = Vec::new();
+    for admin in invite_registration_recipients(Membership::find_confirmed_by_org(&org.uuid, conn).await, &user.uuid) {
+        if let Some(admin_user) = User::find_by_uuid(&admin.user_uuid, conn).await {
+            admins.push(admin_user);
         }
     }
+    Some((org, admins))
+}
+
+/// The confirmed owners and admins of the organization the new member was invited to
+fn invite_registration_recipients(members: Vec<Membership>, new_user_id: &UserId) -> Vec<Membership> {
+    members.into_iter().filter(|m| m.atype >= MembershipType::Admin && &m.user_uuid != new_user_id).collect()
+}
+
+/// Empty unless `CAPTCHA_BYPASS_TOKEN_MINUTES` is set
+fn captcha_bypass_token(email: &str) -> String {
+    match CONFIG.captcha_bypass_token_minutes() {
+        Some(minutes) => crate::auth::encode_jwt(&crate::auth::generate_captcha_bypass_claims(email, minutes)),
+        None => String::new(),
+    }
+}
+
+/// Used when an invited user goes through the registration, while their email already belongs to an account.
+/// Instead of failing, the invite gets accepted for the existing account, the user keeps using their current password.
+async fn accept_invite_for_existing_user(
+    user: &User,
+    token: &str,
+    member_id: Option<&MembershipId>,
+    client_headers: &ClientHeaders,
+    conn: &mut DbConn,
+) -> JsonResult {
+    let claims = decode_invite(token)?;
+    if claims.email != user.email {
+        err!("Registration email does not match invite email")
+    }
+    if member_id != Some(&claims.member_id) {
+        err!("Claim org_user_id does not match organization_user_id")
+    }
+    // Invites sent from the admin panel are not linked to an organization, there is nothing to accept
+    if *claims.member_id == FAKE_ADMIN_UUID {
+        err!("Registration not allowed or user already exists")
+    }
+
+    let Some(member) = Membership::find_by_uuid_and_org(&claims.member_id, &claims.org_id, conn).await else {
+        err!("Error accepting the invitation")
+    };
+    if member.user_uuid != user.uuid {
+        err!("Error accepting the invitation", "Membership does not belong to the existing user")
+    }
+
+    accept_pending_org_invite(user, member, None, conn).await?;
+    Invitation::take(&user.email, conn).await;
+
+    info!(
+        "Organization invite {} of existing user {} accepted during registration from IP {}",
+        claims.member_id, user.uuid, client_headers.ip.ip
+    );
 
     Ok(Json(json!({
       "object": "register",
-      "captchaBypassToken": "",
+      "message": "An account with this email address already exists. The invitation has been accepted, log in with your existing account.",
     })))
 }
 
 #[post("/accounts/set-password", data = "<data>")]
-async fn post_set_password(data: Json<SetPasswordData>, headers: Headers, mut conn: DbConn) -> JsonResult {
+async fn post_set_password(
+    data: Json<SetPasswordData>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
     let data: SetPasswordData = data.into_inner();
     let mut user = headers.user;
 
@@ -353,12 +789,16 @@ async fn post_set_password(data: Json<SetPasswordData>, headers: Headers, mut co
 
     set_kdf_data(&mut user, data.kdf)?;
 
-    user.set_password(
-        &data.master_password_hash,
-        Some(data.key),
-        false,
-        Some(vec![String::from("revision_date")]), // We need to allow revision-date to use the old security_timestamp
-    );
+    match &data.master_password_hash {
+        // We need to allow revision-date to use the old security_timestamp
+        Some(master_password_hash) => {
+            user.set_password(master_password_hash, Some(data.key), false, Some(vec![String::from("revision_date")]))
+        }
+        None => {
+            check_passwordless_setup(&user, CONFIG.allow_passwordless_registration(), &conn).await?;
+            set_passwordless(&mut user, data.key);
+        }
+    }
     user.password_hint = password_hint;
 
     if let Some(keys) = data.keys {
@@ -385,11 +825,18 @@ async fn post_set_password(data: Json<SetPasswordData>, headers: Headers, mut co
     if CONFIG.mail_enabled() {
         mail::send_welcome(&user.email.to_lowercase()).await?;
     } else {
-        Membership::accept_user_invitations(&user.uuid, &mut conn).await?;
+        accept_user_invitations(&user.uuid, &mut conn).await?;
     }
 
-    log_user_event(EventType::UserChangedPassword as i32, &user.uuid, headers.device.atype, &headers.ip.ip, &mut conn)
-        .await;
+    log_user_event(
+        EventType::UserChangedPassword as i32,
+        &user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        &mut conn,
+        &nt,
+    )
+    .await;
 
     user.save(&mut conn).await?;
 
@@ -400,8 +847,8 @@ async fn post_set_password(data: Json<SetPasswordData>, headers: Headers, mut co
 }
 
 #[get("/accounts/profile")]
-async fn profile(headers: Headers, mut conn: DbConn) -> Json<Value> {
-    Json(headers.user.to_json(&mut conn).await)
+async fn profile(headers: Headers, mut conn: DbConn) -> Tagged {
+    Tagged::new(headers.user.to_json(&mut conn).await)
 }
 
 #[derive(Debug, Deserialize)]
@@ -409,6 +856,8 @@ async fn profile(headers: Headers, mut conn: DbConn) -> Json<Value> {
 struct ProfileData {
     // culture: String, // Ignored, always use en-US
     name: String,
+    // Vaultwarden specific, opt-out of optional third-party services like the push relay and HIBP
+    external_services: Option<bool>,
 }
 
 #[put("/accounts/profile", data = "<data>")]
@@ -429,10 +878,42 @@ async fn post_profile(data: Json<ProfileData>, headers: Headers, mut conn: DbCon
     let mut user = headers.user;
     user.name = data.name;
 
+    if let Some(external_services) = data.external_services {
+        if !external_services && user.allows_external_services() {
+            // Stop relaying push notifications for this user. The devices will register again
+            // with a new token once the user allows external services again.
+            for device in Device::find_push_devices_by_user(&user.uuid, &mut conn).await {
+                if let Err(e) = unregister_push_device(&device.push_uuid).await {
+                    warn!("Unable to unregister push device {}: {e}", device.uuid);
+                }
+                Device::clear_push_token_by_uuid(&device.uuid, &mut conn).await?;
+            }
+        }
+        user.external_services = Some(external_services);
+    }
+
     user.save(&mut conn).await?;
     Ok(Json(user.to_json(&mut conn).await))
 }
 
+/// Vaultwarden specific, shows the used storage and the effective limits of the user in bytes
+#[get("/accounts/storage")]
+async fn get_storage(headers: Headers, mut conn: DbConn) -> JsonResult {
+    let user = &headers.user;
+    let attachments_used = Attachment::size_by_user(&user.uuid, &mut conn).await;
+    let Some(sends_used) = Send::size_by_user(&user.uuid, &mut conn).await else {
+        err!("Existing sends overflow")
+    };
+
+    Ok(Json(json!({
+        "attachmentsUsed": attachments_used,
+        "attachmentsLimit": user.effective_attachment_limit().map(|kb| kb.saturating_mul(1024)),
+        "sendsUsed": sends_used,
+        "sendsLimit": user.effective_send_limit().map(|kb| kb.saturating_mul(1024)),
+        "object": "storage"
+    })))
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct AvatarData {
@@ -459,8 +940,21 @@ async fn put_avatar(data: Json<AvatarData>, headers: Headers, mut conn: DbConn)
     Ok(Json(user.to_json(&mut conn).await))
 }
 
+/// Returns whether the lookup is anonymous and has to be rate limited, anonymous lookups are only allowed when enabled.
+fn check_public_key_lookup(authenticated: bool, anonymous_allowed: bool) -> ApiResult<bool> {
+    match (authenticated, anonymous_allowed) {
+        (true, _) => Ok(false),
+        (false, true) => Ok(true),
+        (false, false) => err_code!("Unauthorized", Status::Unauthorized.code),
+    }
+}
+
 #[get("/users/<user_id>/public-key")]
-async fn get_public_keys(user_id: UserId, _headers: Headers, mut conn: DbConn) -> JsonResult {
+async fn get_public_keys(user_id: UserId, headers: Option<Headers>, ip: ClientIp, mut conn: DbConn) -> JsonResult {
+    if check_public_key_lookup(headers.is_some(), CONFIG.public_key_lookup_anonymous())? {
+        crate::ratelimit::check_limit_public_key_lookup(&ip.ip)?;
+    }
+
     let user = match User::find_by_uuid(&user_id, &mut conn).await {
         Some(user) if user.public_key.is_some() => user,
         Some(_) => err_code!("User has no public_key", Status::NotFound.code),
@@ -479,12 +973,19 @@ async fn post_keys(data: Json<KeysData>, headers: Headers, mut conn: DbConn) ->
     let data: KeysData = data.into_inner();
 
     let mut user = headers.user;
+    let replaces_keypair = user.private_key.is_some();
 
     user.private_key = Some(data.encrypted_private_key);
     user.public_key = Some(data.public_key);
 
     user.save(&mut conn).await?;
 
+    if replaces_keypair {
+        if let Err(e) = KeyHistory::record(&user, KeyChangeType::Keypair, &mut conn).await {
+            error!("Error saving key history: {e:#?}");
+        }
+    }
+
     Ok(Json(json!({
         "privateKey": user.private_key,
         "publicKey": user.public_key,
@@ -492,30 +993,155 @@ async fn post_keys(data: Json<KeysData>, headers: Headers, mut conn: DbConn) ->
     })))
 }
 
+// Vaultwarden specific, only the versions and dates of the key changes are returned, no key material
+#[get("/accounts/keys/history")]
+async fn get_key_history(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let history = KeyHistory::find_by_user(&headers.user.uuid, &mut conn).await;
+
+    Json(json!({
+        "version": history.first().map_or(0, |h| h.version),
+        "accountKeyVersion": headers.user.key_version,
+        "history": history.iter().map(KeyHistory::to_json).collect::<Vec<Value>>(),
+        "object": "keyHistory",
+    }))
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ChangePassData {
-    master_password_hash: String,
+    // Either the current master password hash, or for SSO-only accounts a protected action OTP
+    master_password_hash: Option<String>,
+    otp: Option<String>,
     new_master_password_hash: String,
     master_password_hint: Option<String>,
     key: String,
+
+    #[serde(flatten)]
+    claimed_kdf: ClaimedKdfData,
+}
+
+/// The KDF settings a client says it used to derive a new master password hash and key.
+/// Only sent by some clients, the settings themselves can only be changed with `/accounts/kdf`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClaimedKdfData {
+    kdf: Option<i32>,
+    kdf_iterations: Option<i32>,
+    kdf_memory: Option<i32>,
+    kdf_parallelism: Option<i32>,
+}
+
+/// Rejects a new master password which the client claims to have derived with other KDF settings than those of the account,
+/// as every later login would use the settings of the account. This can't verify the settings which were actually used,
+/// the hash looks the same either way, so a client which claims nothing or lies about it passes this check.
+fn check_claimed_kdf(user: &User, claimed: &ClaimedKdfData) -> EmptyResult {
+    let matches = claimed.kdf.is_none_or(|kdf| kdf == user.client_kdf_type)
+        && claimed.kdf_iterations.is_none_or(|iterations| iterations == user.client_kdf_iter)
+        && claimed.kdf_memory.is_none_or(|memory| Some(memory) == user.client_kdf_memory)
+        && claimed.kdf_parallelism.is_none_or(|parallelism| Some(parallelism) == user.client_kdf_parallelism);
+    if !matches {
+        err!(
+            "The KDF settings don't match the settings of the account. Change them in the KDF settings instead",
+            format!("Claimed KDF settings {claimed:?} of user {} don't match the account", user.uuid)
+        )
+    }
+    Ok(())
+}
+
+/// Verifies the proof provided to change the master password, its KDF settings or the account keys.
+/// SSO-only accounts can't authenticate with their master password, they need to provide a protected action OTP instead.
+async fn verify_master_password_proof(
+    user: &User,
+    master_password_hash: Option<&str>,
+    otp: Option<&str>,
+    conn: &mut DbConn,
+) -> EmptyResult {
+    if user.sso_only {
+        let Some(otp) = otp else {
+            err!("This account can only be verified with a one-time code")
+        };
+        validate_protected_action_otp(otp, &user.uuid, true, conn).await
+    } else if master_password_hash.is_some_and(|hash| user.check_valid_password(hash)) {
+        Ok(())
+    } else {
+        err!("Invalid password")
+    }
+}
+
+/// Rejects a new master password which matches the current one or one of the previous `depth` ones kept in the history.
+/// The current password is only added to the history by `record_password_history`, once the new one is saved.
+async fn enforce_password_history(
+    user: &User,
+    new_password_hash: &str,
+    depth: usize,
+    conn: &mut DbConn,
+) -> EmptyResult {
+    if depth == 0 {
+        return Ok(());
+    }
+
+    let reused = user.check_valid_password(new_password_hash)
+        || PasswordHistory::find_by_user(&user.uuid, conn)
+            .await
+            .iter()
+            .take(depth)
+            .any(|h| h.matches(new_password_hash));
+    if reused {
+        err!(format!("You can't reuse any of your last {depth} master passwords"))
+    }
+    Ok(())
+}
+
+/// Adds the replaced master password to the history, which is then trimmed to `depth` entries.
+/// The new password is already saved at this point, so a failure is only logged.
+async fn record_password_history(previous: PasswordHistory, depth: usize, conn: &mut DbConn) {
+    if depth == 0 {
+        return;
+    }
+    let user_id = previous.user_uuid.clone();
+    if let Err(e) = previous.save(conn).await {
+        error!("Error recording the password history of user {user_id}: {e:#?}");
+    } else if let Err(e) = PasswordHistory::prune_by_user(&user_id, depth, conn).await {
+        error!("Error pruning the password history of user {user_id}: {e:#?}");
+    }
 }
 
 #[post("/accounts/password", data = "<data>")]
-async fn post_password(data: Json<ChangePassData>, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
+async fn post_password(data: Json<ChangePassData>, headers: Headers, conn: DbConn, nt: Notify<'_>) -> EmptyResult {
+    _post_password(data, headers, conn, nt, CONFIG.master_password_history() as usize).await
+}
+
+/// Changes the master password, the last `history_depth` master passwords can't be reused
+async fn _post_password(
+    data: Json<ChangePassData>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+    history_depth: usize,
+) -> EmptyResult {
     let data: ChangePassData = data.into_inner();
     let mut user = headers.user;
 
-    if !user.check_valid_password(&data.master_password_hash) {
-        err!("Invalid password")
+    verify_master_password_proof(&user, data.master_password_hash.as_deref(), data.otp.as_deref(), &mut conn).await?;
+    if CONFIG.password_change_kdf_check() {
+        check_claimed_kdf(&user, &data.claimed_kdf)?;
     }
 
     user.password_hint = clean_password_hint(&data.master_password_hint);
     enforce_password_hint_setting(&user.password_hint)?;
+    enforce_password_history(&user, &data.new_master_password_hash, history_depth, &mut conn).await?;
 
-    log_user_event(EventType::UserChangedPassword as i32, &user.uuid, headers.device.atype, &headers.ip.ip, &mut conn)
-        .await;
+    log_user_event(
+        EventType::UserChangedPassword as i32,
+        &user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        &mut conn,
+        &nt,
+    )
+    .await;
 
+    let previous_password = PasswordHistory::from_user(&user);
     user.set_password(
         &data.new_master_password_hash,
         Some(data.key),
@@ -529,6 +1155,9 @@ async fn post_password(data: Json<ChangePassData>, headers: Headers, mut conn: D
     );
 
     let save_result = user.save(&mut conn).await;
+    if save_result.is_ok() {
+        record_password_history(previous_password, history_depth, &mut conn).await;
+    }
 
     // Prevent logging out the client where the user requested this endpoint from.
     // If you do logout the user it will causes issues at the client side.
@@ -538,13 +1167,22 @@ async fn post_password(data: Json<ChangePassData>, headers: Headers, mut conn: D
     save_result
 }
 
+/// The device which keeps its session after a key rotation, based on `KEY_ROTATION_LOGOUT_SCOPE`
+fn key_rotation_logout_exception(scope: &str, acting_device_id: &DeviceId) -> Option<DeviceId> {
+    match scope {
+        "everywhere" => None,
+        _ => Some(acting_device_id.clone()),
+    }
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ChangeKdfData {
     #[serde(flatten)]
     kdf: KDFData,
 
-    master_password_hash: String,
+    master_password_hash: Option<String>,
+    otp: Option<String>,
     new_master_password_hash: String,
     key: String,
 }
@@ -584,21 +1222,133 @@ fn set_kdf_data(user: &mut User, data: KDFData) -> EmptyResult {
     Ok(())
 }
 
+/// Organizations can require stronger KDF settings than the server does.
+/// An invited user has to meet the minimums of every organization they were invited to.
+fn check_org_kdf_minimums(user: &User, orgs: &[Organization]) -> EmptyResult {
+    let minimums = orgs.iter().fold(KdfThresholds::default(), |minimums, org| minimums.strictest(&org.kdf_minimums()));
+    if user.has_weak_kdf(&minimums) {
+        err!("The KDF settings are weaker than the minimum required by the organization")
+    }
+    Ok(())
+}
+
+// Changes the KDF settings together with the master password.
+// Clients also use this to only change the KDF, by sending the same password hashed with the new settings.
+// For that case `/accounts/kdf/upgrade` is a narrower alternative.
 #[post("/accounts/kdf", data = "<data>")]
-async fn post_kdf(data: Json<ChangeKdfData>, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
+async fn post_kdf(
+    data: Json<ChangeKdfData>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+    pool: &rocket::State<DbPool>,
+) -> EmptyResult {
     let data: ChangeKdfData = data.into_inner();
     let mut user = headers.user;
 
-    if !user.check_valid_password(&data.master_password_hash) {
-        err!("Invalid password")
-    }
+    verify_master_password_proof(&user, data.master_password_hash.as_deref(), data.otp.as_deref(), &mut conn).await?;
 
     set_kdf_data(&mut user, data.kdf)?;
+    enforce_password_history(
+        &user,
+        &data.new_master_password_hash,
+        CONFIG.master_password_history() as usize,
+        &mut conn,
+    )
+    .await?;
 
-    user.set_password(&data.new_master_password_hash, Some(data.key), true, None);
-    let save_result = user.save(&mut conn).await;
-
-    nt.send_logout(&user, Some(headers.device.uuid.clone()), &mut conn).await;
+    save_kdf_change(user, &data.new_master_password_hash, data.key, &headers.device, &mut conn, nt, pool).await
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpgradeKdfData {
+    #[serde(flatten)]
+    kdf: KDFData,
+
+    master_password_hash: Option<String>,
+    otp: Option<String>,
+    // The same master password, hashed with the new KDF settings
+    new_master_password_hash: String,
+    // The user key, wrapped with the master key derived using the new KDF settings
+    key: String,
+}
+
+// Only changes the KDF settings, the master password stays the same.
+// The new hash is verified and checked against the password history the same way as for a password change.
+#[post("/accounts/kdf/upgrade", data = "<data>")]
+async fn post_kdf_upgrade(
+    data: Json<UpgradeKdfData>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+    pool: &rocket::State<DbPool>,
+) -> EmptyResult {
+    let data: UpgradeKdfData = data.into_inner();
+    let mut user = headers.user;
+
+    verify_master_password_proof(&user, data.master_password_hash.as_deref(), data.otp.as_deref(), &mut conn).await?;
+
+    let current_kdf = (user.client_kdf_type, user.client_kdf_iter, user.client_kdf_memory, user.client_kdf_parallelism);
+    set_kdf_data(&mut user, data.kdf)?;
+    if current_kdf == (user.client_kdf_type, user.client_kdf_iter, user.client_kdf_memory, user.client_kdf_parallelism)
+    {
+        err!("The KDF settings are unchanged")
+    }
+    enforce_password_history(
+        &user,
+        &data.new_master_password_hash,
+        CONFIG.master_password_history() as usize,
+        &mut conn,
+    )
+    .await?;
+
+    save_kdf_change(user, &data.new_master_password_hash, data.key, &headers.device, &mut conn, nt, pool).await
+}
+
+/// Stores the new master password hash and key after the KDF settings of the user were changed.
+/// Everything is saved at once, after which the other devices are logged out.
+async fn save_kdf_change(
+    mut user: User,
+    new_master_password_hash: &str,
+    key: String,
+    acting_device: &Device,
+    conn: &mut DbConn,
+    nt: Notify<'_>,
+    pool: &rocket::State<DbPool>,
+) -> EmptyResult {
+    // When a grace period is configured, the other devices are allowed to do one final sync with their current session.
+    // The stamp exception needs to be set before `set_password` resets the security-stamp.
+    let grace_seconds = CONFIG.kdf_change_grace_seconds();
+    if grace_seconds > 0 {
+        user.set_stamp_exception_for(vec!["sync".to_string()], grace_seconds as i64);
+    }
+
+    let previous_password = PasswordHistory::from_user(&user);
+    user.set_password(new_master_password_hash, Some(key), true, None);
+    let save_result = user.save(conn).await;
+    if save_result.is_ok() {
+        record_password_history(previous_password, CONFIG.master_password_history() as usize, conn).await;
+    }
+
+    if grace_seconds > 0 && save_result.is_ok() {
+        // Ask the other devices to sync now, and only log them out after the grace period has passed
+        nt.send_user_update(UpdateType::SyncVault, &user, &acting_device.push_uuid, conn).await;
+
+        let pool = pool.inner().clone();
+        let nt = Arc::clone(nt.inner());
+        let acting_device_id = acting_device.uuid.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(tokio::time::Duration::from_secs(grace_seconds)).await;
+            if let Ok(mut conn) = pool.get().await {
+                nt.send_logout(&user, Some(acting_device_id), &mut conn).await;
+            } else {
+                error!("Failed to get DB connection while sending the delayed logout after a KDF change")
+            }
+        });
+    } else {
+        nt.send_logout(&user, Some(acting_device.uuid.clone()), conn).await;
+    }
 
     save_result
 }
@@ -628,7 +1378,7 @@ struct UpdateResetPasswordData {
 }
 
 use super::ciphers::CipherData;
-use super::sends::{update_send_from_data, SendData};
+use super::sends::{update_send_from_data, SendData, SIZE_525_MB};
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -636,7 +1386,11 @@ struct KeyData {
     account_unlock_data: RotateAccountUnlockData,
     account_keys: RotateAccountKeys,
     account_data: RotateAccountData,
-    old_master_key_authentication_hash: String,
+    old_master_key_authentication_hash: Option<String>,
+    // Used instead of the master password hash by SSO-only accounts
+    otp: Option<String>,
+    // Returned by a failed rotation, to only resubmit the items which weren't saved yet
+    retry_token: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -674,6 +1428,158 @@ struct RotateAccountData {
     sends: Vec<SendData>,
 }
 
+/// Returns the provided cipher ids which don't belong to an existing cipher, or which are provided more than once.
+/// The ids are sorted to give a stable error message.
+fn unexpected_cipher_ids(existing: &HashSet<&CipherId>, provided: &[&CipherId]) -> Vec<String> {
+    let mut seen = HashSet::with_capacity(provided.len());
+    let mut unexpected: Vec<String> =
+        provided.iter().filter(|id| !existing.contains(*id) || !seen.insert(**id)).map(|id| id.to_string()).collect();
+    unexpected.sort_unstable();
+    unexpected.dedup();
+    unexpected
+}
+
+/// Returns true when multiple folders of the rotation have the same name.
+/// Folder names are encrypted, so only identical ciphertexts can be detected.
+fn has_duplicate_folder_names(folders: &[UpdateFolderData]) -> bool {
+    let mut seen = HashSet::with_capacity(folders.len());
+    folders.iter().filter(|f| f.id.is_some()).any(|f| !seen.insert(f.name.as_str()))
+}
+
+/// We only rotate the reset password key if it is set.
+fn memberships_to_rotate(mut memberships: Vec<Membership>) -> Vec<Membership> {
+    memberships.retain(|m| m.reset_password_key.is_some());
+    memberships
+}
+
+/// Summarizes what a key rotation would change, based on the same data `post_rotatekey` uses.
+/// All other devices get logged out, and all emergency access grants of the user as grantor and
+/// all reset password keys need to be included in the rotation.
+fn key_rotation_preview(
+    acting_device_id: &DeviceId,
+    device_ids: &[DeviceId],
+    emergency_access: &[EmergencyAccess],
+    memberships: &[Membership],
+) -> Value {
+    let logged_out_devices: Vec<&DeviceId> = device_ids.iter().filter(|id| *id != acting_device_id).collect();
+
+    json!({
+        "deviceCount": logged_out_devices.len(),
+        "deviceIds": logged_out_devices,
+        "emergencyAccessCount": emergency_access.len(),
+        "emergencyAccessIds": emergency_access.iter().map(|ea| &ea.uuid).collect::<Vec<_>>(),
+        "resetPasswordKeyCount": memberships.len(),
+        "organizationIds": memberships.iter().map(|m| &m.org_uuid).collect::<Vec<_>>(),
+        "object": "keyRotationPreview",
+    })
+}
+
+/// Lists the personal ciphers and sends which were last written under another account key version than the current one,
+/// for example because a key rotation was interrupted. Nothing gets decrypted, only the stamped versions are compared.
+fn integrity_scan(user: &User, ciphers: &[Cipher], sends: &[Send]) -> Value {
+    let cipher_ids: Vec<&CipherId> =
+        ciphers.iter().filter(|c| user.is_stale_key_version(c.key_version)).map(|c| &c.uuid).collect();
+    let send_ids: Vec<&SendId> =
+        sends.iter().filter(|s| user.is_stale_key_version(s.key_version)).map(|s| &s.uuid).collect();
+
+    json!({
+        "keyVersion": user.key_version,
+        "cipherIds": cipher_ids,
+        "sendIds": send_ids,
+        "object": "integrityScan",
+    })
+}
+
+/// Lists the personal items which have to be included in a key rotation, the same ones `validate_keydata` checks for.
+/// Ciphers of organizations aren't encrypted with the account key, so they are never part of a rotation.
+fn rotation_expected_items(ciphers: &[Cipher], folders: &[Folder], sends: &[Send]) -> Value {
+    let cipher_ids: Vec<&CipherId> =
+        ciphers.iter().filter(|c| c.organization_uuid.is_none()).map(|c| &c.uuid).collect();
+    let folder_ids: Vec<&FolderId> = folders.iter().map(|f| &f.uuid).collect();
+    let send_ids: Vec<&SendId> = sends.iter().map(|s| &s.uuid).collect();
+
+    json!({
+        "cipherIds": cipher_ids,
+        "folderIds": folder_ids,
+        "sendIds": send_ids,
+        "object": "rotationExpectedItems",
+    })
+}
+
+/// The parts of a key rotation, a failure reports in which one it happened
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RotationStage {
+    User,
+    Folders,
+    EmergencyAccess,
+    ResetPassword,
+    Sends,
+    Ciphers,
+}
+
+impl RotationStage {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Folders => "folders",
+            Self::EmergencyAccess => "emergencyAccess",
+            Self::ResetPassword => "resetPassword",
+            Self::Sends => "sends",
+            Self::Ciphers => "ciphers",
+        }
+    }
+}
+
+/// Why a key rotation failed, with the stage and the item it failed on, so a client can tell what has to be fixed
+#[derive(Debug)]
+struct RotationFailure {
+    stage: RotationStage,
+    item_id: Option<String>,
+    error: crate::Error,
+}
+
+impl RotationFailure {
+    fn new(stage: RotationStage, item_id: Option<String>, error: crate::Error) -> Self {
+        Self {
+            stage,
+            item_id,
+            error,
+        }
+    }
+
+    fn msg(stage: RotationStage, item_id: Option<String>, msg: &str) -> Self {
+        Self::new(stage, item_id, crate::Error::new(msg, msg))
+    }
+
+    fn log_msg(&self) -> String {
+        format!("Key rotation failed at {} (item {:?}): {:?}", self.stage.as_str(), self.item_id, self.error)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "message": self.error.message(),
+            "validationErrors": {"": [ self.error.message() ]},
+            "rotationDiagnostics": {
+                "stage": self.stage.as_str(),
+                "itemId": self.item_id,
+            },
+            "object": "error"
+        })
+    }
+
+    fn into_error(self) -> crate::Error {
+        crate::Error::from((self.log_msg(), self.to_json()))
+    }
+}
+
+/// Returns one of the existing ids which is missing from the provided ones, the lowest one to always report the same.
+fn first_missing_id<T: std::fmt::Display + Eq + std::hash::Hash>(
+    existing: &HashSet<&T>,
+    provided: &HashSet<&T>,
+) -> Option<String> {
+    existing.difference(provided).map(|id| id.to_string()).min()
+}
+
 fn validate_keydata(
     data: &KeyData,
     existing_ciphers: &[Cipher],
@@ -682,38 +1588,71 @@ fn validate_keydata(
     existing_memberships: &[Membership],
     existing_sends: &[Send],
     user: &User,
-) -> EmptyResult {
+) -> Result<(), RotationFailure> {
     if user.client_kdf_type != data.account_unlock_data.master_password_unlock_data.kdf_type
         || user.client_kdf_iter != data.account_unlock_data.master_password_unlock_data.kdf_iterations
         || user.client_kdf_memory != data.account_unlock_data.master_password_unlock_data.kdf_memory
         || user.client_kdf_parallelism != data.account_unlock_data.master_password_unlock_data.kdf_parallelism
         || user.email != data.account_unlock_data.master_password_unlock_data.email
     {
-        err!("Changing the kdf variant or email is not supported during key rotation");
+        return Err(RotationFailure::msg(
+            RotationStage::User,
+            None,
+            "Changing the kdf variant or email is not supported during key rotation",
+        ));
     }
     if user.public_key.as_ref() != Some(&data.account_keys.account_public_key) {
-        err!("Changing the asymmetric keypair is not possible during key rotation")
+        return Err(RotationFailure::msg(
+            RotationStage::User,
+            None,
+            "Changing the asymmetric keypair is not possible during key rotation",
+        ));
     }
 
     // Check that we're correctly rotating all the user's ciphers
     let existing_cipher_ids = existing_ciphers.iter().map(|c| &c.uuid).collect::<HashSet<&CipherId>>();
-    let provided_cipher_ids = data
+    let provided_cipher_id_list = data
         .account_data
         .ciphers
         .iter()
         .filter(|c| c.organization_id.is_none())
         .filter_map(|c| c.id.as_ref())
-        .collect::<HashSet<&CipherId>>();
-    if !provided_cipher_ids.is_superset(&existing_cipher_ids) {
-        err!("All existing ciphers must be included in the rotation")
+        .collect::<Vec<&CipherId>>();
+    let provided_cipher_ids = provided_cipher_id_list.iter().copied().collect::<HashSet<&CipherId>>();
+    if let Some(missing) = first_missing_id(&existing_cipher_ids, &provided_cipher_ids) {
+        return Err(RotationFailure::msg(
+            RotationStage::Ciphers,
+            Some(missing),
+            "All existing ciphers must be included in the rotation",
+        ));
+    }
+
+    if CONFIG.key_rotation_strict_ciphers() {
+        let unexpected = unexpected_cipher_ids(&existing_cipher_ids, &provided_cipher_id_list);
+        if let Some(first) = unexpected.first() {
+            let msg = format!("The rotation contains unknown or duplicate ciphers: {}", unexpected.join(", "));
+            return Err(RotationFailure::msg(RotationStage::Ciphers, Some(first.clone()), &msg));
+        }
     }
 
     // Check that we're correctly rotating all the user's folders
     let existing_folder_ids = existing_folders.iter().map(|f| &f.uuid).collect::<HashSet<&FolderId>>();
     let provided_folder_ids =
         data.account_data.folders.iter().filter_map(|f| f.id.as_ref()).collect::<HashSet<&FolderId>>();
-    if !provided_folder_ids.is_superset(&existing_folder_ids) {
-        err!("All existing folders must be included in the rotation")
+    if let Some(missing) = first_missing_id(&existing_folder_ids, &provided_folder_ids) {
+        return Err(RotationFailure::msg(
+            RotationStage::Folders,
+            Some(missing),
+            "All existing folders must be included in the rotation",
+        ));
+    }
+
+    if CONFIG.key_rotation_unique_folder_names() && has_duplicate_folder_names(&data.account_data.folders) {
+        return Err(RotationFailure::msg(
+            RotationStage::Folders,
+            None,
+            "The rotation contains multiple folders with the same encrypted name",
+        ));
     }
 
     // Check that we're correctly rotating all the user's emergency access keys
@@ -725,8 +1664,12 @@ fn validate_keydata(
         .iter()
         .map(|ea| &ea.id)
         .collect::<HashSet<&EmergencyAccessId>>();
-    if !provided_emergency_access_ids.is_superset(&existing_emergency_access_ids) {
-        err!("All existing emergency access keys must be included in the rotation")
+    if let Some(missing) = first_missing_id(&existing_emergency_access_ids, &provided_emergency_access_ids) {
+        return Err(RotationFailure::msg(
+            RotationStage::EmergencyAccess,
+            Some(missing),
+            "All existing emergency access keys must be included in the rotation",
+        ));
     }
 
     // Check that we're correctly rotating all the user's reset password keys
@@ -738,34 +1681,165 @@ fn validate_keydata(
         .iter()
         .map(|rp| &rp.organization_id)
         .collect::<HashSet<&OrganizationId>>();
-    if !provided_reset_password_ids.is_superset(&existing_reset_password_ids) {
-        err!("All existing reset password keys must be included in the rotation")
+    if let Some(missing) = first_missing_id(&existing_reset_password_ids, &provided_reset_password_ids) {
+        return Err(RotationFailure::msg(
+            RotationStage::ResetPassword,
+            Some(missing),
+            "All existing reset password keys must be included in the rotation",
+        ));
     }
 
     // Check that we're correctly rotating all the user's sends
     let existing_send_ids = existing_sends.iter().map(|s| &s.uuid).collect::<HashSet<&SendId>>();
     let provided_send_ids = data.account_data.sends.iter().filter_map(|s| s.id.as_ref()).collect::<HashSet<&SendId>>();
-    if !provided_send_ids.is_superset(&existing_send_ids) {
-        err!("All existing sends must be included in the rotation")
+    if let Some(missing) = first_missing_id(&existing_send_ids, &provided_send_ids) {
+        return Err(RotationFailure::msg(
+            RotationStage::Sends,
+            Some(missing),
+            "All existing sends must be included in the rotation",
+        ));
     }
 
     Ok(())
 }
 
+// Lets a client show what a key rotation would affect before asking the user to confirm it. Nothing is changed here.
+#[get("/accounts/key-management/rotate-user-account-keys/preview")]
+async fn get_rotatekey_preview(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let user_id = &headers.user.uuid;
+
+    let device_ids: Vec<DeviceId> =
+        Device::find_by_user(user_id, &mut conn).await.into_iter().map(|d| d.uuid).collect();
+    let emergency_access = EmergencyAccess::find_all_by_grantor_uuid(user_id, &mut conn).await;
+    let memberships = memberships_to_rotate(Membership::find_by_user(user_id, &mut conn).await);
+
+    Json(key_rotation_preview(&headers.device.uuid, &device_ids, &emergency_access, &memberships))
+}
+
+// Lets a client build its rotation payload from the items the server expects, instead of from an outdated local vault.
+#[get("/accounts/key-management/rotate-user-account-keys/expected-items")]
+async fn get_rotatekey_expected_items(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let user_id = &headers.user.uuid;
+
+    let ciphers = Cipher::find_owned_by_user(user_id, &mut conn).await;
+    let folders = Folder::find_by_user(user_id, &mut conn).await;
+    let sends = Send::find_by_user(user_id, &mut conn).await;
+
+    Json(rotation_expected_items(&ciphers, &folders, &sends))
+}
+
+/// Minutes during which a failed key rotation can be resumed with its retry token
+const KEY_ROTATION_RETRY_MINUTES: i64 = 15;
+
+static KEY_ROTATION_RETRIES: Lazy<dashmap::DashMap<String, KeyRotationProgress>> = Lazy::new(dashmap::DashMap::new);
+
+/// The items of a failed key rotation which were already saved with the new key.
+/// This is only kept in memory, so a retry has to reach the same instance before it expires.
+struct KeyRotationProgress {
+    user_id: UserId,
+    // The key version the items are stamped with, it's only bumped once for all the attempts of a rotation
+    key_version: i32,
+    // A retry has to continue with the same new user key the already rotated items were encrypted for
+    user_key_hash: String,
+    expires_at: NaiveDateTime,
+    rotated: HashSet<String>,
+}
+
+impl KeyRotationProgress {
+    fn new(user: &User, new_user_key: &str) -> Self {
+        Self {
+            user_id: user.uuid.clone(),
+            key_version: user.key_version + 1,
+            user_key_hash: crypto::sha256_hex(new_user_key),
+            expires_at: Utc::now().naive_utc(),
+            rotated: HashSet::new(),
+        }
+    }
+
+    /// Takes the progress of a failed rotation of this user, so a retry token can only be used once
+    fn resume(
+        token: &str,
+        user_id: &UserId,
+        new_user_key: &str,
+        retries: &dashmap::DashMap<String, Self>,
+    ) -> ApiResult<Self> {
+        match retries.remove(token) {
+            Some((_, progress)) if &progress.user_id == user_id && progress.expires_at > Utc::now().naive_utc() => {
+                if !crypto::ct_eq(&progress.user_key_hash, crypto::sha256_hex(new_user_key)) {
+                    err!("The key rotation can't be resumed with a different user key")
+                }
+                Ok(progress)
+            }
+            _ => err!("The key rotation can't be resumed, the retry token is invalid or expired"),
+        }
+    }
+
+    /// Keeps the progress for a retry, and returns the token to resume it with
+    fn stash(mut self, retries: &dashmap::DashMap<String, Self>) -> String {
+        let now = Utc::now().naive_utc();
+        retries.retain(|_, p| p.expires_at > now);
+
+        let token = crypto::generate_id::<32>();
+        self.expires_at = now + TimeDelta::try_minutes(KEY_ROTATION_RETRY_MINUTES).unwrap();
+        retries.insert(token.clone(), self);
+        token
+    }
+
+    /// Stashes the progress and adds the retry token to the diagnostics returned to the client
+    fn into_retry_error(self, failure: RotationFailure, retries: &dashmap::DashMap<String, Self>) -> crate::Error {
+        let log_msg = format!("{}, user {} can retry", failure.log_msg(), self.user_id);
+        let mut json = failure.to_json();
+        json["retryToken"] = json!(self.stash(retries));
+        crate::Error::from((log_msg, json))
+    }
+
+    fn is_rotated(&self, id: &impl std::fmt::Display) -> bool {
+        self.rotated.contains(&id.to_string())
+    }
+
+    fn mark_rotated(&mut self, id: &impl std::fmt::Display) {
+        self.rotated.insert(id.to_string());
+    }
+
+    /// Leaves out the items which were already rotated, both from the existing items and from the resubmitted ones
+    fn retain_pending<T, I: std::fmt::Display>(&self, items: &mut Vec<T>, id: impl Fn(&T) -> Option<&I>) {
+        items.retain(|item| id(item).is_none_or(|id| !self.is_rotated(id)));
+    }
+}
+
 #[post("/accounts/key-management/rotate-user-account-keys", data = "<data>")]
 async fn post_rotatekey(data: Json<KeyData>, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
     // TODO: See if we can wrap everything within a SQL Transaction. If something fails it should revert everything.
-    let data: KeyData = data.into_inner();
+    let mut data: KeyData = data.into_inner();
 
-    if !headers.user.check_valid_password(&data.old_master_key_authentication_hash) {
-        err!("Invalid password")
-    }
+    verify_master_password_proof(
+        &headers.user,
+        data.old_master_key_authentication_hash.as_deref(),
+        data.otp.as_deref(),
+        &mut conn,
+    )
+    .await?;
+
+    // A retry of a failed rotation only has to contain the items which weren't saved yet
+    let new_user_key = &data.account_unlock_data.master_password_unlock_data.master_key_encrypted_user_key;
+    let mut progress = match data.retry_token.take() {
+        Some(token) => KeyRotationProgress::resume(&token, &headers.user.uuid, new_user_key, &KEY_ROTATION_RETRIES)?,
+        None => KeyRotationProgress::new(&headers.user, new_user_key),
+    };
+    progress.retain_pending(&mut data.account_data.folders, |f| f.id.as_ref());
+    progress.retain_pending(&mut data.account_data.sends, |s| s.id.as_ref());
+    progress.retain_pending(&mut data.account_data.ciphers, |c| c.id.as_ref());
+    progress.retain_pending(&mut data.account_unlock_data.emergency_access_unlock_data, |ea| Some(&ea.id));
+    progress.retain_pending(&mut data.account_unlock_data.organization_account_recovery_unlock_data, |rp| {
+        Some(&rp.organization_id)
+    });
 
     // Validate the import before continuing
     // Bitwarden does not process the import if there is one item invalid.
     // Since we check for the size of the encrypted note length, we need to do that here to pre-validate it.
     // TODO: See if we can optimize the whole cipher adding/importing and prevent duplicate code and checks.
     Cipher::validate_cipher_data(&data.account_data.ciphers)?;
+    Send::validate_send_data(&data.account_data.sends)?;
 
     let user_id = &headers.user.uuid;
 
@@ -774,10 +1848,13 @@ async fn post_rotatekey(data: Json<KeyData>, headers: Headers, mut conn: DbConn,
     let mut existing_ciphers = Cipher::find_owned_by_user(user_id, &mut conn).await;
     let mut existing_folders = Folder::find_by_user(user_id, &mut conn).await;
     let mut existing_emergency_access = EmergencyAccess::find_all_by_grantor_uuid(user_id, &mut conn).await;
-    let mut existing_memberships = Membership::find_by_user(user_id, &mut conn).await;
-    // We only rotate the reset password key if it is set.
-    existing_memberships.retain(|m| m.reset_password_key.is_some());
+    let mut existing_memberships = memberships_to_rotate(Membership::find_by_user(user_id, &mut conn).await);
     let mut existing_sends = Send::find_by_user(user_id, &mut conn).await;
+    progress.retain_pending(&mut existing_ciphers, |c| Some(&c.uuid));
+    progress.retain_pending(&mut existing_folders, |f| Some(&f.uuid));
+    progress.retain_pending(&mut existing_emergency_access, |ea| Some(&ea.uuid));
+    progress.retain_pending(&mut existing_memberships, |m| Some(&m.org_uuid));
+    progress.retain_pending(&mut existing_sends, |s| Some(&s.uuid));
 
     validate_keydata(
         &data,
@@ -787,91 +1864,285 @@ async fn post_rotatekey(data: Json<KeyData>, headers: Headers, mut conn: DbConn,
         &existing_memberships,
         &existing_sends,
         &headers.user,
-    )?;
+    )
+    .map_err(RotationFailure::into_error)?;
+
+    // The re-encrypted sends and ciphers get stamped with the new key version, which is saved with the user at the end.
+    // If the rotation gets interrupted, the integrity scan reports them because their version doesn't match the user.
+    // A resumed rotation keeps the version of its first attempt, so the already rotated items stay valid.
+    let mut headers = headers;
+    headers.user.key_version = progress.key_version;
+
+    let KeyData {
+        account_unlock_data,
+        account_keys,
+        account_data,
+        ..
+    } = data;
+    let RotateAccountUnlockData {
+        emergency_access_unlock_data,
+        master_password_unlock_data,
+        organization_account_recovery_unlock_data,
+    } = account_unlock_data;
+
+    let rotated = async {
+        // Update folder data
+        for folder_data in account_data.folders {
+            // Skip `null` folder id entries.
+            // See: https://github.com/bitwarden/clients/issues/8453
+            if let Some(folder_id) = folder_data.id {
+                let Some(saved_folder) = existing_folders.iter_mut().find(|f| f.uuid == folder_id) else {
+                    return Err(RotationFailure::msg(
+                        RotationStage::Folders,
+                        Some(folder_id.to_string()),
+                        "Folder doesn't exist",
+                    ));
+                };
+
+                saved_folder.name = folder_data.name;
+                saved_folder
+                    .save(&mut conn)
+                    .await
+                    .map_err(|e| RotationFailure::new(RotationStage::Folders, Some(folder_id.to_string()), e))?;
+                progress.mark_rotated(&folder_id);
+            }
+        }
 
-    // Update folder data
-    for folder_data in data.account_data.folders {
-        // Skip `null` folder id entries.
-        // See: https://github.com/bitwarden/clients/issues/8453
-        if let Some(folder_id) = folder_data.id {
-            let Some(saved_folder) = existing_folders.iter_mut().find(|f| f.uuid == folder_id) else {
-                err!("Folder doesn't exist")
+        // Update emergency access data
+        for emergency_access_data in emergency_access_unlock_data {
+            let Some(saved_emergency_access) =
+                existing_emergency_access.iter_mut().find(|ea| ea.uuid == emergency_access_data.id)
+            else {
+                return Err(RotationFailure::msg(
+                    RotationStage::EmergencyAccess,
+                    Some(emergency_access_data.id.to_string()),
+                    "Emergency access doesn't exist or is not owned by the user",
+                ));
             };
 
-            saved_folder.name = folder_data.name;
-            saved_folder.save(&mut conn).await?
+            saved_emergency_access.key_encrypted = Some(emergency_access_data.key_encrypted);
+            saved_emergency_access.save(&mut conn).await.map_err(|e| {
+                RotationFailure::new(RotationStage::EmergencyAccess, Some(emergency_access_data.id.to_string()), e)
+            })?;
+            progress.mark_rotated(&emergency_access_data.id);
         }
-    }
 
-    // Update emergency access data
-    for emergency_access_data in data.account_unlock_data.emergency_access_unlock_data {
-        let Some(saved_emergency_access) =
-            existing_emergency_access.iter_mut().find(|ea| ea.uuid == emergency_access_data.id)
-        else {
-            err!("Emergency access doesn't exist or is not owned by the user")
-        };
+        // Update reset password data
+        for reset_password_data in organization_account_recovery_unlock_data {
+            let Some(membership) =
+                existing_memberships.iter_mut().find(|m| m.org_uuid == reset_password_data.organization_id)
+            else {
+                return Err(RotationFailure::msg(
+                    RotationStage::ResetPassword,
+                    Some(reset_password_data.organization_id.to_string()),
+                    "Reset password doesn't exist",
+                ));
+            };
 
-        saved_emergency_access.key_encrypted = Some(emergency_access_data.key_encrypted);
-        saved_emergency_access.save(&mut conn).await?
-    }
+            membership.reset_password_key = Some(reset_password_data.reset_password_key);
+            membership.save(&mut conn).await.map_err(|e| {
+                RotationFailure::new(
+                    RotationStage::ResetPassword,
+                    Some(reset_password_data.organization_id.to_string()),
+                    e,
+                )
+            })?;
+            progress.mark_rotated(&reset_password_data.organization_id);
+        }
 
-    // Update reset password data
-    for reset_password_data in data.account_unlock_data.organization_account_recovery_unlock_data {
-        let Some(membership) =
-            existing_memberships.iter_mut().find(|m| m.org_uuid == reset_password_data.organization_id)
-        else {
-            err!("Reset password doesn't exist")
-        };
+        // Update send data
+        for send_data in account_data.sends {
+            let send_id = send_data.id.clone().unwrap();
+            let Some(send) = existing_sends.iter_mut().find(|s| s.uuid == send_id) else {
+                return Err(RotationFailure::msg(
+                    RotationStage::Sends,
+                    Some(send_id.to_string()),
+                    "Send doesn't exist",
+                ));
+            };
 
-        membership.reset_password_key = Some(reset_password_data.reset_password_key);
-        membership.save(&mut conn).await?
-    }
+            update_send_from_data(send, send_data, &headers, &mut conn, &nt, UpdateType::None)
+                .await
+                .map_err(|e| RotationFailure::new(RotationStage::Sends, Some(send_id.to_string()), e))?;
+            progress.mark_rotated(&send.uuid);
+        }
 
-    // Update send data
-    for send_data in data.account_data.sends {
-        let Some(send) = existing_sends.iter_mut().find(|s| &s.uuid == send_data.id.as_ref().unwrap()) else {
-            err!("Send doesn't exist")
-        };
+        // Update cipher data
+        use super::ciphers::update_cipher_from_data;
+
+        for cipher_data in account_data.ciphers {
+            if cipher_data.organization_id.is_none() {
+                let cipher_id = cipher_data.id.clone().unwrap();
+                let Some(saved_cipher) = existing_ciphers.iter_mut().find(|c| c.uuid == cipher_id) else {
+                    return Err(RotationFailure::msg(
+                        RotationStage::Ciphers,
+                        Some(cipher_id.to_string()),
+                        "Cipher doesn't exist",
+                    ));
+                };
+
+                // Prevent triggering cipher updates via WebSockets by settings UpdateType::None
+                // The user sessions are invalidated because all the ciphers were re-encrypted and thus triggering an update could cause issues.
+                // We force the users to logout after the user has been saved to try and prevent these issues.
+                update_cipher_from_data(saved_cipher, cipher_data, &headers, None, &mut conn, &nt, UpdateType::None)
+                    .await
+                    .map_err(|e| RotationFailure::new(RotationStage::Ciphers, Some(cipher_id.to_string()), e))?;
+                progress.mark_rotated(&saved_cipher.uuid);
+            }
+        }
 
-        update_send_from_data(send, send_data, &headers, &mut conn, &nt, UpdateType::None).await?;
+        Ok::<(), RotationFailure>(())
     }
+    .await;
 
-    // Update cipher data
-    use super::ciphers::update_cipher_from_data;
-
-    for cipher_data in data.account_data.ciphers {
-        if cipher_data.organization_id.is_none() {
-            let Some(saved_cipher) = existing_ciphers.iter_mut().find(|c| &c.uuid == cipher_data.id.as_ref().unwrap())
-            else {
-                err!("Cipher doesn't exist")
-            };
-
-            // Prevent triggering cipher updates via WebSockets by settings UpdateType::None
-            // The user sessions are invalidated because all the ciphers were re-encrypted and thus triggering an update could cause issues.
-            // We force the users to logout after the user has been saved to try and prevent these issues.
-            update_cipher_from_data(saved_cipher, cipher_data, &headers, None, &mut conn, &nt, UpdateType::None).await?
-        }
+    // The items which were saved already can't be rolled back, so the client gets a token to retry only the remaining ones
+    if let Err(e) = rotated {
+        return Err(progress.into_retry_error(e, &KEY_ROTATION_RETRIES));
     }
 
     // Update user data
     let mut user = headers.user;
 
-    user.private_key = Some(data.account_keys.user_key_encrypted_account_private_key);
+    user.private_key = Some(account_keys.user_key_encrypted_account_private_key);
     user.set_password(
-        &data.account_unlock_data.master_password_unlock_data.master_key_authentication_hash,
-        Some(data.account_unlock_data.master_password_unlock_data.master_key_encrypted_user_key),
+        &master_password_unlock_data.master_key_authentication_hash,
+        Some(master_password_unlock_data.master_key_encrypted_user_key),
         true,
         None,
     );
 
-    let save_result = user.save(&mut conn).await;
+    // All the items are rotated at this point, so a retry only has to save the user again
+    if let Err(e) = user.save(&mut conn).await {
+        let failure = RotationFailure::new(RotationStage::User, Some(user.uuid.to_string()), e);
+        return Err(progress.into_retry_error(failure, &KEY_ROTATION_RETRIES));
+    }
 
-    // Prevent logging out the client where the user requested this endpoint from.
+    if let Err(e) = KeyHistory::record(&user, KeyChangeType::Rotation, &mut conn).await {
+        error!("Error saving key history: {e:#?}");
+    }
+
+    // Prevent logging out the client where the user requested this endpoint from, unless configured otherwise.
     // If you do logout the user it will causes issues at the client side.
     // Adding the device uuid will prevent this.
-    nt.send_logout(&user, Some(headers.device.uuid.clone()), &mut conn).await;
+    let acting_device_id = key_rotation_logout_exception(&CONFIG.key_rotation_logout_scope(), &headers.device.uuid);
+    nt.send_logout(&user, acting_device_id, &mut conn).await;
 
-    save_result
+    Ok(())
+}
+
+// Vaultwarden specific, helps to find items which were damaged by an interrupted key rotation
+#[post("/accounts/integrity-scan")]
+async fn post_integrity_scan(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let ciphers = Cipher::find_owned_by_user(&headers.user.uuid, &mut conn).await;
+    let sends = Send::find_by_user(&headers.user.uuid, &mut conn).await;
+
+    Json(integrity_scan(&headers.user, &ciphers, &sends))
+}
+
+/// Estimates the size of a full sync from the stored encrypted fields, without loading them.
+/// The JSON around these fields isn't counted, so the actual sync is somewhat larger.
+async fn sync_estimate(user_id: &UserId, conn: &mut DbConn) -> Value {
+    let (personal_ciphers, personal_cipher_bytes) = Cipher::count_and_size_owned_by_user(user_id, conn).await;
+    let (folders, folder_bytes) = Folder::count_and_size_by_user(user_id, conn).await;
+    let (sends, send_bytes) = Send::count_and_size_by_user(user_id, conn).await;
+    let (org_ciphers, org_cipher_bytes) = Cipher::count_and_size_of_orgs_visible_to_user(user_id, conn).await;
+    let (collections, collection_bytes) = Collection::count_and_size_by_user_uuid(user_id, conn).await;
+
+    json!({
+        "personal": {
+            "cipherCount": personal_ciphers,
+            "cipherBytes": personal_cipher_bytes,
+            "folderCount": folders,
+            "folderBytes": folder_bytes,
+            "sendCount": sends,
+            "sendBytes": send_bytes,
+        },
+        "organization": {
+            "cipherCount": org_ciphers,
+            "cipherBytes": org_cipher_bytes,
+            "collectionCount": collections,
+            "collectionBytes": collection_bytes,
+        },
+        "totalBytes": personal_cipher_bytes + folder_bytes + send_bytes + org_cipher_bytes + collection_bytes,
+        "object": "syncEstimate",
+    })
+}
+
+// Vaultwarden specific, lets clients on metered connections warn before a large sync
+#[get("/accounts/sync-estimate")]
+async fn get_sync_estimate(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    Json(sync_estimate(&headers.user.uuid, &mut conn).await)
+}
+
+// Vaultwarden specific, the limits which apply to the current user, after the per-user overrides.
+// A `null` limit means unlimited, storage limits are in KB like the configuration.
+#[get("/accounts/limits")]
+fn get_account_limits(headers: Headers) -> Json<Value> {
+    Json(account_limits(&headers.user))
+}
+
+fn account_limits(user: &User) -> Value {
+    let send_storage = user.effective_send_limit();
+    // A single Send file can't be larger than what the clients accept, nor than the send storage
+    let max_send_file_size = send_storage.and_then(|kb| kb.checked_mul(1024)).unwrap_or(SIZE_525_MB).min(SIZE_525_MB);
+
+    json!({
+        // There is no limit on the number of devices of a user
+        "maxDevices": null,
+        "maxOrganizations": Some(CONFIG.max_orgs_per_user()).filter(|l| *l != 0),
+        "attachmentStorage": user.effective_attachment_limit(),
+        "sendStorage": send_storage,
+        "maxSendFileSize": max_send_file_size,
+        "object": "accountLimits",
+    })
+}
+
+// Vaultwarden specific, the security related state of the account in a single call, to be rendered as a dashboard
+#[get("/accounts/security-overview")]
+async fn get_security_overview(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let user = &headers.user;
+    let device_count = Device::find_by_user(&user.uuid, &mut conn).await.len();
+    let twofactors = TwoFactor::find_by_user(&user.uuid, &mut conn).await;
+    // The previous password is only kept, with the date it was changed, when `MASTER_PASSWORD_HISTORY` is enabled
+    let last_password_change = PasswordHistory::find_by_user(&user.uuid, &mut conn).await.first().map(|h| h.created_at);
+    let grants = EmergencyAccess::find_all_by_grantor_uuid(&user.uuid, &mut conn).await;
+
+    Json(security_overview(
+        user,
+        device_count,
+        &twofactors,
+        last_password_change,
+        &grants,
+        &KdfThresholds::from_config(),
+    ))
+}
+
+fn security_overview(
+    user: &User,
+    device_count: usize,
+    twofactors: &[TwoFactor],
+    last_password_change: Option<NaiveDateTime>,
+    grants: &[EmergencyAccess],
+    thresholds: &KdfThresholds,
+) -> Value {
+    // Grants which were confirmed can be used by the grantee to request access, or already did
+    let active_grants = grants.iter().filter(|g| g.status >= EmergencyAccessStatus::Confirmed as i32).count();
+    let recoveries_initiated =
+        grants.iter().filter(|g| g.status == EmergencyAccessStatus::RecoveryInitiated as i32).count();
+
+    json!({
+        "deviceCount": device_count,
+        "emailVerified": user.verified_at.is_some(),
+        "twoFactor": twofactor_status(twofactors),
+        "lastPasswordChangeDate": last_password_change.as_ref().map(format_date),
+        "pendingEmailChange": user.email_new,
+        "emergencyAccess": {
+            "activeGrants": active_grants,
+            "recoveriesInitiated": recoveries_initiated,
+        },
+        "kdfBelowRecommendation": user.has_weak_kdf(thresholds),
+        "object": "securityOverview",
+    })
 }
 
 #[post("/accounts/security-stamp", data = "<data>")]
@@ -881,7 +2152,21 @@ async fn post_sstamp(data: Json<PasswordOrOtpData>, headers: Headers, mut conn:
 
     data.validate(&user, true, &mut conn).await?;
 
-    Device::delete_all_by_user(&user.uuid, &mut conn).await?;
+    let grace_seconds = CONFIG.sstamp_grace_seconds();
+    if grace_seconds > 0 {
+        // Removing the devices would fail their in-flight requests as well, only make sure they can't renew their sessions
+        for mut device in Device::find_by_user(&user.uuid, &mut conn).await {
+            device.revoke_refresh_token();
+            device.delete_twofactor_remember();
+            device.save(&mut conn).await?;
+        }
+        user.set_stamp_grace_period(grace_seconds as i64);
+    } else {
+        Device::delete_all_by_user(&user.uuid, &mut conn).await?;
+    }
+
+    revoke_emergency_access(&user, CONFIG.sstamp_revokes_emergency_access(), &mut conn).await?;
+
     user.reset_security_stamp();
     let save_result = user.save(&mut conn).await;
 
@@ -890,77 +2175,399 @@ async fn post_sstamp(data: Json<PasswordOrOtpData>, headers: Headers, mut conn:
     save_result
 }
 
+/// Revokes all the emergency access grants the user has given or received during a security stamp reset.
+/// Nothing is revoked unless `SSTAMP_REVOKES_EMERGENCY_ACCESS` is enabled.
+async fn revoke_emergency_access(user: &User, revoke: bool, conn: &mut DbConn) -> EmptyResult {
+    if !revoke {
+        return Ok(());
+    }
+
+    let mut grants = EmergencyAccess::find_all_by_grantor_uuid(&user.uuid, conn).await;
+    grants.append(&mut EmergencyAccess::find_all_by_grantee_uuid(&user.uuid, conn).await);
+    for emergency_access in grants {
+        info!(
+            "Revoking emergency access {} (grantor {}, grantee {:?}) because user {} reset their security stamp",
+            emergency_access.uuid, emergency_access.grantor_uuid, emergency_access.grantee_uuid, user.uuid
+        );
+        emergency_access.delete(conn).await?;
+    }
+    Ok(())
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct EmailTokenData {
-    master_password_hash: String,
-    new_email: String,
+struct TransferToOrganizationData {
+    organization_id: OrganizationId,
+    collection_ids: Vec<CollectionId>,
+    // The ciphers need to be encrypted with the organization key already
+    ciphers: Vec<CipherData>,
 }
 
-#[post("/accounts/email-token", data = "<data>")]
-async fn post_email_token(data: Json<EmailTokenData>, headers: Headers, mut conn: DbConn) -> EmptyResult {
-    if !CONFIG.email_change_allowed() {
-        err!("Email change is not allowed.");
+#[post("/accounts/transfer-to-organization", data = "<data>")]
+async fn post_transfer_to_organization(
+    data: Json<TransferToOrganizationData>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
+    let data: TransferToOrganizationData = data.into_inner();
+
+    enforce_verified_email_for_sharing(&headers.user)?;
+
+    if data.ciphers.is_empty() {
+        err!("You must select at least one cipher.")
     }
 
-    let data: EmailTokenData = data.into_inner();
-    let mut user = headers.user;
+    if data.collection_ids.is_empty() {
+        err!("You must select at least one collection.")
+    }
 
-    if !user.check_valid_password(&data.master_password_hash) {
-        err!("Invalid password")
+    let membership =
+        Membership::find_confirmed_by_user_and_org(&headers.user.uuid, &data.organization_id, &mut conn).await;
+    if membership.is_none() {
+        err!("You are not a member of this organization")
     }
 
-    if let Some(existing_user) = User::find_by_mail(&data.new_email, &mut conn).await {
-        if CONFIG.mail_enabled() {
-            // check if existing_user has already registered
-            if existing_user.password_hash.is_empty() {
-                // inform an invited user about how to delete their temporary account if the
-                // request was done intentionally and they want to update their mail address
-                if let Err(e) = mail::send_change_email_invited(&data.new_email, &user.email).await {
-                    error!("Error sending change-email-invited email: {e:#?}");
-                }
-            } else {
-                // inform existing user about the failed attempt to change their mail address
-                if let Err(e) = mail::send_change_email_existing(&data.new_email, &user.email).await {
-                    error!("Error sending change-email-existing email: {e:#?}");
-                }
-            }
+    // Validate everything before moving any cipher, this prevents ending up with a partially transferred vault
+    // because of an invalid collection or cipher in the request.
+    for col_id in &data.collection_ids {
+        let Some(collection) = Collection::find_by_uuid_and_org(col_id, &data.organization_id, &mut conn).await else {
+            err!("Invalid collection ID provided")
+        };
+        if !collection.is_writable_by_user(&headers.user.uuid, &mut conn).await {
+            err!("No rights to modify the collection")
         }
-        err!("Email already in use");
     }
 
-    if !CONFIG.is_email_domain_allowed(&data.new_email) {
-        err!("Email domain not allowed");
+    let owned_cipher_ids: HashSet<CipherId> =
+        Cipher::find_owned_by_user(&headers.user.uuid, &mut conn).await.into_iter().map(|c| c.uuid).collect();
+    let mut seen_cipher_ids = HashSet::with_capacity(data.ciphers.len());
+    for cipher in &data.ciphers {
+        let Some(cipher_id) = &cipher.id else {
+            err!("Request missing ids field")
+        };
+        if !owned_cipher_ids.contains(cipher_id) {
+            err!("Cipher is not owned by you", format!("Cipher {cipher_id} is not owned by user {}", headers.user.uuid))
+        }
+        if !seen_cipher_ids.insert(cipher_id) {
+            err!("Duplicate cipher in request")
+        }
+        if cipher.organization_id.as_ref() != Some(&data.organization_id) {
+            err!("All ciphers need to be encrypted for the target organization")
+        }
     }
 
-    let token = crypto::generate_email_token(6);
+    // A failing cipher doesn't stop the other ciphers from being transferred, the result is reported per cipher
+    let mut results = Vec::with_capacity(data.ciphers.len());
+    for mut cipher in data.ciphers {
+        let Some(cipher_id) = cipher.id.take() else {
+            continue;
+        };
+        let share_data = ShareCipherData {
+            cipher,
+            collection_ids: data.collection_ids.clone(),
+        };
 
-    if CONFIG.mail_enabled() {
-        if let Err(e) = mail::send_change_email(&data.new_email, &token).await {
-            error!("Error sending change-email email: {e:#?}");
+        let error = match share_cipher_by_uuid(&cipher_id, share_data, &headers, &mut conn, &nt, Some(UpdateType::None))
+            .await
+        {
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Failed to transfer cipher {cipher_id} to organization {}: {e}", data.organization_id);
+                Some(e.to_string())
+            }
+        };
+
+        results.push(json!({
+            "id": cipher_id,
+            "success": error.is_none(),
+            "error": error,
+        }));
+    }
+
+    // Multi share actions do not send out a push for each cipher, we need to send a general sync here
+    nt.send_user_update(UpdateType::SyncCiphers, &headers.user, &headers.device.push_uuid, &mut conn).await;
+
+    Ok(Json(json!({
+        "data": results,
+        "object": "list",
+        "continuationToken": null,
+    })))
+}
+
+#[get("/accounts/pending-invites")]
+async fn get_pending_invites(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let mut org_invites = Vec::new();
+    for member in Membership::find_invited_by_user(&headers.user.uuid, &mut conn).await {
+        let Some(org) = Organization::find_by_uuid(&member.org_uuid, &mut conn).await else {
+            continue;
+        };
+        org_invites.push(json!({
+            "id": member.uuid,
+            "organizationId": org.uuid,
+            "organizationName": org.name,
+            "type": member.atype,
+            "resetPasswordKeyRequired": OrgPolicy::org_is_reset_password_auto_enroll(&org.uuid, &mut conn).await,
+            "object": "pendingOrganizationInvite",
+        }));
+    }
+
+    let mut emergency_access_invites = Vec::new();
+    if CONFIG.emergency_access_allowed() {
+        for emergency_invite in EmergencyAccess::find_all_invited_by_grantee_email(&headers.user.email, &mut conn).await
+        {
+            emergency_access_invites.push(emergency_invite.to_json_grantor_details(&mut conn).await);
         }
-    } else {
-        debug!("Email change request for user ({}) to email ({}) with token ({token})", user.uuid, data.new_email);
     }
 
-    user.email_new = Some(data.new_email);
-    user.email_new_token = Some(token);
-    user.save(&mut conn).await
+    Json(json!({
+        "organizations": org_invites,
+        "emergencyAccess": emergency_access_invites,
+        "object": "pendingInvites",
+    }))
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct ChangeEmailData {
-    master_password_hash: String,
-    new_email: String,
+struct AcceptPendingOrgInviteData {
+    id: MembershipId,
+    reset_password_key: Option<String>,
+}
 
-    key: String,
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AcceptAllPendingInvitesData {
+    // Only needed for organizations which require the reset password key at enrollment
+    organizations: Option<Vec<AcceptPendingOrgInviteData>>,
+}
+
+#[post("/accounts/pending-invites/accept-all", data = "<data>")]
+async fn post_accept_all_pending_invites(
+    data: Json<AcceptAllPendingInvitesData>,
+    headers: Headers,
+    mut conn: DbConn,
+) -> Json<Value> {
+    let data: AcceptAllPendingInvitesData = data.into_inner();
+    let mut reset_password_keys: HashMap<MembershipId, Option<String>> =
+        data.organizations.unwrap_or_default().into_iter().map(|o| (o.id, o.reset_password_key)).collect();
+
+    // Every invite is handled on its own, a failing invite does not prevent the others from being accepted
+    let mut results = Vec::new();
+    for member in Membership::find_invited_by_user(&headers.user.uuid, &mut conn).await {
+        let member_id = member.uuid.clone();
+        let reset_password_key = reset_password_keys.remove(&member_id).flatten();
+        let result = accept_pending_org_invite(&headers.user, member, reset_password_key, &mut conn).await;
+        results.push(pending_invite_result(member_id.to_string(), "organization", result));
+    }
+
+    if CONFIG.emergency_access_allowed() {
+        for emergency_invite in EmergencyAccess::find_all_invited_by_grantee_email(&headers.user.email, &mut conn).await
+        {
+            let emer_id = emergency_invite.uuid.to_string();
+            let result = accept_pending_emergency_access_invite(&headers.user, emergency_invite, &mut conn).await;
+            results.push(pending_invite_result(emer_id, "emergencyAccess", result));
+        }
+    }
+
+    Json(json!({
+        "data": results,
+        "object": "list",
+        "continuationToken": null,
+    }))
+}
+
+async fn accept_pending_org_invite(
+    user: &User,
+    member: Membership,
+    reset_password_key: Option<String>,
+    conn: &mut DbConn,
+) -> EmptyResult {
+    if reset_password_key.is_none() && OrgPolicy::org_is_reset_password_auto_enroll(&member.org_uuid, conn).await {
+        err!("Reset password key is required, but not provided.")
+    }
+    accept_org_invite(user, member, reset_password_key, conn).await
+}
+
+async fn accept_pending_emergency_access_invite(
+    user: &User,
+    mut emergency_invite: EmergencyAccess,
+    conn: &mut DbConn,
+) -> EmptyResult {
+    emergency_invite.accept_invite(&user.uuid, &user.email, conn).await?;
+
+    if CONFIG.mail_enabled() {
+        if let Some(grantor_user) = User::find_by_uuid(&emergency_invite.grantor_uuid, conn).await {
+            // The invite is already accepted at this point, so don't report it as failed
+            if let Err(e) = mail::send_emergency_access_invite_accepted(&grantor_user.email, &user.email).await {
+                error!("Error sending emergency access invite accepted email: {e:#?}");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn pending_invite_result(id: String, invite_type: &str, result: EmptyResult) -> Value {
+    let error = result.err().map(|e| e.to_string());
+    json!({
+        "id": id,
+        "type": invite_type,
+        "success": error.is_none(),
+        "error": error,
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EmailTokenData {
+    master_password_hash: String,
+    new_email: String,
+    // Vaultwarden specific, needed to replace an email change to another address which is still pending
+    #[serde(default)]
+    force: bool,
+}
+
+/// Only one email change can be pending, don't silently replace one to a different address.
+/// Requesting a new token for the same address is always allowed.
+fn check_pending_email_change(pending_email: Option<&str>, new_email: &str, force: bool) -> EmptyResult {
+    match pending_email {
+        Some(pending_email) if pending_email != new_email && !force => {
+            err!(format!("An email change to {pending_email} is still pending, use force to replace it"))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EmailValidateData {
+    new_email: String,
+}
+
+/// Returns why a new email address would be refused by `post_email_token`, checked in the same order, or `None` when it's accepted.
+fn email_change_target_error(
+    new_email: &str,
+    in_use: bool,
+    domain_allowed: bool,
+    sso_domain_allowed: bool,
+) -> Option<&'static str> {
+    if !crate::util::is_valid_email(new_email) {
+        Some("Invalid email address")
+    } else if in_use {
+        Some("Email already in use")
+    } else if !domain_allowed {
+        Some("Email domain not allowed")
+    } else if !sso_domain_allowed {
+        Some("The new email must be within one of the SSO domains")
+    } else {
+        None
+    }
+}
+
+// Vaultwarden specific, lets a client check a new email address before asking for the master password.
+// Like `post_email_token` this tells whether the address is in use, which is why it's rate limited per user.
+// Nothing is stored and no email is sent.
+#[post("/accounts/email/validate", data = "<data>")]
+async fn post_email_validate(data: Json<EmailValidateData>, headers: Headers, mut conn: DbConn) -> JsonResult {
+    if !CONFIG.email_change_allowed() {
+        err!("Email change is not allowed.");
+    }
+    crate::ratelimit::check_limit_email_validate(&headers.user.uuid)?;
+
+    let new_email = data.into_inner().new_email.to_lowercase();
+    let in_use = User::find_by_mail(&new_email, &mut conn).await.is_some();
+    let error = email_change_target_error(
+        &new_email,
+        in_use,
+        CONFIG.is_email_domain_allowed(&new_email),
+        CONFIG.is_sso_email_domain_allowed(&new_email),
+    );
+
+    Ok(Json(json!({
+        "valid": error.is_none(),
+        "message": error,
+        "object": "emailValidation",
+    })))
+}
+
+#[post("/accounts/email-token", data = "<data>")]
+async fn post_email_token(data: Json<EmailTokenData>, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    if !CONFIG.email_change_allowed() {
+        err!("Email change is not allowed.");
+    }
+
+    let data: EmailTokenData = data.into_inner();
+    let mut user = headers.user;
+
+    if !user.check_valid_password(&data.master_password_hash) {
+        err!("Invalid password")
+    }
+
+    if let Some(existing_user) = User::find_by_mail(&data.new_email, &mut conn).await {
+        if CONFIG.mail_enabled() && crate::ratelimit::allow_user_email(&user.uuid) {
+            // check if existing_user has already registered
+            if existing_user.password_hash.is_empty() {
+                // inform an invited user about how to delete their temporary account if the
+                // request was done intentionally and they want to update their mail address
+                if let Err(e) = mail::send_change_email_invited(&data.new_email, &user.email).await {
+                    error!("Error sending change-email-invited email: {e:#?}");
+                }
+            } else {
+                // inform existing user about the failed attempt to change their mail address
+                if let Err(e) = mail::send_change_email_existing(&data.new_email, &user.email).await {
+                    error!("Error sending change-email-existing email: {e:#?}");
+                }
+            }
+        }
+        err!("Email already in use");
+    }
+
+    if !CONFIG.is_email_domain_allowed(&data.new_email) {
+        err!("Email domain not allowed");
+    }
+
+    if !CONFIG.is_sso_email_domain_allowed(&data.new_email) {
+        err!("The new email must be within one of the SSO domains");
+    }
+
+    check_pending_email_change(user.email_new.as_deref(), &data.new_email, data.force)?;
+
+    let token = crypto::generate_email_token(CONFIG.email_token_size(), CONFIG.email_token_alphanumeric());
+
+    if CONFIG.mail_enabled() {
+        if crate::ratelimit::allow_user_email(&user.uuid) {
+            if let Err(e) = mail::send_change_email(&data.new_email, &token).await {
+                error!("Error sending change-email email: {e:#?}");
+            }
+        }
+    } else {
+        debug!("Email change request for user ({}) to email ({}) with token ({token})", user.uuid, data.new_email);
+    }
+
+    user.email_new = Some(data.new_email);
+    user.email_new_token = Some(token);
+    user.save(&mut conn).await
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChangeEmailData {
+    master_password_hash: String,
+    new_email: String,
+
+    key: String,
     new_master_password_hash: String,
     token: NumberOrString,
+
+    #[serde(flatten)]
+    claimed_kdf: ClaimedKdfData,
 }
 
 #[post("/accounts/email", data = "<data>")]
-async fn post_email(data: Json<ChangeEmailData>, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
+async fn post_email(data: Json<ChangeEmailData>, headers: Headers, conn: DbConn, nt: Notify<'_>) -> EmptyResult {
+    with_error_delay(_post_email(data, headers, conn, nt)).await
+}
+
+async fn _post_email(data: Json<ChangeEmailData>, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
     if !CONFIG.email_change_allowed() {
         err!("Email change is not allowed.");
     }
@@ -971,11 +2578,19 @@ async fn post_email(data: Json<ChangeEmailData>, headers: Headers, mut conn: DbC
     if !user.check_valid_password(&data.master_password_hash) {
         err!("Invalid password")
     }
+    if CONFIG.password_change_kdf_check() {
+        check_claimed_kdf(&user, &data.claimed_kdf)?;
+    }
 
     if User::find_by_mail(&data.new_email, &mut conn).await.is_some() {
         err!("Email already in use");
     }
 
+    // Also checked here, in case the SSO domains changed after the token was requested
+    if !CONFIG.is_sso_email_domain_allowed(&data.new_email) {
+        err!("The new email must be within one of the SSO domains");
+    }
+
     match user.email_new {
         Some(ref val) => {
             if val != &data.new_email {
@@ -987,32 +2602,190 @@ async fn post_email(data: Json<ChangeEmailData>, headers: Headers, mut conn: DbC
 
     if CONFIG.mail_enabled() {
         // Only check the token if we sent out an email...
-        match user.email_new_token {
-            Some(ref val) => {
-                if *val != data.token.into_string() {
-                    err!("Token mismatch");
+        let token = data.token.into_string();
+        match user.email_new_token.as_deref().map(|val| crypto::check_email_token(val, &token)) {
+            Some(true) => {}
+            Some(false) => {
+                // Discard the pending change after too many wrong tokens, a new token has to be requested
+                if crate::ratelimit::failed_email_change_token(&user.uuid) {
+                    user.email_new = None;
+                    user.email_new_token = None;
+                    user.save(&mut conn).await?;
                 }
+                err!("Token mismatch");
             }
             None => err!("No email change pending"),
         }
+        crate::ratelimit::reset_email_change_token(&user.uuid);
         user.verified_at = Some(Utc::now().naive_utc());
     } else {
         user.verified_at = None;
     }
 
-    user.email = data.new_email;
+    let old_email = std::mem::replace(&mut user.email, data.new_email);
     user.email_new = None;
     user.email_new_token = None;
 
     user.set_password(&data.new_master_password_hash, Some(data.key), true, None);
 
     let save_result = user.save(&mut conn).await;
+    if save_result.is_ok() {
+        relink_emergency_access_invites(&user, &old_email, &mut conn).await;
+    }
 
     nt.send_logout(&user, None, &mut conn).await;
 
     save_result
 }
 
+/// Pending emergency access invites reference the grantee by email and carry the grantor email in their token.
+/// After an email change, move the invites to the new address and send out fresh invite mails.
+async fn relink_emergency_access_invites(user: &User, old_email: &str, conn: &mut DbConn) {
+    let relinked = match EmergencyAccess::relink_all_invited_by_grantee_email(old_email, &user.email, conn).await {
+        Ok(relinked) => relinked,
+        Err(e) => {
+            error!("Error relinking emergency access invites of user {}: {e:#?}", user.uuid);
+            Vec::new()
+        }
+    };
+
+    if !CONFIG.mail_enabled() {
+        return;
+    }
+
+    for ea in relinked {
+        let Some(grantor) = User::find_by_uuid(&ea.grantor_uuid, conn).await else {
+            continue;
+        };
+        if let Err(e) =
+            mail::send_emergency_access_invite(&user.email, user.uuid.clone(), ea.uuid, &grantor.name, &grantor.email)
+                .await
+        {
+            error!("Error resending emergency access invite: {e:#?}");
+        }
+    }
+
+    for ea in EmergencyAccess::find_all_by_grantor_uuid(&user.uuid, conn).await {
+        if ea.status != EmergencyAccessStatus::Invited as i32 {
+            continue;
+        }
+        let Some(grantee_email) = ea.email else {
+            continue;
+        };
+        let Some(grantee) = User::find_by_mail(&grantee_email, conn).await else {
+            continue;
+        };
+        if let Err(e) =
+            mail::send_emergency_access_invite(&grantee_email, grantee.uuid, ea.uuid, &user.name, &user.email).await
+        {
+            error!("Error resending emergency access invite: {e:#?}");
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecoveryEmailTokenData {
+    // Either the current master password hash, or for SSO-only accounts a protected action OTP
+    master_password_hash: Option<String>,
+    otp: Option<String>,
+    new_recovery_email: String,
+}
+
+/// The recovery email receives the same mails as the login email would, it can't be the login email itself.
+fn check_recovery_email(user: &User, recovery_email: &str) -> EmptyResult {
+    if !crate::util::is_valid_email(recovery_email) {
+        err!("Invalid recovery email")
+    }
+    if recovery_email == user.email {
+        err!("The recovery email has to be different from the login email")
+    }
+    Ok(())
+}
+
+// Vaultwarden specific, a separate address for the account recovery mails, used once verified with the mailed token
+#[post("/accounts/recovery-email/token", data = "<data>")]
+async fn post_recovery_email_token(
+    data: Json<RecoveryEmailTokenData>,
+    headers: Headers,
+    mut conn: DbConn,
+) -> EmptyResult {
+    // Without mail the address can't be verified, nor would it receive anything
+    if !CONFIG.mail_enabled() {
+        err!("This server is not configured to send emails.");
+    }
+
+    let data: RecoveryEmailTokenData = data.into_inner();
+    let mut user = headers.user;
+
+    verify_master_password_proof(&user, data.master_password_hash.as_deref(), data.otp.as_deref(), &mut conn).await?;
+
+    let new_recovery_email = data.new_recovery_email.trim().to_lowercase();
+    check_recovery_email(&user, &new_recovery_email)?;
+
+    let token = crypto::generate_email_token(CONFIG.email_token_size(), CONFIG.email_token_alphanumeric());
+    if crate::ratelimit::allow_user_email(&user.uuid) {
+        if let Err(e) = mail::send_recovery_email(&new_recovery_email, &token).await {
+            error!("Error sending recovery-email email: {e:#?}");
+        }
+    }
+
+    user.recovery_email_new = Some(new_recovery_email);
+    user.recovery_email_new_token = Some(token);
+    user.save(&mut conn).await
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecoveryEmailData {
+    new_recovery_email: String,
+    token: NumberOrString,
+}
+
+#[post("/accounts/recovery-email", data = "<data>")]
+async fn post_recovery_email(data: Json<RecoveryEmailData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    with_error_delay(_post_recovery_email(data, headers, conn)).await
+}
+
+async fn _post_recovery_email(data: Json<RecoveryEmailData>, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    let data: RecoveryEmailData = data.into_inner();
+    let mut user = headers.user;
+
+    let new_recovery_email = data.new_recovery_email.trim().to_lowercase();
+    if user.recovery_email_new.as_deref() != Some(new_recovery_email.as_str()) {
+        err!("No recovery email change pending")
+    }
+
+    let token = data.token.into_string();
+    if !user.recovery_email_new_token.as_deref().is_some_and(|val| crypto::check_email_token(val, &token)) {
+        // Like an email change, discard the pending address after too many wrong tokens
+        if crate::ratelimit::failed_email_change_token(&user.uuid) {
+            user.recovery_email_new = None;
+            user.recovery_email_new_token = None;
+            user.save(&mut conn).await?;
+        }
+        err!("Token mismatch");
+    }
+    crate::ratelimit::reset_email_change_token(&user.uuid);
+
+    user.recovery_email = user.recovery_email_new.take();
+    user.recovery_email_new_token = None;
+    user.save(&mut conn).await
+}
+
+#[delete("/accounts/recovery-email", data = "<data>")]
+async fn delete_recovery_email(data: Json<PasswordOrOtpData>, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    let data: PasswordOrOtpData = data.into_inner();
+    let mut user = headers.user;
+
+    data.validate(&user, true, &mut conn).await?;
+
+    user.recovery_email = None;
+    user.recovery_email_new = None;
+    user.recovery_email_new_token = None;
+    user.save(&mut conn).await
+}
+
 #[post("/accounts/verify-email")]
 async fn post_verify_email(headers: Headers) -> EmptyResult {
     let user = headers.user;
@@ -1021,6 +2794,10 @@ async fn post_verify_email(headers: Headers) -> EmptyResult {
         err!("Cannot verify email address");
     }
 
+    if !crate::ratelimit::allow_user_email(&user.uuid) {
+        return Ok(());
+    }
+
     if let Err(e) = mail::send_verify_email(&user.email, &user.uuid).await {
         error!("Error sending verify_email email: {e:#?}");
     }
@@ -1070,8 +2847,10 @@ async fn post_delete_recover(data: Json<DeleteRecoverData>, mut conn: DbConn) ->
     let data: DeleteRecoverData = data.into_inner();
 
     if CONFIG.mail_enabled() {
-        if let Some(user) = User::find_by_mail(&data.email, &mut conn).await {
-            if let Err(e) = mail::send_delete_account(&user.email, &user.uuid).await {
+        if let Some(user) =
+            User::find_by_mail(&data.email, &mut conn).await.filter(|u| crate::ratelimit::allow_user_email(&u.uuid))
+        {
+            if let Err(e) = mail::send_delete_account(user.recovery_address(), &user.uuid).await {
                 error!("Error sending delete account email: {e:#?}");
             }
         }
@@ -1138,7 +2917,11 @@ struct PasswordHintData {
 }
 
 #[post("/accounts/password-hint", data = "<data>")]
-async fn password_hint(data: Json<PasswordHintData>, mut conn: DbConn) -> EmptyResult {
+async fn password_hint(data: Json<PasswordHintData>, conn: DbConn) -> EmptyResult {
+    with_error_delay(_password_hint(data, conn)).await
+}
+
+async fn _password_hint(data: Json<PasswordHintData>, mut conn: DbConn) -> EmptyResult {
     if !CONFIG.password_hints_allowed() || (!CONFIG.mail_enabled() && !CONFIG.show_password_hint()) {
         err!("This server is not configured to provide password hints.");
     }
@@ -1148,29 +2931,30 @@ async fn password_hint(data: Json<PasswordHintData>, mut conn: DbConn) -> EmptyR
     let data: PasswordHintData = data.into_inner();
     let email = &data.email;
 
-    match User::find_by_mail(email, &mut conn).await {
+    let user = User::find_by_mail(email, &mut conn).await.filter(|user| {
+        !CONFIG.password_hints_hide_inactive()
+            || is_password_hint_available(user, crate::ratelimit::is_verify_password_locked(&user.uuid))
+    });
+
+    match user {
         None => {
             // To prevent user enumeration, act as if the user exists.
+            // Inactive accounts get the same response if their hints are hidden.
             if CONFIG.mail_enabled() {
                 // There is still a timing side channel here in that the code
                 // paths that send mail take noticeably longer than ones that
                 // don't. Add a randomized sleep to mitigate this somewhat.
-                use rand::{rngs::SmallRng, Rng, SeedableRng};
-                let mut rng = SmallRng::from_os_rng();
-                let delta: i32 = 100;
-                let sleep_ms = (1_000 + rng.random_range(-delta..=delta)) as u64;
-                tokio::time::sleep(tokio::time::Duration::from_millis(sleep_ms)).await;
+                tokio::time::sleep(crate::util::randomized_delay(1_000, 100)).await;
                 Ok(())
             } else {
                 err!(NO_HINT);
             }
         }
         Some(user) => {
-            let hint: Option<String> = user.password_hint;
             if CONFIG.mail_enabled() {
-                mail::send_password_hint(email, hint).await?;
+                mail::send_password_hint(user.recovery_address(), user.password_hint.clone()).await?;
                 Ok(())
-            } else if let Some(hint) = hint {
+            } else if let Some(hint) = user.password_hint {
                 err!(format!("Your password hint is: {hint}"));
             } else {
                 err!(NO_HINT);
@@ -1179,6 +2963,11 @@ async fn password_hint(data: Json<PasswordHintData>, mut conn: DbConn) -> EmptyR
     }
 }
 
+/// Accounts which are disabled, awaiting approval or locked out shouldn't reveal their password hint
+fn is_password_hint_available(user: &User, locked_out: bool) -> bool {
+    user.enabled && !user.pending_approval && !locked_out
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PreloginData {
@@ -1227,28 +3016,97 @@ pub async fn kdf_upgrade(user: &mut User, pwd_hash: &str, conn: &mut DbConn) ->
 }
 
 #[post("/accounts/verify-password", data = "<data>")]
-async fn verify_password(data: Json<SecretVerificationRequest>, headers: Headers, mut conn: DbConn) -> JsonResult {
+async fn verify_password(
+    data: Json<SecretVerificationRequest>,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
+    with_error_delay(_verify_password(data, headers, conn, nt)).await
+}
+
+async fn _verify_password(
+    data: Json<SecretVerificationRequest>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
     let data: SecretVerificationRequest = data.into_inner();
     let mut user = headers.user;
 
+    crate::ratelimit::check_limit_verify_password(&user.uuid)?;
+
     if !user.check_valid_password(&data.master_password_hash) {
+        log_user_event(
+            EventType::UserFailedLogIn as i32,
+            &user.uuid,
+            headers.device.atype,
+            &headers.ip.ip,
+            &mut conn,
+            &nt,
+        )
+        .await;
+        if let Some(lockout) = crate::ratelimit::failed_verify_password(&user.uuid) {
+            warn!("Password verification for user {} locked for {} seconds", user.uuid, lockout.as_secs());
+        }
         err!("Invalid password")
     }
+    crate::ratelimit::reset_verify_password(&user.uuid);
 
     kdf_upgrade(&mut user, &data.master_password_hash, &mut conn).await?;
 
     Ok(Json(master_password_policy(&user, &conn).await))
 }
 
-async fn _api_key(data: Json<PasswordOrOtpData>, rotate: bool, headers: Headers, mut conn: DbConn) -> JsonResult {
-    use crate::util::format_date;
+// Returns the same policy as a successful `verify-password`, so clients can show the requirements without asking for the password
+#[get("/accounts/master-password-policy")]
+async fn get_user_master_password_policy(headers: Headers, conn: DbConn) -> Json<Value> {
+    Json(master_password_policy(&headers.user, &conn).await)
+}
 
+// Lists the policies of all organizations which currently apply to the user, grouped by policy type
+#[get("/accounts/policies")]
+async fn get_user_policies(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let policies = OrgPolicy::find_active_by_user(&headers.user.uuid, &mut conn).await;
+
+    Json(json!({
+        "data": group_policies_by_type(policies),
+        "continuationToken": null,
+        "object": "list",
+    }))
+}
+
+fn group_policies_by_type(policies: Vec<OrgPolicy>) -> Vec<Value> {
+    let mut by_type: std::collections::BTreeMap<i32, Vec<OrgPolicy>> = std::collections::BTreeMap::new();
+    for policy in policies.into_iter().filter(|p| p.enabled) {
+        by_type.entry(policy.atype).or_default().push(policy);
+    }
+
+    by_type
+        .into_iter()
+        .map(|(atype, policies)| {
+            let mut organization_ids: Vec<&OrganizationId> = policies.iter().map(|p| &p.org_uuid).collect();
+            organization_ids.sort_by_key(|id| id.to_string());
+            organization_ids.dedup();
+
+            json!({
+                "type": atype,
+                "organizationIds": organization_ids,
+                "policies": policies.iter().map(OrgPolicy::to_json).collect::<Vec<Value>>(),
+                "object": "userPolicy",
+            })
+        })
+        .collect()
+}
+
+async fn _api_key(data: Json<PasswordOrOtpData>, rotate: bool, headers: Headers, mut conn: DbConn) -> JsonResult {
     let data: PasswordOrOtpData = data.into_inner();
     let mut user = headers.user;
 
     data.validate(&user, true, &mut conn).await?;
 
     if rotate || user.api_key.is_none() {
+        enforce_verified_email_for_api_keys(&user)?;
         user.api_key = Some(crypto::generate_api_key());
         user.save(&mut conn).await.expect("Error saving API key");
     }
@@ -1260,23 +3118,197 @@ async fn _api_key(data: Json<PasswordOrOtpData>, rotate: bool, headers: Headers,
     })))
 }
 
-#[post("/accounts/api-key", data = "<data>")]
-async fn api_key(data: Json<PasswordOrOtpData>, headers: Headers, conn: DbConn) -> JsonResult {
-    _api_key(data, false, headers, conn).await
-}
-
-#[post("/accounts/rotate-api-key", data = "<data>")]
-async fn rotate_api_key(data: Json<PasswordOrOtpData>, headers: Headers, conn: DbConn) -> JsonResult {
-    _api_key(data, true, headers, conn).await
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiKeyClientCertData {
+    // Removes the binding when empty
+    fingerprint: Option<String>,
+    master_password_hash: Option<String>,
+    otp: Option<String>,
 }
 
-#[get("/devices/knowndevice")]
-async fn get_known_device(device: KnownDevice, mut conn: DbConn) -> JsonResult {
-    let mut result = false;
-    if let Some(user) = User::find_by_mail(&device.email, &mut conn).await {
-        result = Device::find_by_uuid_and_user(&device.uuid, &user.uuid, &mut conn).await.is_some();
+/// Vaultwarden specific, binds the personal API key to the fingerprint of a TLS client certificate.
+/// This only has an effect when the reverse proxy forwards the fingerprint, see `API_KEY_CLIENT_CERT_HEADER`.
+#[put("/accounts/api-key/client-cert", data = "<data>")]
+async fn put_api_key_client_cert(data: Json<ApiKeyClientCertData>, headers: Headers, mut conn: DbConn) -> JsonResult {
+    if CONFIG.api_key_client_cert_header().is_none() {
+        err!("Client certificate binding is not enabled on this server")
     }
-    Ok(Json(json!(result)))
+
+    let data: ApiKeyClientCertData = data.into_inner();
+    let mut user = headers.user;
+
+    PasswordOrOtpData {
+        master_password_hash: data.master_password_hash,
+        otp: data.otp,
+    }
+    .validate(&user, true, &mut conn)
+    .await?;
+
+    let fingerprint = data.fingerprint.as_deref().map(User::normalize_cert_fingerprint).filter(|f| !f.is_empty());
+    // Anything shorter than a SHA-1 fingerprint is most likely a typo
+    if fingerprint.as_ref().is_some_and(|f| f.len() < 40) {
+        err!("Invalid certificate fingerprint")
+    }
+    user.api_key_cert_fingerprint = fingerprint;
+    user.save(&mut conn).await?;
+
+    Ok(Json(json!({
+        "fingerprint": user.api_key_cert_fingerprint,
+        "object": "apiKeyClientCert",
+    })))
+}
+
+/// Vaultwarden specific, returns the encrypted account keys and the KDF settings needed to decrypt them again.
+/// Everything in here is encrypted client side, without the master password the backup is useless.
+/// The backup is signed with the server key, so a copy stored offline can be checked for modifications.
+/// This needs a POST instead of a GET, since the password or OTP has to be sent in the body.
+#[post("/accounts/keys/backup", data = "<data>")]
+async fn post_keys_backup(data: Json<PasswordOrOtpData>, headers: Headers, mut conn: DbConn) -> JsonResult {
+    use crate::util::format_date;
+
+    let data: PasswordOrOtpData = data.into_inner();
+    let user = headers.user;
+
+    data.validate(&user, true, &mut conn).await?;
+
+    let backup = json!({
+        "userId": user.uuid,
+        "email": user.email,
+        "key": user.akey,
+        "privateKey": user.private_key,
+        "publicKey": user.public_key,
+        "kdf": user.client_kdf_type,
+        "kdfIterations": user.client_kdf_iter,
+        "kdfMemory": user.client_kdf_memory,
+        "kdfParallelism": user.client_kdf_parallelism,
+        "securityStamp": user.security_stamp,
+        "accountRevisionDate": format_date(&user.updated_at),
+        "creationDate": format_date(&Utc::now().naive_utc()),
+    });
+    let signature = crate::auth::encode_jwt(&crate::auth::generate_keys_backup_claims(user.uuid, backup.clone()));
+
+    Ok(Json(json!({
+        "backup": backup,
+        "signature": signature,
+        "object": "keysBackup",
+    })))
+}
+
+/// Vaultwarden specific, a signed statement about the security settings of the account at this point in time.
+/// The user can hand it to a third party, which can check the `signature` with the public key of this server,
+/// it's an RS256 JWT which contains the attestation in its `attestation` claim. It can also be checked with `/accounts/attestation/verify`.
+/// Only settings are attested, no keys or other secrets.
+#[get("/accounts/attestation")]
+async fn get_attestation(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let user = headers.user;
+    let twofactors = TwoFactor::find_by_user(&user.uuid, &mut conn).await;
+
+    let attestation = account_attestation(&user, &twofactors, &crypto::generate_id::<16>());
+    let signature = crate::auth::encode_jwt(&crate::auth::generate_attestation_claims(user.uuid, attestation.clone()));
+
+    Json(json!({
+        "attestation": attestation,
+        "signature": signature,
+        "object": "accountAttestation",
+    }))
+}
+
+fn account_attestation(user: &User, twofactors: &[TwoFactor], nonce: &str) -> Value {
+    use crate::util::format_date;
+
+    let mut providers: Vec<i32> = twofactors
+        .iter()
+        .filter(|tf| tf.enabled && tf.atype != TwoFactorType::Remember as i32)
+        .map(|tf| tf.atype)
+        .collect();
+    providers.sort_unstable();
+
+    json!({
+        "userId": user.uuid,
+        "email": user.email,
+        "kdf": user.client_kdf_type,
+        "kdfIterations": user.client_kdf_iter,
+        "kdfMemory": user.client_kdf_memory,
+        "kdfParallelism": user.client_kdf_parallelism,
+        "twoFactorEnabled": !providers.is_empty(),
+        "twoFactorProviders": providers,
+        "emailVerified": user.verified_at.is_some(),
+        "accountCreationDate": format_date(&user.created_at),
+        "attestationDate": format_date(&Utc::now().naive_utc()),
+        "nonce": nonce,
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AttestationVerifyData {
+    signature: String,
+}
+
+// Vaultwarden specific, lets a third party without an account check an attestation of this server
+#[post("/accounts/attestation/verify", data = "<data>")]
+fn post_attestation_verify(data: Json<AttestationVerifyData>) -> JsonResult {
+    let claims = crate::auth::decode_attestation(&data.into_inner().signature)?;
+
+    Ok(Json(json!({
+        "attestation": claims.attestation,
+        "object": "accountAttestation",
+    })))
+}
+
+#[post("/accounts/api-key", data = "<data>")]
+async fn api_key(data: Json<PasswordOrOtpData>, headers: Headers, conn: DbConn) -> JsonResult {
+    _api_key(data, false, headers, conn).await
+}
+
+#[post("/accounts/rotate-api-key", data = "<data>")]
+async fn rotate_api_key(data: Json<PasswordOrOtpData>, headers: Headers, conn: DbConn) -> JsonResult {
+    _api_key(data, true, headers, conn).await
+}
+
+#[get("/devices/knowndevice")]
+async fn get_known_device(device: KnownDevice, mut conn: DbConn) -> JsonResult {
+    let mut result = false;
+    if let Some(user) = User::find_by_mail(&device.email, &mut conn).await {
+        result = Device::find_by_uuid_and_user(&device.uuid, &user.uuid, &mut conn).await.is_some();
+    }
+    Ok(Json(json!(result)))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KnownDeviceConfirmData {
+    device_identifier: DeviceId,
+    device_name: String,
+    device_type: i32,
+}
+
+// Vaultwarden specific, registers a device of the logged in user ahead of time, so `get_known_device` recognizes it.
+// Confirming an already known device doesn't change anything.
+#[post("/devices/knowndevice/confirm", data = "<data>")]
+async fn post_known_device_confirm(
+    data: Json<KnownDeviceConfirmData>,
+    headers: Headers,
+    mut conn: DbConn,
+) -> JsonResult {
+    let data = data.into_inner();
+    let user_devices = Device::find_by_user(&headers.user.uuid, &mut conn).await;
+
+    if !Device::is_known(&user_devices, &data.device_identifier) {
+        let device_type = DeviceType::from_i32(data.device_type) as i32;
+        Device::new(
+            data.device_identifier.clone(),
+            headers.user.uuid.clone(),
+            data.device_name,
+            device_type,
+            &mut conn,
+        )
+        .await?;
+        info!("Device {} of user {} confirmed as known", data.device_identifier, headers.user.uuid);
+    }
+
+    Ok(Json(json!(true)))
 }
 
 struct KnownDevice {
@@ -1303,12 +3335,25 @@ impl<'r> FromRequest<'r> for KnownDevice {
             return Outcome::Error((Status::BadRequest, "X-Request-Email value is required"));
         };
 
-        let uuid = if let Some(uuid) = req.headers().get_one("X-Device-Identifier") {
+        let uuid: DeviceId = if let Some(uuid) = req.headers().get_one("X-Device-Identifier") {
             uuid.to_string().into()
         } else {
             return Outcome::Error((Status::BadRequest, "X-Device-Identifier value is required"));
         };
 
+        // This is checked before login, so there usually is no access token to bind the identifier to
+        if CONFIG.device_identifier_binding() {
+            let token_device = req
+                .headers()
+                .get_one("Authorization")
+                .and_then(|auth| auth.strip_prefix("Bearer "))
+                .and_then(|token| decode_login(token).ok())
+                .map(|claims| claims.device);
+            if token_device.is_some_and(|device| device != uuid) {
+                return Outcome::Error((Status::BadRequest, "X-Device-Identifier does not match the access token"));
+            }
+        }
+
         Outcome::Success(KnownDevice {
             email,
             uuid,
@@ -1316,10 +3361,17 @@ impl<'r> FromRequest<'r> for KnownDevice {
     }
 }
 
-#[get("/devices")]
-async fn get_all_devices(headers: Headers, mut conn: DbConn) -> JsonResult {
-    let devices = Device::find_with_auth_request_by_user(&headers.user.uuid, &mut conn).await;
-    let devices = devices.iter().map(|device| device.to_json()).collect::<Vec<Value>>();
+#[derive(FromForm, Default)]
+struct DevicesFilter {
+    // Also list the removed devices which are still kept, see `DEVICE_TOMBSTONE_DAYS`
+    #[field(name = "includeRevoked")]
+    include_revoked: bool,
+}
+
+#[get("/devices?<filter..>")]
+async fn get_all_devices(filter: DevicesFilter, headers: Headers, mut conn: DbConn) -> JsonResult {
+    let devices = Device::find_with_auth_request_by_user(&headers.user.uuid, filter.include_revoked, &mut conn).await;
+    let devices = devices.iter().map(|device| device.to_json(&headers.device.uuid)).collect::<Vec<Value>>();
 
     Ok(Json(json!({
         "data": devices,
@@ -1328,12 +3380,201 @@ async fn get_all_devices(headers: Headers, mut conn: DbConn) -> JsonResult {
     })))
 }
 
+// Vaultwarden specific, to move the device list of a user to another instance
+#[get("/devices/export")]
+async fn get_devices_export(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let devices = Device::find_by_user(&headers.user.uuid, &mut conn).await;
+    let devices_json: Vec<Value> = devices.iter().map(Device::to_export_json).collect();
+
+    Json(json!({
+        "data": devices_json,
+        "object": "deviceExport",
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DevicesImportData {
+    data: Vec<DeviceImport>,
+}
+
+// Vaultwarden specific, recreates the devices of an export. They have to login and register for push notifications again
+#[post("/devices/import", data = "<data>")]
+async fn post_devices_import(data: Json<DevicesImportData>, headers: Headers, mut conn: DbConn) -> JsonResult {
+    let user_id = &headers.user.uuid;
+    let user_devices = Device::find_by_user_including_deleted(user_id, &mut conn).await;
+    let (to_create, mut skipped) = Device::split_import(data.into_inner().data, &user_devices);
+
+    let mut created = Vec::with_capacity(to_create.len());
+    for device in to_create {
+        let device_type = DeviceType::from_i32(device.r#type) as i32;
+        match Device::new(device.identifier.clone(), user_id.clone(), device.name, device_type, &mut conn).await {
+            Ok(device) => created.push(device.uuid),
+            Err(e) => {
+                warn!("Skipped importing device {} of user {user_id}: {e:?}", device.identifier);
+                skipped.push(device.identifier);
+            }
+        }
+    }
+
+    Ok(Json(json!({
+        "created": created,
+        "skipped": skipped,
+        "object": "deviceImport",
+    })))
+}
+
 #[get("/devices/identifier/<device_id>")]
 async fn get_device(device_id: DeviceId, headers: Headers, mut conn: DbConn) -> JsonResult {
     let Some(device) = Device::find_by_uuid_and_user(&device_id, &headers.user.uuid, &mut conn).await else {
         err!("No device found");
     };
-    Ok(Json(device.to_json()))
+    let mut device_json = device.to_json();
+    device_json["current"] = json!(device.is_current(&headers.device.uuid));
+    Ok(Json(device_json))
+}
+
+// Vaultwarden specific, polled by a new device while it's waiting for approval
+#[get("/devices/approval")]
+async fn get_device_approval(headers: Headers) -> Json<Value> {
+    Json(json!({
+        "id": headers.device.uuid,
+        "pendingApproval": headers.device.pending_approval,
+        "encryptedUserKey": headers.device.encrypted_user_key,
+        "object": "deviceApproval",
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeviceApprovalKeyData {
+    public_key: String,
+}
+
+// Vaultwarden specific, the pending device registers the key the user key has to be encrypted with on approval
+#[put("/devices/approval/key", data = "<data>")]
+async fn put_device_approval_key(data: Json<DeviceApprovalKeyData>, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    let mut device = headers.device;
+    if !device.pending_approval {
+        err!("Device is not pending approval")
+    }
+
+    device.approval_public_key = Some(data.into_inner().public_key);
+    device.save(&mut conn).await
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeviceApprovalData {
+    approved: bool,
+    encrypted_user_key: Option<String>,
+}
+
+// Vaultwarden specific, approves or denies a pending device from an already approved device
+#[put("/devices/<device_id>/approval", data = "<data>")]
+async fn put_device_approval(
+    device_id: DeviceId,
+    data: Json<DeviceApprovalData>,
+    headers: Headers,
+    mut conn: DbConn,
+) -> JsonResult {
+    let data = data.into_inner();
+    let Some(mut device) = Device::find_by_uuid_and_user(&device_id, &headers.user.uuid, &mut conn).await else {
+        err!("No device found");
+    };
+
+    if device.resolve_approval(&headers.device, data.approved, data.encrypted_user_key)? {
+        device.save(&mut conn).await?;
+        info!("Device {device_id} of user {} approved from device {}", headers.user.uuid, headers.device.uuid);
+        Ok(Json(device.to_json()))
+    } else {
+        // Denied devices are removed, which invalidates their tokens
+        device.delete(&mut conn).await?;
+        info!("Device {device_id} of user {} denied from device {}", headers.user.uuid, headers.device.uuid);
+        Ok(Json(json!({
+            "id": device_id,
+            "object": "device",
+        })))
+    }
+}
+
+// Removes the device, this invalidates its access and refresh tokens
+#[delete("/devices/<device_id>")]
+async fn delete_device(device_id: DeviceId, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> EmptyResult {
+    let Some(device) = Device::find_by_uuid_and_user(&device_id, &headers.user.uuid, &mut conn).await else {
+        err!("No device found");
+    };
+
+    if device.is_push_device() {
+        if let Err(e) = unregister_push_device(&device.push_uuid).await {
+            error!("Unable to unregister device {device_id} from Bitwarden server: {e}");
+        }
+    }
+
+    let revocation = RevokedSession::device(headers.user.uuid, device.uuid.clone());
+    revocation.save(&mut conn).await?;
+    nt.close_event_streams(&revocation);
+    if CONFIG.device_tombstone_days().is_some() {
+        let mut device = device;
+        device.soft_delete();
+        device.save(&mut conn).await
+    } else {
+        device.delete(&mut conn).await
+    }
+}
+
+#[post("/devices/<device_id>/delete")]
+async fn post_delete_device(device_id: DeviceId, headers: Headers, conn: DbConn, nt: Notify<'_>) -> EmptyResult {
+    delete_device(device_id, headers, conn, nt).await
+}
+
+// Revokes the refresh token of the device without removing it.
+// The device needs to login again once its current access token expires.
+#[post("/devices/<device_id>/deactivate")]
+async fn post_deactivate_device(
+    device_id: DeviceId,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> EmptyResult {
+    let Some(mut device) = Device::find_by_uuid_and_user(&device_id, &headers.user.uuid, &mut conn).await else {
+        err!("No device found");
+    };
+
+    device.revoke_refresh_token();
+    device.save(&mut conn).await?;
+    let revocation = RevokedSession::device(headers.user.uuid, device.uuid);
+    revocation.save(&mut conn).await?;
+    nt.close_event_streams(&revocation);
+    Ok(())
+}
+
+// Lists the recent revocations which affect the session of the requesting device.
+// This also accepts access tokens of revoked sessions, so a reconnecting client can confirm it needs to login again.
+#[get("/accounts/sessions/revoked?<since>")]
+async fn get_revoked_sessions(since: Option<&str>, headers: RevokedSessionHeaders, mut conn: DbConn) -> JsonResult {
+    let since = match since {
+        // ISO 8601 format
+        Some(since) => match NaiveDateTime::parse_from_str(since, "%+") {
+            Ok(since) => Some(since),
+            Err(_) => err!("Invalid since date"),
+        },
+        None => None,
+    };
+
+    let revoked_json: Vec<Value> = RevokedSession::find_by_user(&headers.user.uuid, &mut conn)
+        .await
+        .iter()
+        .filter(|r| since.is_none_or(|since| r.revoked_at >= since) && r.applies_to(&headers.device_id))
+        .map(RevokedSession::to_json)
+        .collect();
+
+    Ok(Json(json!({
+        "data": revoked_json,
+        "reauthenticate": headers.revoked,
+        "object": "list",
+        "continuationToken": null,
+    })))
 }
 
 #[derive(Deserialize)]
@@ -1354,6 +3595,16 @@ async fn put_device_token(
     headers: Headers,
     mut conn: DbConn,
 ) -> EmptyResult {
+    if CONFIG.device_identifier_binding() && device_id != headers.device.uuid {
+        err!(format!("Error: device {device_id} does not match the device of the access token"))
+    }
+
+    // The push tokens are cleared when a user opts out of external services, don't store them again
+    if !headers.user.allows_external_services() {
+        debug!("Not saving the push token of device {device_id}, the user opted out of external services");
+        return Ok(());
+    }
+
     let data = data.into_inner();
     let token = data.push_token;
 
@@ -1407,6 +3658,43 @@ async fn post_clear_device_token(device_id: DeviceId, conn: DbConn) -> EmptyResu
     put_clear_device_token(device_id, conn).await
 }
 
+// Missing categories are left unchanged
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PushPreferencesData {
+    ciphers: Option<bool>,
+    folders: Option<bool>,
+    sends: Option<bool>,
+    auth_requests: Option<bool>,
+}
+
+#[put("/devices/identifier/<device_id>/push-preferences", data = "<data>")]
+async fn put_device_push_preferences(
+    device_id: DeviceId,
+    data: Json<PushPreferencesData>,
+    headers: Headers,
+    mut conn: DbConn,
+) -> JsonResult {
+    let data = data.into_inner();
+    let Some(mut device) = Device::find_by_uuid_and_user(&device_id, &headers.user.uuid, &mut conn).await else {
+        err!("No device found");
+    };
+
+    for (category, allowed) in [
+        (PushCategory::Ciphers, data.ciphers),
+        (PushCategory::Folders, data.folders),
+        (PushCategory::Sends, data.sends),
+        (PushCategory::AuthRequests, data.auth_requests),
+    ] {
+        if let Some(allowed) = allowed {
+            device.set_push_allowed(category, allowed);
+        }
+    }
+    device.save(&mut conn).await?;
+
+    Ok(Json(device.to_json()))
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct AuthRequestRequest {
@@ -1423,6 +3711,17 @@ struct AuthRequestRequest {
 async fn post_auth_request(
     data: Json<AuthRequestRequest>,
     client_headers: ClientHeaders,
+    origin: AuthRequestOrigin,
+    conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
+    with_error_delay(_post_auth_request(data, client_headers, origin, conn, nt)).await
+}
+
+async fn _post_auth_request(
+    data: Json<AuthRequestRequest>,
+    client_headers: ClientHeaders,
+    origin: AuthRequestOrigin,
     mut conn: DbConn,
     nt: Notify<'_>,
 ) -> JsonResult {
@@ -1456,46 +3755,29 @@ async fn post_auth_request(
         client_headers.device_type,
         &client_headers.ip.ip,
         &mut conn,
+        &nt,
     )
     .await;
 
-    Ok(Json(json!({
-        "id": auth_request.uuid,
-        "publicKey": auth_request.public_key,
-        "requestDeviceType": DeviceType::from_i32(auth_request.device_type).to_string(),
-        "requestIpAddress": auth_request.request_ip,
-        "key": null,
-        "masterPasswordHash": null,
-        "creationDate": format_date(&auth_request.creation_date),
-        "responseDate": null,
-        "requestApproved": false,
-        "origin": CONFIG.domain_origin(),
-        "object": "auth-request"
-    })))
+    let mut auth_request_json = auth_request.to_json_for_requester(&origin.origin);
+    // A new request is neither approved nor denied, but clients expect `false` here
+    auth_request_json["requestApproved"] = json!(false);
+    Ok(Json(auth_request_json))
 }
 
 #[get("/auth-requests/<auth_request_id>")]
-async fn get_auth_request(auth_request_id: AuthRequestId, headers: Headers, mut conn: DbConn) -> JsonResult {
+async fn get_auth_request(
+    auth_request_id: AuthRequestId,
+    headers: Headers,
+    origin: AuthRequestOrigin,
+    mut conn: DbConn,
+) -> JsonResult {
     let Some(auth_request) = AuthRequest::find_by_uuid_and_user(&auth_request_id, &headers.user.uuid, &mut conn).await
     else {
         err!("AuthRequest doesn't exist", "Record not found or user uuid does not match")
     };
 
-    let response_date_utc = auth_request.response_date.map(|response_date| format_date(&response_date));
-
-    Ok(Json(json!({
-        "id": &auth_request_id,
-        "publicKey": auth_request.public_key,
-        "requestDeviceType": DeviceType::from_i32(auth_request.device_type).to_string(),
-        "requestIpAddress": auth_request.request_ip,
-        "key": auth_request.enc_key,
-        "masterPasswordHash": auth_request.master_password_hash,
-        "creationDate": format_date(&auth_request.creation_date),
-        "responseDate": response_date_utc,
-        "requestApproved": auth_request.approved,
-        "origin": CONFIG.domain_origin(),
-        "object":"auth-request"
-    })))
+    Ok(Json(auth_request.to_json(&origin.origin)))
 }
 
 #[derive(Debug, Deserialize)]
@@ -1512,6 +3794,7 @@ async fn put_auth_request(
     auth_request_id: AuthRequestId,
     data: Json<AuthResponseRequest>,
     headers: Headers,
+    origin: AuthRequestOrigin,
     mut conn: DbConn,
     ant: AnonymousNotify<'_>,
     nt: Notify<'_>,
@@ -1523,7 +3806,11 @@ async fn put_auth_request(
         err!("AuthRequest doesn't exist", "Record not found or user uuid does not match")
     };
 
-    if headers.device.uuid != data.device_identifier {
+    if !headers.device.can_answer_auth_request(
+        &auth_request,
+        &data.device_identifier,
+        CONFIG.auth_request_same_device_only(),
+    ) {
         err!("AuthRequest doesn't exist", "Device verification failed")
     }
 
@@ -1531,15 +3818,13 @@ async fn put_auth_request(
         err!("An authentication request with the same device already exists")
     }
 
-    let response_date = Utc::now().naive_utc();
-    let response_date_utc = format_date(&response_date);
+    if data.request_approved && CONFIG.auth_request_key_check() && !auth_request.check_key_envelope(&data.key) {
+        err!("The key isn't encrypted for the requesting device", "Key envelope verification failed")
+    }
 
     if data.request_approved {
-        auth_request.approved = Some(data.request_approved);
-        auth_request.enc_key = Some(data.key);
-        auth_request.master_password_hash = data.master_password_hash;
-        auth_request.response_device_id = Some(data.device_identifier.clone());
-        auth_request.response_date = Some(response_date);
+        // Record the device which actually approved the request, not the one it claims to be
+        auth_request.approve(headers.device.uuid.clone(), data.key, data.master_password_hash);
         auth_request.save(&mut conn).await?;
 
         ant.send_auth_response(&auth_request.user_uuid, &auth_request.uuid).await;
@@ -1551,9 +3836,12 @@ async fn put_auth_request(
             headers.device.atype,
             &headers.ip.ip,
             &mut conn,
+            &nt,
         )
         .await;
     } else {
+        auth_request.approved = Some(false);
+        auth_request.response_date = Some(Utc::now().naive_utc());
         // If denied, there's no reason to keep the request
         auth_request.delete(&mut conn).await?;
         log_user_event(
@@ -1562,23 +3850,12 @@ async fn put_auth_request(
             headers.device.atype,
             &headers.ip.ip,
             &mut conn,
+            &nt,
         )
         .await;
     }
 
-    Ok(Json(json!({
-        "id": &auth_request_id,
-        "publicKey": auth_request.public_key,
-        "requestDeviceType": DeviceType::from_i32(auth_request.device_type).to_string(),
-        "requestIpAddress": auth_request.request_ip,
-        "key": auth_request.enc_key,
-        "masterPasswordHash": auth_request.master_password_hash,
-        "creationDate": format_date(&auth_request.creation_date),
-        "responseDate": response_date_utc,
-        "requestApproved": auth_request.approved,
-        "origin": CONFIG.domain_origin(),
-        "object":"auth-request"
-    })))
+    Ok(Json(auth_request.to_json(&origin.origin)))
 }
 
 #[get("/auth-requests/<auth_request_id>/response?<code>")]
@@ -1586,8 +3863,21 @@ async fn get_auth_request_response(
     auth_request_id: AuthRequestId,
     code: &str,
     client_headers: ClientHeaders,
+    origin: AuthRequestOrigin,
+    conn: DbConn,
+) -> JsonResult {
+    with_error_delay(_get_auth_request_response(auth_request_id, code, client_headers, origin, conn)).await
+}
+
+async fn _get_auth_request_response(
+    auth_request_id: AuthRequestId,
+    code: &str,
+    client_headers: ClientHeaders,
+    origin: AuthRequestOrigin,
     mut conn: DbConn,
 ) -> JsonResult {
+    crate::ratelimit::check_limit_auth_request_poll(&auth_request_id)?;
+
     let Some(auth_request) = AuthRequest::find_by_uuid(&auth_request_id, &mut conn).await else {
         err!("AuthRequest doesn't exist", "User not found")
     };
@@ -1596,58 +3886,119 @@ async fn get_auth_request_response(
         || auth_request.request_ip != client_headers.ip.ip.to_string()
         || !auth_request.check_access_code(code)
     {
+        failed_auth_request_code(&auth_request, &mut conn).await?;
         err!("AuthRequest doesn't exist", "Invalid device, IP or code")
     }
 
-    let response_date_utc = auth_request.response_date.map(|response_date| format_date(&response_date));
+    Ok(Json(auth_request.to_json_for_requester(&origin.origin)))
+}
 
-    Ok(Json(json!({
-        "id": &auth_request_id,
-        "publicKey": auth_request.public_key,
-        "requestDeviceType": DeviceType::from_i32(auth_request.device_type).to_string(),
-        "requestIpAddress": auth_request.request_ip,
-        "key": auth_request.enc_key,
-        "masterPasswordHash": auth_request.master_password_hash,
-        "creationDate": format_date(&auth_request.creation_date),
-        "responseDate": response_date_utc,
-        "requestApproved": auth_request.approved,
-        "origin": CONFIG.domain_origin(),
-        "object":"auth-request"
-    })))
+/// Counts a wrong access code, device or IP address, the request is removed once there were too many of them
+async fn failed_auth_request_code(auth_request: &AuthRequest, conn: &mut DbConn) -> EmptyResult {
+    if crate::ratelimit::failed_auth_request_code(&auth_request.uuid) {
+        warn!("Too many failed access codes for auth request {}, removing it", auth_request.uuid);
+        auth_request.delete(conn).await?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RefreshAuthRequestCodeData {
+    access_code: String,
+}
+
+// Lets the requesting device keep a pending request alive, without changing its id and the fingerprint shown to the user.
+// The previous access code stops working immediately.
+#[post("/auth-requests/<auth_request_id>/refresh-code", data = "<data>")]
+async fn post_auth_request_refresh_code(
+    auth_request_id: AuthRequestId,
+    data: Json<RefreshAuthRequestCodeData>,
+    client_headers: ClientHeaders,
+    origin: AuthRequestOrigin,
+    conn: DbConn,
+) -> JsonResult {
+    with_error_delay(_post_auth_request_refresh_code(auth_request_id, data, client_headers, origin, conn)).await
+}
+
+async fn _post_auth_request_refresh_code(
+    auth_request_id: AuthRequestId,
+    data: Json<RefreshAuthRequestCodeData>,
+    client_headers: ClientHeaders,
+    origin: AuthRequestOrigin,
+    mut conn: DbConn,
+) -> JsonResult {
+    let data = data.into_inner();
+    crate::ratelimit::check_limit_auth_request_poll(&auth_request_id)?;
+
+    let Some(mut auth_request) = AuthRequest::find_by_uuid(&auth_request_id, &mut conn).await else {
+        err!("AuthRequest doesn't exist", "User not found")
+    };
+
+    if auth_request.device_type != client_headers.device_type
+        || auth_request.request_ip != client_headers.ip.ip.to_string()
+        || auth_request.is_expired()
+    {
+        err!("AuthRequest doesn't exist", "Invalid device or IP, or expired")
+    }
+
+    if !auth_request.check_access_code(&data.access_code) {
+        failed_auth_request_code(&auth_request, &mut conn).await?;
+        err!("AuthRequest doesn't exist", "Invalid code")
+    }
+
+    let Some(access_code) = auth_request.refresh_access_code(&data.access_code) else {
+        err!("AuthRequest doesn't exist", "Invalid code or already answered")
+    };
+    auth_request.save(&mut conn).await?;
+
+    let mut auth_request_json = auth_request.to_json_for_requester(&origin.origin);
+    auth_request_json["accessCode"] = json!(access_code);
+    Ok(Json(auth_request_json))
 }
 
 // Now unused but not yet removed
 // cf https://github.com/bitwarden/clients/blob/9b2fbdba1c028bf3394064609630d2ec224baefa/libs/common/src/services/api.service.ts#L245
-#[get("/auth-requests")]
-async fn get_auth_requests(headers: Headers, conn: DbConn) -> JsonResult {
-    get_auth_requests_pending(headers, conn).await
+#[derive(FromForm, Default)]
+struct AuthRequestsFilter {
+    // Only list the requests which were approved by this device
+    #[field(name = "responseDeviceId")]
+    response_device_id: Option<DeviceId>,
+}
+
+#[get("/auth-requests?<filter..>")]
+async fn get_auth_requests(
+    filter: AuthRequestsFilter,
+    headers: Headers,
+    origin: AuthRequestOrigin,
+    mut conn: DbConn,
+) -> JsonResult {
+    let Some(response_device_id) = filter.response_device_id else {
+        return get_auth_requests_pending(headers, origin, conn).await;
+    };
+
+    let auth_requests = AuthRequest::find_by_user(&headers.user.uuid, &mut conn).await;
+    Ok(Json(json!({
+        "data": auth_requests
+            .iter()
+            .filter(|request| request.approving_device_id() == Some(&response_device_id))
+            .map(|request| request.to_json(&origin.origin))
+            .collect::<Vec<Value>>(),
+        "continuationToken": null,
+        "object": "list"
+    })))
 }
 
 #[get("/auth-requests/pending")]
-async fn get_auth_requests_pending(headers: Headers, mut conn: DbConn) -> JsonResult {
+async fn get_auth_requests_pending(headers: Headers, origin: AuthRequestOrigin, mut conn: DbConn) -> JsonResult {
     let auth_requests = AuthRequest::find_by_user(&headers.user.uuid, &mut conn).await;
 
     Ok(Json(json!({
         "data": auth_requests
             .iter()
             .filter(|request| request.approved.is_none())
-            .map(|request| {
-            let response_date_utc = request.response_date.map(|response_date| format_date(&response_date));
-
-            json!({
-                "id": request.uuid,
-                "publicKey": request.public_key,
-                "requestDeviceType": DeviceType::from_i32(request.device_type).to_string(),
-                "requestIpAddress": request.request_ip,
-                "key": request.enc_key,
-                "masterPasswordHash": request.master_password_hash,
-                "creationDate": format_date(&request.creation_date),
-                "responseDate": response_date_utc,
-                "requestApproved": request.approved,
-                "origin": CONFIG.domain_origin(),
-                "object":"auth-request"
-            })
-        }).collect::<Vec<Value>>(),
+            .map(|request| request.to_json(&origin.origin))
+            .collect::<Vec<Value>>(),
         "continuationToken": null,
         "object": "list"
     })))
@@ -1661,3 +4012,1350 @@ pub async fn purge_auth_requests(pool: DbPool) {
         error!("Failed to get DB connection while purging trashed ciphers")
     }
 }
+
+/// Removes the devices which were soft-deleted longer ago than the configured number of days.
+/// When soft-deleting is disabled, the remaining ones are removed right away.
+pub async fn purge_device_tombstones(pool: DbPool) {
+    debug!("Purging device tombstones");
+    let Ok(mut conn) = pool.get().await else {
+        error!("Failed to get DB connection while purging device tombstones");
+        return;
+    };
+
+    let days = CONFIG.device_tombstone_days().unwrap_or(0);
+    let cutoff = Utc::now().naive_utc() - TimeDelta::try_days(i64::from(days)).unwrap();
+    if let Err(e) = Device::purge_deleted_before(&cutoff, &mut conn).await {
+        error!("Failed to purge device tombstones: {e:#?}");
+    }
+}
+
+/// Deletes accounts which had to verify their email on signup but never did,
+/// once they are older than the configured number of days.
+/// Accounts with any ciphers, sends, organization memberships or emergency access are kept.
+pub async fn purge_unverified_accounts(pool: DbPool) {
+    let Some(days) = CONFIG.purge_unverified_accounts_after() else {
+        return;
+    };
+    if !CONFIG.signups_verify() {
+        return;
+    }
+
+    debug!("Purging unverified accounts");
+    let Ok(mut conn) = pool.get().await else {
+        error!("Failed to get DB connection while purging unverified accounts");
+        return;
+    };
+
+    let cutoff = Utc::now().naive_utc() - TimeDelta::try_days(i64::from(days)).unwrap();
+    purge_unverified_accounts_before(&cutoff, &mut conn).await;
+}
+
+async fn purge_unverified_accounts_before(cutoff: &NaiveDateTime, conn: &mut DbConn) {
+    for user in User::find_unverified_created_before(cutoff, conn).await {
+        if !user.is_stale_unverified(cutoff) || has_account_activity(&user.uuid, conn).await {
+            continue;
+        }
+
+        let (user_id, email) = (user.uuid.clone(), user.email.clone());
+        match user.delete(conn).await {
+            Ok(()) => info!("Purged unverified account {email} ({user_id})"),
+            Err(e) => error!("Failed to purge unverified account {email} ({user_id}): {e:#?}"),
+        }
+    }
+}
+
+async fn has_account_activity(user_id: &UserId, conn: &mut DbConn) -> bool {
+    Cipher::count_owned_by_user(user_id, conn).await > 0
+        || !Send::find_by_user(user_id, conn).await.is_empty()
+        || !Membership::find_any_state_by_user(user_id, conn).await.is_empty()
+        || !EmergencyAccess::find_all_by_grantor_uuid(user_id, conn).await.is_empty()
+        || !EmergencyAccess::find_all_by_grantee_uuid(user_id, conn).await.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::WS_USERS;
+
+    fn owned_cipher(user: &User, key_version: Option<i32>) -> Cipher {
+        let mut cipher = Cipher::new(1, String::from("2.name"));
+        cipher.user_uuid = Some(user.uuid.clone());
+        cipher.key_version = key_version;
+        cipher
+    }
+
+    #[test]
+    fn test_integrity_scan_reports_version_mismatch() {
+        let mut user = User::new(String::from("user@example.ext"), None);
+        user.key_version = 2;
+
+        let current = owned_cipher(&user, Some(2));
+        let interrupted = owned_cipher(&user, Some(3));
+        let left_behind = owned_cipher(&user, Some(1));
+        let unstamped = owned_cipher(&user, None);
+        let mut send =
+            Send::new(0, String::from("2.name"), String::from("{}"), String::from("2.key"), Utc::now().naive_utc());
+        send.key_version = Some(1);
+
+        let scan = integrity_scan(&user, &[current, interrupted, left_behind, unstamped], &[send]);
+        assert_eq!(scan["cipherIds"].as_array().unwrap().len(), 3);
+        assert_eq!(scan["sendIds"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_claimed_kdf_consistent() {
+        let user = User::new(String::from("user@example.ext"), None);
+
+        assert!(check_claimed_kdf(&user, &ClaimedKdfData::default()).is_ok());
+        let claimed = ClaimedKdfData {
+            kdf: Some(user.client_kdf_type),
+            kdf_iterations: Some(user.client_kdf_iter),
+            ..Default::default()
+        };
+        assert!(check_claimed_kdf(&user, &claimed).is_ok());
+    }
+
+    #[test]
+    fn test_claimed_kdf_inconsistent() {
+        let user = User::new(String::from("user@example.ext"), None);
+
+        let fewer_iterations = ClaimedKdfData {
+            kdf: Some(user.client_kdf_type),
+            kdf_iterations: Some(5_000),
+            ..Default::default()
+        };
+        assert!(check_claimed_kdf(&user, &fewer_iterations).is_err());
+
+        let other_kdf = ClaimedKdfData {
+            kdf: Some(UserKdfType::Argon2id as i32),
+            kdf_memory: Some(64),
+            kdf_parallelism: Some(4),
+            ..Default::default()
+        };
+        assert!(check_claimed_kdf(&user, &other_kdf).is_err());
+    }
+
+    #[test]
+    fn test_public_key_lookup_authenticated() {
+        assert!(!check_public_key_lookup(true, false).unwrap());
+        assert!(!check_public_key_lookup(true, true).unwrap());
+    }
+
+    #[test]
+    fn test_public_key_lookup_anonymous() {
+        assert!(check_public_key_lookup(false, false).is_err());
+        // Anonymous lookups have to go through the rate limiter
+        assert!(check_public_key_lookup(false, true).unwrap());
+    }
+
+    #[test]
+    fn test_integrity_scan_without_rotation() {
+        let user = User::new(String::from("user@example.ext"), None);
+        let ciphers = [owned_cipher(&user, None), owned_cipher(&user, Some(0))];
+
+        let scan = integrity_scan(&user, &ciphers, &[]);
+        assert!(scan["cipherIds"].as_array().unwrap().is_empty());
+    }
+
+    fn register_data(email: &str, name: Option<&str>) -> RegisterData {
+        serde_json::from_value(json!({
+            "email": email,
+            "kdf": 0,
+            "kdfIterations": 600_000,
+            "key": "key",
+            "masterPasswordHash": "hash",
+            "name": name,
+            "emailVerificationToken": "token",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_registration_keys_required() {
+        let keys = KeysData {
+            encrypted_private_key: String::from("2.private"),
+            public_key: String::from("public"),
+        };
+
+        assert!(check_registration_keys(Some(&keys), true).is_ok());
+        assert!(check_registration_keys(None, true).is_err());
+    }
+
+    #[test]
+    fn test_registration_keys_optional() {
+        let data = register_data("user@example.ext", None);
+
+        assert!(data.keys.is_none());
+        assert!(check_registration_keys(data.keys.as_ref(), false).is_ok());
+    }
+
+    fn register_claims(email: &str, name: Option<&str>, verified: bool) -> RegisterVerifyClaims {
+        RegisterVerifyClaims {
+            nbf: 0,
+            exp: 0,
+            iss: String::new(),
+            sub: email.to_string(),
+            name: name.map(str::to_string),
+            verified,
+        }
+    }
+
+    #[test]
+    fn test_register_verify_claims_name_from_claims() {
+        let mut data = register_data("user@example.ext", None);
+
+        let verified = apply_register_verify_claims(&mut data, register_claims("user@example.ext", Some("User"), true));
+
+        // The name is not part of the register/finish call, so it should be taken from the claims.
+        assert!(verified.unwrap());
+        assert_eq!(data.name.as_deref(), Some("User"));
+    }
+
+    #[test]
+    fn test_register_verify_claims_keeps_name_without_claim() {
+        let mut data = register_data("user@example.ext", Some("Provided"));
+
+        let verified = apply_register_verify_claims(&mut data, register_claims("user@example.ext", None, false));
+
+        assert!(!verified.unwrap());
+        assert_eq!(data.name.as_deref(), Some("Provided"));
+    }
+
+    #[test]
+    fn test_register_verify_token_valid() {
+        let mut claims = register_claims("user@example.ext", Some("User"), true);
+        claims.exp = 1_000;
+
+        let status = register_verify_token_status(Some(claims), Some("User@Example.ext"), 900);
+
+        assert_eq!(status["valid"], true);
+        assert_eq!(status["email"], "user@example.ext");
+        assert_eq!(status["name"], "User");
+        assert_eq!(status["verified"], true);
+    }
+
+    #[test]
+    fn test_register_verify_token_expired() {
+        let mut claims = register_claims("user@example.ext", None, false);
+        claims.exp = 1_000;
+
+        let status = register_verify_token_status(Some(claims), None, 2_000);
+
+        assert_eq!(status["valid"], false);
+        assert_eq!(status["expired"], true);
+        assert_eq!(status["email"], Value::Null);
+        assert_eq!(register_verify_token_status(None, None, 2_000)["expired"], false);
+    }
+
+    #[test]
+    fn test_register_verify_token_mismatch() {
+        let mut claims = register_claims("user@example.ext", Some("User"), true);
+        claims.exp = 1_000;
+
+        let status = register_verify_token_status(Some(claims), Some("other@example.ext"), 900);
+
+        assert_eq!(status["valid"], false);
+        assert_eq!(status["expired"], false);
+        assert_eq!(status["name"], Value::Null);
+    }
+
+    #[test]
+    fn test_register_verify_claims_email_mismatch() {
+        let mut data = register_data("user@example.ext", None);
+
+        let result = apply_register_verify_claims(&mut data, register_claims("other@example.ext", Some("Other"), true));
+
+        assert!(result.is_err());
+        assert_eq!(data.name, None);
+    }
+
+    fn cipher_ids(ids: &[&str]) -> Vec<CipherId> {
+        ids.iter().map(|id| CipherId::from(id.to_string())).collect()
+    }
+
+    #[test]
+    fn test_rotation_exact_cipher_ids() {
+        let existing = cipher_ids(&["a", "b"]);
+        let provided = cipher_ids(&["b", "a"]);
+
+        let unexpected =
+            unexpected_cipher_ids(&existing.iter().collect(), &provided.iter().collect::<Vec<&CipherId>>());
+
+        assert!(unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_rotation_extra_cipher_ids() {
+        let existing = cipher_ids(&["a", "b"]);
+        let provided = cipher_ids(&["a", "d", "b", "c"]);
+
+        let unexpected =
+            unexpected_cipher_ids(&existing.iter().collect(), &provided.iter().collect::<Vec<&CipherId>>());
+
+        assert_eq!(unexpected, vec!["c".to_string(), "d".to_string()]);
+    }
+
+    #[test]
+    fn test_rotation_duplicate_cipher_ids() {
+        let existing = cipher_ids(&["a", "b"]);
+        let provided = cipher_ids(&["a", "b", "a"]);
+
+        let unexpected =
+            unexpected_cipher_ids(&existing.iter().collect(), &provided.iter().collect::<Vec<&CipherId>>());
+
+        assert_eq!(unexpected, vec!["a".to_string()]);
+    }
+
+    fn folder(id: Option<&str>, name: &str) -> UpdateFolderData {
+        UpdateFolderData {
+            id: id.map(|id| FolderId::from(id.to_string())),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_rotation_duplicate_folder_names() {
+        let unique = [folder(Some("a"), "2.name|a"), folder(Some("b"), "2.name|b"), folder(None, "2.name|a")];
+        assert!(!has_duplicate_folder_names(&unique));
+
+        let duplicate = [folder(Some("a"), "2.name|a"), folder(Some("b"), "2.name|a")];
+        assert!(has_duplicate_folder_names(&duplicate));
+    }
+
+    #[test]
+    fn test_rotation_preview_matches_rotation() {
+        let user_id = UserId::from(crate::util::get_uuid());
+        let acting_device = DeviceId::from(crate::util::get_uuid());
+        let device_ids = vec![
+            acting_device.clone(),
+            DeviceId::from(crate::util::get_uuid()),
+            DeviceId::from(crate::util::get_uuid()),
+        ];
+
+        let mut enrolled = Membership::new(user_id.clone(), OrganizationId::from(crate::util::get_uuid()), None);
+        enrolled.reset_password_key = Some(String::from("reset-key"));
+        let enrolled_org = enrolled.org_uuid.clone();
+        let not_enrolled = Membership::new(user_id, OrganizationId::from(crate::util::get_uuid()), None);
+        let memberships = memberships_to_rotate(vec![enrolled, not_enrolled]);
+        let grants = emergency_access_grants();
+
+        let preview = key_rotation_preview(&acting_device, &device_ids, &grants, &memberships);
+
+        // Everything except the device doing the rotation gets logged out
+        assert_eq!(preview["deviceCount"], 2);
+        assert!(!preview["deviceIds"].as_array().unwrap().contains(&json!(acting_device)));
+        // All grants and reset password keys have to be included in the rotation
+        assert_eq!(preview["emergencyAccessCount"], grants.len());
+        assert_eq!(preview["resetPasswordKeyCount"], 1);
+        assert_eq!(preview["organizationIds"], json!([enrolled_org]));
+    }
+
+    #[test]
+    fn test_rotation_expected_items_match_validation() {
+        let mut user = User::new(String::from("user@example.ext"), None);
+        user.public_key = Some(String::from("public-key"));
+
+        let ciphers = [owned_cipher(&user, None), owned_cipher(&user, None)];
+        let folders = [Folder::new(user.uuid.clone(), String::from("2.folder"))];
+        let sends =
+            [Send::new(0, String::from("2.name"), String::from("{}"), String::from("2.key"), Utc::now().naive_utc())];
+        let expected = rotation_expected_items(&ciphers, &folders, &sends);
+
+        let key_data = |cipher_ids: &Value| -> KeyData {
+            let ciphers: Vec<Value> = cipher_ids
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|id| json!({"id": id, "type": 1, "name": "2.name"}))
+                .collect();
+            let folders: Vec<Value> = expected["folderIds"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|id| json!({"id": id, "name": "2.folder"}))
+                .collect();
+            let sends: Vec<Value> = expected["sendIds"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|id| {
+                    json!({
+                        "id": id,
+                        "type": 0,
+                        "key": "2.key",
+                        "name": "2.name",
+                        "deletionDate": "2030-01-01T00:00:00Z",
+                        "disabled": false,
+                    })
+                })
+                .collect();
+            serde_json::from_value(json!({
+                "accountUnlockData": {
+                    "emergencyAccessUnlockData": [],
+                    "masterPasswordUnlockData": {
+                        "kdfType": user.client_kdf_type,
+                        "kdfIterations": user.client_kdf_iter,
+                        "email": user.email,
+                        "masterKeyAuthenticationHash": "hash",
+                        "masterKeyEncryptedUserKey": "2.key",
+                    },
+                    "organizationAccountRecoveryUnlockData": [],
+                },
+                "accountKeys": {
+                    "userKeyEncryptedAccountPrivateKey": "2.private-key",
+                    "accountPublicKey": "public-key",
+                },
+                "accountData": {"ciphers": ciphers, "folders": folders, "sends": sends},
+            }))
+            .unwrap()
+        };
+
+        let complete = key_data(&expected["cipherIds"]);
+        assert!(validate_keydata(&complete, &ciphers, &folders, &[], &[], &sends, &user).is_ok());
+
+        let missing = key_data(&json!([ciphers[0].uuid]));
+        assert!(validate_keydata(&missing, &ciphers, &folders, &[], &[], &sends, &user).is_err());
+    }
+
+    #[test]
+    fn test_registration_meets_org_kdf_minimums() {
+        let register = |kdf: i32, kdf_iterations: i32, kdf_memory: Option<i32>, kdf_parallelism: Option<i32>| {
+            let mut user = User::new(String::from("invited@example.ext"), None);
+            set_kdf_data(
+                &mut user,
+                KDFData {
+                    kdf,
+                    kdf_iterations,
+                    kdf_memory,
+                    kdf_parallelism,
+                },
+            )
+            .unwrap();
+            user
+        };
+        let pbkdf2_user = register(UserKdfType::Pbkdf2 as i32, 600_000, None, None);
+        let argon2_user = register(UserKdfType::Argon2id as i32, 3, Some(64), Some(4));
+
+        let plain_org = Organization::new(String::from("Plain"), String::from("owner@example.ext"), None, None);
+        let mut strict_org = Organization::new(String::from("Strict"), String::from("owner@example.ext"), None, None);
+        strict_org.set_kdf_minimums(Some(800_000), None, Some(128), None).unwrap();
+        let orgs = [plain_org, strict_org];
+        let (plain, strict) = (&orgs[..1], &orgs[1..]);
+
+        // Without an invite, or when invited by an organization without minimums, the server floor is enough
+        assert!(check_org_kdf_minimums(&pbkdf2_user, &[]).is_ok());
+        assert!(check_org_kdf_minimums(&pbkdf2_user, plain).is_ok());
+
+        // The minimums of all inviting organizations apply
+        assert!(check_org_kdf_minimums(&pbkdf2_user, &orgs).is_err());
+        assert!(check_org_kdf_minimums(&argon2_user, strict).is_err());
+
+        let strong_pbkdf2_user = register(UserKdfType::Pbkdf2 as i32, 900_000, None, None);
+        let strong_argon2_user = register(UserKdfType::Argon2id as i32, 3, Some(256), Some(4));
+        assert!(check_org_kdf_minimums(&strong_pbkdf2_user, &orgs).is_ok());
+        assert!(check_org_kdf_minimums(&strong_argon2_user, strict).is_ok());
+
+        // Minimums no client could register with are refused
+        let mut org = Organization::new(String::from("Other"), String::from("owner@example.ext"), None, None);
+        assert!(org.set_kdf_minimums(None, None, Some(2048), None).is_err());
+        assert!(org.set_kdf_minimums(Some(0), None, None, None).is_err());
+    }
+
+    #[test]
+    fn test_rotation_failure_reports_stage_and_item() {
+        let mut user = User::new(String::from("user@example.ext"), None);
+        user.public_key = Some(String::from("public-key"));
+
+        let ciphers = [owned_cipher(&user, None)];
+        let folders = [Folder::new(user.uuid.clone(), String::from("2.folder"))];
+        let emergency_access = [EmergencyAccess::new(user.uuid.clone(), String::from("grantee@example.ext"), 2, 0, 7)];
+        let mut membership = Membership::new(user.uuid.clone(), OrganizationId::from(crate::util::get_uuid()), None);
+        membership.reset_password_key = Some(String::from("2.reset-key"));
+        let memberships = [membership];
+        let sends =
+            [Send::new(0, String::from("2.name"), String::from("{}"), String::from("2.key"), Utc::now().naive_utc())];
+
+        let complete = json!({
+            "accountUnlockData": {
+                "emergencyAccessUnlockData": [{"id": emergency_access[0].uuid, "keyEncrypted": "4.key"}],
+                "masterPasswordUnlockData": {
+                    "kdfType": user.client_kdf_type,
+                    "kdfIterations": user.client_kdf_iter,
+                    "email": user.email,
+                    "masterKeyAuthenticationHash": "hash",
+                    "masterKeyEncryptedUserKey": "2.key",
+                },
+                "organizationAccountRecoveryUnlockData": [
+                    {"organizationId": memberships[0].org_uuid, "resetPasswordKey": "4.key"}
+                ],
+            },
+            "accountKeys": {
+                "userKeyEncryptedAccountPrivateKey": "2.private-key",
+                "accountPublicKey": "public-key",
+            },
+            "accountData": {
+                "ciphers": [{"id": ciphers[0].uuid, "type": 1, "name": "2.name"}],
+                "folders": [{"id": folders[0].uuid, "name": "2.folder"}],
+                "sends": [{
+                    "id": sends[0].uuid,
+                    "type": 0,
+                    "key": "2.key",
+                    "name": "2.name",
+                    "deletionDate": "2030-01-01T00:00:00Z",
+                    "disabled": false,
+                }],
+            },
+        });
+        let validate = |data: Value| {
+            let data: KeyData = serde_json::from_value(data).unwrap();
+            validate_keydata(&data, &ciphers, &folders, &emergency_access, &memberships, &sends, &user)
+        };
+        let diagnostics = |data: Value| validate(data).unwrap_err().to_json()["rotationDiagnostics"].clone();
+        assert!(validate(complete.clone()).is_ok());
+
+        let mut changed_email = complete.clone();
+        changed_email["accountUnlockData"]["masterPasswordUnlockData"]["email"] = json!("other@example.ext");
+        assert_eq!(diagnostics(changed_email), json!({"stage": "user", "itemId": null}));
+
+        let mut changed_keypair = complete.clone();
+        changed_keypair["accountKeys"]["accountPublicKey"] = json!("other-public-key");
+        assert_eq!(diagnostics(changed_keypair)["stage"], "user");
+
+        let missing_items = [
+            ("/accountData/ciphers", "ciphers", ciphers[0].uuid.to_string()),
+            ("/accountData/folders", "folders", folders[0].uuid.to_string()),
+            ("/accountUnlockData/emergencyAccessUnlockData", "emergencyAccess", emergency_access[0].uuid.to_string()),
+            (
+                "/accountUnlockData/organizationAccountRecoveryUnlockData",
+                "resetPassword",
+                memberships[0].org_uuid.to_string(),
+            ),
+            ("/accountData/sends", "sends", sends[0].uuid.to_string()),
+        ];
+        for (pointer, stage, item_id) in missing_items {
+            let mut missing = complete.clone();
+            *missing.pointer_mut(pointer).unwrap() = json!([]);
+            assert_eq!(diagnostics(missing), json!({"stage": stage, "itemId": item_id}));
+        }
+    }
+
+    #[test]
+    fn test_rotation_expected_items_exclude_org_ciphers() {
+        let user = User::new(String::from("user@example.ext"), None);
+        let personal = owned_cipher(&user, None);
+        let personal_id = personal.uuid.clone();
+        let mut org_cipher = Cipher::new(1, String::from("2.name"));
+        org_cipher.organization_uuid = Some(OrganizationId::from(crate::util::get_uuid()));
+
+        let expected = rotation_expected_items(&[personal, org_cipher], &[], &[]);
+        assert_eq!(expected["cipherIds"], json!([personal_id]));
+    }
+
+    #[test]
+    fn test_registration_region_from_invite() {
+        let region = registration_region(Some(String::from("us")), Some(String::from("eu")), "eu", "eu,us").unwrap();
+        assert_eq!(region.as_deref(), Some("us"));
+
+        // An invited account without a region in its invite keeps the region it was created with
+        let region = registration_region(None, Some(String::from("us")), "eu", "eu,us").unwrap();
+        assert_eq!(region.as_deref(), Some("us"));
+    }
+
+    #[test]
+    fn test_registration_region_from_default() {
+        assert_eq!(registration_region(None, None, "eu", "eu,us").unwrap().as_deref(), Some("eu"));
+        assert_eq!(registration_region(None, None, "", "eu,us").unwrap(), None);
+
+        // Without configured regions accounts aren't tagged
+        assert_eq!(registration_region(Some(String::from("us")), None, "", "").unwrap(), None);
+    }
+
+    #[test]
+    fn test_registration_region_must_be_configured() {
+        assert!(registration_region(Some(String::from("apac")), None, "eu", "eu,us").is_err());
+        assert!(registration_region(None, Some(String::from("apac")), "eu", "eu,us").is_err());
+    }
+
+    #[test]
+    fn test_email_change_target_allowed() {
+        assert_eq!(email_change_target_error("new@example.com", false, true, true), None);
+    }
+
+    #[test]
+    fn test_email_change_target_in_use() {
+        assert_eq!(email_change_target_error("taken@example.com", true, true, true), Some("Email already in use"));
+        // Same order as the email change itself, the address being in use is reported first
+        assert_eq!(email_change_target_error("taken@example.com", true, false, false), Some("Email already in use"));
+    }
+
+    #[test]
+    fn test_email_change_target_domain_not_allowed() {
+        assert_eq!(email_change_target_error("new@example.org", false, false, true), Some("Email domain not allowed"));
+        assert_eq!(
+            email_change_target_error("new@example.org", false, true, false),
+            Some("The new email must be within one of the SSO domains")
+        );
+        assert_eq!(email_change_target_error("not-an-email", false, true, true), Some("Invalid email address"));
+    }
+
+    #[test]
+    fn test_pending_email_change_rejected_without_force() {
+        assert!(check_pending_email_change(Some("a@example.com"), "b@example.com", false).is_err());
+        // Requesting a new token for the pending address is fine
+        assert!(check_pending_email_change(Some("a@example.com"), "a@example.com", false).is_ok());
+        assert!(check_pending_email_change(None, "b@example.com", false).is_ok());
+    }
+
+    #[test]
+    fn test_pending_email_change_replaced_with_force() {
+        assert!(check_pending_email_change(Some("a@example.com"), "b@example.com", true).is_ok());
+    }
+
+    #[test]
+    fn test_policies_of_multiple_orgs_are_grouped() {
+        let org_a = OrganizationId::from(crate::util::get_uuid());
+        let org_b = OrganizationId::from(crate::util::get_uuid());
+        let policies = vec![
+            OrgPolicy::new(org_a.clone(), OrgPolicyType::TwoFactorAuthentication, true, String::from("{}")),
+            OrgPolicy::new(org_b.clone(), OrgPolicyType::TwoFactorAuthentication, true, String::from("{}")),
+            OrgPolicy::new(org_a.clone(), OrgPolicyType::SingleOrg, true, String::from("{}")),
+            OrgPolicy::new(org_b.clone(), OrgPolicyType::MasterPassword, true, String::from(r#"{"minLength":12}"#)),
+            OrgPolicy::new(org_b.clone(), OrgPolicyType::DisableSend, false, String::from("{}")),
+        ];
+
+        let grouped = group_policies_by_type(policies);
+        let types: Vec<i64> = grouped.iter().map(|p| p["type"].as_i64().unwrap()).collect();
+        assert_eq!(types, [0, 1, 3]);
+
+        // Overlapping policies are listed once, with all organizations which enforce them
+        let two_factor = &grouped[0];
+        assert_eq!(two_factor["organizationIds"].as_array().unwrap().len(), 2);
+        assert_eq!(two_factor["policies"].as_array().unwrap().len(), 2);
+
+        assert_eq!(grouped[1]["organizationIds"], json!([org_b]));
+        assert_eq!(grouped[1]["policies"][0]["data"]["minLength"], 12);
+        assert_eq!(grouped[2]["organizationIds"], json!([org_a]));
+    }
+
+    #[test]
+    fn test_password_hint_for_normal_account() {
+        let user = User::new(String::from("user@example.com"), None);
+        assert!(is_password_hint_available(&user, false));
+        assert!(!is_password_hint_available(&user, true));
+    }
+
+    #[test]
+    fn test_password_hint_hidden_for_disabled_account() {
+        let mut user = User::new(String::from("user@example.com"), None);
+        user.enabled = false;
+        assert!(!is_password_hint_available(&user, false));
+
+        let mut user = User::new(String::from("pending@example.com"), None);
+        user.pending_approval = true;
+        assert!(!is_password_hint_available(&user, false));
+    }
+
+    #[test]
+    fn test_key_rotation_resume_skips_rotated_items() {
+        let retries = dashmap::DashMap::new();
+        let mut user = User::new(String::from("user@example.ext"), None);
+        user.key_version = 2;
+        let rotated = CipherId::from(crate::util::get_uuid());
+        let pending = CipherId::from(crate::util::get_uuid());
+
+        let mut progress = KeyRotationProgress::new(&user, "2.new-key");
+        progress.mark_rotated(&rotated);
+        let token = progress.stash(&retries);
+
+        let progress = KeyRotationProgress::resume(&token, &user.uuid, "2.new-key", &retries).unwrap();
+        assert_eq!(progress.key_version, 3);
+        let mut ids = vec![Some(rotated), Some(pending.clone()), None];
+        progress.retain_pending(&mut ids, Option::as_ref);
+        assert_eq!(ids, vec![Some(pending), None]);
+
+        // A retry token can only be used once
+        assert!(KeyRotationProgress::resume(&token, &user.uuid, "2.new-key", &retries).is_err());
+    }
+
+    #[test]
+    fn test_key_rotation_resume_rejects_other_user_and_expired() {
+        let retries = dashmap::DashMap::new();
+        let user = User::new(String::from("user@example.ext"), None);
+        let other_user_id = UserId::from(crate::util::get_uuid());
+
+        let token = KeyRotationProgress::new(&user, "2.new-key").stash(&retries);
+        assert!(KeyRotationProgress::resume(&token, &other_user_id, "2.new-key", &retries).is_err());
+
+        let token = KeyRotationProgress::new(&user, "2.new-key").stash(&retries);
+        retries.get_mut(&token).unwrap().expires_at = Utc::now().naive_utc() - TimeDelta::try_minutes(1).unwrap();
+        assert!(KeyRotationProgress::resume(&token, &user.uuid, "2.new-key", &retries).is_err());
+
+        // The items which were rotated already are encrypted with the key of the first attempt
+        let token = KeyRotationProgress::new(&user, "2.new-key").stash(&retries);
+        assert!(KeyRotationProgress::resume(&token, &user.uuid, "2.other-key", &retries).is_err());
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_rotatekey_resumes_after_failure() {
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let mut user = User::new(String::from("rotate@example.ext"), None);
+            user.public_key = Some(String::from("public-key"));
+            user.set_password("old-hash", Some(String::from("2.old-key")), false, None);
+            user.save(&mut conn).await.unwrap();
+            let device_id = DeviceId::from(crate::util::get_uuid());
+            Device::new(
+                device_id.clone(),
+                user.uuid.clone(),
+                String::from("test"),
+                DeviceType::LinuxDesktop as i32,
+                &mut conn,
+            )
+            .await
+            .unwrap();
+
+            let mut folder = Folder::new(user.uuid.clone(), String::from("2.folder"));
+            folder.save(&mut conn).await.unwrap();
+            let deletion_date = Utc::now() + TimeDelta::try_days(7).unwrap();
+            let mut send = Send::new(
+                SendType::Text as i32,
+                String::from("2.send"),
+                String::from("{}"),
+                String::from("2.send-key"),
+                deletion_date.naive_utc(),
+            );
+            send.user_uuid = Some(user.uuid.clone());
+            send.save(&mut conn).await.unwrap();
+
+            let key_data = |ciphers: Value, retry_token: Option<String>| -> Json<KeyData> {
+                Json(
+                    serde_json::from_value(json!({
+                        "accountUnlockData": {
+                            "emergencyAccessUnlockData": [],
+                            "masterPasswordUnlockData": {
+                                "kdfType": user.client_kdf_type,
+                                "kdfIterations": user.client_kdf_iter,
+                                "kdfParallelism": user.client_kdf_parallelism,
+                                "kdfMemory": user.client_kdf_memory,
+                                "email": user.email,
+                                "masterKeyAuthenticationHash": "new-hash",
+                                "masterKeyEncryptedUserKey": "2.new-key",
+                            },
+                            "organizationAccountRecoveryUnlockData": [],
+                        },
+                        "accountKeys": {
+                            "userKeyEncryptedAccountPrivateKey": "2.private-key",
+                            "accountPublicKey": "public-key",
+                        },
+                        "accountData": {
+                            "ciphers": ciphers,
+                            "folders": [{ "id": folder.uuid, "name": "2.folder-rotated" }],
+                            "sends": [{
+                                "id": send.uuid,
+                                "type": SendType::Text as i32,
+                                "key": "2.send-key-rotated",
+                                "deletionDate": deletion_date,
+                                "disabled": false,
+                                "name": "2.send-rotated",
+                                "text": { "text": "2.text", "hidden": false },
+                            }],
+                        },
+                        "oldMasterKeyAuthenticationHash": "old-hash",
+                        "retryToken": retry_token,
+                    }))
+                    .unwrap(),
+                )
+            };
+
+            // The unknown cipher fails the rotation after the folder and the send were saved with the new key
+            let unknown_cipher = json!([{ "id": crate::util::get_uuid(), "type": 1, "name": "2.name" }]);
+            let headers = crate::auth::test_headers(&user.uuid, &device_id, &mut conn).await;
+            let err =
+                post_rotatekey(key_data(unknown_cipher, None), headers, pool.get().await.unwrap(), (&*WS_USERS).into())
+                    .await
+                    .unwrap_err();
+            let body: Value = serde_json::from_str(&err.to_string()).unwrap();
+            assert_eq!(body["rotationDiagnostics"]["stage"], "ciphers");
+            let retry_token = body["retryToken"].as_str().map(String::from);
+            assert!(retry_token.is_some());
+
+            let interrupted = User::find_by_uuid(&user.uuid, &mut conn).await.unwrap();
+            assert_eq!(interrupted.key_version, user.key_version);
+            assert_eq!(interrupted.akey, user.akey);
+
+            let headers = crate::auth::test_headers(&user.uuid, &device_id, &mut conn).await;
+            post_rotatekey(key_data(json!([]), retry_token), headers, pool.get().await.unwrap(), (&*WS_USERS).into())
+                .await
+                .unwrap();
+
+            let rotated = User::find_by_uuid(&user.uuid, &mut conn).await.unwrap();
+            assert_eq!(rotated.akey, "2.new-key");
+            assert_eq!(rotated.key_version, user.key_version + 1);
+            let sends = Send::find_by_user(&user.uuid, &mut conn).await;
+            assert_eq!(sends[0].akey, "2.send-key-rotated");
+            let scan = integrity_scan(&rotated, &[], &sends);
+            assert!(scan["sendIds"].as_array().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_invite_registration_notifies_org_admins() {
+        let org_id = OrganizationId::from(crate::util::get_uuid());
+        let new_user = User::new(String::from("invited@example.com"), None);
+        let member = |atype: MembershipType| {
+            let mut member = Membership::new(UserId::from(crate::util::get_uuid()), org_id.clone(), None);
+            member.atype = atype as i32;
+            member
+        };
+        let owner = member(MembershipType::Owner);
+        let admin = member(MembershipType::Admin);
+        let mut joined = member(MembershipType::Admin);
+        joined.user_uuid = new_user.uuid.clone();
+
+        let expected = vec![owner.uuid.clone(), admin.uuid.clone()];
+
+        let recipients = invite_registration_recipients(
+            vec![owner, admin, member(MembershipType::User), member(MembershipType::Manager), joined],
+            &new_user.uuid,
+        );
+        let recipient_ids: Vec<_> = recipients.into_iter().map(|m| m.uuid).collect();
+        assert_eq!(recipient_ids, expected);
+    }
+
+    #[test]
+    fn test_key_rotation_logout_scope() {
+        let device_id = DeviceId::from(crate::util::get_uuid());
+        assert_eq!(key_rotation_logout_exception("keep_current", &device_id), Some(device_id.clone()));
+        assert_eq!(key_rotation_logout_exception("everywhere", &device_id), None);
+    }
+
+    #[test]
+    fn test_account_attestation() {
+        let mut user = User::new(String::from("user@example.com"), None);
+        let mut disabled = TwoFactor::new(user.uuid.clone(), TwoFactorType::Email, String::from("{}"));
+        disabled.enabled = false;
+
+        let attestation = account_attestation(&user, &[disabled], "nonce");
+        assert_eq!(attestation["twoFactorEnabled"], false);
+        assert_eq!(attestation["emailVerified"], false);
+        assert_eq!(attestation["kdfIterations"], user.client_kdf_iter);
+        assert_eq!(attestation["nonce"], "nonce");
+
+        user.verified_at = Some(Utc::now().naive_utc());
+        let twofactors = [
+            TwoFactor::new(user.uuid.clone(), TwoFactorType::Webauthn, String::from("[]")),
+            TwoFactor::new(user.uuid.clone(), TwoFactorType::Authenticator, String::from("secret")),
+        ];
+        let attestation = account_attestation(&user, &twofactors, "nonce");
+        assert_eq!(attestation["twoFactorEnabled"], true);
+        assert_eq!(attestation["twoFactorProviders"], json!([0, 7]));
+        assert_eq!(attestation["emailVerified"], true);
+        assert!(!attestation.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn test_register_with_initial_folder() {
+        let user = User::new(String::from("user@example.com"), None);
+        let mut data = register_data("user@example.com", None);
+        assert!(data.initial_folder.is_none());
+        data.initial_folder = serde_json::from_value(json!({"name": "2.work|folder"})).unwrap();
+
+        let folder = initial_folder(&user, data.initial_folder).unwrap();
+        assert_eq!(folder.user_uuid, user.uuid);
+        assert_eq!(folder.name, "2.work|folder");
+        assert!(initial_folder(&user, None).is_none());
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_passwordless_setup_requires_sso_login() {
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+
+            // The public registration always needs a master password
+            let data = serde_json::from_value::<RegisterData>(json!({
+                "email": "user@example.com",
+                "kdf": 0,
+                "kdfIterations": 600_000,
+                "key": "2.user-key",
+            }));
+            assert!(data.is_err());
+
+            let mut sso_user = User::new(String::from("sso@example.com"), None);
+            sso_user.save(&mut conn).await.unwrap();
+            SsoUser {
+                user_uuid: sso_user.uuid.clone(),
+                identifier: crate::sso::OIDCIdentifier::from("https://sso.example.ext/subject"),
+            }
+            .save(&mut conn)
+            .await
+            .unwrap();
+            let mut password_user = User::new(String::from("password@example.com"), None);
+            password_user.save(&mut conn).await.unwrap();
+
+            assert!(check_passwordless_setup(&sso_user, true, &conn).await.is_ok());
+            assert!(check_passwordless_setup(&sso_user, false, &conn).await.is_err());
+            assert!(check_passwordless_setup(&password_user, true, &conn).await.is_err());
+
+            let security_stamp = sso_user.security_stamp.clone();
+            set_passwordless(&mut sso_user, String::from("2.user-key"));
+            assert!(sso_user.sso_only);
+            assert_eq!(sso_user.akey, "2.user-key");
+            // The session of the SSO login stays valid to finish the setup
+            assert_eq!(sso_user.security_stamp, security_stamp);
+        });
+    }
+
+    #[test]
+    fn test_passwordless_account_rejects_password() {
+        let mut user = User::new(String::from("user@example.com"), None);
+        set_passwordless(&mut user, String::from("2.user-key"));
+
+        assert!(user.password_hash.is_empty());
+        assert!(!user.check_valid_password(""));
+        assert!(!user.check_valid_password("hash"));
+    }
+
+    #[test]
+    fn test_account_limit_overrides() {
+        let mut user = User::new(String::from("user@example.com"), None);
+        let limits = account_limits(&user);
+        assert_eq!(limits["attachmentStorage"], json!(CONFIG.user_attachment_limit()));
+        assert_eq!(limits["sendStorage"], json!(CONFIG.user_send_limit()));
+
+        // The per-user overrides take precedence over the global limits
+        user.attachment_limit = Some(2048);
+        user.send_limit = Some(0);
+        let limits = account_limits(&user);
+        assert_eq!(limits["attachmentStorage"], 2048);
+        assert_eq!(limits["sendStorage"], 0);
+        assert_eq!(limits["maxSendFileSize"], 0);
+
+        user.send_limit = Some(1024 * 1024);
+        assert_eq!(account_limits(&user)["maxSendFileSize"], SIZE_525_MB);
+    }
+
+    #[test]
+    fn test_security_overview_reflects_account() {
+        let thresholds = KdfThresholds {
+            pbkdf2_iterations: 600_000,
+            argon2_iterations: 3,
+            argon2_memory: 64,
+            argon2_parallelism: 4,
+        };
+        let mut user = User::new(String::from("user@example.ext"), None);
+
+        let overview = security_overview(&user, 0, &[], None, &[], &thresholds);
+        assert_eq!(overview["deviceCount"], 0);
+        assert_eq!(overview["emailVerified"], false);
+        assert_eq!(overview["twoFactor"]["enabled"], false);
+        assert!(overview["lastPasswordChangeDate"].is_null());
+        assert!(overview["pendingEmailChange"].is_null());
+        assert_eq!(overview["emergencyAccess"]["activeGrants"], 0);
+        assert_eq!(overview["kdfBelowRecommendation"], false);
+
+        let changed_at = Utc::now().naive_utc();
+        user.verified_at = Some(changed_at);
+        user.email_new = Some(String::from("new@example.ext"));
+        user.client_kdf_iter = 100_000;
+        let twofactors = [TwoFactor::new(user.uuid.clone(), TwoFactorType::Authenticator, String::new())];
+        let grant = |status: EmergencyAccessStatus| {
+            EmergencyAccess::new(
+                user.uuid.clone(),
+                String::from("grantee@example.ext"),
+                status as i32,
+                EmergencyAccessType::View as i32,
+                7,
+            )
+        };
+        let grants = [
+            grant(EmergencyAccessStatus::Invited),
+            grant(EmergencyAccessStatus::Confirmed),
+            grant(EmergencyAccessStatus::RecoveryInitiated),
+        ];
+
+        let overview = security_overview(&user, 3, &twofactors, Some(changed_at), &grants, &thresholds);
+        assert_eq!(overview["deviceCount"], 3);
+        assert_eq!(overview["emailVerified"], true);
+        assert_eq!(overview["twoFactor"]["authenticator"], true);
+        assert_eq!(overview["lastPasswordChangeDate"], format_date(&changed_at));
+        assert_eq!(overview["pendingEmailChange"], "new@example.ext");
+        assert_eq!(overview["emergencyAccess"]["activeGrants"], 2);
+        assert_eq!(overview["emergencyAccess"]["recoveriesInitiated"], 1);
+        assert_eq!(overview["kdfBelowRecommendation"], true);
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_purge_keeps_unverified_accounts_with_activity() {
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let cutoff = Utc::now().naive_utc() - TimeDelta::try_days(30).unwrap();
+            let stale_user = |email: &str| {
+                let mut user = User::new(String::from(email), None);
+                user.created_at = cutoff - TimeDelta::try_days(1).unwrap();
+                user.signup_verification = true;
+                user
+            };
+
+            let mut idle = stale_user("idle@example.ext");
+            idle.save(&mut conn).await.unwrap();
+
+            // Created before signups had to verify their email
+            let mut legacy = stale_user("legacy@example.ext");
+            legacy.signup_verification = false;
+            legacy.save(&mut conn).await.unwrap();
+
+            let mut verified = stale_user("verified@example.ext");
+            verified.verified_at = Some(verified.created_at);
+            verified.save(&mut conn).await.unwrap();
+
+            let mut sender = stale_user("sender@example.ext");
+            sender.save(&mut conn).await.unwrap();
+            let mut send = Send::new(
+                SendType::Text as i32,
+                String::from("2.send"),
+                String::from("{}"),
+                String::from("2.send-key"),
+                Utc::now().naive_utc() + TimeDelta::try_days(7).unwrap(),
+            );
+            send.user_uuid = Some(sender.uuid.clone());
+            send.save(&mut conn).await.unwrap();
+
+            let mut grantor = stale_user("grantor@example.ext");
+            grantor.save(&mut conn).await.unwrap();
+            EmergencyAccess::new(
+                grantor.uuid.clone(),
+                String::from("grantee@example.ext"),
+                EmergencyAccessStatus::Invited as i32,
+                EmergencyAccessType::View as i32,
+                7,
+            )
+            .save(&mut conn)
+            .await
+            .unwrap();
+
+            purge_unverified_accounts_before(&cutoff, &mut conn).await;
+
+            assert!(User::find_by_uuid(&idle.uuid, &mut conn).await.is_none());
+            for kept in [&legacy, &verified, &sender, &grantor] {
+                assert!(User::find_by_uuid(&kept.uuid, &mut conn).await.is_some(), "{} was purged", kept.email);
+            }
+        });
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_confirmed_device_is_known() {
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let mut user = User::new(String::from("known@example.ext"), None);
+            user.save(&mut conn).await.unwrap();
+            let mut devices = Vec::new();
+            for name in ["confirmed", "removed"] {
+                let device_id = DeviceId::from(crate::util::get_uuid());
+                let device_type = DeviceType::LinuxDesktop as i32;
+                devices.push(
+                    Device::new(device_id, user.uuid.clone(), String::from(name), device_type, &mut conn)
+                        .await
+                        .unwrap(),
+                );
+            }
+            let (confirmed, mut removed) = (devices.remove(0), devices.remove(0));
+            removed.soft_delete();
+            removed.save(&mut conn).await.unwrap();
+
+            let is_known = |email: &str, uuid: &DeviceId| {
+                let known_device = KnownDevice {
+                    email: String::from(email),
+                    uuid: uuid.clone(),
+                };
+                let pool = pool.clone();
+                async move { get_known_device(known_device, pool.get().await.unwrap()).await.unwrap().into_inner() }
+            };
+            assert_eq!(is_known(&user.email, &confirmed.uuid).await, json!(true));
+            assert_eq!(is_known(&user.email, &removed.uuid).await, json!(false));
+            assert_eq!(is_known(&user.email, &DeviceId::from(crate::util::get_uuid())).await, json!(false));
+            assert_eq!(is_known("other@example.ext", &confirmed.uuid).await, json!(false));
+        });
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_sstamp_revokes_emergency_access() {
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let mut user = User::new(String::from("reset@example.ext"), None);
+            user.save(&mut conn).await.unwrap();
+            let mut other = User::new(String::from("other@example.ext"), None);
+            other.save(&mut conn).await.unwrap();
+
+            let grant = |grantor: &User, email: &str, status: EmergencyAccessStatus| {
+                EmergencyAccess::new(
+                    grantor.uuid.clone(),
+                    String::from(email),
+                    status as i32,
+                    EmergencyAccessType::Takeover as i32,
+                    7,
+                )
+            };
+            let mut given = grant(&user, "invited@example.ext", EmergencyAccessStatus::Invited);
+            given.save(&mut conn).await.unwrap();
+            let mut received = grant(&other, &user.email, EmergencyAccessStatus::Confirmed);
+            received.grantee_uuid = Some(user.uuid.clone());
+            received.save(&mut conn).await.unwrap();
+            let mut unrelated = grant(&other, "unrelated@example.ext", EmergencyAccessStatus::Invited);
+            unrelated.save(&mut conn).await.unwrap();
+
+            // Without SSTAMP_REVOKES_EMERGENCY_ACCESS the grants are left alone
+            revoke_emergency_access(&user, false, &mut conn).await.unwrap();
+            assert_eq!(EmergencyAccess::find_all_by_grantor_uuid(&user.uuid, &mut conn).await.len(), 1);
+            assert_eq!(EmergencyAccess::find_all_by_grantee_uuid(&user.uuid, &mut conn).await.len(), 1);
+
+            revoke_emergency_access(&user, true, &mut conn).await.unwrap();
+            assert!(EmergencyAccess::find_all_by_grantor_uuid(&user.uuid, &mut conn).await.is_empty());
+            assert!(EmergencyAccess::find_all_by_grantee_uuid(&user.uuid, &mut conn).await.is_empty());
+            let remaining = EmergencyAccess::find_all_by_grantor_uuid(&other.uuid, &mut conn).await;
+            assert_eq!(remaining.len(), 1);
+            assert_eq!(remaining[0].uuid, unrelated.uuid);
+        });
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_revoked_device_cannot_refresh() {
+        use crate::auth::{refresh_tokens, AuthMethod, AuthTokens};
+
+        crate::auth::init_test_keys();
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let mut user = User::new(String::from("devices@example.ext"), None);
+            user.save(&mut conn).await.unwrap();
+            let mut devices = Vec::new();
+            for name in ["acting", "deactivated", "deleted"] {
+                let device_id = DeviceId::from(crate::util::get_uuid());
+                let device_type = DeviceType::LinuxDesktop as i32;
+                devices.push(
+                    Device::new(device_id, user.uuid.clone(), String::from(name), device_type, &mut conn)
+                        .await
+                        .unwrap(),
+                );
+            }
+            let ip = ClientIp {
+                ip: std::net::IpAddr::from([127, 0, 0, 1]),
+                country: None,
+            };
+            let refresh_token =
+                |device: &Device| AuthTokens::new(device, &user, AuthMethod::Password, None).refresh_token();
+            let (acting, deactivated, deleted) = (&devices[0], &devices[1], &devices[2]);
+            let (deactivated_token, deleted_token) = (refresh_token(deactivated), refresh_token(deleted));
+            assert!(refresh_tokens(&ip, &deactivated_token, None, &mut conn).await.is_ok());
+
+            let headers = crate::auth::test_headers(&user.uuid, &acting.uuid, &mut conn).await;
+            post_deactivate_device(deactivated.uuid.clone(), headers, pool.get().await.unwrap(), (&*WS_USERS).into())
+                .await
+                .unwrap();
+            let headers = crate::auth::test_headers(&user.uuid, &acting.uuid, &mut conn).await;
+            delete_device(deleted.uuid.clone(), headers, pool.get().await.unwrap(), (&*WS_USERS).into()).await.unwrap();
+
+            assert!(refresh_tokens(&ip, &deactivated_token, None, &mut conn).await.is_err());
+            assert!(refresh_tokens(&ip, &deleted_token, None, &mut conn).await.is_err());
+            assert!(refresh_tokens(&ip, &refresh_token(acting), None, &mut conn).await.is_ok());
+        });
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_profile_creation_date() {
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let mut user = User::new(String::from("profile@example.ext"), None);
+            user.created_at =
+                NaiveDateTime::parse_from_str("2024-01-02 03:04:05.000006", "%Y-%m-%d %H:%M:%S%.f").unwrap();
+            user.save(&mut conn).await.unwrap();
+            let device_id = DeviceId::from(crate::util::get_uuid());
+            let device_type = DeviceType::LinuxDesktop as i32;
+            Device::new(device_id.clone(), user.uuid.clone(), String::from("test"), device_type, &mut conn)
+                .await
+                .unwrap();
+
+            // The creation date is stored and returned as UTC, like the other date fields
+            let headers = crate::auth::test_headers(&user.uuid, &device_id, &mut conn).await;
+            let profile = headers.user.to_json(&mut conn).await;
+            assert_eq!(profile["creationDate"], "2024-01-02T03:04:05.000006Z");
+
+            let data = Json(serde_json::from_value(json!({ "name": "Renamed" })).unwrap());
+            let updated = post_profile(data, headers, pool.get().await.unwrap()).await.unwrap().into_inner();
+            assert_eq!(updated["name"], "Renamed");
+            assert_eq!(updated["creationDate"], "2024-01-02T03:04:05.000006Z");
+        });
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_invited_registration_notifies_org_admins() {
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let org = Organization::new(String::from("Org"), String::from("org@example.ext"), None, None);
+            org.save(&mut conn).await.unwrap();
+            for (email, atype) in [
+                ("owner@example.ext", MembershipType::Owner),
+                ("admin@example.ext", MembershipType::Admin),
+                ("member@example.ext", MembershipType::User),
+            ] {
+                let mut existing = User::new(String::from(email), None);
+                existing.save(&mut conn).await.unwrap();
+                let mut member = Membership::new(existing.uuid.clone(), org.uuid.clone(), None);
+                member.atype = atype as i32;
+                member.status = MembershipStatus::Confirmed as i32;
+                member.save(&mut conn).await.unwrap();
+            }
+
+            let mut user = User::new(String::from("invited@example.ext"), None);
+            user.save(&mut conn).await.unwrap();
+            let mut invited =
+                Membership::new(user.uuid.clone(), org.uuid.clone(), Some(String::from("owner@example.ext")));
+            invited.status = MembershipStatus::Accepted as i32;
+            invited.save(&mut conn).await.unwrap();
+            let claims = crate::auth::generate_invite_claims(
+                user.uuid.clone(),
+                user.email.clone(),
+                org.uuid.clone(),
+                invited.uuid.clone(),
+                Some(String::from("owner@example.ext")),
+                None,
+            );
+
+            let (notified_org, admins) = invite_registration_admins(&user, &claims, &mut conn).await.unwrap();
+            assert_eq!(notified_org.uuid, org.uuid);
+            let mut emails: Vec<&str> = admins.iter().map(|u| u.email.as_str()).collect();
+            emails.sort_unstable();
+            assert_eq!(emails, vec!["admin@example.ext", "owner@example.ext"]);
+
+            // The invite has to belong to the registered user
+            let other = User::new(String::from("other@example.ext"), None);
+            assert!(invite_registration_admins(&other, &claims, &mut conn).await.is_none());
+        });
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_opted_out_user_push_token_is_not_saved() {
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let mut user = User::new(String::from("private@example.ext"), None);
+            user.external_services = Some(false);
+            user.save(&mut conn).await.unwrap();
+            let device_id = DeviceId::from(crate::util::get_uuid());
+            let device_type = DeviceType::Android as i32;
+            Device::new(device_id.clone(), user.uuid.clone(), String::from("phone"), device_type, &mut conn)
+                .await
+                .unwrap();
+
+            let headers = crate::auth::test_headers(&user.uuid, &device_id, &mut conn).await;
+            let data = Json(PushToken {
+                push_token: String::from("push-token"),
+            });
+            put_device_token(device_id.clone(), data, headers, pool.get().await.unwrap()).await.unwrap();
+
+            let device = Device::find_by_uuid(&device_id, &mut conn).await.unwrap();
+            assert!(device.push_token.is_none());
+            assert!(Device::find_push_devices_by_user(&user.uuid, &mut conn).await.is_empty());
+        });
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_sync_estimate_scales_with_item_count() {
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let mut user = User::new(String::from("user@example.ext"), None);
+            user.save(&mut conn).await.unwrap();
+
+            owned_cipher(&user, None).save(&mut conn).await.unwrap();
+            let one = sync_estimate(&user.uuid, &mut conn).await;
+            for _ in 1..10 {
+                owned_cipher(&user, None).save(&mut conn).await.unwrap();
+            }
+            let ten = sync_estimate(&user.uuid, &mut conn).await;
+            assert_eq!(ten["personal"]["cipherCount"], 10);
+            assert_eq!(
+                ten["personal"]["cipherBytes"].as_i64().unwrap(),
+                one["personal"]["cipherBytes"].as_i64().unwrap() * 10
+            );
+            assert_eq!(ten["totalBytes"], ten["personal"]["cipherBytes"]);
+            assert_eq!(ten["organization"]["cipherCount"], 0);
+
+            // Organization ciphers are only counted once the user can see them
+            let org = Organization::new(String::from("Org"), String::from("org@example.ext"), None, None);
+            org.save(&mut conn).await.unwrap();
+            let mut org_cipher = Cipher::new(1, String::from("2.name"));
+            org_cipher.organization_uuid = Some(org.uuid.clone());
+            org_cipher.save(&mut conn).await.unwrap();
+            let mut member = Membership::new(user.uuid.clone(), org.uuid.clone(), None);
+            member.access_all = true;
+            member.save(&mut conn).await.unwrap();
+            let accepted = sync_estimate(&user.uuid, &mut conn).await;
+            assert_eq!(accepted["organization"]["cipherCount"], 0);
+
+            member.status = MembershipStatus::Confirmed as i32;
+            member.save(&mut conn).await.unwrap();
+            let confirmed = sync_estimate(&user.uuid, &mut conn).await;
+            assert_eq!(confirmed["personal"]["cipherCount"], 10);
+            assert_eq!(confirmed["organization"]["cipherCount"], 1);
+            assert_eq!(confirmed["organization"]["cipherBytes"], one["personal"]["cipherBytes"]);
+        });
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_password_history_prevents_reuse() {
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let mut user = User::new(String::from("history@example.ext"), None);
+            user.set_password("hash-1", Some(String::from("2.key")), false, None);
+            user.save(&mut conn).await.unwrap();
+            let device_id = DeviceId::from(crate::util::get_uuid());
+            Device::new(
+                device_id.clone(),
+                user.uuid.clone(),
+                String::from("test"),
+                DeviceType::LinuxDesktop as i32,
+                &mut conn,
+            )
+            .await
+            .unwrap();
+
+            // Changes the password from `current` to `new`, while the last two passwords can't be reused
+            let change = |current: &str, new: &str| {
+                let data = ChangePassData {
+                    master_password_hash: Some(String::from(current)),
+                    otp: None,
+                    new_master_password_hash: String::from(new),
+                    master_password_hint: None,
+                    key: String::from("2.key"),
+                    claimed_kdf: ClaimedKdfData::default(),
+                };
+                let (pool, user_id, device_id) = (pool.clone(), user.uuid.clone(), device_id.clone());
+                async move {
+                    let mut conn = pool.get().await.unwrap();
+                    let headers = crate::auth::test_headers(&user_id, &device_id, &mut conn).await;
+                    _post_password(Json(data), headers, conn, (&*WS_USERS).into(), 2).await
+                }
+            };
+
+            assert!(change("hash-1", "hash-1").await.is_err(), "the current password can't be reused");
+            change("hash-1", "hash-2").await.unwrap();
+            assert!(change("hash-2", "hash-1").await.is_err(), "a password within the history can't be reused");
+            change("hash-2", "hash-3").await.unwrap();
+            change("hash-3", "hash-4").await.unwrap();
+            // Only `hash-3` and `hash-2` are kept, so `hash-1` can be used again
+            assert_eq!(PasswordHistory::find_by_user(&user.uuid, &mut conn).await.len(), 2);
+            change("hash-4", "hash-1").await.unwrap();
+
+            let user = User::find_by_uuid(&user.uuid, &mut conn).await.unwrap();
+            assert!(user.check_valid_password("hash-1"));
+        });
+    }
+}
@@ -13,7 +13,11 @@ use serde_json::Value;
 use crate::auth::ClientVersion;
 use crate::util::{save_temp_file, NumberOrString};
 use crate::{
-    api::{self, core::log_event, EmptyResult, JsonResult, Notify, PasswordOrOtpData, UpdateType},
+    api::{
+        self,
+        core::{enforce_verified_email_for_sharing, log_event, two_factor},
+        EmptyResult, JsonResult, Notify, PasswordOrOtpData, UpdateType,
+    },
     auth::Headers,
     config::PathType,
     crypto,
@@ -108,6 +112,8 @@ struct SyncData {
 
 #[get("/sync?<data..>")]
 async fn sync(data: SyncData, headers: Headers, client_version: Option<ClientVersion>, mut conn: DbConn) -> JsonResult {
+    two_factor::enforce_2fa_setup_for_sync(&headers.user, &mut conn).await?;
+
     let user_json = headers.user.to_json(&mut conn).await;
 
     // Get all ciphers which are visible by the user
@@ -424,6 +430,7 @@ pub async fn update_cipher_from_data(
     } else {
         cipher.user_uuid = Some(headers.user.uuid.clone());
     }
+    cipher.key_version = cipher.user_uuid.as_ref().map(|_| headers.user.key_version);
 
     if let Some(ref folder_id) = data.folder_id {
         if Folder::find_by_uuid_and_user(folder_id, &headers.user.uuid, conn).await.is_none() {
@@ -904,11 +911,11 @@ async fn post_collections_admin(
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct ShareCipherData {
+pub struct ShareCipherData {
     #[serde(alias = "Cipher")]
-    cipher: CipherData,
+    pub cipher: CipherData,
     #[serde(alias = "CollectionIds")]
-    collection_ids: Vec<CollectionId>,
+    pub collection_ids: Vec<CollectionId>,
 }
 
 #[post("/ciphers/<cipher_id>/share", data = "<data>")]
@@ -953,6 +960,8 @@ async fn put_cipher_share_selected(
 ) -> EmptyResult {
     let mut data: ShareSelectedCipherData = data.into_inner();
 
+    enforce_verified_email_for_sharing(&headers.user)?;
+
     if data.ciphers.is_empty() {
         err!("You must select at least one cipher.")
     }
@@ -987,7 +996,7 @@ async fn put_cipher_share_selected(
     Ok(())
 }
 
-async fn share_cipher_by_uuid(
+pub async fn share_cipher_by_uuid(
     cipher_id: &CipherId,
     data: ShareCipherData,
     headers: &Headers,
@@ -995,6 +1004,8 @@ async fn share_cipher_by_uuid(
     nt: &Notify<'_>,
     override_ut: Option<UpdateType>,
 ) -> JsonResult {
+    enforce_verified_email_for_sharing(&headers.user)?;
+
     let mut cipher = match Cipher::find_by_uuid(cipher_id, conn).await {
         Some(cipher) => {
             if cipher.is_write_accessible_to_user(&headers.user.uuid, conn).await {
@@ -1173,7 +1184,11 @@ async fn save_attachment(
     };
 
     let size_limit = if let Some(ref user_id) = cipher.user_uuid {
-        match CONFIG.user_attachment_limit() {
+        let user_limit = match User::find_by_uuid(user_id, &mut conn).await {
+            Some(user) => user.effective_attachment_limit(),
+            None => CONFIG.user_attachment_limit(),
+        };
+        match user_limit {
             Some(0) => err!("Attachments are disabled"),
             Some(limit_kb) => {
                 let already_used = Attachment::size_by_user(user_id, &mut conn).await;
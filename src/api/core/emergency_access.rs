@@ -5,7 +5,7 @@ use serde_json::Value;
 use crate::{
     api::{
         core::{CipherSyncData, CipherSyncType},
-        EmptyResult, JsonResult,
+        EmptyResult, JsonResult, Notify, PasswordOrOtpData, UpdateType,
     },
     auth::{decode_emergency_access_invite, Headers},
     db::{models::*, DbConn, DbPool},
@@ -18,6 +18,7 @@ pub fn routes() -> Vec<Route> {
     routes![
         get_contacts,
         get_grantees,
+        get_emergency_access_summary,
         get_emergency_access,
         put_emergency_access,
         post_emergency_access,
@@ -25,6 +26,7 @@ pub fn routes() -> Vec<Route> {
         post_delete_emergency_access,
         send_invite,
         resend_invite,
+        post_rotation_notify,
         accept_invite,
         confirm_emergency_access,
         initiate_emergency_access,
@@ -92,6 +94,41 @@ async fn get_grantees(headers: Headers, mut conn: DbConn) -> Json<Value> {
     }))
 }
 
+// Combines the trusted and granted lists, so a client can show both directions at once.
+#[get("/accounts/emergency-access/summary")]
+async fn get_emergency_access_summary(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let user = headers.user;
+    let (granted, received) = if CONFIG.emergency_access_allowed() {
+        let mut received = EmergencyAccess::find_all_by_grantee_uuid(&user.uuid, &mut conn).await;
+        for invite in EmergencyAccess::find_all_invited_by_grantee_email(&user.email, &mut conn).await {
+            if !received.iter().any(|ea| ea.uuid == invite.uuid) {
+                received.push(invite);
+            }
+        }
+        (EmergencyAccess::find_all_by_grantor_uuid(&user.uuid, &mut conn).await, received)
+    } else {
+        (Vec::new(), Vec::new())
+    };
+
+    let mut granted_json = Vec::with_capacity(granted.len());
+    for ea in granted.iter().filter(|ea| ea.is_grantor(&user)) {
+        if let Some(grantee) = ea.to_json_grantee_details(&mut conn).await {
+            granted_json.push(grantee)
+        }
+    }
+
+    let mut received_json = Vec::with_capacity(received.len());
+    for ea in received.iter().filter(|ea| ea.is_grantee(&user)) {
+        received_json.push(ea.to_json_grantor_details(&mut conn).await);
+    }
+
+    Json(json!({
+        "trusted": granted_json,
+        "granted": received_json,
+        "object": "emergencyAccessSummary",
+    }))
+}
+
 #[get("/emergency-access/<emer_id>")]
 async fn get_emergency_access(emer_id: EmergencyAccessId, headers: Headers, mut conn: DbConn) -> JsonResult {
     check_emergency_access_enabled()?;
@@ -324,6 +361,82 @@ async fn resend_invite(emer_id: EmergencyAccessId, headers: Headers, mut conn: D
     Ok(())
 }
 
+/// How a grantee is told about a key rotation of the grantor, depending on the state of the emergency access
+#[derive(Debug, PartialEq)]
+enum RotationNotice {
+    // Still open, the invite is sent again
+    Invite,
+    // The grantee holds an encrypted key, which was replaced by the rotation
+    KeyUpdated,
+}
+
+fn rotation_notice(emergency_access: &EmergencyAccess) -> Option<RotationNotice> {
+    match emergency_access.status {
+        status if status == EmergencyAccessStatus::Invited as i32 => Some(RotationNotice::Invite),
+        // Confirmed, or with a recovery in progress
+        status if status >= EmergencyAccessStatus::Confirmed as i32 => Some(RotationNotice::KeyUpdated),
+        // Accepted grants don't hold a key yet, they still have to be confirmed
+        _ => None,
+    }
+}
+
+// Vaultwarden specific, after a key rotation the grantees are notified so they sync the newly encrypted keys
+#[post("/emergency-access/rotation-notify", data = "<data>")]
+async fn post_rotation_notify(
+    data: Json<PasswordOrOtpData>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> EmptyResult {
+    check_emergency_access_enabled()?;
+
+    let data: PasswordOrOtpData = data.into_inner();
+    let grantor_user = headers.user;
+    data.validate(&grantor_user, true, &mut conn).await?;
+
+    for emergency_access in EmergencyAccess::find_all_by_grantor_uuid(&grantor_user.uuid, &mut conn).await {
+        match rotation_notice(&emergency_access) {
+            Some(RotationNotice::Invite) => {
+                let Some(email) = emergency_access.email.as_deref() else {
+                    continue;
+                };
+                if CONFIG.mail_enabled() {
+                    if let Err(e) = mail::send_emergency_access_invite(
+                        email,
+                        grantor_user.uuid.clone(),
+                        emergency_access.uuid.clone(),
+                        &grantor_user.name,
+                        &grantor_user.email,
+                    )
+                    .await
+                    {
+                        error!("Error sending emergency access invite to {email}: {e:#?}");
+                    }
+                }
+            }
+            Some(RotationNotice::KeyUpdated) => {
+                let Some(grantee_user) = emergency_access.grantee_uuid.as_ref() else {
+                    continue;
+                };
+                let Some(grantee_user) = User::find_by_uuid(grantee_user, &mut conn).await else {
+                    continue;
+                };
+                nt.send_user_update(UpdateType::SyncSettings, &grantee_user, &None, &mut conn).await;
+                if CONFIG.mail_enabled() {
+                    if let Err(e) =
+                        mail::send_emergency_access_grantor_key_rotated(&grantee_user.email, &grantor_user.name).await
+                    {
+                        error!("Error sending emergency access key rotation email to {}: {e:#?}", grantee_user.email);
+                    }
+                }
+            }
+            None => (),
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct AcceptData {
@@ -835,3 +948,104 @@ pub async fn emergency_notification_reminder_job(pool: DbPool) {
         error!("Failed to get DB connection while searching emergency notification reminder")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation_notice_for_each_active_grant() {
+        let grantor = UserId::from(crate::util::get_uuid());
+        let notices: Vec<Option<RotationNotice>> = [
+            EmergencyAccessStatus::Invited,
+            EmergencyAccessStatus::Accepted,
+            EmergencyAccessStatus::Confirmed,
+            EmergencyAccessStatus::RecoveryInitiated,
+            EmergencyAccessStatus::RecoveryApproved,
+        ]
+        .into_iter()
+        .map(|status| {
+            let emergency_access = EmergencyAccess::new(
+                grantor.clone(),
+                String::from("grantee@example.com"),
+                status as i32,
+                EmergencyAccessType::View as i32,
+                7,
+            );
+            rotation_notice(&emergency_access)
+        })
+        .collect();
+
+        assert_eq!(
+            notices,
+            vec![
+                Some(RotationNotice::Invite),
+                None,
+                Some(RotationNotice::KeyUpdated),
+                Some(RotationNotice::KeyUpdated),
+                Some(RotationNotice::KeyUpdated),
+            ]
+        );
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_rotation_notify_reaches_active_grantees() {
+        use crate::api::WS_USERS;
+        use rocket_ws::Message;
+
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let mut grantor = User::new(String::from("grantor@example.ext"), None);
+            grantor.set_password("hash", Some(String::from("2.key")), false, None);
+            grantor.save(&mut conn).await.unwrap();
+            let device_id = DeviceId::from(crate::util::get_uuid());
+            Device::new(
+                device_id.clone(),
+                grantor.uuid.clone(),
+                String::from("test"),
+                DeviceType::LinuxDesktop as i32,
+                &mut conn,
+            )
+            .await
+            .unwrap();
+
+            let mut connections = Vec::new();
+            for (email, status) in [
+                ("confirmed@example.ext", EmergencyAccessStatus::Confirmed),
+                ("initiated@example.ext", EmergencyAccessStatus::RecoveryInitiated),
+                ("approved@example.ext", EmergencyAccessStatus::RecoveryApproved),
+                ("accepted@example.ext", EmergencyAccessStatus::Accepted),
+            ] {
+                let mut grantee = User::new(String::from(email), None);
+                grantee.save(&mut conn).await.unwrap();
+                let status = status as i32;
+                let mut grant = EmergencyAccess::new(
+                    grantor.uuid.clone(),
+                    String::from(email),
+                    status,
+                    EmergencyAccessType::View as i32,
+                    7,
+                );
+                grant.grantee_uuid = Some(grantee.uuid.clone());
+                grant.save(&mut conn).await.unwrap();
+                let (_, rx) = WS_USERS.add_connection(&grantee.uuid);
+                connections.push((status, rx));
+            }
+
+            let headers = crate::auth::test_headers(&grantor.uuid, &device_id, &mut conn).await;
+            let data = PasswordOrOtpData {
+                master_password_hash: Some(String::from("hash")),
+                otp: None,
+            };
+            post_rotation_notify(Json(data), headers, pool.get().await.unwrap(), (&*WS_USERS).into()).await.unwrap();
+
+            for (status, mut rx) in connections {
+                // Accepted grants don't hold a key of the grantor yet
+                let notified = matches!(rx.try_recv(), Ok(Message::Binary(_)));
+                assert_eq!(notified, status != EmergencyAccessStatus::Accepted as i32, "grant {status}");
+                assert!(rx.try_recv().is_err());
+            }
+        });
+    }
+}
@@ -5,8 +5,9 @@ use rocket::{form::FromForm, serde::json::Json, Route};
 use serde_json::Value;
 
 use crate::{
-    api::{EmptyResult, JsonResult},
+    api::{EmptyResult, JsonResult, Notify},
     auth::{AdminHeaders, Headers},
+    crypto,
     db::{
         models::{Cipher, CipherId, Event, Membership, MembershipId, OrganizationId, UserId},
         DbConn, DbPool,
@@ -18,7 +19,7 @@ use crate::{
 /// ###############################################################################################################
 /// /api routes
 pub fn routes() -> Vec<Route> {
-    routes![get_org_events, get_cipher_events, get_user_events,]
+    routes![get_org_events, get_cipher_events, get_user_events, export_personal_events, verify_personal_events_export,]
 }
 
 #[derive(FromForm)]
@@ -136,6 +137,79 @@ async fn get_user_events(
     })))
 }
 
+#[get("/accounts/events/export?<start>&<end>")]
+async fn export_personal_events(start: &str, end: &str, headers: Headers, mut conn: DbConn) -> JsonResult {
+    let events_json: Vec<Value> =
+        Event::find_by_user_uuid(&headers.user.uuid, &parse_date(start), &parse_date(end), &mut conn)
+            .await
+            .iter()
+            .map(|e| e.to_json())
+            .collect();
+
+    // The chain is computed over the rows returned here, it says nothing about the events stored in the database
+    let integrity = CONFIG.events_export_hmac_key().map(|key| {
+        json!({
+            "algorithm": "HMAC-SHA256",
+            "chain": event_hmac_chain(&key, &events_json),
+        })
+    });
+
+    Ok(Json(json!({
+        "data": events_json,
+        "integrity": integrity,
+        "object": "list",
+    })))
+}
+
+#[derive(Deserialize)]
+struct EventsExportIntegrity {
+    chain: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct EventsExportData {
+    data: Vec<Value>,
+    integrity: EventsExportIntegrity,
+}
+
+#[post("/accounts/events/export/verify", data = "<data>")]
+async fn verify_personal_events_export(data: Json<EventsExportData>, _headers: Headers) -> JsonResult {
+    let Some(key) = CONFIG.events_export_hmac_key() else {
+        err!("Event export integrity is not enabled on this server")
+    };
+    let data = data.into_inner();
+    let first_invalid = verify_event_hmac_chain(&key, &data.data, &data.integrity.chain).err();
+
+    Ok(Json(json!({
+        "valid": first_invalid.is_none(),
+        "firstInvalidIndex": first_invalid,
+    })))
+}
+
+/// Every link covers the event and the previous link, so removing, reordering or modifying any exported event breaks the chain from there on
+fn event_hmac_chain(key: &str, events: &[Value]) -> Vec<String> {
+    let mut previous = String::new();
+    events
+        .iter()
+        .map(|event| {
+            previous = crypto::hmac_sha256_sign(key, &format!("{previous}|{event}"));
+            previous.clone()
+        })
+        .collect()
+}
+
+/// Returns the index of the first event which doesn't match the chain
+fn verify_event_hmac_chain(key: &str, events: &[Value], chain: &[String]) -> Result<(), usize> {
+    let expected = event_hmac_chain(key, events);
+    if let Some(index) = expected.iter().zip(chain).position(|(expected, link)| !crypto::ct_eq(expected, link)) {
+        return Err(index);
+    }
+    if expected.len() != chain.len() {
+        return Err(expected.len().min(chain.len()));
+    }
+    Ok(())
+}
+
 fn get_continuation_token(events_json: &[Value]) -> Option<&str> {
     // When the length of the vec equals the max page_size there probably is more data
     // When it is less, then all events are loaded.
@@ -172,7 +246,12 @@ struct EventCollection {
 // https://github.com/bitwarden/server/blob/9ebe16587175b1c0e9208f84397bb75d0d595510/src/Events/Controllers/CollectController.cs
 // https://github.com/bitwarden/server/blob/9ebe16587175b1c0e9208f84397bb75d0d595510/src/Core/AdminConsole/Services/Implementations/EventService.cs
 #[post("/collect", format = "application/json", data = "<data>")]
-async fn post_events_collect(data: Json<Vec<EventCollection>>, headers: Headers, mut conn: DbConn) -> EmptyResult {
+async fn post_events_collect(
+    data: Json<Vec<EventCollection>>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> EmptyResult {
     if !CONFIG.org_events_enabled() {
         return Ok(());
     }
@@ -184,10 +263,12 @@ async fn post_events_collect(data: Json<Vec<EventCollection>>, headers: Headers,
                 _log_user_event(
                     event.r#type,
                     &headers.user.uuid,
+                    &headers.user.uuid,
                     headers.device.atype,
                     Some(event_date),
                     &headers.ip.ip,
                     &mut conn,
+                    &nt,
                 )
                 .await;
             }
@@ -230,20 +311,46 @@ async fn post_events_collect(data: Json<Vec<EventCollection>>, headers: Headers,
     Ok(())
 }
 
-pub async fn log_user_event(event_type: i32, user_id: &UserId, device_type: i32, ip: &IpAddr, conn: &mut DbConn) {
+pub async fn log_user_event(
+    event_type: i32,
+    user_id: &UserId,
+    device_type: i32,
+    ip: &IpAddr,
+    conn: &mut DbConn,
+    nt: &Notify<'_>,
+) {
     if !CONFIG.org_events_enabled() {
         return;
     }
-    _log_user_event(event_type, user_id, device_type, None, ip, conn).await;
+    _log_user_event(event_type, user_id, user_id, device_type, None, ip, conn, nt).await;
 }
 
+/// Same as `log_user_event`, but for events on `user_id` which were caused by another user
+pub async fn log_user_event_by(
+    event_type: i32,
+    user_id: &UserId,
+    act_user_id: &UserId,
+    device_type: i32,
+    ip: &IpAddr,
+    conn: &mut DbConn,
+    nt: &Notify<'_>,
+) {
+    if !CONFIG.org_events_enabled() {
+        return;
+    }
+    _log_user_event(event_type, user_id, act_user_id, device_type, None, ip, conn, nt).await;
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn _log_user_event(
     event_type: i32,
     user_id: &UserId,
+    act_user_id: &UserId,
     device_type: i32,
     event_date: Option<NaiveDateTime>,
     ip: &IpAddr,
     conn: &mut DbConn,
+    nt: &Notify<'_>,
 ) {
     let memberships = Membership::find_by_user(user_id, conn).await;
     let mut events: Vec<Event> = Vec::with_capacity(memberships.len() + 1); // We need an event per org and one without an org
@@ -251,9 +358,10 @@ async fn _log_user_event(
     // Upstream saves the event also without any org_id.
     let mut event = Event::new(event_type, event_date);
     event.user_uuid = Some(user_id.clone());
-    event.act_user_uuid = Some(user_id.clone());
+    event.act_user_uuid = Some(act_user_id.clone());
     event.device_type = Some(device_type);
     event.ip_address = Some(ip.to_string());
+    nt.send_user_event(&event);
     events.push(event);
 
     // For each org a user is a member of store these events per org
@@ -262,7 +370,7 @@ async fn _log_user_event(
         event.user_uuid = Some(user_id.clone());
         event.org_uuid = Some(membership.org_uuid);
         event.org_user_uuid = Some(membership.uuid);
-        event.act_user_uuid = Some(user_id.clone());
+        event.act_user_uuid = Some(act_user_id.clone());
         event.device_type = Some(device_type);
         event.ip_address = Some(ip.to_string());
         events.push(event);
@@ -346,3 +454,87 @@ pub async fn event_cleanup_job(pool: DbPool) {
         error!("Failed to get DB connection while trying to cleanup the events table")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "export-key";
+
+    fn events() -> Vec<Value> {
+        (0..3)
+            .map(|i| {
+                json!({
+                    "type": 1000 + i,
+                    "userId": "user",
+                    "date": format!("2026-01-0{}T00:00:00.000000Z", i + 1),
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_event_chain() {
+        let events = events();
+        let chain = event_hmac_chain(KEY, &events);
+        assert_eq!(chain.len(), events.len());
+        assert_eq!(verify_event_hmac_chain(KEY, &events, &chain), Ok(()));
+    }
+
+    #[test]
+    fn test_modified_event_chain_is_detected() {
+        let mut events = events();
+        let chain = event_hmac_chain(KEY, &events);
+
+        events[1]["type"] = json!(9999);
+        assert_eq!(verify_event_hmac_chain(KEY, &events, &chain), Err(1));
+
+        // Dropping the last event
+        let events = self::events();
+        assert_eq!(verify_event_hmac_chain(KEY, &events[..2], &chain), Err(2));
+
+        // A different server key
+        assert_eq!(verify_event_hmac_chain("other-key", &events, &chain), Err(0));
+    }
+
+    // `log_user_event` only calls this when `ORG_EVENTS_ENABLED` is set, which the event stream requires as well
+    #[cfg(sqlite)]
+    #[test]
+    fn test_logged_user_event_is_streamed() {
+        use crate::api::WS_USERS;
+        use crate::db::models::{DeviceId, DeviceType, EventType, Organization, User};
+        use rocket_ws::Message;
+
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let mut user = User::new(String::from("user@example.ext"), None);
+            user.save(&mut conn).await.unwrap();
+            let mut other = User::new(String::from("other@example.ext"), None);
+            other.save(&mut conn).await.unwrap();
+            let org = Organization::new(String::from("Org"), String::from("org@example.ext"), None, None);
+            org.save(&mut conn).await.unwrap();
+            Membership::new(user.uuid.clone(), org.uuid.clone(), None).save(&mut conn).await.unwrap();
+
+            let device_id = DeviceId::from(crate::util::get_uuid());
+            let (_, mut rx) = WS_USERS.add_event_stream(&user.uuid, device_id.clone());
+            let (_, mut other_rx) = WS_USERS.add_event_stream(&other.uuid, device_id);
+
+            let ip: IpAddr = "127.0.0.1".parse().unwrap();
+            let event_type = EventType::UserLoggedIn as i32;
+            let device_type = DeviceType::Android as i32;
+            let nt = (&*WS_USERS).into();
+            _log_user_event(event_type, &user.uuid, &user.uuid, device_type, None, &ip, &mut conn, &nt).await;
+
+            let Ok(Message::Text(text)) = rx.try_recv() else {
+                panic!("The logged event wasn't streamed");
+            };
+            let streamed: Value = serde_json::from_str(&text).unwrap();
+            assert_eq!(streamed["type"], event_type);
+            assert_eq!(streamed["userId"], user.uuid.to_string());
+            assert_eq!(streamed["organizationId"], Value::Null);
+            // The copies of the event for the organizations of the user are only stored
+            assert!(rx.try_recv().is_err());
+            assert!(other_rx.try_recv().is_err());
+        });
+    }
+}
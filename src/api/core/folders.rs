@@ -31,13 +31,32 @@ async fn get_folder(folder_id: FolderId, headers: Headers, mut conn: DbConn) ->
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FolderData {
     pub name: String,
     pub id: Option<FolderId>,
 }
 
+impl FolderData {
+    /// Same limit as the upstream server for the encrypted folder name
+    const MAX_NAME_LENGTH: usize = 1000;
+
+    /// Only used for the initial folder of a registration, which can't be corrected after the account got created
+    pub fn validate(&self) -> EmptyResult {
+        if self.name.trim().is_empty() {
+            err!("The field Name is required.")
+        }
+        if self.name.len() > Self::MAX_NAME_LENGTH {
+            err!(format!(
+                "The field Name exceeds the maximum encrypted value length of {} characters.",
+                Self::MAX_NAME_LENGTH
+            ))
+        }
+        Ok(())
+    }
+}
+
 #[post("/folders", data = "<data>")]
 async fn post_folders(data: Json<FolderData>, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> JsonResult {
     let data: FolderData = data.into_inner();
@@ -100,3 +119,19 @@ async fn delete_folder(folder_id: FolderId, headers: Headers, mut conn: DbConn,
     nt.send_folder_update(UpdateType::SyncFolderDelete, &folder, &headers.device, &mut conn).await;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_folder_name_validation() {
+        let folder = |name: &str| FolderData {
+            name: String::from(name),
+            id: None,
+        };
+        assert!(folder("2.encrypted|name").validate().is_ok());
+        assert!(folder(" ").validate().is_err());
+        assert!(folder(&"a".repeat(1001)).validate().is_err());
+    }
+}
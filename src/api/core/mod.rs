@@ -8,12 +8,14 @@ mod public;
 mod sends;
 pub mod two_factor;
 
-pub use accounts::purge_auth_requests;
-pub use ciphers::{purge_trashed_ciphers, CipherData, CipherSyncData, CipherSyncType};
+pub use accounts::{purge_auth_requests, purge_device_tombstones, purge_unverified_accounts};
+pub use ciphers::{
+    purge_trashed_ciphers, share_cipher_by_uuid, CipherData, CipherSyncData, CipherSyncType, ShareCipherData,
+};
 pub use emergency_access::{emergency_notification_reminder_job, emergency_request_timeout_job};
-pub use events::{event_cleanup_job, log_event, log_user_event};
+pub use events::{event_cleanup_job, log_event, log_user_event, log_user_event_by};
 use reqwest::Method;
-pub use sends::purge_sends;
+pub use sends::{purge_sends, SendData};
 
 pub fn routes() -> Vec<Route> {
     let mut eq_domains_routes = routes![get_eq_domains, post_eq_domains, put_eq_domains];
@@ -136,9 +138,10 @@ async fn put_eq_domains(data: Json<EquivDomainData>, headers: Headers, conn: DbC
 }
 
 #[get("/hibp/breach?<username>")]
-async fn hibp_breach(username: &str, _headers: Headers) -> JsonResult {
+async fn hibp_breach(username: &str, headers: Headers) -> JsonResult {
     let username: String = url::form_urlencoded::byte_serialize(username.as_bytes()).collect();
-    if let Some(api_key) = crate::CONFIG.hibp_api_key() {
+    // Users who opted out of external services only get the manual check link
+    if let Some(api_key) = crate::CONFIG.hibp_api_key().filter(|_| headers.user.allows_external_services()) {
         let url = format!(
             "https://haveibeenpwned.com/api/v3/breachedaccount/{username}?truncateResponse=false&includeUnverified=false"
         );
@@ -271,6 +274,8 @@ async fn accept_org_invite(
         err!("User already accepted the invitation");
     }
 
+    enforce_max_orgs_per_user(&user.uuid, conn).await?;
+
     // This check is also done at accept_invite, _confirm_invite, _activate_member, edit_member, admin::update_membership_type
     // It returns different error messages per function.
     if member.atype < MembershipType::Admin {
@@ -306,3 +311,143 @@ async fn accept_org_invite(
 
     Ok(())
 }
+
+/// Returns true when the user still needs to verify their email address because it's enforced.
+/// Without mail, users are not able to verify.
+fn verification_pending(user: &User, enforced: bool, mail_enabled: bool) -> bool {
+    enforced && mail_enabled && user.verified_at.is_none()
+}
+
+/// Returns true when the user still needs to verify their email address before being allowed to share,
+/// based on the `REQUIRE_VERIFIED_EMAIL_FOR_SHARING` setting.
+pub fn user_requires_verification(user: &User) -> bool {
+    verification_pending(user, crate::CONFIG.require_verified_email_for_sharing(), crate::CONFIG.mail_enabled())
+}
+
+/// Only used for the gated operations, creating Sends and sharing items with an organization.
+pub fn enforce_verified_email_for_sharing(user: &User) -> EmptyResult {
+    if user_requires_verification(user) {
+        err!("You need to verify your email address before you can share items or create Sends. Check your account settings to send a verification email.")
+    }
+    Ok(())
+}
+
+/// Used before a personal API key is created or rotated, based on the `REQUIRE_VERIFIED_EMAIL_FOR_API_KEYS` setting.
+pub fn enforce_verified_email_for_api_keys(user: &User) -> EmptyResult {
+    check_verified_email_for_api_keys(
+        user,
+        crate::CONFIG.require_verified_email_for_api_keys(),
+        crate::CONFIG.mail_enabled(),
+    )
+}
+
+fn check_verified_email_for_api_keys(user: &User, enforced: bool, mail_enabled: bool) -> EmptyResult {
+    if verification_pending(user, enforced, mail_enabled) {
+        err!("You need to verify your email address before you can create an API key. Check your account settings to send a verification email.")
+    }
+    Ok(())
+}
+
+/// A limit of 0 means a user can be a member of any number of organizations
+fn org_limit_reached(current: i64, limit: u32) -> bool {
+    limit != 0 && current >= i64::from(limit)
+}
+
+/// Used before a user creates or joins another organization, based on the `MAX_ORGS_PER_USER` setting.
+pub async fn enforce_max_orgs_per_user(user_id: &UserId, conn: &mut DbConn) -> EmptyResult {
+    check_max_orgs_per_user(user_id, crate::CONFIG.max_orgs_per_user(), conn).await
+}
+
+async fn check_max_orgs_per_user(user_id: &UserId, limit: u32, conn: &mut DbConn) -> EmptyResult {
+    if org_limit_reached(Membership::count_accepted_and_confirmed_by_user(user_id, conn).await, limit) {
+        err!(format!("You can't be a member of more than {limit} organizations. Leave another organization first."))
+    }
+    Ok(())
+}
+
+/// Accepts all open invitations of a user at once, but only as many as `MAX_ORGS_PER_USER` allows.
+/// The other invitations stay pending.
+pub async fn accept_user_invitations(user_id: &UserId, conn: &mut DbConn) -> EmptyResult {
+    accept_user_invitations_up_to(user_id, crate::CONFIG.max_orgs_per_user(), conn).await
+}
+
+async fn accept_user_invitations_up_to(user_id: &UserId, limit: u32, conn: &mut DbConn) -> EmptyResult {
+    if limit == 0 {
+        return Membership::accept_user_invitations(user_id, conn).await;
+    }
+
+    let mut count = Membership::count_accepted_and_confirmed_by_user(user_id, conn).await;
+    for mut member in Membership::find_invited_by_user(user_id, conn).await {
+        if org_limit_reached(count, limit) {
+            info!("Invitation {} of user {user_id} left pending, the organization limit has been reached", member.uuid);
+            continue;
+        }
+        member.status = MembershipStatus::Accepted as i32;
+        member.save(conn).await?;
+        count += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invite_beyond_org_limit_is_rejected() {
+        assert!(!org_limit_reached(1, 2));
+        assert!(org_limit_reached(2, 2));
+        assert!(org_limit_reached(3, 2));
+    }
+
+    #[test]
+    fn test_no_org_limit() {
+        assert!(!org_limit_reached(0, 0));
+        assert!(!org_limit_reached(1000, 0));
+    }
+
+    #[test]
+    fn test_api_key_requires_verified_email() {
+        let mut user = User::new(String::from("user@example.com"), None);
+        assert!(check_verified_email_for_api_keys(&user, true, true).is_err());
+        // Not enforced, or no way to verify without mail
+        assert!(check_verified_email_for_api_keys(&user, false, true).is_ok());
+        assert!(check_verified_email_for_api_keys(&user, true, false).is_ok());
+
+        user.verified_at = Some(chrono::Utc::now().naive_utc());
+        assert!(check_verified_email_for_api_keys(&user, true, true).is_ok());
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_invite_beyond_org_limit_stays_pending() {
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let mut user = User::new(String::from("member@example.ext"), None);
+            user.save(&mut conn).await.unwrap();
+
+            let mut memberships = Vec::new();
+            for (name, status) in [
+                ("Joined", MembershipStatus::Confirmed),
+                ("First", MembershipStatus::Invited),
+                ("Second", MembershipStatus::Invited),
+            ] {
+                let org = Organization::new(String::from(name), String::from("org@example.ext"), None, None);
+                org.save(&mut conn).await.unwrap();
+                let mut member = Membership::new(user.uuid.clone(), org.uuid.clone(), None);
+                member.status = status as i32;
+                member.save(&mut conn).await.unwrap();
+                memberships.push(member);
+            }
+
+            accept_user_invitations_up_to(&user.uuid, 2, &mut conn).await.unwrap();
+            assert_eq!(Membership::count_accepted_and_confirmed_by_user(&user.uuid, &mut conn).await, 2);
+            assert_eq!(Membership::find_invited_by_user(&user.uuid, &mut conn).await.len(), 1);
+
+            // Accepting the remaining invitation, or creating another organization, is rejected at the limit
+            assert!(check_max_orgs_per_user(&user.uuid, 2, &mut conn).await.is_err());
+            assert!(check_max_orgs_per_user(&user.uuid, 3, &mut conn).await.is_ok());
+            assert!(check_max_orgs_per_user(&user.uuid, 0, &mut conn).await.is_ok());
+        });
+    }
+}
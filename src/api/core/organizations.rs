@@ -7,7 +7,7 @@ use std::collections::{HashMap, HashSet};
 use crate::api::admin::FAKE_ADMIN_UUID;
 use crate::{
     api::{
-        core::{accept_org_invite, log_event, two_factor, CipherSyncData, CipherSyncType},
+        core::{accept_org_invite, enforce_max_orgs_per_user, log_event, two_factor, CipherSyncData, CipherSyncType},
         EmptyResult, JsonResult, Notify, PasswordOrOtpData, UpdateType,
     },
     auth::{decode_invite, AdminHeaders, Headers, ManagerHeaders, ManagerHeadersLoose, OrgMemberHeaders, OwnerHeaders},
@@ -32,6 +32,8 @@ pub fn routes() -> Vec<Route> {
         put_collection_users,
         put_organization,
         post_organization,
+        get_organization_kdf_minimums,
+        put_organization_kdf_minimums,
         post_organization_collections,
         delete_organization_collection_member,
         post_organization_collection_delete_member,
@@ -55,6 +57,7 @@ pub fn routes() -> Vec<Route> {
         get_user,
         edit_member,
         put_member,
+        put_member_profile_attributes,
         delete_member,
         bulk_delete_member,
         post_delete_member,
@@ -186,6 +189,7 @@ async fn create_organization(headers: Headers, data: Json<OrgData>, mut conn: Db
             "You may not create an organization. You belong to an organization which has a policy that prohibits you from being a member of any other organization."
         )
     }
+    enforce_max_orgs_per_user(&headers.user.uuid, &mut conn).await?;
 
     let data: OrgData = data.into_inner();
     let (private_key, public_key) = if data.keys.is_some() {
@@ -325,6 +329,61 @@ async fn post_organization(
     Ok(Json(org.to_json()))
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KdfMinimumsData {
+    pbkdf2_iterations: Option<i32>,
+    argon2_iterations: Option<i32>,
+    argon2_memory: Option<i32>,
+    argon2_parallelism: Option<i32>,
+}
+
+// Vaultwarden specific, users registering through an invite of the organization need at least these KDF settings
+#[get("/organizations/<org_id>/kdf-minimums")]
+async fn get_organization_kdf_minimums(org_id: OrganizationId, headers: AdminHeaders, mut conn: DbConn) -> JsonResult {
+    if org_id != headers.org_id {
+        err!("Organization not found", "Organization id's do not match");
+    }
+
+    match Organization::find_by_uuid(&org_id, &mut conn).await {
+        Some(org) => Ok(Json(org.kdf_minimums_json())),
+        None => err!("Can't find organization details"),
+    }
+}
+
+#[put("/organizations/<org_id>/kdf-minimums", data = "<data>")]
+async fn put_organization_kdf_minimums(
+    org_id: OrganizationId,
+    headers: OwnerHeaders,
+    data: Json<KdfMinimumsData>,
+    mut conn: DbConn,
+) -> JsonResult {
+    if org_id != headers.org_id {
+        err!("Organization not found", "Organization id's do not match");
+    }
+    let data: KdfMinimumsData = data.into_inner();
+
+    let Some(mut org) = Organization::find_by_uuid(&org_id, &mut conn).await else {
+        err!("Organization not found")
+    };
+
+    org.set_kdf_minimums(data.pbkdf2_iterations, data.argon2_iterations, data.argon2_memory, data.argon2_parallelism)?;
+    org.save(&mut conn).await?;
+
+    log_event(
+        EventType::OrganizationUpdated as i32,
+        org_id.as_ref(),
+        &org_id,
+        &headers.user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        &mut conn,
+    )
+    .await;
+
+    Ok(Json(org.kdf_minimums_json()))
+}
+
 // GET /api/collections?writeOnly=false
 #[get("/collections")]
 async fn get_user_collections(headers: Headers, mut conn: DbConn) -> Json<Value> {
@@ -1710,6 +1769,55 @@ async fn edit_member(
     member_to_edit.save(&mut conn).await
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProfileAttributesData {
+    attributes: Value,
+}
+
+// Vaultwarden specific, the attributes are shown read-only in the profile of the member
+#[put("/organizations/<org_id>/users/<member_id>/profile-attributes", data = "<data>")]
+async fn put_member_profile_attributes(
+    org_id: OrganizationId,
+    member_id: MembershipId,
+    data: Json<ProfileAttributesData>,
+    headers: AdminHeaders,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> EmptyResult {
+    if org_id != headers.org_id {
+        err!("Organization not found", "Organization id's do not match");
+    }
+    let data: ProfileAttributesData = data.into_inner();
+
+    let Some(mut member) = Membership::find_by_uuid_and_org(&member_id, &org_id, &mut conn).await else {
+        err!("The specified user isn't member of the organization")
+    };
+
+    if member.atype == MembershipType::Owner && headers.membership_type != MembershipType::Owner {
+        err!("Only Owners can edit Owner users")
+    }
+
+    member.set_profile_attributes(&data.attributes)?;
+    member.save(&mut conn).await?;
+
+    log_event(
+        EventType::OrganizationUserUpdated as i32,
+        &member.uuid,
+        &org_id,
+        &headers.user.uuid,
+        headers.device.atype,
+        &headers.ip.ip,
+        &mut conn,
+    )
+    .await;
+
+    if let Some(user) = User::find_by_uuid(&member.user_uuid, &mut conn).await {
+        nt.send_user_update(UpdateType::SyncSettings, &user, &headers.device.push_uuid, &mut conn).await;
+    }
+    Ok(())
+}
+
 #[delete("/organizations/<org_id>/users", data = "<data>")]
 async fn bulk_delete_member(
     org_id: OrganizationId,
@@ -11,7 +11,7 @@ use rocket::serde::json::Json;
 use serde_json::Value;
 
 use crate::{
-    api::{ApiResult, EmptyResult, JsonResult, Notify, UpdateType},
+    api::{core::enforce_verified_email_for_sharing, ApiResult, EmptyResult, JsonResult, Notify, UpdateType},
     auth::{ClientIp, Headers, Host},
     config::PathType,
     db::{models::*, DbConn, DbPool},
@@ -33,11 +33,23 @@ static ANON_PUSH_DEVICE: Lazy<Device> = Lazy::new(|| {
         push_token: None,
         refresh_token: String::new(),
         twofactor_remember: None,
+
+        push_registration_success: None,
+        push_registration_date: None,
+        push_registration_error: None,
+
+        pending_approval: false,
+        approval_public_key: None,
+        encrypted_user_key: None,
+
+        push_muted: 0,
+        deleted_at: None,
+        session_public_key: None,
     }
 });
 
 // The max file size allowed by Bitwarden clients and add an extra 5% to avoid issues
-const SIZE_525_MB: i64 = 550_502_400;
+pub const SIZE_525_MB: i64 = 550_502_400;
 
 pub fn routes() -> Vec<rocket::Route> {
     routes![
@@ -68,7 +80,7 @@ pub async fn purge_sends(pool: DbPool) {
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SendData {
-    r#type: i32,
+    pub r#type: i32,
     key: String,
     password: Option<String>,
     max_access_count: Option<NumberOrString>,
@@ -78,10 +90,10 @@ pub struct SendData {
     hide_email: Option<bool>,
 
     // Data field
-    name: String,
-    notes: Option<String>,
-    text: Option<Value>,
-    file: Option<Value>,
+    pub name: String,
+    pub notes: Option<String>,
+    pub text: Option<Value>,
+    pub file: Option<Value>,
     file_length: Option<NumberOrString>,
 
     // Used for key rotations
@@ -124,7 +136,9 @@ async fn enforce_disable_hide_email_policy(data: &SendData, headers: &Headers, c
     Ok(())
 }
 
-fn create_send(data: SendData, user_id: UserId) -> ApiResult<Send> {
+fn create_send(data: SendData, user: &User) -> ApiResult<Send> {
+    Send::validate_send_data(std::slice::from_ref(&data))?;
+
     let data_val = if data.r#type == SendType::Text as i32 {
         data.text
     } else if data.r#type == SendType::File as i32 {
@@ -147,7 +161,8 @@ fn create_send(data: SendData, user_id: UserId) -> ApiResult<Send> {
     }
 
     let mut send = Send::new(data.r#type, data.name, data_str, data.key, data.deletion_date.naive_utc());
-    send.user_uuid = Some(user_id);
+    send.user_uuid = Some(user.uuid.clone());
+    send.key_version = Some(user.key_version);
     send.notes = data.notes;
     send.max_access_count = match data.max_access_count {
         Some(m) => Some(m.into_i32()?),
@@ -186,6 +201,7 @@ async fn get_send(send_id: SendId, headers: Headers, mut conn: DbConn) -> JsonRe
 #[post("/sends", data = "<data>")]
 async fn post_send(data: Json<SendData>, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> JsonResult {
     enforce_disable_send_policy(&headers, &mut conn).await?;
+    enforce_verified_email_for_sharing(&headers.user)?;
 
     let data: SendData = data.into_inner();
     enforce_disable_hide_email_policy(&data, &headers, &mut conn).await?;
@@ -194,7 +210,7 @@ async fn post_send(data: Json<SendData>, headers: Headers, mut conn: DbConn, nt:
         err!("File sends should use /api/sends/file")
     }
 
-    let mut send = create_send(data, headers.user.uuid)?;
+    let mut send = create_send(data, &headers.user)?;
     send.save(&mut conn).await?;
     nt.send_send_update(
         UpdateType::SyncSendCreate,
@@ -227,6 +243,7 @@ struct UploadDataV2<'f> {
 #[post("/sends/file", format = "multipart/form-data", data = "<data>")]
 async fn post_send_file(data: Form<UploadData<'_>>, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> JsonResult {
     enforce_disable_send_policy(&headers, &mut conn).await?;
+    enforce_verified_email_for_sharing(&headers.user)?;
 
     let UploadData {
         model,
@@ -243,7 +260,7 @@ async fn post_send_file(data: Form<UploadData<'_>>, headers: Headers, mut conn:
 
     enforce_disable_hide_email_policy(&model, &headers, &mut conn).await?;
 
-    let size_limit = match CONFIG.user_send_limit() {
+    let size_limit = match headers.user.effective_send_limit() {
         Some(0) => err!("File uploads are disabled"),
         Some(limit_kb) => {
             let Some(already_used) = Send::size_by_user(&headers.user.uuid, &mut conn).await else {
@@ -264,7 +281,7 @@ async fn post_send_file(data: Form<UploadData<'_>>, headers: Headers, mut conn:
         err!("Send storage limit exceeded with this file");
     }
 
-    let mut send = create_send(model, headers.user.uuid)?;
+    let mut send = create_send(model, &headers.user)?;
     if send.atype != SendType::File as i32 {
         err!("Send content is not a file");
     }
@@ -299,6 +316,7 @@ async fn post_send_file(data: Form<UploadData<'_>>, headers: Headers, mut conn:
 #[post("/sends/file/v2", data = "<data>")]
 async fn post_send_file_v2(data: Json<SendData>, headers: Headers, mut conn: DbConn) -> JsonResult {
     enforce_disable_send_policy(&headers, &mut conn).await?;
+    enforce_verified_email_for_sharing(&headers.user)?;
 
     let data = data.into_inner();
 
@@ -316,7 +334,7 @@ async fn post_send_file_v2(data: Json<SendData>, headers: Headers, mut conn: DbC
         err!("Send size can't be negative")
     }
 
-    let size_limit = match CONFIG.user_send_limit() {
+    let size_limit = match headers.user.effective_send_limit() {
         Some(0) => err!("File uploads are disabled"),
         Some(limit_kb) => {
             let Some(already_used) = Send::size_by_user(&headers.user.uuid, &mut conn).await else {
@@ -337,7 +355,7 @@ async fn post_send_file_v2(data: Json<SendData>, headers: Headers, mut conn: DbC
         err!("Send storage limit exceeded with this file");
     }
 
-    let mut send = create_send(data, headers.user.uuid)?;
+    let mut send = create_send(data, &headers.user)?;
 
     let file_id = crate::crypto::generate_send_file_id();
 
@@ -624,6 +642,8 @@ pub async fn update_send_from_data(
         err!("Sends can't change type")
     }
 
+    Send::validate_send_data(std::slice::from_ref(&data))?;
+
     if data.deletion_date > Utc::now() + TimeDelta::try_days(31).unwrap() {
         err!(
             "You cannot have a Send with a deletion date that far into the future. Adjust the Deletion Date to a value less than 31 days from now and try again."
@@ -653,6 +673,7 @@ pub async fn update_send_from_data(
     send.expiration_date = data.expiration_date.map(|d| d.naive_utc());
     send.hide_email = data.hide_email;
     send.disabled = data.disabled;
+    send.key_version = Some(headers.user.key_version);
 
     // Only change the value if it's present
     if let Some(password) = data.password {
@@ -706,3 +727,82 @@ async fn put_remove_password(send_id: SendId, headers: Headers, mut conn: DbConn
 
     Ok(Json(send.to_json()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::WS_USERS;
+
+    fn send_data(r#type: SendType, name: &str) -> Json<SendData> {
+        Json(
+            serde_json::from_value(json!({
+                "type": r#type as i32,
+                "key": "2.send-key",
+                "deletionDate": Utc::now() + TimeDelta::try_days(7).unwrap(),
+                "disabled": false,
+                "name": name,
+                "text": { "text": "2.text", "hidden": false },
+                "file": { "fileName": "2.file-name" },
+                "fileLength": 1024,
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_send_endpoints_validate_encrypted_length() {
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let mut user = User::new(String::from("send@example.ext"), None);
+            user.save(&mut conn).await.unwrap();
+            let device_id = DeviceId::from(crate::util::get_uuid());
+            Device::new(
+                device_id.clone(),
+                user.uuid.clone(),
+                String::from("test"),
+                DeviceType::LinuxDesktop as i32,
+                &mut conn,
+            )
+            .await
+            .unwrap();
+            let oversized = format!("2.{}", "a".repeat(1000));
+
+            let headers = crate::auth::test_headers(&user.uuid, &device_id, &mut conn).await;
+            let send =
+                post_send(send_data(SendType::Text, "2.name"), headers, pool.get().await.unwrap(), (&*WS_USERS).into())
+                    .await
+                    .unwrap()
+                    .into_inner();
+            let send_id = SendId::from(send["id"].as_str().unwrap().to_string());
+
+            let headers = crate::auth::test_headers(&user.uuid, &device_id, &mut conn).await;
+            let result = post_send(
+                send_data(SendType::Text, &oversized),
+                headers,
+                pool.get().await.unwrap(),
+                (&*WS_USERS).into(),
+            )
+            .await;
+            assert!(result.is_err());
+
+            let headers = crate::auth::test_headers(&user.uuid, &device_id, &mut conn).await;
+            let result = put_send(
+                send_id.clone(),
+                send_data(SendType::Text, &oversized),
+                headers,
+                pool.get().await.unwrap(),
+                (&*WS_USERS).into(),
+            )
+            .await;
+            assert!(result.is_err());
+            assert_eq!(Send::find_by_uuid(&send_id, &mut conn).await.unwrap().name, "2.name");
+
+            let headers = crate::auth::test_headers(&user.uuid, &device_id, &mut conn).await;
+            let result =
+                post_send_file_v2(send_data(SendType::File, &oversized), headers, pool.get().await.unwrap()).await;
+            assert!(result.is_err());
+            assert_eq!(Send::find_by_user(&user.uuid, &mut conn).await.len(), 1);
+        });
+    }
+}
@@ -3,7 +3,10 @@ use rocket::serde::json::Json;
 use rocket::Route;
 
 use crate::{
-    api::{core::log_user_event, core::two_factor::_generate_recover_code, EmptyResult, JsonResult, PasswordOrOtpData},
+    api::{
+        core::log_user_event, core::two_factor::_generate_recover_code, EmptyResult, JsonResult, Notify,
+        PasswordOrOtpData,
+    },
     auth::{ClientIp, Headers},
     crypto,
     db::{
@@ -55,7 +58,12 @@ struct EnableAuthenticatorData {
 }
 
 #[post("/two-factor/authenticator", data = "<data>")]
-async fn activate_authenticator(data: Json<EnableAuthenticatorData>, headers: Headers, mut conn: DbConn) -> JsonResult {
+async fn activate_authenticator(
+    data: Json<EnableAuthenticatorData>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
     let data: EnableAuthenticatorData = data.into_inner();
     let key = data.key;
     let token = data.token.into_string();
@@ -84,7 +92,8 @@ async fn activate_authenticator(data: Json<EnableAuthenticatorData>, headers: He
 
     _generate_recover_code(&mut user, &mut conn).await;
 
-    log_user_event(EventType::UserUpdated2fa as i32, &user.uuid, headers.device.atype, &headers.ip.ip, &mut conn).await;
+    log_user_event(EventType::UserUpdated2fa as i32, &user.uuid, headers.device.atype, &headers.ip.ip, &mut conn, &nt)
+        .await;
 
     Ok(Json(json!({
         "enabled": true,
@@ -94,8 +103,13 @@ async fn activate_authenticator(data: Json<EnableAuthenticatorData>, headers: He
 }
 
 #[put("/two-factor/authenticator", data = "<data>")]
-async fn activate_authenticator_put(data: Json<EnableAuthenticatorData>, headers: Headers, conn: DbConn) -> JsonResult {
-    activate_authenticator(data, headers, conn).await
+async fn activate_authenticator_put(
+    data: Json<EnableAuthenticatorData>,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
+    activate_authenticator(data, headers, conn, nt).await
 }
 
 pub async fn validate_totp_code_str(
@@ -189,7 +203,12 @@ struct DisableAuthenticatorData {
 }
 
 #[delete("/two-factor/authenticator", data = "<data>")]
-async fn disable_authenticator(data: Json<DisableAuthenticatorData>, headers: Headers, mut conn: DbConn) -> JsonResult {
+async fn disable_authenticator(
+    data: Json<DisableAuthenticatorData>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
     let user = headers.user;
     let type_ = data.r#type.into_i32()?;
 
@@ -206,6 +225,7 @@ async fn disable_authenticator(data: Json<DisableAuthenticatorData>, headers: He
                 headers.device.atype,
                 &headers.ip.ip,
                 &mut conn,
+                &nt,
             )
             .await;
         } else {
@@ -5,7 +5,7 @@ use rocket::Route;
 use crate::{
     api::{
         core::{log_user_event, two_factor::_generate_recover_code},
-        EmptyResult, JsonResult, PasswordOrOtpData,
+        EmptyResult, JsonResult, Notify, PasswordOrOtpData,
     },
     auth::Headers,
     crypto,
@@ -63,7 +63,7 @@ pub async fn send_token(user_id: &UserId, conn: &mut DbConn) -> EmptyResult {
     let type_ = TwoFactorType::Email as i32;
     let mut twofactor = TwoFactor::find_by_user_and_type(user_id, type_, conn).await.map_res("Two factor not found")?;
 
-    let generated_token = crypto::generate_email_token(CONFIG.email_token_size());
+    let generated_token = crypto::generate_email_token(CONFIG.email_token_size(), CONFIG.email_token_alphanumeric());
 
     let mut twofactor_data = EmailTokenData::from_json(&twofactor.data)?;
     twofactor_data.set_token(generated_token);
@@ -131,7 +131,7 @@ async fn send_email(data: Json<SendEmailData>, headers: Headers, mut conn: DbCon
         tf.delete(&mut conn).await?;
     }
 
-    let generated_token = crypto::generate_email_token(CONFIG.email_token_size());
+    let generated_token = crypto::generate_email_token(CONFIG.email_token_size(), CONFIG.email_token_alphanumeric());
     let twofactor_data = EmailTokenData::new(data.email, generated_token);
 
     // Uses EmailVerificationChallenge as type to show that it's not verified yet.
@@ -154,7 +154,7 @@ struct EmailData {
 
 /// Verify email belongs to user and can be used for 2FA email codes.
 #[put("/two-factor/email", data = "<data>")]
-async fn email(data: Json<EmailData>, headers: Headers, mut conn: DbConn) -> JsonResult {
+async fn email(data: Json<EmailData>, headers: Headers, mut conn: DbConn, nt: Notify<'_>) -> JsonResult {
     let data: EmailData = data.into_inner();
     let mut user = headers.user;
 
@@ -176,7 +176,7 @@ async fn email(data: Json<EmailData>, headers: Headers, mut conn: DbConn) -> Jso
         err!("No token available")
     };
 
-    if !crypto::ct_eq(issued_token, data.token) {
+    if !crypto::check_email_token(issued_token, data.token) {
         err!("Token is invalid")
     }
 
@@ -187,7 +187,8 @@ async fn email(data: Json<EmailData>, headers: Headers, mut conn: DbConn) -> Jso
 
     _generate_recover_code(&mut user, &mut conn).await;
 
-    log_user_event(EventType::UserUpdated2fa as i32, &user.uuid, headers.device.atype, &headers.ip.ip, &mut conn).await;
+    log_user_event(EventType::UserUpdated2fa as i32, &user.uuid, headers.device.atype, &headers.ip.ip, &mut conn, &nt)
+        .await;
 
     Ok(Json(json!({
         "email": email_data.email,
@@ -217,7 +218,7 @@ pub async fn validate_email_code_str(
         )
     };
 
-    if !crypto::ct_eq(issued_token, token) {
+    if !crypto::check_email_token(issued_token, token) {
         email_data.add_attempt();
         if email_data.attempts >= CONFIG.email_attempts_limit() {
             email_data.reset_token();
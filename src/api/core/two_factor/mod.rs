@@ -7,7 +7,7 @@ use serde_json::Value;
 use crate::{
     api::{
         core::{log_event, log_user_event},
-        EmptyResult, JsonResult, PasswordOrOtpData,
+        EmptyResult, JsonResult, Notify, PasswordOrOtpData,
     },
     auth::{ClientHeaders, Headers},
     crypto,
@@ -33,6 +33,9 @@ pub fn routes() -> Vec<Route> {
         disable_twofactor,
         disable_twofactor_put,
         get_device_verification_settings,
+        get_remembered_devices,
+        delete_remembered_devices,
+        get_twofactor_status,
     ];
 
     routes.append(&mut authenticator::routes());
@@ -79,7 +82,12 @@ struct RecoverTwoFactor {
 }
 
 #[post("/two-factor/recover", data = "<data>")]
-async fn recover(data: Json<RecoverTwoFactor>, client_headers: ClientHeaders, mut conn: DbConn) -> JsonResult {
+async fn recover(
+    data: Json<RecoverTwoFactor>,
+    client_headers: ClientHeaders,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
     let data: RecoverTwoFactor = data.into_inner();
 
     use crate::db::models::User;
@@ -109,6 +117,7 @@ async fn recover(data: Json<RecoverTwoFactor>, client_headers: ClientHeaders, mu
         client_headers.device_type,
         &client_headers.ip.ip,
         &mut conn,
+        &nt,
     )
     .await;
 
@@ -135,7 +144,12 @@ struct DisableTwoFactorData {
 }
 
 #[post("/two-factor/disable", data = "<data>")]
-async fn disable_twofactor(data: Json<DisableTwoFactorData>, headers: Headers, mut conn: DbConn) -> JsonResult {
+async fn disable_twofactor(
+    data: Json<DisableTwoFactorData>,
+    headers: Headers,
+    mut conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
     let data: DisableTwoFactorData = data.into_inner();
     let user = headers.user;
 
@@ -151,8 +165,15 @@ async fn disable_twofactor(data: Json<DisableTwoFactorData>, headers: Headers, m
 
     if let Some(twofactor) = TwoFactor::find_by_user_and_type(&user.uuid, type_, &mut conn).await {
         twofactor.delete(&mut conn).await?;
-        log_user_event(EventType::UserDisabled2fa as i32, &user.uuid, headers.device.atype, &headers.ip.ip, &mut conn)
-            .await;
+        log_user_event(
+            EventType::UserDisabled2fa as i32,
+            &user.uuid,
+            headers.device.atype,
+            &headers.ip.ip,
+            &mut conn,
+            &nt,
+        )
+        .await;
     }
 
     if TwoFactor::find_by_user(&user.uuid, &mut conn).await.is_empty() {
@@ -167,8 +188,65 @@ async fn disable_twofactor(data: Json<DisableTwoFactorData>, headers: Headers, m
 }
 
 #[put("/two-factor/disable", data = "<data>")]
-async fn disable_twofactor_put(data: Json<DisableTwoFactorData>, headers: Headers, conn: DbConn) -> JsonResult {
-    disable_twofactor(data, headers, conn).await
+async fn disable_twofactor_put(
+    data: Json<DisableTwoFactorData>,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
+    disable_twofactor(data, headers, conn, nt).await
+}
+
+// Vaultwarden specific, lists the devices which can currently skip the 2FA because it was remembered
+#[get("/accounts/two-factor/remembered")]
+async fn get_remembered_devices(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let devices = Device::find_by_user(&headers.user.uuid, &mut conn).await;
+    let devices_json: Vec<Value> =
+        devices.iter().filter(|d| d.twofactor_remember.is_some()).map(Device::to_json).collect();
+
+    Json(json!({
+        "data": devices_json,
+        "object": "list",
+        "continuationToken": null,
+    }))
+}
+
+// Vaultwarden specific, forgets the remembered 2FA of all devices, independent of their sessions
+#[delete("/accounts/two-factor/remembered", data = "<data>")]
+async fn delete_remembered_devices(data: Json<PasswordOrOtpData>, headers: Headers, mut conn: DbConn) -> EmptyResult {
+    let data: PasswordOrOtpData = data.into_inner();
+    data.validate(&headers.user, true, &mut conn).await?;
+
+    Device::clear_twofactor_remember_by_user(&headers.user.uuid, &mut conn).await?;
+    info!("Remembered 2FA of all devices of user {} removed", headers.user.uuid);
+    Ok(())
+}
+
+// Vaultwarden specific, a summary of the 2FA methods the user has enabled, without any of their data
+#[get("/accounts/two-factor/status")]
+async fn get_twofactor_status(headers: Headers, mut conn: DbConn) -> Json<Value> {
+    let twofactors = TwoFactor::find_by_user(&headers.user.uuid, &mut conn).await;
+    Json(twofactor_status(&twofactors))
+}
+
+pub(crate) fn twofactor_status(twofactors: &[TwoFactor]) -> Value {
+    let enabled = |types: &[i32]| twofactors.iter().any(|tf| tf.enabled && types.contains(&tf.atype));
+    let authenticator = enabled(&[TwoFactorType::Authenticator as i32]);
+    let email = enabled(&[TwoFactorType::Email as i32]);
+    let duo = enabled(&[TwoFactorType::Duo as i32]);
+    let yubikey = enabled(&[TwoFactorType::YubiKey as i32]);
+    // Legacy U2F keys are used through WebAuthn
+    let webauthn = enabled(&[TwoFactorType::Webauthn as i32, TwoFactorType::U2f as i32]);
+
+    json!({
+        "enabled": authenticator || email || duo || yubikey || webauthn,
+        "authenticator": authenticator,
+        "email": email,
+        "duo": duo,
+        "yubiKey": yubikey,
+        "webAuthn": webauthn,
+        "object": "twoFactorStatus",
+    })
 }
 
 pub async fn enforce_2fa_policy(
@@ -207,6 +285,24 @@ pub async fn enforce_2fa_policy(
     Ok(())
 }
 
+/// Blocks the sync of users who are subject to the two-step login policy of an organization, but have no 2FA set up.
+/// Only enforced with `REQUIRE_2FA_SETUP_FOR_SYNC`, so existing users aren't suddenly locked out of their vault.
+pub async fn enforce_2fa_setup_for_sync(user: &User, conn: &mut DbConn) -> EmptyResult {
+    if !CONFIG.require_2fa_setup_for_sync()
+        || !OrgPolicy::is_applicable_to_user(&user.uuid, OrgPolicyType::TwoFactorAuthentication, None, conn).await
+    {
+        return Ok(());
+    }
+    check_2fa_setup_for_sync(&TwoFactor::find_by_user(&user.uuid, conn).await)
+}
+
+fn check_2fa_setup_for_sync(twofactors: &[TwoFactor]) -> EmptyResult {
+    if twofactor_status(twofactors)["enabled"] != true {
+        err!("An organization you are a member of requires two-step login. Set up two-step login in your account settings before you can sync your vault.")
+    }
+    Ok(())
+}
+
 pub async fn enforce_2fa_policy_for_org(
     org_id: &OrganizationId,
     act_user_id: &UserId,
@@ -305,3 +401,46 @@ fn get_device_verification_settings(_headers: Headers, _conn: DbConn) -> Json<Va
         "object":"deviceVerificationSettings"
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_twofactor_status_reflects_enabled_providers() {
+        let user_id = UserId::from(crate::util::get_uuid());
+        let mut email = TwoFactor::new(user_id.clone(), TwoFactorType::Email, String::from("{}"));
+        email.enabled = false;
+        let twofactors = [
+            TwoFactor::new(user_id.clone(), TwoFactorType::Authenticator, String::from("secret")),
+            TwoFactor::new(user_id, TwoFactorType::U2f, String::from("[]")),
+            email,
+        ];
+
+        let status = twofactor_status(&twofactors);
+        assert_eq!(status["enabled"], true);
+        assert_eq!(status["authenticator"], true);
+        assert_eq!(status["webAuthn"], true);
+        assert_eq!(status["email"], false);
+        assert_eq!(status["duo"], false);
+        assert_eq!(status["yubiKey"], false);
+        assert!(!status.to_string().contains("secret"));
+
+        assert_eq!(twofactor_status(&[])["enabled"], false);
+    }
+
+    #[test]
+    fn test_sync_requires_2fa_setup_under_policy() {
+        let user_id = UserId::from(crate::util::get_uuid());
+        assert!(check_2fa_setup_for_sync(&[]).is_err());
+
+        // Remembered devices and pending challenges are no second factor
+        let remember = TwoFactor::new(user_id.clone(), TwoFactorType::Remember, String::new());
+        let mut disabled = TwoFactor::new(user_id.clone(), TwoFactorType::Authenticator, String::from("secret"));
+        disabled.enabled = false;
+        assert!(check_2fa_setup_for_sync(&[remember, disabled]).is_err());
+
+        let authenticator = TwoFactor::new(user_id, TwoFactorType::Authenticator, String::from("secret"));
+        assert!(check_2fa_setup_for_sync(&[authenticator]).is_ok());
+    }
+}
@@ -69,7 +69,7 @@ async fn request_otp(headers: Headers, mut conn: DbConn) -> EmptyResult {
         pa.delete(&mut conn).await?;
     }
 
-    let generated_token = crypto::generate_email_token(CONFIG.email_token_size());
+    let generated_token = crypto::generate_email_token(CONFIG.email_token_size(), CONFIG.email_token_alphanumeric());
     let pa_data = ProtectedActionData::new(generated_token);
 
     // Uses EmailVerificationChallenge as type to show that it's not verified yet.
@@ -130,7 +130,7 @@ pub async fn validate_protected_action_otp(
         err!("Token has expired")
     }
 
-    if !crypto::ct_eq(&pa_data.token, otp) {
+    if !crypto::check_email_token(&pa_data.token, otp) {
         pa.save(conn).await?;
         err!("Token is invalid")
     }
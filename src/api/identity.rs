@@ -12,13 +12,16 @@ use serde_json::Value;
 use crate::{
     api::{
         core::{
-            accounts::{PreloginData, RegisterData, _prelogin, _register, kdf_upgrade},
+            accounts::{
+                PreloginData, RegisterData, RegisterVerificationData, RegisterVerificationResponse, _prelogin,
+                _register, _register_verification_email, kdf_upgrade,
+            },
             log_user_event,
             two_factor::{authenticator, duo, duo_oidc, email, enforce_2fa_policy, webauthn, yubikey},
         },
         master_password_policy,
         push::register_push_device,
-        ApiResult, EmptyResult, JsonResult,
+        with_error_delay, ApiResult, EmptyResult, JsonResult, Notify,
     },
     auth,
     auth::{generate_organization_api_key_login_claims, AuthMethod, ClientHeaders, ClientIp, ClientVersion},
@@ -49,6 +52,7 @@ async fn login(
     client_header: ClientHeaders,
     client_version: Option<ClientVersion>,
     mut conn: DbConn,
+    nt: Notify<'_>,
 ) -> JsonResult {
     let data: ConnectData = data.into_inner();
 
@@ -70,7 +74,7 @@ async fn login(
             _check_is_some(&data.device_name, "device_name cannot be blank")?;
             _check_is_some(&data.device_type, "device_type cannot be blank")?;
 
-            _password_login(data, &mut user_id, &mut conn, &client_header.ip, &client_version).await
+            _password_login(data, &mut user_id, &mut conn, &client_header.ip, &client_version, &nt).await
         }
         "client_credentials" => {
             _check_is_some(&data.client_id, "client_id cannot be blank")?;
@@ -81,7 +85,14 @@ async fn login(
             _check_is_some(&data.device_name, "device_name cannot be blank")?;
             _check_is_some(&data.device_type, "device_type cannot be blank")?;
 
-            _api_key_login(data, &mut user_id, &mut conn, &client_header.ip).await
+            _api_key_login(
+                data,
+                &mut user_id,
+                &mut conn,
+                &client_header.ip,
+                client_header.client_cert_fingerprint.as_deref(),
+            )
+            .await
         }
         "authorization_code" if CONFIG.sso_enabled() => {
             _check_is_some(&data.client_id, "client_id cannot be blank")?;
@@ -91,7 +102,7 @@ async fn login(
             _check_is_some(&data.device_name, "device_name cannot be blank")?;
             _check_is_some(&data.device_type, "device_type cannot be blank")?;
 
-            _sso_login(data, &mut user_id, &mut conn, &client_header.ip, &client_version).await
+            _sso_login(data, &mut user_id, &mut conn, &client_header.ip, &client_version, &nt).await
         }
         "authorization_code" => err!("SSO sign-in is not available"),
         t => err!("Invalid type", t),
@@ -106,6 +117,7 @@ async fn login(
                     client_header.device_type,
                     &client_header.ip.ip,
                     &mut conn,
+                    &nt,
                 )
                 .await;
             }
@@ -117,6 +129,7 @@ async fn login(
                         client_header.device_type,
                         &client_header.ip.ip,
                         &mut conn,
+                        &nt,
                     )
                     .await
                 }
@@ -169,6 +182,7 @@ async fn _sso_login(
     conn: &mut DbConn,
     ip: &ClientIp,
     client_version: &Option<ClientVersion>,
+    nt: &Notify<'_>,
 ) -> JsonResult {
     AuthMethod::Sso.check_scope(data.scope.as_ref())?;
 
@@ -265,9 +279,27 @@ async fn _sso_login(
                 }
             )
         }
+        Some((user, _)) if user.pending_approval => {
+            err!(
+                "This account is awaiting approval by an administrator",
+                format!("IP: {}. Username: {}.", ip.ip, user.name),
+                ErrorEvent {
+                    event: EventType::UserFailedLogIn
+                }
+            )
+        }
+        Some((user, _)) if !CONFIG.serves_region(user.region.as_deref()) => {
+            err!(
+                "This account belongs to another region, login on a server of that region",
+                format!("IP: {}. Username: {}. Region: {:?}.", ip.ip, user.name, user.region),
+                ErrorEvent {
+                    event: EventType::UserFailedLogIn
+                }
+            )
+        }
         Some((mut user, sso_user)) => {
             let mut device = get_device(&data, conn, &user).await?;
-            let twofactor_token = twofactor_auth(&mut user, &data, &mut device, ip, client_version, conn).await?;
+            let twofactor_token = twofactor_auth(&mut user, &data, &mut device, ip, client_version, conn, nt).await?;
 
             if user.private_key.is_none() {
                 // User was invited a stub was created
@@ -313,6 +345,8 @@ async fn _sso_login(
         auth_user.expires_in,
     )?;
 
+    register_device_key(&mut device, &data)?;
+
     authenticated_response(&user, &mut device, auth_tokens, twofactor_token, &now, conn, ip).await
 }
 
@@ -322,6 +356,7 @@ async fn _password_login(
     conn: &mut DbConn,
     ip: &ClientIp,
     client_version: &Option<ClientVersion>,
+    nt: &Notify<'_>,
 ) -> JsonResult {
     // Validate scope
     AuthMethod::Password.check_scope(data.scope.as_ref())?;
@@ -329,8 +364,21 @@ async fn _password_login(
     // Ratelimit the login
     crate::ratelimit::check_limit_login(&ip.ip)?;
 
-    // Get the user
+    // There is no captcha challenge, the only accepted response is the bypass token issued on registration
     let username = data.username.as_ref().unwrap().trim();
+    if CONFIG.captcha_bypass_token_minutes().is_some()
+        && data.captcha_response.as_deref().is_some_and(|token| !auth::redeem_captcha_bypass(token, username))
+    {
+        err!(
+            "The captcha bypass token is invalid or was already used",
+            format!("IP: {}. Username: {username}.", ip.ip),
+            ErrorEvent {
+                event: EventType::UserFailedLogIn
+            }
+        )
+    }
+
+    // Get the user
     let Some(mut user) = User::find_by_mail(username, conn).await else {
         err!("Username or password is incorrect. Try again", format!("IP: {}. Username: {username}.", ip.ip))
     };
@@ -349,11 +397,33 @@ async fn _password_login(
         )
     }
 
+    // Accounts of another residency region have to login on a server of their own region
+    if !CONFIG.serves_region(user.region.as_deref()) {
+        err!(
+            "This account belongs to another region, login on a server of that region",
+            format!("IP: {}. Username: {username}. Region: {:?}.", ip.ip, user.region),
+            ErrorEvent {
+                event: EventType::UserFailedLogIn
+            }
+        )
+    }
+
+    if user.pending_approval {
+        err!(
+            "This account is awaiting approval by an administrator",
+            format!("IP: {}. Username: {username}.", ip.ip),
+            ErrorEvent {
+                event: EventType::UserFailedLogIn
+            }
+        )
+    }
+
     let password = data.password.as_ref().unwrap();
 
     // If we get an auth request, we don't check the user's password, but the access code of the auth request
+    let mut auth_request = None;
     if let Some(ref auth_request_id) = data.auth_request {
-        let Some(auth_request) = AuthRequest::find_by_uuid_and_user(auth_request_id, &user.uuid, conn).await else {
+        let Some(request) = AuthRequest::find_by_uuid_and_user(auth_request_id, &user.uuid, conn).await else {
             err!(
                 "Auth request not found. Try again.",
                 format!("IP: {}. Username: {username}.", ip.ip),
@@ -363,14 +433,19 @@ async fn _password_login(
             )
         };
 
-        let expiration_time = auth_request.creation_date + chrono::Duration::minutes(5);
+        let expiration_time = request.creation_date + chrono::Duration::minutes(5);
         let request_expired = Utc::now().naive_utc() >= expiration_time;
 
-        if auth_request.user_uuid != user.uuid
-            || !auth_request.approved.unwrap_or(false)
+        // The requesting device proves it holds the private key of the request by signing the challenge nonce,
+        // and an approved request can only be used for a single login.
+        if request.user_uuid != user.uuid
+            || !request.approved.unwrap_or(false)
+            || request.authentication_date.is_some()
             || request_expired
-            || ip.ip.to_string() != auth_request.request_ip
-            || !auth_request.check_access_code(password)
+            || ip.ip.to_string() != request.request_ip
+            || !request.check_access_code(password)
+            || !request
+                .check_nonce_signature(data.auth_request_signature.as_deref(), CONFIG.auth_request_require_nonce())
         {
             err!(
                 "Username or access code is incorrect. Try again",
@@ -380,6 +455,7 @@ async fn _password_login(
                 }
             )
         }
+        auth_request = Some(request);
     } else if !user.check_valid_password(password) {
         err!(
             "Username or password is incorrect. Try again",
@@ -390,6 +466,18 @@ async fn _password_login(
         )
     }
 
+    // SSO-only accounts can still unlock their vault with the master password, but can't use it to log in.
+    // This is checked after the password, to not disclose this setting to anyone without the password.
+    if user.sso_only && data.auth_request.is_none() {
+        err!(
+            "SSO sign-in is required for this account",
+            format!("IP: {}. Username: {username}.", ip.ip),
+            ErrorEvent {
+                event: EventType::UserFailedLogIn,
+            }
+        )
+    }
+
     // Change the KDF Iterations (only when not logging in with an auth request)
     if data.auth_request.is_none() {
         kdf_upgrade(&mut user, password, conn).await?;
@@ -431,13 +519,49 @@ async fn _password_login(
 
     let mut device = get_device(&data, conn, &user).await?;
 
-    let twofactor_token = twofactor_auth(&mut user, &data, &mut device, ip, client_version, conn).await?;
+    let twofactor_token = twofactor_auth(&mut user, &data, &mut device, ip, client_version, conn, nt).await?;
+
+    // Only redeem the auth request once the login can't fail anymore because of a missing second factor
+    if let Some(mut auth_request) = auth_request {
+        if !auth_request.redeem(conn).await {
+            err!(
+                "Username or access code is incorrect. Try again",
+                format!("IP: {}. Username: {username}.", ip.ip),
+                ErrorEvent {
+                    event: EventType::UserFailedLogIn,
+                }
+            )
+        }
+    }
+
+    register_device_key(&mut device, &data)?;
 
     let auth_tokens = auth::AuthTokens::new(&device, &user, AuthMethod::Password, data.client_id);
 
     authenticated_response(&user, &mut device, auth_tokens, twofactor_token, &now, conn, ip).await
 }
 
+/// Alerts the user when a known device logs in from an unusual location, new devices already trigger their own email.
+/// This never blocks the login, any failure is only logged.
+async fn check_login_location(user: &User, device: &Device, ip: &ClientIp, now: &NaiveDateTime, conn: &mut DbConn) {
+    if !CONFIG.login_anomaly_alerts() {
+        return;
+    }
+
+    let history = LoginLocation::find_by_user(&user.uuid, conn).await;
+    if CONFIG.mail_enabled() && !device.is_new() && LoginLocation::is_anomaly(&history, &ip.ip, ip.country.as_deref()) {
+        if let Err(e) =
+            mail::send_login_anomaly(&user.email, &ip.ip.to_string(), ip.country.as_deref(), now, device).await
+        {
+            error!("Error sending login anomaly email: {e:#?}");
+        }
+    }
+
+    if let Err(e) = LoginLocation::record(&user.uuid, &ip.ip, ip.country.clone(), history, conn).await {
+        error!("Error recording login location of user {}: {e:#?}", user.uuid);
+    }
+}
+
 async fn authenticated_response(
     user: &User,
     device: &mut Device,
@@ -462,6 +586,8 @@ async fn authenticated_response(
         }
     }
 
+    check_login_location(user, device, ip, now, conn).await;
+
     // register push device
     if !device.is_new() {
         register_push_device(device, conn).await?;
@@ -492,6 +618,17 @@ async fn authenticated_response(
         },
     });
 
+    // Passwordless accounts can't unlock with a password, a device gets the user key once another device approved it
+    if user.sso_only && user.password_hash.is_empty() {
+        result["UserDecryptionOptions"]["TrustedDeviceOption"] = json!({
+            "HasAdminApproval": false,
+            "HasLoginApprovingDevice": true,
+            "HasManageResetPasswordPermission": false,
+            "EncryptedPrivateKey": null,
+            "EncryptedUserKey": device.encrypted_user_key,
+        });
+    }
+
     if !user.akey.is_empty() {
         result["Key"] = Value::String(user.akey.clone());
     }
@@ -509,13 +646,16 @@ async fn _api_key_login(
     user_id: &mut Option<UserId>,
     conn: &mut DbConn,
     ip: &ClientIp,
+    client_cert_fingerprint: Option<&str>,
 ) -> JsonResult {
     // Ratelimit the login
     crate::ratelimit::check_limit_login(&ip.ip)?;
 
     // Validate scope
     match data.scope.as_ref() {
-        Some(scope) if scope == &AuthMethod::UserApiKey.scope() => _user_api_key_login(data, user_id, conn, ip).await,
+        Some(scope) if scope == &AuthMethod::UserApiKey.scope() => {
+            _user_api_key_login(data, user_id, conn, ip, client_cert_fingerprint).await
+        }
         Some(scope) if scope == &AuthMethod::OrgApiKey.scope() => _organization_api_key_login(data, conn, ip).await,
         _ => err!("Scope not supported"),
     }
@@ -526,6 +666,7 @@ async fn _user_api_key_login(
     user_id: &mut Option<UserId>,
     conn: &mut DbConn,
     ip: &ClientIp,
+    client_cert_fingerprint: Option<&str>,
 ) -> JsonResult {
     // Get the user via the client_id
     let client_id = data.client_id.as_ref().unwrap();
@@ -551,6 +692,26 @@ async fn _user_api_key_login(
         )
     }
 
+    if !CONFIG.serves_region(user.region.as_deref()) {
+        err!(
+            "This account belongs to another region, login on a server of that region (API key login)",
+            format!("IP: {}. Username: {}. Region: {:?}.", ip.ip, user.email, user.region),
+            ErrorEvent {
+                event: EventType::UserFailedLogIn
+            }
+        )
+    }
+
+    if user.pending_approval {
+        err!(
+            "This account is awaiting approval by an administrator (API key login)",
+            format!("IP: {}. Username: {}.", ip.ip, user.email),
+            ErrorEvent {
+                event: EventType::UserFailedLogIn
+            }
+        )
+    }
+
     // Check API key. Note that API key logins bypass 2FA.
     let client_secret = data.client_secret.as_ref().unwrap();
     if !user.check_valid_api_key(client_secret) {
@@ -563,6 +724,17 @@ async fn _user_api_key_login(
         )
     }
 
+    // Only enforced while the proxy header is configured, otherwise there is nothing to compare against
+    if CONFIG.api_key_client_cert_header().is_some() && !user.check_api_key_cert_fingerprint(client_cert_fingerprint) {
+        err!(
+            "Client certificate does not match the API key",
+            format!("IP: {}. Username: {}.", ip.ip, user.email),
+            ErrorEvent {
+                event: EventType::UserFailedLogIn
+            }
+        )
+    }
+
     let mut device = get_device(&data, conn, &user).await?;
 
     if CONFIG.mail_enabled() && device.is_new() {
@@ -581,6 +753,8 @@ async fn _user_api_key_login(
         }
     }
 
+    check_login_location(&user, &device, ip, &Utc::now().naive_utc(), conn).await;
+
     // ---
     // Disabled this variable, it was used to generate the JWT
     // Because this might get used in the future, and is add by the Bitwarden Server, lets keep it, but then commented out
@@ -589,6 +763,8 @@ async fn _user_api_key_login(
     // let orgs = Membership::find_confirmed_by_user(&user.uuid, conn).await;
     let access_claims = auth::LoginJwtClaims::default(&device, &user, &AuthMethod::UserApiKey, data.client_id);
 
+    register_device_key(&mut device, &data)?;
+
     // Save to update `device.updated_at` to track usage and toggle new status
     device.save(conn).await?;
 
@@ -642,6 +818,23 @@ async fn _organization_api_key_login(data: ConnectData, conn: &mut DbConn, ip: &
     })))
 }
 
+/// Registers the key a device of one of the `SESSION_BINDING_DEVICE_TYPES` signs its requests with.
+/// This only happens on a login with credentials, a stolen access or refresh token can't be used to replace the key.
+fn register_device_key(device: &mut Device, data: &ConnectData) -> EmptyResult {
+    if !CONFIG.is_session_binding_device_type(device.atype) {
+        return Ok(());
+    }
+    let Some(ref public_key) = data.device_public_key else {
+        err!("A device key is required to login with this device")
+    };
+    if auth::parse_device_public_key(public_key).is_none() {
+        err!("Invalid device key, it has to be a base64 encoded RSA public key of at least 2048 bits")
+    }
+
+    device.session_public_key = Some(public_key.clone());
+    Ok(())
+}
+
 /// Retrieves an existing device or creates a new device from ConnectData and the User
 async fn get_device(data: &ConnectData, conn: &mut DbConn, user: &User) -> ApiResult<Device> {
     // On iOS, device_type sends "iOS", on others it sends a number
@@ -664,6 +857,7 @@ async fn twofactor_auth(
     ip: &ClientIp,
     client_version: &Option<ClientVersion>,
     conn: &mut DbConn,
+    nt: &Notify<'_>,
 ) -> ApiResult<Option<String>> {
     let twofactors = TwoFactor::find_by_user(&user.uuid, conn).await;
 
@@ -690,8 +884,6 @@ async fn twofactor_auth(
 
     let selected_twofactor = twofactors.into_iter().find(|tf| tf.atype == selected_id && tf.enabled);
 
-    use crate::crypto::ct_eq;
-
     let selected_data = _selected_data(selected_twofactor);
     let mut remember = data.two_factor_remember.unwrap_or(0);
 
@@ -724,17 +916,13 @@ async fn twofactor_auth(
             email::validate_email_code_str(&user.uuid, twofactor_code, &selected_data?, &ip.ip, conn).await?
         }
         Some(TwoFactorType::Remember) => {
-            match device.twofactor_remember {
-                Some(ref code) if !CONFIG.disable_2fa_remember() && ct_eq(code, twofactor_code) => {
-                    remember = 1; // Make sure we also return the token here, otherwise it will only remember the first time
-                }
-                _ => {
-                    err_json!(
-                        _json_err_twofactor(&twofactor_ids, &user.uuid, data, client_version, conn).await?,
-                        "2FA Remember token not provided"
-                    )
-                }
+            if CONFIG.disable_2fa_remember() || !device.check_twofactor_remember(twofactor_code) {
+                err_json!(
+                    _json_err_twofactor(&twofactor_ids, &user.uuid, data, client_version, conn).await?,
+                    "2FA Remember token not provided"
+                )
             }
+            remember = 1; // Make sure we also return the token here, otherwise it will only remember the first time
         }
         Some(TwoFactorType::RecoveryCode) => {
             // Check if recovery code is correct
@@ -746,7 +934,7 @@ async fn twofactor_auth(
             TwoFactor::delete_all_by_user(&user.uuid, conn).await?;
             enforce_2fa_policy(user, &user.uuid, device.atype, &ip.ip, conn).await?;
 
-            log_user_event(EventType::UserRecovered2fa as i32, &user.uuid, device.atype, &ip.ip, conn).await;
+            log_user_event(EventType::UserRecovered2fa as i32, &user.uuid, device.atype, &ip.ip, conn, nt).await;
 
             // Remove the recovery code, not needed without twofactors
             user.totp_recover = None;
@@ -884,69 +1072,31 @@ async fn prelogin(data: Json<PreloginData>, conn: DbConn) -> Json<Value> {
 }
 
 #[post("/accounts/register", data = "<data>")]
-async fn identity_register(data: Json<RegisterData>, conn: DbConn) -> JsonResult {
-    _register(data, false, conn).await
-}
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct RegisterVerificationData {
-    email: String,
-    name: Option<String>,
-    // receiveMarketingEmails: bool,
-}
-
-#[derive(rocket::Responder)]
-enum RegisterVerificationResponse {
-    NoContent(()),
-    Token(Json<String>),
+async fn identity_register(
+    data: Json<RegisterData>,
+    client_headers: ClientHeaders,
+    conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
+    with_error_delay(_register(data, false, client_headers, conn, nt)).await
 }
 
 #[post("/accounts/register/send-verification-email", data = "<data>")]
 async fn register_verification_email(
     data: Json<RegisterVerificationData>,
-    mut conn: DbConn,
+    conn: DbConn,
 ) -> ApiResult<RegisterVerificationResponse> {
-    let data = data.into_inner();
-
-    // the registration can only continue if signup is allowed or there exists an invitation
-    if !(CONFIG.is_signup_allowed(&data.email)
-        || (!CONFIG.mail_enabled() && Invitation::find_by_mail(&data.email, &mut conn).await.is_some()))
-    {
-        err!("Registration not allowed or user already exists")
-    }
-
-    let should_send_mail = CONFIG.mail_enabled() && CONFIG.signups_verify();
-
-    let token_claims = auth::generate_register_verify_claims(data.email.clone(), data.name.clone(), should_send_mail);
-    let token = auth::encode_jwt(&token_claims);
-
-    if should_send_mail {
-        let user = User::find_by_mail(&data.email, &mut conn).await;
-        if user.filter(|u| u.private_key.is_some()).is_some() {
-            // There is still a timing side channel here in that the code
-            // paths that send mail take noticeably longer than ones that
-            // don't. Add a randomized sleep to mitigate this somewhat.
-            use rand::{rngs::SmallRng, Rng, SeedableRng};
-            let mut rng = SmallRng::from_os_rng();
-            let delta: i32 = 100;
-            let sleep_ms = (1_000 + rng.random_range(-delta..=delta)) as u64;
-            tokio::time::sleep(tokio::time::Duration::from_millis(sleep_ms)).await;
-        } else {
-            mail::send_register_verify_email(&data.email, &token).await?;
-        }
-
-        Ok(RegisterVerificationResponse::NoContent(()))
-    } else {
-        // If email verification is not required, return the token directly
-        // the clients will use this token to finish the registration
-        Ok(RegisterVerificationResponse::Token(Json(token)))
-    }
+    _register_verification_email(data, conn).await
 }
 
 #[post("/accounts/register/finish", data = "<data>")]
-async fn register_finish(data: Json<RegisterData>, conn: DbConn) -> JsonResult {
-    _register(data, true, conn).await
+async fn register_finish(
+    data: Json<RegisterData>,
+    client_headers: ClientHeaders,
+    conn: DbConn,
+    nt: Notify<'_>,
+) -> JsonResult {
+    with_error_delay(_register(data, true, client_headers, conn, nt)).await
 }
 
 // https://github.com/bitwarden/jslib/blob/master/common/src/models/request/tokenRequest.ts
@@ -985,6 +1135,10 @@ struct ConnectData {
     #[field(name = uncased("device_type"))]
     #[field(name = uncased("devicetype"))]
     device_type: Option<String>,
+    // Needed for session binding, see `SESSION_BINDING_DEVICE_TYPES`
+    #[field(name = uncased("device_public_key"))]
+    #[field(name = uncased("devicepublickey"))]
+    device_public_key: Option<String>,
     #[allow(unused)]
     #[field(name = uncased("device_push_token"))]
     #[field(name = uncased("devicepushtoken"))]
@@ -1002,6 +1156,14 @@ struct ConnectData {
     two_factor_remember: Option<i32>,
     #[field(name = uncased("authrequest"))]
     auth_request: Option<AuthRequestId>,
+    // Signature of the challenge nonce of the auth request, made by the requesting device
+    #[field(name = uncased("auth_request_signature"))]
+    #[field(name = uncased("authrequestsignature"))]
+    auth_request_signature: Option<String>,
+    // Only the captcha bypass token returned on registration is known, there is no captcha
+    #[field(name = uncased("captcha_response"))]
+    #[field(name = uncased("captcharesponse"))]
+    captcha_response: Option<String>,
     // Needed for authorization code
     #[field(name = uncased("code"))]
     code: Option<String>,
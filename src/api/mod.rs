@@ -14,8 +14,10 @@ pub use crate::api::{
     admin::routes as admin_routes,
     core::catchers as core_catchers,
     core::purge_auth_requests,
+    core::purge_device_tombstones,
     core::purge_sends,
     core::purge_trashed_ciphers,
+    core::purge_unverified_accounts,
     core::routes as core_routes,
     core::two_factor::send_incomplete_2fa_notifications,
     core::{emergency_notification_reminder_job, emergency_request_timeout_job},
@@ -43,6 +45,26 @@ pub type ApiResult<T> = Result<T, crate::error::Error>;
 pub type JsonResult = ApiResult<Json<Value>>;
 pub type EmptyResult = ApiResult<()>;
 
+/// Used for sensitive endpoints, so failed requests can't be told apart from (slower) successful ones by their timing.
+/// Error responses take at least the randomized minimum delay set by `AUTH_ERROR_MIN_DELAY_MS` and `AUTH_ERROR_DELAY_JITTER_MS`.
+pub async fn with_error_delay<T>(response: impl std::future::Future<Output = ApiResult<T>>) -> ApiResult<T> {
+    let min_delay =
+        crate::util::randomized_delay(CONFIG.auth_error_min_delay_ms(), CONFIG.auth_error_delay_jitter_ms());
+    delay_error_response(min_delay, response).await
+}
+
+async fn delay_error_response<T>(
+    min_delay: std::time::Duration,
+    response: impl std::future::Future<Output = ApiResult<T>>,
+) -> ApiResult<T> {
+    let start = tokio::time::Instant::now();
+    let result = response.await;
+    if result.is_err() {
+        tokio::time::sleep_until(start + min_delay).await;
+    }
+    result
+}
+
 // Common structs representing JSON data received
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -59,6 +81,9 @@ impl PasswordOrOtpData {
         use crate::api::core::two_factor::protected_actions::validate_protected_action_otp;
 
         match (self.master_password_hash.as_deref(), self.otp.as_deref()) {
+            (Some(_), None) if user.sso_only => {
+                err!("This account can only be verified with a one-time code");
+            }
             (Some(pw_hash), None) => {
                 if !user.check_valid_password(pw_hash) {
                     err!("Invalid password");
@@ -98,8 +123,18 @@ async fn master_password_policy(user: &User, conn: &DbConn) -> Value {
         .filter_map(|p| serde_json::from_str(&p.data).ok())
         .collect();
 
-    let mut mpp_json = if !master_password_policies.is_empty() {
-        json!(master_password_policies.into_iter().reduce(|acc, policy| {
+    let fallback = if CONFIG.sso_enabled() {
+        CONFIG.sso_master_password_policy_value()
+    } else {
+        None
+    };
+    merge_master_password_policies(master_password_policies, fallback)
+}
+
+// The server wide policy is only used when none of the organizations of the user has one
+fn merge_master_password_policies(policies: Vec<MasterPasswordPolicy>, fallback: Option<Value>) -> Value {
+    let mut mpp_json = if !policies.is_empty() {
+        json!(policies.into_iter().reduce(|acc, policy| {
             MasterPasswordPolicy {
                 min_complexity: acc.min_complexity.max(policy.min_complexity),
                 min_length: acc.min_length.max(policy.min_length),
@@ -110,13 +145,67 @@ async fn master_password_policy(user: &User, conn: &DbConn) -> Value {
                 enforce_on_login: acc.enforce_on_login || policy.enforce_on_login,
             }
         }))
-    } else if CONFIG.sso_enabled() {
-        CONFIG.sso_master_password_policy_value().unwrap_or(json!({}))
     } else {
-        json!({})
+        fallback.unwrap_or(json!({}))
     };
 
     // NOTE: Upstream still uses PascalCase here for `Object`!
     mpp_json["Object"] = json!("masterPasswordPolicy");
     mpp_json
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap().block_on(future)
+    }
+
+    #[test]
+    fn test_error_response_respects_min_delay() {
+        let min_delay = Duration::from_millis(100);
+        let start = std::time::Instant::now();
+        let result =
+            block_on(delay_error_response(min_delay, async { EmptyResult::Err(crate::error::Error::empty()) }));
+
+        assert!(result.is_err());
+        assert!(start.elapsed() >= min_delay);
+    }
+
+    #[test]
+    fn test_successful_response_is_not_delayed() {
+        let min_delay = Duration::from_secs(10);
+        let start = std::time::Instant::now();
+        let result = block_on(delay_error_response(min_delay, async { EmptyResult::Ok(()) }));
+
+        assert!(result.is_ok());
+        assert!(start.elapsed() < min_delay);
+    }
+
+    #[test]
+    fn test_merge_master_password_policies() {
+        let policies: Vec<MasterPasswordPolicy> = [
+            r#"{"minComplexity":2,"minLength":12,"requireUpper":true}"#,
+            r#"{"minLength":14,"requireNumbers":true,"enforceOnLogin":true}"#,
+        ]
+        .iter()
+        .map(|p| serde_json::from_str(p).unwrap())
+        .collect();
+        let server_policy = json!({"minLength": 20});
+
+        let merged = merge_master_password_policies(policies, Some(server_policy.clone()));
+        assert_eq!(merged["minComplexity"], 2);
+        assert_eq!(merged["minLength"], 14);
+        assert_eq!(merged["requireUpper"], true);
+        assert_eq!(merged["requireNumbers"], true);
+        assert_eq!(merged["requireLower"], false);
+        assert_eq!(merged["enforceOnLogin"], true);
+        assert_eq!(merged["Object"], "masterPasswordPolicy");
+
+        // The server policy is only used without any organization policy
+        assert_eq!(merge_master_password_policies(Vec::new(), Some(server_policy))["minLength"], 20);
+        assert_eq!(merge_master_password_policies(Vec::new(), None), json!({"Object": "masterPasswordPolicy"}));
+    }
+}
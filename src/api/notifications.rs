@@ -1,16 +1,19 @@
 use std::{net::IpAddr, sync::Arc, time::Duration};
 
-use chrono::{NaiveDateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use rmpv::Value;
 use rocket::{futures::StreamExt, Route};
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{Receiver, Sender};
 
 use rocket_ws::{Message, WebSocket};
 
 use crate::{
-    auth::{ClientIp, WsAccessTokenHeader},
+    auth::{check_session_signature, ClientIp, LoginJwtClaims, WsAccessTokenHeader},
     db::{
-        models::{AuthRequestId, Cipher, CollectionId, Device, DeviceId, Folder, PushId, Send as DbSend, User, UserId},
+        models::{
+            AuthRequestId, Cipher, CollectionId, Device, DeviceId, Event, Folder, PushId, RevokedSession,
+            Send as DbSend, User, UserId,
+        },
         DbConn,
     },
     Error, CONFIG,
@@ -21,6 +24,7 @@ use once_cell::sync::Lazy;
 pub static WS_USERS: Lazy<Arc<WebSocketUsers>> = Lazy::new(|| {
     Arc::new(WebSocketUsers {
         map: Arc::new(dashmap::DashMap::new()),
+        event_streams: Arc::new(dashmap::DashMap::new()),
     })
 });
 
@@ -39,7 +43,7 @@ static NOTIFICATIONS_DISABLED: Lazy<bool> = Lazy::new(|| !CONFIG.enable_websocke
 
 pub fn routes() -> Vec<Route> {
     if CONFIG.enable_websocket() {
-        routes![websockets_hub, anonymous_websockets_hub]
+        routes![websockets_hub, anonymous_websockets_hub, events_websockets_hub]
     } else {
         info!("WebSocket are disabled, realtime sync functionality will not work!");
         routes![]
@@ -78,6 +82,22 @@ impl Drop for WSEntryMapGuard {
     }
 }
 
+struct WSEventStreamGuard {
+    users: Arc<WebSocketUsers>,
+    user_uuid: UserId,
+    entry_uuid: uuid::Uuid,
+    addr: IpAddr,
+}
+
+impl Drop for WSEventStreamGuard {
+    fn drop(&mut self) {
+        info!("Closing WS event stream from {}", self.addr);
+        if let Some(mut entry) = self.users.event_streams.get_mut(self.user_uuid.as_ref()) {
+            entry.retain(|(uuid, _, _)| uuid != &self.entry_uuid);
+        }
+    }
+}
+
 struct WSAnonymousEntryMapGuard {
     subscriptions: Arc<AnonymousWebSocketSubscriptions>,
     token: String,
@@ -101,13 +121,32 @@ impl Drop for WSAnonymousEntryMapGuard {
     }
 }
 
+/// Loads the device of the access token of a WebSocket connection.
+/// Devices with session binding have to sign the connection request, the same as any other request.
+async fn ws_device(
+    claims: &LoginJwtClaims,
+    header_token: &WsAccessTokenHeader,
+    conn: &mut DbConn,
+) -> Result<Device, Error> {
+    let Some(device) = Device::find_by_uuid_and_user(&claims.device, &claims.sub, conn).await else {
+        err_code!("Invalid device id", 401)
+    };
+    if let Err(e) =
+        check_session_signature(&device, "GET", &header_token.uri, header_token.session_signature.as_deref())
+    {
+        err_code!(e, 401)
+    }
+    Ok(device)
+}
+
 #[allow(tail_expr_drop_order)]
 #[get("/hub?<data..>")]
-fn websockets_hub<'r>(
+async fn websockets_hub<'r>(
     ws: WebSocket,
     data: WsAccessToken,
     ip: ClientIp,
     header_token: WsAccessTokenHeader,
+    mut conn: DbConn,
 ) -> Result<rocket_ws::Stream!['r], Error> {
     let addr = ip.ip;
     info!("Accepting Rocket WS connection from {addr}");
@@ -123,14 +162,13 @@ fn websockets_hub<'r>(
     let Ok(claims) = crate::auth::decode_login(&token) else {
         err_code!("Invalid token", 401)
     };
+    ws_device(&claims, &header_token, &mut conn).await?;
 
     let (mut rx, guard) = {
         let users = Arc::clone(&WS_USERS);
 
         // Add a channel to send messages to this client to the map
-        let entry_uuid = uuid::Uuid::new_v4();
-        let (tx, rx) = tokio::sync::mpsc::channel::<Message>(100);
-        users.map.entry(claims.sub.to_string()).or_default().push((entry_uuid, tx));
+        let (entry_uuid, rx) = users.add_connection(&claims.sub);
 
         // Once the guard goes out of scope, the connection will have been closed and the entry will be deleted from the map
         (rx, WSEntryMapGuard::new(users, claims.sub, entry_uuid, addr))
@@ -253,6 +291,102 @@ fn anonymous_websockets_hub<'r>(ws: WebSocket, token: String, ip: ClientIp) -> R
     })
 }
 
+// Vaultwarden specific, streams the security events of the user as JSON text messages while they are logged.
+// This is a plain WebSocket without the SignalR protocol of the hubs above. It's closed when the session gets revoked.
+#[allow(tail_expr_drop_order)]
+#[get("/events-hub?<data..>")]
+async fn events_websockets_hub<'r>(
+    ws: WebSocket,
+    data: WsAccessToken,
+    ip: ClientIp,
+    header_token: WsAccessTokenHeader,
+    mut conn: DbConn,
+) -> Result<rocket_ws::Stream!['r], Error> {
+    let addr = ip.ip;
+    info!("Accepting Rocket WS event stream from {addr}");
+
+    if !CONFIG.org_events_enabled() {
+        err_code!("Event logging is disabled", 404)
+    }
+
+    let token = if let Some(token) = data.access_token {
+        token
+    } else if let Some(token) = header_token.access_token {
+        token
+    } else {
+        err_code!("Invalid claim", 401)
+    };
+
+    let Ok(claims) = crate::auth::decode_login(&token) else {
+        err_code!("Invalid token", 401)
+    };
+    ws_device(&claims, &header_token, &mut conn).await?;
+
+    // The stream shows the security events of the user, so the session of the token has to be valid still
+    let Some(user) = User::find_by_uuid(&claims.sub, &mut conn).await else {
+        err_code!("Invalid token", 401)
+    };
+    if !user.enabled || user.security_stamp != claims.sstamp {
+        err_code!("Invalid security stamp", 401)
+    }
+    let issued_at = DateTime::from_timestamp(claims.nbf, 0).unwrap_or_default().naive_utc();
+    if RevokedSession::find_by_user(&user.uuid, &mut conn)
+        .await
+        .iter()
+        .any(|r| r.revokes_token(&claims.device, &issued_at))
+    {
+        err_code!("The session of this device was revoked", 401)
+    }
+
+    let (mut rx, guard) = {
+        let users = Arc::clone(&WS_USERS);
+        let (entry_uuid, rx) = users.add_event_stream(&claims.sub, claims.device);
+
+        (
+            rx,
+            WSEventStreamGuard {
+                users,
+                user_uuid: claims.sub,
+                entry_uuid,
+                addr,
+            },
+        )
+    };
+
+    Ok({
+        rocket_ws::Stream! { ws => {
+            let mut ws = ws;
+            let _guard = guard;
+            let mut interval = tokio::time::interval(Duration::from_secs(15));
+            loop {
+                tokio::select! {
+                    res = ws.next() =>  {
+                        match res {
+                            Some(Ok(Message::Ping(ping))) => yield Message::Pong(ping),
+                            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                            // Nothing is expected from the client
+                            Some(Ok(_)) => {},
+                        }
+                    }
+
+                    res = rx.recv() => {
+                        match res {
+                            Some(Message::Close(frame)) => {
+                                yield Message::Close(frame);
+                                break;
+                            }
+                            Some(res) => yield res,
+                            None => break,
+                        }
+                    }
+
+                    _ = interval.tick() => yield Message::Ping(Vec::new())
+                }
+            }
+        }}
+    })
+}
+
 //
 // Websockets server
 //
@@ -322,12 +456,57 @@ static INITIAL_MESSAGE: InitialMessage<'static> = InitialMessage {
 
 // We attach the UUID to the sender so we can differentiate them when we need to remove them from the Vec
 type UserSenders = (uuid::Uuid, Sender<Message>);
+// The device of the session is kept to close the stream when that session gets revoked
+type EventStreamSenders = (uuid::Uuid, DeviceId, Sender<Message>);
 #[derive(Clone)]
 pub struct WebSocketUsers {
     map: Arc<dashmap::DashMap<String, Vec<UserSenders>>>,
+    event_streams: Arc<dashmap::DashMap<String, Vec<EventStreamSenders>>>,
 }
 
 impl WebSocketUsers {
+    /// Streams a security event to the event streams of its user, see `events_websockets_hub`.
+    /// Streams which can't keep up miss the event, logging an event never waits for a client.
+    pub fn send_user_event(&self, event: &Event) {
+        let Some(user_id) = &event.user_uuid else {
+            return;
+        };
+        if let Some(streams) = self.event_streams.get(user_id.as_ref()).map(|v| v.clone()) {
+            let message = Message::text(event.to_json().to_string());
+            for (_, _, sender) in streams.iter() {
+                if let Err(e) = sender.try_send(message.clone()) {
+                    warn!("Error sending WS event {e}");
+                }
+            }
+        }
+    }
+
+    /// Adds a channel for a WebSocket connection of the user, the returned id is used to remove it again
+    pub(crate) fn add_connection(&self, user_id: &UserId) -> (uuid::Uuid, Receiver<Message>) {
+        let entry_uuid = uuid::Uuid::new_v4();
+        let (tx, rx) = tokio::sync::mpsc::channel::<Message>(100);
+        self.map.entry(user_id.to_string()).or_default().push((entry_uuid, tx));
+        (entry_uuid, rx)
+    }
+
+    /// Adds an event stream for the session of `device_id`, the returned id is used to remove it again
+    pub(crate) fn add_event_stream(&self, user_id: &UserId, device_id: DeviceId) -> (uuid::Uuid, Receiver<Message>) {
+        let entry_uuid = uuid::Uuid::new_v4();
+        let (tx, rx) = tokio::sync::mpsc::channel::<Message>(100);
+        self.event_streams.entry(user_id.to_string()).or_default().push((entry_uuid, device_id, tx));
+        (entry_uuid, rx)
+    }
+
+    /// Closes the event streams of the sessions which were revoked
+    pub fn close_event_streams(&self, revocation: &RevokedSession) {
+        if let Some(streams) = self.event_streams.get(revocation.user_uuid.as_ref()).map(|v| v.clone()) {
+            for (_, _, sender) in streams.iter().filter(|(_, device_id, _)| revocation.applies_to(device_id)) {
+                // The stream closes itself once the message is received, a full channel is closed on its next message
+                sender.try_send(Message::Close(None)).ok();
+            }
+        }
+    }
+
     async fn send_update(&self, user_id: &UserId, data: &[u8]) {
         if let Some(user) = self.map.get(user_id.as_ref()).map(|v| v.clone()) {
             for (_, sender) in user.iter() {
@@ -360,6 +539,13 @@ impl WebSocketUsers {
     }
 
     pub async fn send_logout(&self, user: &User, acting_device_id: Option<DeviceId>, conn: &mut DbConn) {
+        // Keep track of the revocation, even when notifications are disabled, so clients can look it up when reconnecting
+        let revocation = RevokedSession::all_devices(user.uuid.clone(), acting_device_id.clone());
+        if let Err(e) = revocation.save(conn).await {
+            error!("Error recording revoked sessions of user {}: {e:#?}", user.uuid);
+        }
+        self.close_event_streams(&revocation);
+
         // Skip any processing if both WebSockets and Push are not active
         if *NOTIFICATIONS_DISABLED {
             return;
@@ -657,3 +843,54 @@ pub enum UpdateType {
 
 pub type Notify<'a> = &'a rocket::State<Arc<WebSocketUsers>>;
 pub type AnonymousNotify<'a> = &'a rocket::State<Arc<AnonymousWebSocketSubscriptions>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::EventType;
+
+    #[test]
+    fn test_event_streams_only_get_their_user_events() {
+        let users = WebSocketUsers {
+            map: Arc::new(dashmap::DashMap::new()),
+            event_streams: Arc::new(dashmap::DashMap::new()),
+        };
+        let user_id = UserId::from(crate::util::get_uuid());
+        let other_user_id = UserId::from(crate::util::get_uuid());
+        let acting_device = DeviceId::from(crate::util::get_uuid());
+        let other_device = DeviceId::from(crate::util::get_uuid());
+
+        let stream = |user_id: &UserId, device_id: &DeviceId| {
+            let (tx, rx) = tokio::sync::mpsc::channel::<Message>(100);
+            users.event_streams.entry(user_id.to_string()).or_default().push((
+                uuid::Uuid::new_v4(),
+                device_id.clone(),
+                tx,
+            ));
+            rx
+        };
+        let mut acting_rx = stream(&user_id, &acting_device);
+        let mut other_rx = stream(&user_id, &other_device);
+        let mut other_user_rx = stream(&other_user_id, &other_device);
+
+        let mut event = Event::new(EventType::UserLoggedIn as i32, None);
+        event.user_uuid = Some(user_id.clone());
+        users.send_user_event(&event);
+
+        for rx in [&mut acting_rx, &mut other_rx] {
+            let Ok(Message::Text(text)) = rx.try_recv() else {
+                panic!("The event wasn't streamed");
+            };
+            let streamed: serde_json::Value = serde_json::from_str(&text).unwrap();
+            assert_eq!(streamed["type"], EventType::UserLoggedIn as i32);
+            assert_eq!(streamed["userId"], user_id.to_string());
+        }
+        assert!(other_user_rx.try_recv().is_err());
+
+        // Revoking the other sessions only closes their streams
+        users.close_event_streams(&RevokedSession::all_devices(user_id, Some(acting_device)));
+        assert!(matches!(other_rx.try_recv(), Ok(Message::Close(None))));
+        assert!(acting_rx.try_recv().is_err());
+        assert!(other_user_rx.try_recv().is_err());
+    }
+}
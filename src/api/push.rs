@@ -1,3 +1,4 @@
+use chrono::Utc;
 use reqwest::{
     header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
     Method,
@@ -7,6 +8,7 @@ use tokio::sync::RwLock;
 
 use crate::{
     api::{ApiResult, EmptyResult, UpdateType},
+    crypto,
     db::models::{AuthRequestId, Cipher, Device, DeviceId, Folder, PushId, Send, User, UserId},
     http_client::make_http_request,
     util::{format_date, get_uuid},
@@ -84,6 +86,13 @@ pub async fn register_push_device(device: &mut Device, conn: &mut crate::db::DbC
         return Ok(());
     }
 
+    if let Some(user) = User::find_by_uuid(&device.user_uuid, conn).await {
+        if !user.allows_external_services() {
+            debug!("Skipping the registration of device {:?}, the user opted out of external services", device.uuid);
+            return Ok(());
+        }
+    }
+
     if device.push_token.is_none() {
         warn!("Skipping the registration of the device {:?} because the push_token field is empty.", device.uuid);
         warn!("To get rid of this message you need to logout, clear the app data and login again on the device.");
@@ -97,6 +106,25 @@ pub async fn register_push_device(device: &mut Device, conn: &mut crate::db::DbC
         device.push_uuid = Some(PushId(get_uuid()));
     }
 
+    // Store the outcome in both cases, so the device list shows why a device doesn't receive notifications
+    let result = send_push_registration(device).await;
+    device.set_push_registration_result(&result);
+
+    if let Err(e) = device.save(conn).await {
+        err!(format!("An error occurred while trying to save the (registered) device push uuid: {e}"));
+    }
+
+    // A failing webhook shouldn't prevent the device from logging in or updating its token
+    match result {
+        Err(e) if CONFIG.push_webhook_url().is_some() => {
+            error!("{e}");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+async fn send_push_registration(device: &Device) -> EmptyResult {
     //Needed to register a device for push to bitwarden :
     let data = json!({
         "deviceId": device.push_uuid, // Unique UUID per user/device
@@ -108,6 +136,10 @@ pub async fn register_push_device(device: &mut Device, conn: &mut crate::db::DbC
         "installationId": CONFIG.push_installation_id(),
     });
 
+    if let Some(url) = CONFIG.push_webhook_url() {
+        return send_to_push_webhook(&url, "register", data).await;
+    }
+
     let auth_api_token = get_auth_api_token().await?;
     let auth_header = format!("Bearer {auth_api_token}");
 
@@ -123,10 +155,6 @@ pub async fn register_push_device(device: &mut Device, conn: &mut crate::db::DbC
         err!(format!("An error occurred while proceeding registration of a device: {e}"));
     }
 
-    if let Err(e) = device.save(conn).await {
-        err!(format!("An error occurred while trying to save the (registered) device push uuid: {e}"));
-    }
-
     Ok(())
 }
 
@@ -134,6 +162,14 @@ pub async fn unregister_push_device(push_id: &Option<PushId>) -> EmptyResult {
     if !CONFIG.push_enabled() || push_id.is_none() {
         return Ok(());
     }
+
+    if let Some(url) = CONFIG.push_webhook_url() {
+        if let Err(e) = send_to_push_webhook(&url, "unregister", json!({ "deviceId": push_id })).await {
+            error!("{e}");
+        }
+        return Ok(());
+    }
+
     let auth_api_token = get_auth_api_token().await?;
 
     let auth_header = format!("Bearer {auth_api_token}");
@@ -162,25 +198,23 @@ pub async fn push_cipher_update(ut: UpdateType, cipher: &Cipher, device: &Device
         return;
     };
 
-    if Device::check_user_has_push_device(user_id, conn).await {
-        send_to_push_relay(json!({
-            "userId": user_id,
+    let notification = json!({
+        "userId": user_id,
+        "organizationId": null,
+        "deviceId": device.push_uuid, // Should be the records unique uuid of the acting device (unique uuid per user/device)
+        "identifier": device.uuid, // Should be the acting device id (aka uuid per device/app)
+        "type": ut as i32,
+        "payload": {
+            "id": cipher.uuid,
+            "userId": cipher.user_uuid,
             "organizationId": null,
-            "deviceId": device.push_uuid, // Should be the records unique uuid of the acting device (unique uuid per user/device)
-            "identifier": device.uuid, // Should be the acting device id (aka uuid per device/app)
-            "type": ut as i32,
-            "payload": {
-                "id": cipher.uuid,
-                "userId": cipher.user_uuid,
-                "organizationId": null,
-                "collectionIds": null,
-                "revisionDate": format_date(&cipher.updated_at)
-            },
-            "clientType": null,
-            "installationId": null
-        }))
-        .await;
-    }
+            "collectionIds": null,
+            "revisionDate": format_date(&cipher.updated_at)
+        },
+        "clientType": null,
+        "installationId": null
+    });
+    send_user_push(user_id, ut, &device.uuid, notification, conn).await;
 }
 
 pub async fn push_logout(user: &User, acting_device_id: Option<DeviceId>, conn: &mut crate::db::DbConn) {
@@ -222,41 +256,94 @@ pub async fn push_user_update(ut: UpdateType, user: &User, push_uuid: &Option<Pu
 }
 
 pub async fn push_folder_update(ut: UpdateType, folder: &Folder, device: &Device, conn: &mut crate::db::DbConn) {
-    if Device::check_user_has_push_device(&folder.user_uuid, conn).await {
-        tokio::task::spawn(send_to_push_relay(json!({
+    let notification = json!({
+        "userId": folder.user_uuid,
+        "organizationId": null,
+        "deviceId": device.push_uuid, // Should be the records unique uuid of the acting device (unique uuid per user/device)
+        "identifier": device.uuid, // Should be the acting device id (aka uuid per device/app)
+        "type": ut as i32,
+        "payload": {
+            "id": folder.uuid,
             "userId": folder.user_uuid,
+            "revisionDate": format_date(&folder.updated_at)
+        },
+        "clientType": null,
+        "installationId": null
+    });
+    send_user_push(&folder.user_uuid, ut, &device.uuid, notification, conn).await;
+}
+
+pub async fn push_send_update(ut: UpdateType, send: &Send, device: &Device, conn: &mut crate::db::DbConn) {
+    if let Some(s) = &send.user_uuid {
+        let notification = json!({
+            "userId": send.user_uuid,
             "organizationId": null,
             "deviceId": device.push_uuid, // Should be the records unique uuid of the acting device (unique uuid per user/device)
             "identifier": device.uuid, // Should be the acting device id (aka uuid per device/app)
             "type": ut as i32,
             "payload": {
-                "id": folder.uuid,
-                "userId": folder.user_uuid,
-                "revisionDate": format_date(&folder.updated_at)
+                "id": send.uuid,
+                "userId": send.user_uuid,
+                "revisionDate": format_date(&send.revision_date)
             },
             "clientType": null,
             "installationId": null
-        })));
+        });
+        send_user_push(s, ut, &device.uuid, notification, conn).await;
     }
 }
 
-pub async fn push_send_update(ut: UpdateType, send: &Send, device: &Device, conn: &mut crate::db::DbConn) {
-    if let Some(s) = &send.user_uuid {
-        if Device::check_user_has_push_device(s, conn).await {
-            tokio::task::spawn(send_to_push_relay(json!({
-                "userId": send.user_uuid,
-                "organizationId": null,
-                "deviceId": device.push_uuid, // Should be the records unique uuid of the acting device (unique uuid per user/device)
-                "identifier": device.uuid, // Should be the acting device id (aka uuid per device/app)
-                "type": ut as i32,
-                "payload": {
-                    "id": send.uuid,
-                    "userId": send.user_uuid,
-                    "revisionDate": format_date(&send.revision_date)
-                },
-                "clientType": null,
-                "installationId": null
-            })));
+/// The devices a push notification about an update of a user is sent to
+#[derive(Debug)]
+enum PushRecipients {
+    None,
+    // The relay delivers it to all push devices of the user, except the acting one
+    User,
+    // Some devices muted this type of update, so it's sent to each of the other devices on its own
+    Devices(Vec<PushId>),
+}
+
+fn push_recipients(devices: &[Device], ut: UpdateType, acting_device_id: &DeviceId) -> PushRecipients {
+    if devices.is_empty() {
+        PushRecipients::None
+    } else if devices.iter().all(|d| d.wants_push(ut)) {
+        PushRecipients::User
+    } else {
+        let push_ids: Vec<PushId> = devices
+            .iter()
+            .filter(|d| d.wants_push(ut) && d.uuid != *acting_device_id)
+            .filter_map(|d| d.push_uuid.clone())
+            .collect();
+        if push_ids.is_empty() {
+            PushRecipients::None
+        } else {
+            PushRecipients::Devices(push_ids)
+        }
+    }
+}
+
+/// Sends `notification` to the push devices of `user_id` which didn't mute this type of update.
+/// A notification with only a `deviceId` and without a `userId` is delivered by the relay to that single device.
+async fn send_user_push(
+    user_id: &UserId,
+    ut: UpdateType,
+    acting_device_id: &DeviceId,
+    notification: Value,
+    conn: &mut crate::db::DbConn,
+) {
+    let devices = Device::find_push_devices_by_user(user_id, conn).await;
+    match push_recipients(&devices, ut, acting_device_id) {
+        PushRecipients::None => {}
+        PushRecipients::User => {
+            tokio::task::spawn(send_to_push_relay(notification));
+        }
+        PushRecipients::Devices(push_ids) => {
+            for push_id in push_ids {
+                let mut notification = notification.clone();
+                notification["userId"] = Value::Null;
+                notification["deviceId"] = json!(push_id);
+                tokio::task::spawn(send_to_push_relay(notification));
+            }
         }
     }
 }
@@ -266,6 +353,13 @@ async fn send_to_push_relay(notification_data: Value) {
         return;
     }
 
+    if let Some(url) = CONFIG.push_webhook_url() {
+        if let Err(e) = send_to_push_webhook(&url, "send", notification_data).await {
+            error!("{e}");
+        }
+        return;
+    }
+
     let auth_api_token = match get_auth_api_token().await {
         Ok(s) => s,
         Err(e) => {
@@ -296,22 +390,49 @@ async fn send_to_push_relay(notification_data: Value) {
     };
 }
 
+/// Posts a push event to the configured webhook, which is used instead of the Bitwarden push relay.
+/// The request is signed with an HMAC-SHA256 of `<timestamp>.<body>`, using the configured webhook secret.
+async fn send_to_push_webhook(url: &str, event: &str, data: Value) -> EmptyResult {
+    let body = json!({
+        "event": event,
+        "data": data,
+    })
+    .to_string();
+    let timestamp = Utc::now().timestamp().to_string();
+    let signature =
+        crypto::hmac_sha256_sign(&CONFIG.push_webhook_secret().unwrap_or_default(), &format!("{timestamp}.{body}"));
+
+    if let Err(e) = make_http_request(Method::POST, url)?
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Timestamp", timestamp)
+        .header("X-Webhook-Signature", format!("sha256={signature}"))
+        .timeout(Duration::from_secs(CONFIG.push_webhook_timeout()))
+        .body(body)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+    {
+        err!(format!("An error occurred while sending a {event} event to the push webhook: {e}"));
+    }
+
+    Ok(())
+}
+
 pub async fn push_auth_request(user_id: &UserId, auth_request_id: &str, device: &Device, conn: &mut crate::db::DbConn) {
-    if Device::check_user_has_push_device(user_id, conn).await {
-        tokio::task::spawn(send_to_push_relay(json!({
+    let notification = json!({
+        "userId": user_id,
+        "organizationId": null,
+        "deviceId": device.push_uuid, // Should be the records unique uuid of the acting device (unique uuid per user/device)
+        "identifier": device.uuid, // Should be the acting device id (aka uuid per device/app)
+        "type": UpdateType::AuthRequest as i32,
+        "payload": {
             "userId": user_id,
-            "organizationId": null,
-            "deviceId": device.push_uuid, // Should be the records unique uuid of the acting device (unique uuid per user/device)
-            "identifier": device.uuid, // Should be the acting device id (aka uuid per device/app)
-            "type": UpdateType::AuthRequest as i32,
-            "payload": {
-                "userId": user_id,
-                "id": auth_request_id,
-            },
-            "clientType": null,
-            "installationId": null
-        })));
-    }
+            "id": auth_request_id,
+        },
+        "clientType": null,
+        "installationId": null
+    });
+    send_user_push(user_id, UpdateType::AuthRequest, &device.uuid, notification, conn).await;
 }
 
 pub async fn push_auth_response(
@@ -320,19 +441,66 @@ pub async fn push_auth_response(
     device: &Device,
     conn: &mut crate::db::DbConn,
 ) {
-    if Device::check_user_has_push_device(user_id, conn).await {
-        tokio::task::spawn(send_to_push_relay(json!({
+    let notification = json!({
+        "userId": user_id,
+        "organizationId": null,
+        "deviceId": device.push_uuid, // Should be the records unique uuid of the acting device (unique uuid per user/device)
+        "identifier": device.uuid, // Should be the acting device id (aka uuid per device/app)
+        "type": UpdateType::AuthRequestResponse as i32,
+        "payload": {
             "userId": user_id,
-            "organizationId": null,
-            "deviceId": device.push_uuid, // Should be the records unique uuid of the acting device (unique uuid per user/device)
-            "identifier": device.uuid, // Should be the acting device id (aka uuid per device/app)
-            "type": UpdateType::AuthRequestResponse as i32,
-            "payload": {
-                "userId": user_id,
-                "id": auth_request_id,
-            },
-            "clientType": null,
-            "installationId": null
-        })));
+            "id": auth_request_id,
+        },
+        "clientType": null,
+        "installationId": null
+    });
+    send_user_push(user_id, UpdateType::AuthRequestResponse, &device.uuid, notification, conn).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::PushCategory;
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_muted_device_is_left_out_of_push() {
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let user_id = UserId::from(get_uuid());
+            let mut devices = Vec::new();
+            for name in ["acting", "muted", "other"] {
+                let mut device =
+                    Device::new(DeviceId::from(get_uuid()), user_id.clone(), name.to_string(), 0, &mut conn)
+                        .await
+                        .unwrap();
+                device.push_uuid = Some(PushId(get_uuid()));
+                device.push_token = Some(String::from("push-token"));
+                device.save(&mut conn).await.unwrap();
+                devices.push(device);
+            }
+            let acting = devices[0].uuid.clone();
+
+            let push_devices = Device::find_push_devices_by_user(&user_id, &mut conn).await;
+            assert!(matches!(
+                push_recipients(&push_devices, UpdateType::SyncSendCreate, &acting),
+                PushRecipients::User
+            ));
+
+            devices[1].set_push_allowed(PushCategory::Sends, false);
+            devices[1].save(&mut conn).await.unwrap();
+            let push_devices = Device::find_push_devices_by_user(&user_id, &mut conn).await;
+            let PushRecipients::Devices(push_ids) = push_recipients(&push_devices, UpdateType::SyncSendCreate, &acting)
+            else {
+                panic!("A muted device should be left out");
+            };
+            assert_eq!(push_ids.len(), 1);
+            assert_eq!(Some(&push_ids[0].0), devices[2].push_uuid.as_ref().map(|p| &p.0));
+            // Other types of updates still go to all devices
+            assert!(matches!(
+                push_recipients(&push_devices, UpdateType::SyncCipherUpdate, &acting),
+                PushRecipients::User
+            ));
+        });
     }
 }
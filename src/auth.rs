@@ -1,9 +1,16 @@
 // JWT Handling
 use chrono::{DateTime, TimeDelta, Utc};
+use dashmap::{mapref::entry::Entry, DashMap};
+use data_encoding::BASE64;
 use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header};
 use num_traits::FromPrimitive;
 use once_cell::sync::{Lazy, OnceCell};
-use openssl::rsa::Rsa;
+use openssl::{
+    hash::MessageDigest,
+    pkey::{Id, PKey, Public},
+    rsa::Rsa,
+    sign::Verifier,
+};
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
 use std::{env, net::IpAddr};
@@ -11,6 +18,7 @@ use std::{env, net::IpAddr};
 use crate::{
     api::ApiResult,
     config::PathType,
+    crypto,
     db::models::{
         AttachmentId, CipherId, CollectionId, DeviceId, DeviceType, EmergencyAccessId, MembershipId, OrgApiKeyId,
         OrganizationId, SendFileId, SendId, UserId,
@@ -20,6 +28,7 @@ use crate::{
 };
 
 const JWT_ALGORITHM: Algorithm = Algorithm::RS256;
+pub const JWT_LEEWAY_SECONDS: u64 = 30;
 
 // Limit when BitWarden consider the token as expired
 pub static BW_EXPIRATION: Lazy<TimeDelta> = Lazy::new(|| TimeDelta::try_minutes(5).unwrap());
@@ -40,6 +49,9 @@ static JWT_SEND_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|send", CONFIG.do
 static JWT_ORG_API_KEY_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|api.organization", CONFIG.domain_origin()));
 static JWT_FILE_DOWNLOAD_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|file_download", CONFIG.domain_origin()));
 static JWT_REGISTER_VERIFY_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|register_verify", CONFIG.domain_origin()));
+static JWT_KEYS_BACKUP_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|keys_backup", CONFIG.domain_origin()));
+static JWT_CAPTCHA_BYPASS_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|captcha_bypass", CONFIG.domain_origin()));
+static JWT_ATTESTATION_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|attestation", CONFIG.domain_origin()));
 
 static PRIVATE_RSA_KEY: OnceCell<EncodingKey> = OnceCell::new();
 static PUBLIC_RSA_KEY: OnceCell<DecodingKey> = OnceCell::new();
@@ -85,16 +97,24 @@ pub async fn initialize_keys() -> Result<(), Error> {
 }
 
 pub fn encode_jwt<T: Serialize>(claims: &T) -> String {
-    match jsonwebtoken::encode(&JWT_HEADER, claims, PRIVATE_RSA_KEY.wait()) {
+    encode_jwt_with(claims, PRIVATE_RSA_KEY.wait())
+}
+
+fn encode_jwt_with<T: Serialize>(claims: &T, key: &EncodingKey) -> String {
+    match jsonwebtoken::encode(&JWT_HEADER, claims, key) {
         Ok(token) => token,
         Err(e) => panic!("Error encoding jwt {e}"),
     }
 }
 
 pub fn decode_jwt<T: DeserializeOwned>(token: &str, issuer: String) -> Result<T, Error> {
+    decode_jwt_inner(token, issuer, true)
+}
+
+fn decode_jwt_inner<T: DeserializeOwned>(token: &str, issuer: String, validate_exp: bool) -> Result<T, Error> {
     let mut validation = jsonwebtoken::Validation::new(JWT_ALGORITHM);
-    validation.leeway = 30; // 30 seconds
-    validation.validate_exp = true;
+    validation.leeway = JWT_LEEWAY_SECONDS;
+    validation.validate_exp = validate_exp;
     validation.validate_nbf = true;
     validation.set_issuer(&[issuer]);
 
@@ -154,6 +174,11 @@ pub fn decode_register_verify(token: &str) -> Result<RegisterVerifyClaims, Error
     decode_jwt(token, JWT_REGISTER_VERIFY_ISSUER.to_string())
 }
 
+/// Also returns the claims of an expired token, the caller has to check `exp` itself.
+pub fn decode_register_verify_allow_expired(token: &str) -> Result<RegisterVerifyClaims, Error> {
+    decode_jwt_inner(token, JWT_REGISTER_VERIFY_ISSUER.to_string(), false)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginJwtClaims {
     // Not before
@@ -289,6 +314,9 @@ pub struct InviteJwtClaims {
     pub org_id: OrganizationId,
     pub member_id: MembershipId,
     pub invited_by_email: Option<String>,
+    // Residency region of the invited account
+    #[serde(default)]
+    pub region: Option<String>,
 }
 
 pub fn generate_invite_claims(
@@ -297,6 +325,7 @@ pub fn generate_invite_claims(
     org_id: OrganizationId,
     member_id: MembershipId,
     invited_by_email: Option<String>,
+    region: Option<String>,
 ) -> InviteJwtClaims {
     let time_now = Utc::now();
     let expire_hours = i64::from(CONFIG.invitation_expiration_hours());
@@ -309,6 +338,7 @@ pub fn generate_invite_claims(
         org_id,
         member_id,
         invited_by_email,
+        region,
     }
 }
 
@@ -446,6 +476,68 @@ pub struct BasicJwtClaims {
     pub sub: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeysBackupClaims {
+    // Issued at
+    pub iat: i64,
+    // Issuer
+    pub iss: String,
+    // Subject
+    pub sub: UserId,
+
+    // The backup itself, so a stored copy can be checked against the public key of this server
+    pub backup: serde_json::Value,
+}
+
+pub fn generate_keys_backup_claims(user_id: UserId, backup: serde_json::Value) -> KeysBackupClaims {
+    KeysBackupClaims {
+        iat: Utc::now().timestamp(),
+        iss: JWT_KEYS_BACKUP_ISSUER.to_string(),
+        sub: user_id,
+        backup,
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttestationClaims {
+    // Issued at
+    pub iat: i64,
+    // Issuer
+    pub iss: String,
+    // Subject
+    pub sub: UserId,
+
+    // The attested account state, including a nonce so every attestation is unique
+    pub attestation: serde_json::Value,
+}
+
+pub fn generate_attestation_claims(user_id: UserId, attestation: serde_json::Value) -> AttestationClaims {
+    AttestationClaims {
+        iat: Utc::now().timestamp(),
+        iss: JWT_ATTESTATION_ISSUER.to_string(),
+        sub: user_id,
+        attestation,
+    }
+}
+
+/// Attestations are a point in time statement, they don't expire.
+/// Anyone with the public key of this server can do the same check, it's a regular RS256 JWT.
+pub fn decode_attestation(token: &str) -> Result<AttestationClaims, Error> {
+    decode_attestation_with(token, PUBLIC_RSA_KEY.wait())
+}
+
+fn decode_attestation_with(token: &str, key: &DecodingKey) -> Result<AttestationClaims, Error> {
+    let mut validation = jsonwebtoken::Validation::new(JWT_ALGORITHM);
+    validation.validate_exp = false;
+    validation.set_required_spec_claims(&["iat", "iss", "sub"]);
+    validation.set_issuer(&[JWT_ATTESTATION_ISSUER.as_str()]);
+
+    match jsonwebtoken::decode(token.trim(), key, &validation) {
+        Ok(d) => Ok(d.claims),
+        Err(_) => err!("The attestation signature is invalid"),
+    }
+}
+
 pub fn generate_delete_claims(uuid: String) -> BasicJwtClaims {
     let time_now = Utc::now();
     let expire_hours = i64::from(CONFIG.invitation_expiration_hours());
@@ -478,6 +570,46 @@ pub fn generate_admin_claims() -> BasicJwtClaims {
     }
 }
 
+/// Returned on registration, the new account can then skip the login ratelimit for a short while
+pub fn generate_captcha_bypass_claims(email: &str, lifetime_minutes: u32) -> BasicJwtClaims {
+    let time_now = Utc::now();
+    BasicJwtClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + TimeDelta::try_minutes(i64::from(lifetime_minutes)).unwrap()).timestamp(),
+        iss: JWT_CAPTCHA_BYPASS_ISSUER.to_string(),
+        sub: email.trim().to_lowercase(),
+    }
+}
+
+/// Captcha bypass tokens which were redeemed already, kept until they expire
+static REDEEMED_CAPTCHA_BYPASS_TOKENS: Lazy<DashMap<String, i64>> = Lazy::new(DashMap::new);
+
+/// Only accepts captcha bypass tokens which were issued for this email and didn't expire yet.
+/// A token answers a single captcha challenge, it is refused once it was redeemed.
+pub fn redeem_captcha_bypass(token: &str, email: &str) -> bool {
+    decode_jwt::<BasicJwtClaims>(token, JWT_CAPTCHA_BYPASS_ISSUER.to_string()).is_ok_and(|claims| {
+        captcha_bypass_matches(&claims, email) && redeem_once(&REDEEMED_CAPTCHA_BYPASS_TOKENS, token, claims.exp)
+    })
+}
+
+fn redeem_once(redeemed: &DashMap<String, i64>, token: &str, exp: i64) -> bool {
+    let now = Utc::now().timestamp();
+    redeemed.retain(|_, token_exp| *token_exp > now);
+    match redeemed.entry(crypto::sha256_hex(token)) {
+        Entry::Occupied(_) => false,
+        Entry::Vacant(entry) => {
+            entry.insert(exp);
+            true
+        }
+    }
+}
+
+fn captcha_bypass_matches(claims: &BasicJwtClaims, email: &str) -> bool {
+    claims.iss == *JWT_CAPTCHA_BYPASS_ISSUER
+        && claims.sub == email.trim().to_lowercase()
+        && claims.exp > Utc::now().timestamp()
+}
+
 pub fn generate_send_claims(send_id: &SendId, file_id: &SendFileId) -> BasicJwtClaims {
     let time_now = Utc::now();
     BasicJwtClaims {
@@ -519,21 +651,7 @@ impl<'r> FromRequest<'r> for Host {
             referer.to_string()
         } else {
             // Try to guess from the headers
-            let protocol = if let Some(proto) = headers.get_one("X-Forwarded-Proto") {
-                proto
-            } else if env::var("ROCKET_TLS").is_ok() {
-                "https"
-            } else {
-                "http"
-            };
-
-            let host = if let Some(host) = headers.get_one("X-Forwarded-Host") {
-                host
-            } else {
-                headers.get_one("Host").unwrap_or_default()
-            };
-
-            format!("{protocol}://{host}")
+            guess_host_from_headers(headers)
         };
 
         Outcome::Success(Host {
@@ -542,9 +660,77 @@ impl<'r> FromRequest<'r> for Host {
     }
 }
 
+fn guess_host_from_headers(headers: &rocket::http::HeaderMap<'_>) -> String {
+    let protocol = if let Some(proto) = headers.get_one("X-Forwarded-Proto") {
+        proto
+    } else if env::var("ROCKET_TLS").is_ok() {
+        "https"
+    } else {
+        "http"
+    };
+
+    let host = if let Some(host) = headers.get_one("X-Forwarded-Host") {
+        host
+    } else {
+        headers.get_one("Host").unwrap_or_default()
+    };
+
+    format!("{protocol}://{host}")
+}
+
+/// Resolves the origin which is returned to the clients for a request coming from `request_origin`.
+/// Without a configured allowlist the domain origin is always used, as are requests without a usable origin.
+/// Otherwise the request origin has to be the domain origin or one of the allowlisted origins, else `None` is returned.
+pub fn resolve_allowed_origin(request_origin: Option<&str>, domain_origin: &str, allowlist: &str) -> Option<String> {
+    let to_origin = |url: &str| url::Url::parse(url.trim()).ok().map(|u| u.origin().ascii_serialization());
+
+    if allowlist.trim().is_empty() {
+        return Some(domain_origin.to_string());
+    }
+
+    let Some(request_origin) = request_origin.and_then(to_origin).filter(|o| o != "null") else {
+        return Some(domain_origin.to_string());
+    };
+
+    if request_origin == domain_origin
+        || allowlist.split(',').filter_map(to_origin).any(|allowed| allowed == request_origin)
+    {
+        Some(request_origin)
+    } else {
+        None
+    }
+}
+
+/// The origin to report in auth-request responses, see `resolve_allowed_origin`.
+pub struct AuthRequestOrigin {
+    pub origin: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthRequestOrigin {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let request_origin = match headers.get_one("Origin") {
+            Some(origin) => origin.to_string(),
+            None => guess_host_from_headers(headers),
+        };
+
+        match resolve_allowed_origin(Some(&request_origin), &CONFIG.domain_origin(), &CONFIG.auth_request_origins()) {
+            Some(origin) => Outcome::Success(AuthRequestOrigin {
+                origin,
+            }),
+            None => err_handler!("Origin is not allowed", format!("Origin: {request_origin}")),
+        }
+    }
+}
+
 pub struct ClientHeaders {
     pub device_type: i32,
     pub ip: ClientIp,
+    // Forwarded by the reverse proxy in the `API_KEY_CLIENT_CERT_HEADER`, if configured
+    pub client_cert_fingerprint: Option<String>,
 }
 
 #[rocket::async_trait]
@@ -560,9 +746,15 @@ impl<'r> FromRequest<'r> for ClientHeaders {
         let device_type: i32 =
             request.headers().get_one("device-type").map(|d| d.parse().unwrap_or(14)).unwrap_or_else(|| 14);
 
+        let client_cert_fingerprint = CONFIG
+            .api_key_client_cert_header()
+            .and_then(|header| request.headers().get_one(&header).map(String::from))
+            .filter(|fingerprint| !fingerprint.is_empty());
+
         Outcome::Success(ClientHeaders {
             device_type,
             ip,
+            client_cert_fingerprint,
         })
     }
 }
@@ -574,6 +766,28 @@ pub struct Headers {
     pub ip: ClientIp,
 }
 
+/// The JWT keys can only be set once per process, the tests which need them share a generated key
+#[cfg(test)]
+pub fn init_test_keys() {
+    static TEST_RSA_KEY: Lazy<Rsa<openssl::pkey::Private>> = Lazy::new(|| Rsa::generate(2048).unwrap());
+    PRIVATE_RSA_KEY.get_or_init(|| EncodingKey::from_rsa_pem(&TEST_RSA_KEY.private_key_to_pem().unwrap()).unwrap());
+    PUBLIC_RSA_KEY.get_or_init(|| DecodingKey::from_rsa_pem(&TEST_RSA_KEY.public_key_to_pem().unwrap()).unwrap());
+}
+
+/// The headers of a request of this user and device, as they are currently saved
+#[cfg(test)]
+pub async fn test_headers(user_id: &UserId, device_id: &DeviceId, conn: &mut DbConn) -> Headers {
+    Headers {
+        host: String::from("https://vault.example.ext"),
+        device: Device::find_by_uuid(device_id, conn).await.unwrap(),
+        user: User::find_by_uuid(user_id, conn).await.unwrap(),
+        ip: ClientIp {
+            ip: IpAddr::from([127, 0, 0, 1]),
+            country: None,
+        },
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Headers {
     type Error = &'static str;
@@ -601,6 +815,12 @@ impl<'r> FromRequest<'r> for Headers {
             err_handler!("Invalid claim")
         };
 
+        if CONFIG.device_identifier_binding()
+            && !is_device_identifier_bound(headers.get_one("X-Device-Identifier"), &claims.device)
+        {
+            err_handler!("Device identifier is missing or does not match the access token")
+        }
+
         let device_id = claims.device;
         let user_id = claims.sub;
 
@@ -613,10 +833,31 @@ impl<'r> FromRequest<'r> for Headers {
             err_handler!("Invalid device id")
         };
 
+        if let Err(e) = check_session_signature(
+            &device,
+            request.method().as_str(),
+            &request.uri().to_string(),
+            headers.get_one("X-Session-Signature"),
+        ) {
+            err_handler!(e)
+        }
+
         let Some(user) = User::find_by_uuid(&user_id, &mut conn).await else {
             err_handler!("Device has no user associated")
         };
 
+        if !user.enabled {
+            err_handler!("This user has been disabled")
+        }
+
+        if user.pending_approval {
+            err_handler!("This account is awaiting approval by an administrator")
+        }
+
+        if device.pending_approval && !pending_device_allows_route(request.route().and_then(|r| r.name.as_deref())) {
+            err_handler!("This device has to be approved from another device first")
+        }
+
         if user.security_stamp != claims.sstamp {
             if let Some(stamp_exception) =
                 user.stamp_exception.as_deref().and_then(|s| serde_json::from_str::<UserStampException>(s).ok())
@@ -628,7 +869,7 @@ impl<'r> FromRequest<'r> for Headers {
                 // Check if the stamp exception has expired first.
                 // Then, check if the current route matches any of the allowed routes.
                 // After that check the stamp in exception matches the one in the claims.
-                if Utc::now().timestamp() > stamp_exception.expire {
+                if stamp_exception.is_expired() {
                     // If the stamp exception has been expired remove it from the database.
                     // This prevents checking this stamp exception for new requests.
                     let mut user = user;
@@ -637,7 +878,7 @@ impl<'r> FromRequest<'r> for Headers {
                         error!("Error updating user: {e:#?}");
                     }
                     err_handler!("Stamp exception is expired")
-                } else if !stamp_exception.routes.contains(&current_route.to_string()) {
+                } else if !stamp_exception.allows_route(current_route) {
                     err_handler!("Invalid security stamp: Current route and exception route do not match")
                 } else if stamp_exception.security_stamp != claims.sstamp {
                     err_handler!("Invalid security stamp for matched stamp exception")
@@ -656,6 +897,170 @@ impl<'r> FromRequest<'r> for Headers {
     }
 }
 
+/// The routes a device can use while it waits for the approval of another device:
+/// waiting for the approval itself, and managing its own push token.
+const PENDING_DEVICE_ROUTES: &[&str] =
+    &["get_device_approval", "put_device_approval_key", "post_device_token", "put_device_token"];
+
+fn pending_device_allows_route(route: Option<&str>) -> bool {
+    route.is_some_and(|route| PENDING_DEVICE_ROUTES.contains(&route))
+}
+
+/// Checks the `X-Device-Identifier` header of a request against the device of its access token.
+/// The device is part of the signed login claims, which makes it impossible to combine a stolen token
+/// with the identifier of another device. Requests without the header are rejected, or leaving it out would skip this.
+pub fn is_device_identifier_bound(device_identifier: Option<&str>, token_device: &DeviceId) -> bool {
+    device_identifier.is_some_and(|device_identifier| DeviceId::from(device_identifier.to_string()) == *token_device)
+}
+
+/// Nonces of the accepted session signatures, kept for as long as their timestamp would be accepted
+static SESSION_SIGNATURE_NONCES: Lazy<DashMap<String, i64>> = Lazy::new(DashMap::new);
+
+/// Parses the key a device registers on login, a base64 encoded DER public key.
+/// It's also the key the user key is encrypted with on approval, so only RSA keys of at least 2048 bits are accepted.
+pub fn parse_device_public_key(public_key: &str) -> Option<PKey<Public>> {
+    let der = BASE64.decode(public_key.as_bytes()).ok()?;
+    let pkey = PKey::public_key_from_der(&der).ok()?;
+    (pkey.id() == Id::RSA && pkey.bits() >= 2048).then_some(pkey)
+}
+
+/// Requests of devices of the `SESSION_BINDING_DEVICE_TYPES` have to be signed with the device key registered on login.
+/// Used for the API requests as well as for the WebSocket connections.
+pub fn check_session_signature(
+    device: &Device,
+    method: &str,
+    uri: &str,
+    signature_header: Option<&str>,
+) -> Result<(), &'static str> {
+    if !CONFIG.is_session_binding_device_type(device.atype) {
+        return Ok(());
+    }
+    // Devices which logged in before session binding was enabled have to login again
+    let Some(public_key) = device.session_public_key.as_deref() else {
+        return Err("No device key registered for this session");
+    };
+    verify_session_signature(
+        public_key,
+        &device.uuid,
+        method,
+        uri,
+        signature_header,
+        Utc::now().timestamp(),
+        CONFIG.session_binding_max_skew_seconds(),
+        &SESSION_SIGNATURE_NONCES,
+    )
+}
+
+/// Checks the `X-Session-Signature` header of a request of a device which registered a session key.
+/// The header is `<timestamp>.<nonce>.<signature>`, the base64 signature is a SHA-256 signature over
+/// `<method>\n<uri>\n<timestamp>\n<nonce>`. A nonce is only accepted once per device, and only while the timestamp
+/// is within `max_skew` seconds of `now`, which makes a captured request useless for a replay.
+#[allow(clippy::too_many_arguments)]
+pub fn verify_session_signature(
+    public_key: &str,
+    device_id: &DeviceId,
+    method: &str,
+    uri: &str,
+    signature_header: Option<&str>,
+    now: i64,
+    max_skew: i64,
+    used_nonces: &DashMap<String, i64>,
+) -> Result<(), &'static str> {
+    let Some(signature_header) = signature_header else {
+        return Err("Missing session signature");
+    };
+    let mut parts = signature_header.splitn(3, '.');
+    let (Some(timestamp), Some(nonce), Some(signature)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err("Malformed session signature");
+    };
+    let Ok(signed_at) = timestamp.parse::<i64>() else {
+        return Err("Malformed session signature");
+    };
+    if nonce.is_empty() || nonce.len() > 64 {
+        return Err("Malformed session signature");
+    }
+    if now.abs_diff(signed_at) > max_skew.unsigned_abs() {
+        return Err("Session signature expired");
+    }
+
+    let (Some(pkey), Ok(signature)) = (parse_device_public_key(public_key), BASE64.decode(signature.as_bytes())) else {
+        return Err("Invalid session signature");
+    };
+    let message = format!("{method}\n{uri}\n{timestamp}\n{nonce}");
+    let valid = Verifier::new(MessageDigest::sha256(), &pkey)
+        .and_then(|mut verifier| {
+            verifier.update(message.as_bytes())?;
+            verifier.verify(&signature)
+        })
+        .unwrap_or(false);
+    if !valid {
+        return Err("Invalid session signature");
+    }
+
+    // Prevent the map from growing indefinitely, the nonces of expired timestamps are refused anyway
+    if used_nonces.len() >= 10_000 {
+        used_nonces.retain(|_, used_at| now.abs_diff(*used_at) <= max_skew.unsigned_abs());
+    }
+    match used_nonces.entry(format!("{device_id}.{nonce}")) {
+        Entry::Occupied(_) => Err("Replayed session signature"),
+        Entry::Vacant(entry) => {
+            entry.insert(signed_at);
+            Ok(())
+        }
+    }
+}
+
+/// Like `Headers`, but the session of the access token doesn't have to be valid anymore.
+/// Only used to let a client find out that its session was revoked and it needs to login again.
+pub struct RevokedSessionHeaders {
+    pub device_id: DeviceId,
+    pub user: User,
+    // True when the security stamp changed or the device was removed since the token was issued
+    pub revoked: bool,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RevokedSessionHeaders {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let access_token: &str = match request.headers().get_one("Authorization") {
+            Some(a) => match a.rsplit("Bearer ").next() {
+                Some(split) => split,
+                None => err_handler!("No access token provided"),
+            },
+            None => err_handler!("No access token provided"),
+        };
+
+        // The token itself still needs to be signed by us and not be expired
+        let Ok(claims) = decode_login(access_token) else {
+            err_handler!("Invalid claim")
+        };
+
+        let mut conn = match DbConn::from_request(request).await {
+            Outcome::Success(conn) => conn,
+            _ => err_handler!("Error getting DB"),
+        };
+
+        let Some(user) = User::find_by_uuid(&claims.sub, &mut conn).await else {
+            err_handler!("Token has no user associated")
+        };
+
+        if !user.enabled {
+            err_handler!("This user has been disabled")
+        }
+
+        let device_exists = Device::find_by_uuid_and_user(&claims.device, &user.uuid, &mut conn).await.is_some();
+        let revoked = !device_exists || user.security_stamp != claims.sstamp;
+
+        Outcome::Success(Self {
+            device_id: claims.device,
+            user,
+            revoked,
+        })
+    }
+}
+
 pub struct OrgHeaders {
     pub host: String,
     pub device: Device,
@@ -988,6 +1393,7 @@ impl From<OrgMemberHeaders> for Headers {
 
 pub struct ClientIp {
     pub ip: IpAddr,
+    pub country: Option<String>,
 }
 
 #[rocket::async_trait]
@@ -1011,8 +1417,16 @@ impl<'r> FromRequest<'r> for ClientIp {
 
         let ip = ip.or_else(|| req.remote().map(|r| r.ip())).unwrap_or_else(|| "0.0.0.0".parse().unwrap());
 
+        let country_header = CONFIG.ip_country_header();
+        let country = if country_header.is_empty() {
+            None
+        } else {
+            req.headers().get_one(&country_header).map(|c| c.trim().to_uppercase()).filter(|c| !c.is_empty())
+        };
+
         Outcome::Success(ClientIp {
             ip,
+            country,
         })
     }
 }
@@ -1048,6 +1462,9 @@ impl<'r> FromRequest<'r> for Secure {
 
 pub struct WsAccessTokenHeader {
     pub access_token: Option<String>,
+    // Needed to check the session signature of devices with session binding
+    pub session_signature: Option<String>,
+    pub uri: String,
 }
 
 #[rocket::async_trait]
@@ -1065,6 +1482,8 @@ impl<'r> FromRequest<'r> for WsAccessTokenHeader {
 
         Outcome::Success(Self {
             access_token,
+            session_signature: headers.get_one("X-Session-Signature").map(String::from),
+            uri: request.uri().to_string(),
         })
     }
 }
@@ -1210,10 +1629,10 @@ pub async fn refresh_tokens(
         Ok(claims) => claims,
     };
 
-    // Get device by refresh token
+    // Get device by refresh token, a revoked or removed device will not be found anymore
     let mut device = match Device::find_by_refresh_token(&refresh_claims.device_token, conn).await {
-        None => err!("Invalid refresh token"),
-        Some(device) => device,
+        Some(device) if device.check_refresh_token(&refresh_claims.device_token) => device,
+        _ => err!("Invalid refresh token"),
     };
 
     // Save to update `updated_at`.
@@ -1224,6 +1643,14 @@ pub async fn refresh_tokens(
         Some(user) => user,
     };
 
+    if !user.enabled {
+        err!("This user has been disabled")
+    }
+
+    if user.pending_approval {
+        err!("This account is awaiting approval by an administrator")
+    }
+
     let auth_tokens = match refresh_claims.sub {
         AuthMethod::Sso if CONFIG.sso_enabled() && CONFIG.sso_auth_only_not_session() => {
             AuthTokens::new(&device, &user, refresh_claims.sub, client_id)
@@ -1239,3 +1666,250 @@ pub async fn refresh_tokens(
 
     Ok((device, auth_tokens))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_captcha_bypass_claims_issued() {
+        let claims = generate_captcha_bypass_claims(" User@Example.com", 5);
+        assert_eq!(claims.sub, "user@example.com");
+        assert_eq!(claims.exp - claims.nbf, 5 * 60);
+        assert_eq!(claims.iss, *JWT_CAPTCHA_BYPASS_ISSUER);
+    }
+
+    #[test]
+    fn test_captcha_bypass_claims_accepted() {
+        let claims = generate_captcha_bypass_claims("user@example.com", 5);
+        assert!(captcha_bypass_matches(&claims, "USER@example.com"));
+        assert!(!captcha_bypass_matches(&claims, "other@example.com"));
+
+        let mut expired = generate_captcha_bypass_claims("user@example.com", 5);
+        expired.exp = Utc::now().timestamp() - 1;
+        assert!(!captcha_bypass_matches(&expired, "user@example.com"));
+
+        // Other tokens with the same claims, like the one to delete an account, are not accepted
+        let mut other_issuer = generate_captcha_bypass_claims("user@example.com", 5);
+        other_issuer.iss = JWT_DELETE_ISSUER.to_string();
+        assert!(!captcha_bypass_matches(&other_issuer, "user@example.com"));
+    }
+
+    #[test]
+    fn test_pending_device_routes() {
+        assert!(pending_device_allows_route(Some("get_device_approval")));
+        assert!(pending_device_allows_route(Some("put_device_approval_key")));
+        // Everything else, including the approval of other devices, needs an approved device
+        assert!(!pending_device_allows_route(Some("sync")));
+        assert!(!pending_device_allows_route(Some("put_device_approval")));
+        assert!(!pending_device_allows_route(Some("delete_device")));
+        assert!(!pending_device_allows_route(None));
+    }
+
+    #[test]
+    fn test_captcha_bypass_redeemed_once() {
+        let redeemed = DashMap::new();
+        let exp = Utc::now().timestamp() + 60;
+        assert!(redeem_once(&redeemed, "token", exp));
+        assert!(!redeem_once(&redeemed, "token", exp));
+        assert!(redeem_once(&redeemed, "other-token", exp));
+
+        // Expired tokens are refused by their claims, so they don't have to be remembered
+        redeemed.insert(String::from("expired"), Utc::now().timestamp() - 1);
+        redeem_once(&redeemed, "another-token", exp);
+        assert!(!redeemed.contains_key("expired"));
+    }
+
+    #[test]
+    fn test_attestation_signature() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let enc = EncodingKey::from_rsa_pem(&rsa.private_key_to_pem().unwrap()).unwrap();
+        let dec = DecodingKey::from_rsa_pem(&rsa.public_key_to_pem().unwrap()).unwrap();
+
+        let attestation = serde_json::json!({"kdfIterations": 600000, "twoFactorEnabled": true, "nonce": "abc"});
+        let token = encode_jwt_with(
+            &generate_attestation_claims(UserId::from(String::from("user")), attestation.clone()),
+            &enc,
+        );
+
+        let claims = decode_attestation_with(&token, &dec).unwrap();
+        assert_eq!(claims.attestation, attestation);
+        assert_eq!(*claims.sub, "user");
+
+        // Changing any of the attested fields breaks the signature
+        let (header, rest) = token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let mut forged = generate_attestation_claims(UserId::from(String::from("user")), attestation);
+        forged.attestation["twoFactorEnabled"] = serde_json::json!(false);
+        let forged_payload = data_encoding::BASE64URL_NOPAD.encode(&serde_json::to_vec(&forged).unwrap());
+        assert!(decode_attestation_with(&format!("{header}.{forged_payload}.{signature}"), &dec).is_err());
+
+        // And it's only valid for the key of this server
+        let other = Rsa::generate(2048).unwrap();
+        let other_dec = DecodingKey::from_rsa_pem(&other.public_key_to_pem().unwrap()).unwrap();
+        assert!(decode_attestation_with(&token, &other_dec).is_err());
+    }
+
+    const DOMAIN_ORIGIN: &str = "https://vault.example.com";
+    const ALLOWLIST: &str = "https://vault.example.org, https://vault.example.net:8443/path";
+
+    #[test]
+    fn test_resolve_origin_without_allowlist() {
+        let origin = resolve_allowed_origin(Some("https://other.example.org"), DOMAIN_ORIGIN, "");
+        assert_eq!(origin.as_deref(), Some(DOMAIN_ORIGIN));
+    }
+
+    #[test]
+    fn test_resolve_origin_multiple_domains() {
+        let resolve = |origin| resolve_allowed_origin(Some(origin), DOMAIN_ORIGIN, ALLOWLIST);
+
+        assert_eq!(resolve("https://vault.example.com").as_deref(), Some(DOMAIN_ORIGIN));
+        assert_eq!(resolve("https://vault.example.org").as_deref(), Some("https://vault.example.org"));
+        assert_eq!(resolve("https://vault.example.org/#/login").as_deref(), Some("https://vault.example.org"));
+        assert_eq!(resolve("https://vault.example.net:8443").as_deref(), Some("https://vault.example.net:8443"));
+        assert_eq!(resolve("HTTPS://VAULT.EXAMPLE.ORG").as_deref(), Some("https://vault.example.org"));
+    }
+
+    #[test]
+    fn test_resolve_origin_rejects_unknown_origins() {
+        let resolve = |origin| resolve_allowed_origin(Some(origin), DOMAIN_ORIGIN, ALLOWLIST);
+
+        assert_eq!(resolve("https://evil.example.org"), None);
+        assert_eq!(resolve("http://vault.example.org"), None);
+        assert_eq!(resolve("https://vault.example.net"), None);
+    }
+
+    #[test]
+    fn test_resolve_origin_falls_back_to_domain() {
+        assert_eq!(resolve_allowed_origin(None, DOMAIN_ORIGIN, ALLOWLIST).as_deref(), Some(DOMAIN_ORIGIN));
+        assert_eq!(resolve_allowed_origin(Some("null"), DOMAIN_ORIGIN, ALLOWLIST).as_deref(), Some(DOMAIN_ORIGIN));
+    }
+
+    #[test]
+    fn test_device_identifier_matches_token() {
+        let device_id = crate::util::get_uuid();
+        let token_device = DeviceId::from(device_id.clone());
+
+        assert!(is_device_identifier_bound(Some(&device_id), &token_device));
+    }
+
+    fn session_key() -> (openssl::pkey::PKey<openssl::pkey::Private>, String) {
+        let key = PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        let public_key = BASE64.encode(&key.public_key_to_der().unwrap());
+        (key, public_key)
+    }
+
+    fn session_signature(
+        key: &openssl::pkey::PKey<openssl::pkey::Private>,
+        uri: &str,
+        timestamp: i64,
+        nonce: &str,
+    ) -> String {
+        let mut signer = openssl::sign::Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(format!("GET\n{uri}\n{timestamp}\n{nonce}").as_bytes()).unwrap();
+        format!("{timestamp}.{nonce}.{}", BASE64.encode(&signer.sign_to_vec().unwrap()))
+    }
+
+    #[test]
+    fn test_valid_session_signature() {
+        let (key, public_key) = session_key();
+        let device_id = DeviceId::from(crate::util::get_uuid());
+        let nonces = DashMap::new();
+        let now = Utc::now().timestamp();
+
+        let signature = session_signature(&key, "/api/sync", now - 10, "nonce-1");
+        assert_eq!(
+            verify_session_signature(&public_key, &device_id, "GET", "/api/sync", Some(&signature), now, 60, &nonces),
+            Ok(())
+        );
+
+        // The signature only covers this request
+        let signature = session_signature(&key, "/api/sync", now, "nonce-2");
+        let result = verify_session_signature(
+            &public_key,
+            &device_id,
+            "GET",
+            "/api/ciphers",
+            Some(&signature),
+            now,
+            60,
+            &nonces,
+        );
+        assert_eq!(result, Err("Invalid session signature"));
+
+        // And it's only valid for the key of the device
+        let (_, other_key) = session_key();
+        let result =
+            verify_session_signature(&other_key, &device_id, "GET", "/api/sync", Some(&signature), now, 60, &nonces);
+        assert_eq!(result, Err("Invalid session signature"));
+    }
+
+    #[test]
+    fn test_only_rsa_device_keys_are_accepted() {
+        let (_, public_key) = session_key();
+        assert!(parse_device_public_key(&public_key).is_some());
+
+        // The user key is encrypted with the device key on approval, which isn't possible with an EC key
+        let group = openssl::ec::EcGroup::from_curve_name(openssl::nid::Nid::X9_62_PRIME256V1).unwrap();
+        let ec_key = PKey::from_ec_key(openssl::ec::EcKey::generate(&group).unwrap()).unwrap();
+        assert!(parse_device_public_key(&BASE64.encode(&ec_key.public_key_to_der().unwrap())).is_none());
+        assert!(parse_device_public_key("not a key").is_none());
+    }
+
+    #[test]
+    fn test_missing_session_signature_is_rejected() {
+        let (_, public_key) = session_key();
+        let device_id = DeviceId::from(crate::util::get_uuid());
+        let nonces = DashMap::new();
+        let now = Utc::now().timestamp();
+
+        let verify =
+            |header| verify_session_signature(&public_key, &device_id, "GET", "/api/sync", header, now, 60, &nonces);
+        assert_eq!(verify(None), Err("Missing session signature"));
+        assert_eq!(verify(Some("")), Err("Malformed session signature"));
+        assert_eq!(verify(Some(&format!("{now}.nonce"))), Err("Malformed session signature"));
+    }
+
+    #[test]
+    fn test_replayed_session_signature_is_rejected() {
+        let (key, public_key) = session_key();
+        let device_id = DeviceId::from(crate::util::get_uuid());
+        let nonces = DashMap::new();
+        let now = Utc::now().timestamp();
+        let verify = |header: &str, now| {
+            verify_session_signature(&public_key, &device_id, "GET", "/api/sync", Some(header), now, 60, &nonces)
+        };
+
+        let signature = session_signature(&key, "/api/sync", now, "nonce");
+        assert_eq!(verify(&signature, now), Ok(()));
+        assert_eq!(verify(&signature, now + 1), Err("Replayed session signature"));
+
+        // Old signatures are refused, even with a fresh nonce
+        let old = session_signature(&key, "/api/sync", now - 120, "other-nonce");
+        assert_eq!(verify(&old, now), Err("Session signature expired"));
+
+        // The same nonce can be used by another device
+        let other_device = DeviceId::from(crate::util::get_uuid());
+        let result = verify_session_signature(
+            &public_key,
+            &other_device,
+            "GET",
+            "/api/sync",
+            Some(&signature),
+            now,
+            60,
+            &nonces,
+        );
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn test_forged_device_identifier_is_rejected() {
+        let token_device = DeviceId::from(crate::util::get_uuid());
+        let forged = crate::util::get_uuid();
+
+        assert!(!is_device_identifier_bound(Some(&forged), &token_device));
+        assert!(!is_device_identifier_bound(Some(""), &token_device));
+        assert!(!is_device_identifier_bound(None, &token_device));
+    }
+}
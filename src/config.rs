@@ -432,6 +432,13 @@ make_config! {
         push_installation_id:   Pass,   false,  def,    String::new();
         /// Installation key |> The installation key from https://bitwarden.com/host
         push_installation_key:  Pass,   false,  def,    String::new();
+        /// Push webhook url |> Post push events to this url instead of using the Bitwarden push relay.
+        /// The installation id and key are not needed in that case
+        push_webhook_url:       String, false,  option;
+        /// Push webhook secret |> Used to sign the requests to the push webhook with HMAC-SHA256
+        push_webhook_secret:    Pass,   false,  option;
+        /// Push webhook timeout |> Maximum number of seconds to wait for a response of the push webhook
+        push_webhook_timeout:   u64,    false,  def,    10;
     },
     jobs {
         /// Job scheduler poll interval |> How often the job scheduler thread checks for jobs to run.
@@ -464,6 +471,12 @@ make_config! {
         /// Purge incomplete SSO nonce. |> Cron schedule of the job that cleans leftover nonce in db due to incomplete SSO login.
         /// Defaults to daily. Set blank to disable this job.
         purge_incomplete_sso_nonce: String, false,  def,   "0 20 0 * * *".to_string();
+        /// Unverified account purge schedule |> Cron schedule of the job that deletes accounts which never verified their email.
+        /// Defaults to daily. Set blank to disable this job.
+        purge_unverified_accounts_schedule: String, false, def, "0 25 0 * * *".to_string();
+        /// Device tombstone purge schedule |> Cron schedule of the job that removes soft-deleted devices after the period set in `device_tombstone_days`.
+        /// Defaults to daily. Set blank to disable this job.
+        purge_device_tombstones_schedule: String, false, def, "0 30 0 * * *".to_string();
     },
 
     /// General settings
@@ -477,6 +490,9 @@ make_config! {
         domain_origin:          String, false,  auto,   |c| extract_url_origin(&c.domain);
         /// Domain path |> Domain URL path (in https://example.com:8443/path, /path is the path)
         domain_path:            String, false,  auto,   |c| extract_url_path(&c.domain);
+        /// Auth request origins |> Comma-separated list of additional origins, like https://vault.example.org, from which login with device requests are accepted.
+        /// The origin of the request is then returned to the clients instead of the domain origin, and requests from any other origin are rejected. Leave empty to always use the domain origin
+        auth_request_origins:   String, true,   def,    String::new();
         /// Enable web vault
         web_vault_enabled:      bool,   false,  def,    true;
 
@@ -486,6 +502,8 @@ make_config! {
 
         /// HIBP Api Key |> HaveIBeenPwned API Key, request it here: https://haveibeenpwned.com/API/Key
         hibp_api_key:           Pass,   true,   option;
+        /// Allow external services by default |> Whether optional third-party services, like the Bitwarden push relay and HIBP checks, are used for users which didn't set a preference in their profile
+        external_services_default: bool, true,  def,    true;
 
         /// Per-user attachment storage limit (KB) |> Max kilobytes of attachment storage allowed per user. When this limit is reached, the user will not be allowed to upload further attachments.
         user_attachment_limit:  i64,    true,   option;
@@ -513,6 +531,9 @@ make_config! {
         disable_icon_download:  bool,   true,   def,    false;
         /// Allow new signups |> Controls whether new users can register. Users can be invited by the vaultwarden admin even if this is disabled
         signups_allowed:        bool,   true,   def,    true;
+        /// Require admin approval for signups |> New users who sign up on their own can't log in until an admin approves their account.
+        /// Users invited by the admin, an organization or via emergency access don't need an approval
+        registration_requires_approval: bool, true, def, false;
         /// Require email verification on signups. On new client versions, this will require verification at signup time. On older clients,
         /// this will prevent logins from succeeding until the address has been verified
         signups_verify:         bool,   true,   def,    false;
@@ -520,20 +541,51 @@ make_config! {
         signups_verify_resend_time: u64, true,  def,    3_600;
         /// If signups require email verification, limit how many emails are automatically sent when login is attempted (0 means no limit)
         signups_verify_resend_limit: u32, true, def,    6;
+        /// Require keys on signup |> Reject registrations which don't include the account keypair. Some clients generate it after the registration
+        signups_require_keys:   bool,   true,   def,    false;
+        /// Purge unverified accounts after (days) |> If signups require email verification, delete accounts which never verified their email after this many days.
+        /// Only accounts which signed up while verification was required are purged. Accounts which requested a verification email within this period, or have any items, sends, organization memberships or emergency access, are kept
+        purge_unverified_accounts_after: u32, true, option;
+        /// Require verified email for sharing |> Users need to verify their email address before they can create Sends or share items with an organization. Only applies when mail is enabled
+        require_verified_email_for_sharing: bool, true, def, false;
+        /// Require verified email for API keys |> Users need to verify their email address before they can create or rotate their personal API key. Only applies when mail is enabled
+        require_verified_email_for_api_keys: bool, true, def, false;
         /// Email domain whitelist |> Allow signups only from this list of comma-separated domains, even when signups are otherwise disabled
         signups_domains_whitelist: String, true, def,   String::new();
+        /// Data residency regions |> Comma-separated list of the regions accounts can be assigned to, for example `eu,us`. Leave empty to not tag accounts with a region
+        residency_regions:       String, false, def, String::new();
+        /// Default residency region |> Region assigned to new accounts which weren't invited with a region, must be one of the residency regions
+        residency_default_region: String, false, def, String::new();
+        /// Residency region of this server |> Only accounts of this region, or without a region, can register and login on this server. Leave empty to serve all regions
+        residency_node_region:   String, false, def, String::new();
         /// Enable event logging |> Enables event logging for organizations.
         org_events_enabled:     bool,   false,  def,    false;
         /// Org creation users |> Allow org creation only by this list of comma-separated user emails.
         /// Blank or 'all' means all users can create orgs; 'none' means no users can create orgs.
         org_creation_users:     String, true,   def,    String::new();
+        /// Max organizations per user |> Maximum number of organizations a user can create or join. Open invitations beyond this stay pending. 0 means unlimited
+        max_orgs_per_user:      u32,    true,   def,    0;
         /// Allow invitations |> Controls whether users can be invited by organization admins, even when signups are otherwise disabled
         invitations_allowed:    bool,   true,   def,    true;
+        /// Accept invites of existing users on registration |> When an invited user registers again while their email already belongs to an account,
+        /// accept the organization invite for that account instead of failing with a "user already exists" error
+        invite_existing_user_auto_accept: bool, true, def, false;
+        /// Notify admins of invited registrations |> Send an email to the owners and admins of an organization when an invited member completes their registration
+        invite_registration_notify: bool, true, def, false;
         /// Invitation token expiration time (in hours) |> The number of hours after which an organization invite token, emergency access invite token,
         /// email verification token and deletion request token will expire (must be at least 1)
         invitation_expiration_hours: u32, false, def, 120;
         /// Enable emergency access |> Controls whether users can enable emergency access to their accounts. This setting applies globally to all users.
         emergency_access_allowed:    bool,   true,   def,    true;
+        /// Notify grantors of auto-accepted emergency access |> When mail is disabled, emergency access invites are accepted automatically when the grantee registers.
+        /// If enabled, the grantor's clients are notified so the accepted emergency contact shows up without a manual refresh.
+        emergency_access_auto_accept_notify: bool, true, def, true;
+        /// Security stamp reset revokes emergency access |> When a user resets their security stamp (deauthorize sessions), also revoke all emergency access
+        /// grants this user has given or received, including pending invites. Useful when an account has been compromised.
+        sstamp_revokes_emergency_access: bool, true, def, false;
+        /// Security stamp reset grace period |> Number of seconds the previous security stamp stays valid after a user resets it (deauthorize sessions),
+        /// so requests which are already in flight can complete. Refresh tokens are revoked immediately, but a stolen access token also stays usable during this period. Disabled with 0
+        sstamp_grace_seconds: u64, true, def, 0;
         /// Allow email change |> Controls whether users can change their email. This setting applies globally to all users.
         email_change_allowed:    bool,   true,   def,    true;
         /// Password iterations |> Number of server-side passwords hashing iterations for the password hash.
@@ -545,6 +597,27 @@ make_config! {
         /// if SMTP service is not configured and password hints are allowed. Not recommended for publicly-accessible instances
         /// because this provides unauthenticated access to potentially sensitive data.
         show_password_hint:     bool,   true,   def,    false;
+        /// Hide password hints of inactive accounts |> Act as if accounts which are disabled, awaiting approval or locked out
+        /// after failed password verifications have no password hint
+        password_hints_hide_inactive: bool, true, def,  false;
+        /// Allow PIN unlock |> Tell clients whether users may unlock their vault with a PIN. When disabled, nobody may use it,
+        /// otherwise it's only forbidden for members of an organization with the Remove unlock with PIN policy
+        pin_unlock_allowed:            bool, true, def,  true;
+        /// Minimum PBKDF2 iterations |> Users using PBKDF2 with fewer client side iterations are listed in the KDF audit of the admin API
+        kdf_min_pbkdf2_iterations: i32, true,   def,    600_000;
+        /// Minimum Argon2 iterations |> Users using Argon2id with fewer client side iterations are listed in the KDF audit of the admin API
+        kdf_min_argon2_iterations: i32, true,   def,    3;
+        /// Minimum Argon2 memory (MiB) |> Users using Argon2id with less client side memory are listed in the KDF audit of the admin API
+        kdf_min_argon2_memory:  i32,    true,   def,    64;
+        /// Minimum Argon2 parallelism |> Users using Argon2id with a lower client side parallelism are listed in the KDF audit of the admin API
+        kdf_min_argon2_parallelism: i32, true,  def,    4;
+        /// KDF change grace period (Know the risks!) |> Number of seconds other logged-in devices can still use their old session to run one final sync
+        /// after a KDF change, before they are logged out. During this window a leaked session of that user keeps access to the sync endpoint,
+        /// so keep it short. Set to 0 to log out all other devices immediately (max 600).
+        kdf_change_grace_seconds: u64,  true,   def,    0;
+        /// Master password history |> Number of previous master passwords a user is not allowed to reuse when changing their password or KDF settings.
+        /// Set to 0 to disable. Every stored entry costs one extra password hash verification on each change.
+        master_password_history: u32,   true,   def,    0;
 
         /// Admin token/Argon2 PHC |> The plain text token or Argon2 PHC string used to authenticate in this very same page. Changing it here will not deauthorize the current session!
         admin_token:            Pass,   true,   option;
@@ -554,6 +627,9 @@ make_config! {
 
         /// Events days retain |> Number of days to retain events stored in the database. If unset, events are kept indefinitely.
         events_days_retain:     i64,    false,   option;
+        /// Events export HMAC key |> When set, personal event log exports include a chained HMAC over the exported events.
+        /// This allows detecting modifications of an exported copy, it does not protect the events stored in the database
+        events_export_hmac_key: Pass,   false,   option;
     },
 
     /// Advanced settings
@@ -563,6 +639,13 @@ make_config! {
         ip_header:              String, true,   def,    "X-Real-IP".to_string();
         /// Internal IP header property, used to avoid recomputing each time
         _ip_header_enabled:     bool,   false,  generated,    |c| &c.ip_header.trim().to_lowercase() != "none";
+        /// Client country header |> Header containing the country code of the client, as set by a reverse proxy with GeoIP support (e.g. CF-IPCountry).
+        /// Only used by the login anomaly alerts. If empty or missing, logins are compared by network instead
+        ip_country_header:      String, true,   def,    String::new();
+        /// API key client certificate header |> Header containing the fingerprint of the TLS client certificate, as forwarded by a reverse proxy doing mTLS (e.g. `X-SSL-Client-Fingerprint`).
+        /// When set, users can bind their personal API key to a certificate fingerprint, and logins with that API key without the matching certificate are rejected.
+        /// The proxy has to overwrite this header on every request, otherwise clients can set it themselves
+        api_key_client_cert_header: String, true, option;
         /// Icon service |> The predefined icon services are: internal, bitwarden, duckduckgo, google.
         /// To specify a custom icon service, set a URL template with exactly one instance of `{}`,
         /// which is replaced with the domain. For example: `https://icon.example.com/domain/{}`.
@@ -603,6 +686,10 @@ make_config! {
         /// Note that the checkbox would still be present, but ignored.
         disable_2fa_remember:   bool,   true,   def,    false;
 
+        /// Require 2FA setup before sync |> Block the vault sync of users who are subject to the two-step login policy of an organization,
+        /// but have no 2FA set up, with an error asking them to set it up. Otherwise only the clients ask them to
+        require_2fa_setup_for_sync: bool, true, def, false;
+
         /// Disable authenticator time drifted codes to be valid |> Enabling this only allows the current TOTP code to be valid
         /// TOTP codes of the previous and next 30 seconds will be invalid.
         authenticator_disable_time_drift: bool, true, def, false;
@@ -613,6 +700,17 @@ make_config! {
         /// Require new device emails |> When a user logs in an email is required to be sent.
         /// If sending the email fails the login attempt will fail.
         require_device_email:   bool,   true,   def,     false;
+        /// Login anomaly alerts |> Send an email when a known device logs in from a network, or country, which doesn't match any of the recent logins of the user
+        login_anomaly_alerts:   bool,   true,   def,     false;
+        /// Require device approval |> New devices have to be approved from an already approved device of the user before they are able to sync.
+        /// The first device of a user is always approved
+        device_approval_required: bool, true,   def,     false;
+        /// Keep removed devices (days) |> Instead of deleting a removed device right away, keep it as revoked for this many days.
+        /// Clients can then tell a revoked device from one which never existed. Leave empty to delete devices right away
+        device_tombstone_days: u32, true, option;
+        /// Unique device names |> Reject new devices whose name is already used by another device of the same user, ignoring case.
+        /// Clients name devices after the browser or platform, so a second device of the same kind can't login anymore
+        device_names_unique: bool, true,   def,     false;
 
         /// Reload templates (Dev) |> When this is set to true, the templates get reloaded with every request.
         /// ONLY use this during development, as it can slow down the server
@@ -664,12 +762,96 @@ make_config! {
         login_ratelimit_seconds:       u64, false, def, 60;
         /// Max burst size for login requests |> Allow a burst of requests of up to this size, while maintaining the average indicated by `login_ratelimit_seconds`. Note that this applies to both the login and the 2FA, so it's recommended to allow a burst size of at least 2
         login_ratelimit_max_burst:     u32, false, def, 10;
+        /// Captcha bypass token lifetime (minutes) |> Return a signed captcha bypass token on registration, which answers one captcha challenge of the first login
+        /// within this many minutes. It doesn't skip the login ratelimit. Leave empty to return an empty token
+        captcha_bypass_token_minutes:  u32, false, option;
 
         /// Seconds between admin login requests |> Number of seconds, on average, between admin requests from the same IP address before rate limiting kicks in
         admin_ratelimit_seconds:       u64, false, def, 300;
         /// Max burst size for admin login requests |> Allow a burst of requests of up to this size, while maintaining the average indicated by `admin_ratelimit_seconds`
         admin_ratelimit_max_burst:     u32, false, def, 3;
 
+        /// Seconds between registrations |> Number of seconds, on average, between registration requests from the same IP address, or the same /64 network for IPv6, before rate limiting kicks in
+        signups_ratelimit_seconds:     u64, false, def, 360;
+        /// Max burst size for registrations |> Allow a burst of registration requests of up to this size, while maintaining the average indicated by `signups_ratelimit_seconds`. Set to 0 to disable
+        signups_ratelimit_max_burst:   u32, false, def, 10;
+        /// Seconds between registrations per email domain |> Number of seconds, on average, between registration requests for email addresses of the same domain before rate limiting kicks in
+        signups_domain_ratelimit_seconds:   u64, false, def, 60;
+        /// Max burst size for registrations per email domain |> Allow a burst of registration requests for the same email domain of up to this size, while maintaining the average indicated by `signups_domain_ratelimit_seconds`. Disabled by default with 0, as many users share the domains of the large email providers
+        signups_domain_ratelimit_max_burst: u32, false, def, 0;
+        /// Seconds between registrations with the same name |> Number of seconds, on average, between registration requests with the same name before rate limiting kicks in
+        signups_name_ratelimit_seconds:   u64, false, def, 600;
+        /// Max burst size for registrations with the same name |> Allow a burst of registration requests with the same name of up to this size, while maintaining the average indicated by `signups_name_ratelimit_seconds`. Disabled by default with 0
+        signups_name_ratelimit_max_burst: u32, false, def, 0;
+        /// Seconds between emails per user |> Number of seconds, on average, between emails triggered by the actions of the same user, like email verification, email change and account deletion requests
+        user_email_ratelimit_seconds:   u64, false, def, 300;
+        /// Max burst size for emails per user |> Allow a burst of emails triggered by the same user of up to this size, while maintaining the average indicated by `user_email_ratelimit_seconds`. Further emails are silently dropped. Disabled by default with 0
+        user_email_ratelimit_max_burst: u32, false, def, 0;
+
+        /// Anonymous public key lookups |> Allow looking up the public key of a user by id without being logged in, for sharing between instances.
+        /// No other user data is returned, and these lookups are rate limited per IP address
+        public_key_lookup_anonymous:   bool, true, def, false;
+        /// Seconds between anonymous public key lookups |> Number of seconds, on average, between anonymous public key lookups from the same IP address before rate limiting kicks in
+        public_key_lookup_ratelimit_seconds:   u64, false, def, 10;
+        /// Max burst size for anonymous public key lookups |> Allow a burst of anonymous public key lookups of up to this size, while maintaining the average indicated by `public_key_lookup_ratelimit_seconds`
+        public_key_lookup_ratelimit_max_burst: u32, false, def, 10;
+        /// Seconds between email change validations |> Number of seconds, on average, between validations of a new email address by the same user before rate limiting kicks in
+        email_validate_ratelimit_seconds:   u64, false, def, 60;
+        /// Max burst size for email change validations |> Allow a burst of email address validations of up to this size, while maintaining the average indicated by `email_validate_ratelimit_seconds`
+        email_validate_ratelimit_max_burst: u32, false, def, 10;
+        /// Seconds between auth request response fetches |> Number of seconds, on average, between unauthenticated fetches of the response to the same login with device request before rate limiting kicks in
+        auth_request_poll_ratelimit_seconds:   u64, false, def, 2;
+        /// Max burst size for auth request response fetches |> Allow a burst of response fetches of up to this size, while maintaining the average indicated by `auth_request_poll_ratelimit_seconds`
+        auth_request_poll_ratelimit_max_burst: u32, false, def, 30;
+        /// Max failed auth request access codes |> Number of response fetches of a login with device request with a wrong access code, device or IP address after which the request is deleted
+        auth_request_max_failed_codes:         u32, false, def, 10;
+
+        /// Max failed password verifications |> Number of failed master password verifications of a logged in user before the verification gets locked out temporarily
+        verify_password_max_attempts:   u32, false, def, 5;
+        /// Password verification lockout seconds |> Initial lockout after too many failed master password verifications. It doubles with every further failure, up to one hour
+        verify_password_lockout_seconds: u64, false, def, 60;
+        /// Min delay of authentication errors (ms) |> Error responses of sensitive endpoints, like registration, password hints,
+        /// password verification, email changes and login with device requests, take at least this long. 0 disables the delay
+        auth_error_min_delay_ms: u64, true, def, 0;
+        /// Jitter of the authentication error delay (ms) |> Random amount of time added to or removed from the minimum delay
+        auth_error_delay_jitter_ms: u64, true, def, 100;
+        /// Bind device identifiers to the access token |> Reject authenticated requests where the `X-Device-Identifier` header doesn't match the device the access token was issued to.
+        /// The device is part of the signed token, so a stolen token can't be used with the identifier of another device. Requests without the header are rejected, so only enable this when all clients send it
+        device_identifier_binding: bool, true, def, false;
+        /// Session binding device types |> Comma separated list of device type numbers, like `0,1` for Android and iOS, of which the devices have to register
+        /// a key when they login. Every authenticated request and WebSocket connection of the device has to be signed with it, so a stolen access token can't be replayed
+        session_binding_device_types: String, true, def, String::new();
+        /// Session signature max age (seconds) |> Maximum difference between the timestamp of a session signature and the server time
+        session_binding_max_skew_seconds: i64, true, def, 60;
+
+        /// Unregister push devices of disabled users |> When an admin disables a user, also unregister all their devices from the push relay.
+        /// The devices have to register again after the user has been enabled and logged in again
+        disable_user_unregister_push:  bool, true, def, false;
+        /// Strict key rotation |> Reject key rotations which contain personal cipher ids that don't exist or are listed more than once,
+        /// instead of only checking that all existing ciphers are included. Can break clients which add items during a rotation
+        key_rotation_strict_ciphers:   bool, true, def, false;
+        /// Unique folder names on key rotation |> Reject key rotations in which multiple folders have the same encrypted name.
+        /// Only identical ciphertexts can be detected, which points to a client bug
+        key_rotation_unique_folder_names: bool, true, def, false;
+        /// Logout scope after key rotation |> Which sessions are logged out after a key rotation, `keep_current` keeps the session of the device
+        /// which rotated the keys, `everywhere` also logs out that device to force a clean login on all of them
+        key_rotation_logout_scope: String, true, def, "keep_current".to_string();
+        /// Check KDF settings on password changes |> Reject password and email changes for which the client states it used other KDF settings than those of the account.
+        /// The server can't tell which settings were actually used, so clients which don't state them are not checked
+        password_change_kdf_check: bool, true, def, false;
+        /// Require auth request nonce signature |> Only allow a login with an approved device request when the requesting device signs the challenge nonce
+        /// which is only returned to it, with the private key of the request. Clients which don't send the signature won't be able to login with device requests
+        auth_request_require_nonce:    bool, true, def, false;
+        /// Check auth request key envelopes |> Reject approvals of which the key isn't RSA encrypted for a key of the same size as the public key
+        /// of the requesting device. The padding hides the recipient, so this catches malformed responses but not a key encrypted for another device of the same size
+        auth_request_key_check:    bool, true, def, false;
+        /// Only approve auth requests from the same device |> Only allow the device given in an auth request response to answer it,
+        /// instead of any trusted device of the user. The approving device is recorded on the request in both cases
+        auth_request_same_device_only:    bool, true, def, false;
+        /// Include master password hash in auth requests |> Return the master password hash, which an approving device can send along,
+        /// in the auth request responses. Disable when the passwordless login flow of your clients doesn't need it
+        include_auth_request_master_password_hash: bool, true, def, true;
+
         /// Admin session lifetime |> Set the lifetime of admin sessions to this value (in minutes).
         admin_session_lifetime:        i64, true,  def, 20;
 
@@ -694,8 +876,12 @@ make_config! {
         sso_enabled:                    bool,   true,   def,    false;
         /// Only SSO login |> Disable Email+Master Password login
         sso_only:                       bool,   true,   def,    false;
+        /// Allow passwordless registration |> Allow SSO users to set up their account without a master password. These accounts are SSO-only, they can't log in with a password
+        allow_passwordless_registration: bool,  true,   def,    false;
         /// Allow email association |> Associate existing non-SSO user based on email
         sso_signups_match_email:        bool,   true,   def,    true;
+        /// SSO email domains |> Comma-separated list of domains. When set, users can only change their email to an address within these domains
+        sso_email_domains:              String, true,   def,    String::new();
         /// Allow unknown email verification status |> Allowing this with `SSO_SIGNUPS_MATCH_EMAIL=true` open potential account takeover.
         sso_allow_unknown_email_verification: bool, true, def, false;
         /// Client ID
@@ -800,8 +986,10 @@ make_config! {
     email_2fa: _enable_email_2fa {
         /// Enabled |> Disabling will prevent users from setting up new email 2FA and using existing email 2FA configured
         _enable_email_2fa:      bool,   true,   auto,    |c| c._enable_smtp && (c.smtp_host.is_some() || c.use_sendmail);
-        /// Email token size |> Number of digits in an email 2FA token (min: 6, max: 255). Note that the Bitwarden clients are hardcoded to mention 6 digit codes regardless of this setting.
+        /// Email token size |> Number of characters in email tokens, used for email 2FA, protected actions and email changes (min: 6, max: 12). Note that the Bitwarden clients are hardcoded to mention 6 digit codes regardless of this setting.
         email_token_size:       u8,     true,   def,      6;
+        /// Alphanumeric email tokens |> Use uppercase letters and digits in email tokens instead of only digits. The tokens are checked case-insensitively
+        email_token_alphanumeric: bool, true,   def,      false;
         /// Token expiration time |> Maximum time in seconds a token is valid. The time the user has to open email client and copy token.
         email_expiration_time:  u64,    true,   def,      600;
         /// Maximum attempts |> Maximum attempts before an email token is reset and a new email will need to be sent
@@ -813,6 +1001,9 @@ make_config! {
     },
 }
 
+/// Largest storage limit (in KB) which can still be converted to bytes
+pub const MAX_FILESIZE_KB: i64 = i64::MAX >> 10;
+
 fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
     // Validate connection URL is valid and DB feature is enabled
     let url = &cfg.database_url;
@@ -829,6 +1020,73 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         err!("PASSWORD_ITERATIONS should be at least 100000 or higher. The default is 600000!");
     }
 
+    if cfg.kdf_change_grace_seconds > 600 {
+        err!("`KDF_CHANGE_GRACE_SECONDS` can't be more than 600 seconds");
+    }
+
+    if cfg.master_password_history > 24 {
+        err!("`MASTER_PASSWORD_HISTORY` can't be more than 24");
+    }
+
+    if cfg.signups_ratelimit_max_burst > 0 && cfg.signups_ratelimit_seconds < 1 {
+        err!("`SIGNUPS_RATELIMIT_SECONDS` should be at least 1");
+    }
+
+    if cfg.signups_domain_ratelimit_max_burst > 0 && cfg.signups_domain_ratelimit_seconds < 1 {
+        err!("`SIGNUPS_DOMAIN_RATELIMIT_SECONDS` should be at least 1");
+    }
+
+    if cfg.signups_name_ratelimit_max_burst > 0 && cfg.signups_name_ratelimit_seconds < 1 {
+        err!("`SIGNUPS_NAME_RATELIMIT_SECONDS` should be at least 1");
+    }
+
+    if cfg.session_binding_device_types.split(',').map(str::trim).any(|t| !t.is_empty() && t.parse::<i32>().is_err()) {
+        err!("`SESSION_BINDING_DEVICE_TYPES` must be a comma separated list of device type numbers");
+    }
+
+    if cfg.session_binding_max_skew_seconds < 1 {
+        err!("`SESSION_BINDING_MAX_SKEW_SECONDS` should be at least 1");
+    }
+
+    if !cfg.residency_default_region.is_empty()
+        && !is_region_in_list(&cfg.residency_default_region, &cfg.residency_regions)
+    {
+        err!("`RESIDENCY_DEFAULT_REGION` must be one of the `RESIDENCY_REGIONS`");
+    }
+
+    if !cfg.residency_node_region.is_empty() && !is_region_in_list(&cfg.residency_node_region, &cfg.residency_regions) {
+        err!("`RESIDENCY_NODE_REGION` must be one of the `RESIDENCY_REGIONS`");
+    }
+
+    if cfg.user_email_ratelimit_max_burst > 0 && cfg.user_email_ratelimit_seconds < 1 {
+        err!("`USER_EMAIL_RATELIMIT_SECONDS` should be at least 1");
+    }
+
+    if cfg.public_key_lookup_ratelimit_max_burst < 1 || cfg.public_key_lookup_ratelimit_seconds < 1 {
+        err!("`PUBLIC_KEY_LOOKUP_RATELIMIT_SECONDS` and `PUBLIC_KEY_LOOKUP_RATELIMIT_MAX_BURST` should be at least 1");
+    }
+
+    if cfg.email_validate_ratelimit_max_burst < 1 || cfg.email_validate_ratelimit_seconds < 1 {
+        err!("`EMAIL_VALIDATE_RATELIMIT_SECONDS` and `EMAIL_VALIDATE_RATELIMIT_MAX_BURST` should be at least 1");
+    }
+
+    if cfg.auth_request_poll_ratelimit_max_burst < 1 || cfg.auth_request_poll_ratelimit_seconds < 1 {
+        err!("`AUTH_REQUEST_POLL_RATELIMIT_SECONDS` and `AUTH_REQUEST_POLL_RATELIMIT_MAX_BURST` should be at least 1");
+    }
+
+    if cfg.auth_request_max_failed_codes < 1 {
+        err!("`AUTH_REQUEST_MAX_FAILED_CODES` should be at least 1");
+    }
+
+    match cfg.key_rotation_logout_scope.as_str() {
+        "keep_current" | "everywhere" => (),
+        _ => err!("`KEY_ROTATION_LOGOUT_SCOPE` is invalid. It needs to be one of the following options: keep_current or everywhere"),
+    }
+
+    if cfg.verify_password_max_attempts < 1 {
+        err!("`VERIFY_PASSWORD_MAX_ATTEMPTS` should be at least 1");
+    }
+
     let limit = 256;
     if cfg.database_max_conns < 1 || cfg.database_max_conns > limit {
         err!(format!("`DATABASE_MAX_CONNS` contains an invalid value. Ensure it is between 1 and {limit}.",));
@@ -855,6 +1113,13 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         );
     }
 
+    for origin in cfg.auth_request_origins.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+        let lowercase = origin.to_lowercase();
+        if !(lowercase.starts_with("http://") || lowercase.starts_with("https://")) || Url::parse(origin).is_err() {
+            err!(format!("`AUTH_REQUEST_ORIGINS` contains an invalid origin: {origin}"));
+        }
+    }
+
     let connect_src = cfg.allowed_connect_src.to_lowercase();
     for url in connect_src.split_whitespace() {
         if !url.starts_with("https://") || Url::parse(url).is_err() {
@@ -881,7 +1146,10 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         }
     }
 
-    if cfg.push_enabled && (cfg.push_installation_id == String::new() || cfg.push_installation_key == String::new()) {
+    if cfg.push_enabled
+        && cfg.push_webhook_url.is_none()
+        && (cfg.push_installation_id == String::new() || cfg.push_installation_key == String::new())
+    {
         err!(
             "Misconfigured Push Notification service\n\
             ########################################################################################\n\
@@ -910,6 +1178,16 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         if Url::parse(&push_identity_uri).is_err() {
             err!("Invalid URL format for `PUSH_IDENTITY_URI`.");
         }
+
+        if let Some(push_webhook_url) = &cfg.push_webhook_url {
+            if Url::parse(push_webhook_url).is_err() {
+                err!("Invalid URL format for `PUSH_WEBHOOK_URL`.");
+            }
+
+            if cfg.push_webhook_secret.as_ref().is_none_or(|s| s.is_empty()) {
+                err!("`PUSH_WEBHOOK_SECRET` is required when `PUSH_WEBHOOK_URL` is set.")
+            }
+        }
     }
 
     // Server (v2025.6.2): https://github.com/bitwarden/server/blob/d094be3267f2030bd0dc62106bc6871cf82682f5/src/Core/Constants.cs#L103
@@ -940,8 +1218,6 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
                      Supported flags: {KNOWN_FLAGS:?}"));
     }
 
-    const MAX_FILESIZE_KB: i64 = i64::MAX >> 10;
-
     if let Some(limit) = cfg.user_attachment_limit {
         if !(0i64..=MAX_FILESIZE_KB).contains(&limit) {
             err!("`USER_ATTACHMENT_LIMIT` is out of bounds");
@@ -977,6 +1253,12 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         validate_sso_master_password_policy(&cfg.sso_master_password_policy)?;
     }
 
+    if cfg.allow_passwordless_registration && !cfg.sso_enabled {
+        err!(
+            "`ALLOW_PASSWORDLESS_REGISTRATION` requires `SSO_ENABLED`, passwordless accounts can only sign in with SSO"
+        )
+    }
+
     if cfg._enable_yubico {
         if cfg.yubico_client_id.is_some() != cfg.yubico_secret_key.is_some() {
             err!("Both `YUBICO_CLIENT_ID` and `YUBICO_SECRET_KEY` must be set for Yubikey OTP support")
@@ -1045,8 +1327,8 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
             err!(format!("SMTP_FROM '{}' is not a valid email address", cfg.smtp_from))
         }
 
-        if cfg._enable_email_2fa && cfg.email_token_size < 6 {
-            err!("`EMAIL_TOKEN_SIZE` has a minimum size of 6")
+        if !(6..=12).contains(&cfg.email_token_size) {
+            err!("`EMAIL_TOKEN_SIZE` has to be between 6 and 12")
         }
     }
 
@@ -1129,6 +1411,22 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         err!("`AUTH_REQUEST_PURGE_SCHEDULE` is not a valid cron expression")
     }
 
+    if !cfg.purge_unverified_accounts_schedule.is_empty()
+        && cfg.purge_unverified_accounts_schedule.parse::<Schedule>().is_err()
+    {
+        err!("`PURGE_UNVERIFIED_ACCOUNTS_SCHEDULE` is not a valid cron expression")
+    }
+
+    if !cfg.purge_device_tombstones_schedule.is_empty()
+        && cfg.purge_device_tombstones_schedule.parse::<Schedule>().is_err()
+    {
+        err!("`PURGE_DEVICE_TOMBSTONES_SCHEDULE` is not a valid cron expression")
+    }
+
+    if cfg.purge_unverified_accounts_after == Some(0) {
+        err!("`PURGE_UNVERIFIED_ACCOUNTS_AFTER` must be at least 1 day")
+    }
+
     if !cfg.disable_admin_token {
         match cfg.admin_token.as_ref() {
             Some(t) if t.starts_with("$argon2") => {
@@ -1154,6 +1452,23 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
     Ok(())
 }
 
+/// Tests whether a region is in a comma-separated list of regions, an empty region never is
+pub fn is_region_in_list(region: &str, regions: &str) -> bool {
+    !region.is_empty() && regions.split(',').any(|r| r.trim() == region)
+}
+
+/// An empty list allows every domain.
+fn is_email_domain_in_list(email: &str, domains: &str) -> bool {
+    let e: Vec<&str> = email.rsplitn(2, '@').collect();
+    if e.len() != 2 || e[0].is_empty() || e[1].is_empty() {
+        warn!("Failed to parse email address '{email}'");
+        return false;
+    }
+    let email_domain = e[0].to_lowercase();
+
+    domains.is_empty() || domains.split(',').any(|d| d.trim() == email_domain)
+}
+
 fn validate_internal_sso_issuer_url(sso_authority: &String) -> Result<openidconnect::IssuerUrl, Error> {
     match openidconnect::IssuerUrl::new(sso_authority.clone()) {
         Err(err) => err!(format!("Invalid sso_authority URL ({sso_authority}): {err}")),
@@ -1431,15 +1746,24 @@ impl Config {
     /// is in signups_domains_whitelist, or if no whitelist is set (so there
     /// are no domain restrictions in effect).
     pub fn is_email_domain_allowed(&self, email: &str) -> bool {
-        let e: Vec<&str> = email.rsplitn(2, '@').collect();
-        if e.len() != 2 || e[0].is_empty() || e[1].is_empty() {
-            warn!("Failed to parse email address '{email}'");
-            return false;
-        }
-        let email_domain = e[0].to_lowercase();
-        let whitelist = self.signups_domains_whitelist();
+        is_email_domain_in_list(email, &self.signups_domains_whitelist())
+    }
+
+    /// Accounts without a region are served everywhere, like all accounts when no region is set for this server.
+    pub fn serves_region(&self, region: Option<&str>) -> bool {
+        let node_region = self.residency_node_region();
+        node_region.is_empty() || region.is_none_or(|region| region == node_region)
+    }
+
+    /// Devices of these types can register a session key, which binds their access tokens to it.
+    pub fn is_session_binding_device_type(&self, atype: i32) -> bool {
+        self.session_binding_device_types().split(',').map(str::trim).any(|t| t.parse::<i32>() == Ok(atype))
+    }
 
-        whitelist.is_empty() || whitelist.split(',').any(|d| d.trim() == email_domain)
+    /// Tests whether an email address is within the configured SSO domains.
+    /// Only restricts anything when SSO is enabled and the domain list is set.
+    pub fn is_sso_email_domain_allowed(&self, email: &str) -> bool {
+        !self.sso_enabled() || is_email_domain_in_list(email, &self.sso_email_domains())
     }
 
     /// Tests whether signup is allowed for an email address, taking into
@@ -1648,6 +1972,7 @@ where
     reg!("email/delete_account", ".html");
     reg!("email/emergency_access_invite_accepted", ".html");
     reg!("email/emergency_access_invite_confirmed", ".html");
+    reg!("email/emergency_access_grantor_key_rotated", ".html");
     reg!("email/emergency_access_recovery_approved", ".html");
     reg!("email/emergency_access_recovery_initiated", ".html");
     reg!("email/emergency_access_recovery_rejected", ".html");
@@ -1656,11 +1981,16 @@ where
     reg!("email/incomplete_2fa_login", ".html");
     reg!("email/invite_accepted", ".html");
     reg!("email/invite_confirmed", ".html");
+    reg!("email/invite_registered", ".html");
+    reg!("email/login_anomaly", ".html");
     reg!("email/new_device_logged_in", ".html");
     reg!("email/protected_action", ".html");
     reg!("email/pw_hint_none", ".html");
     reg!("email/pw_hint_some", ".html");
+    reg!("email/recovery_email", ".html");
     reg!("email/register_verify_email", ".html");
+    reg!("email/registration_approved", ".html");
+    reg!("email/registration_pending_approval", ".html");
     reg!("email/send_2fa_removed_from_org", ".html");
     reg!("email/send_emergency_access_invite", ".html");
     reg!("email/send_org_invite", ".html");
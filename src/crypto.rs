@@ -33,6 +33,20 @@ pub fn hmac_sign(key: &str, data: &str) -> String {
     HEXLOWER.encode(signature.as_ref())
 }
 
+pub fn hmac_sha256_sign(key: &str, data: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+    let signature = hmac::sign(&key, data.as_bytes());
+
+    HEXLOWER.encode(signature.as_ref())
+}
+
+//
+// Digest
+//
+pub fn sha256_hex(data: &str) -> String {
+    HEXLOWER.encode(digest::digest(&digest::SHA256, data.as_bytes()).as_ref())
+}
+
 //
 // Random values
 //
@@ -95,9 +109,21 @@ pub fn generate_attachment_id() -> AttachmentId {
     AttachmentId(generate_id::<10>()) // 80 bits
 }
 
-/// Generates a numeric token for email-based verifications.
-pub fn generate_email_token(token_size: u8) -> String {
-    get_random_string_numeric(token_size as usize)
+/// Generates a token for email-based verifications, numeric or alphanumeric.
+/// Alphanumeric tokens only use uppercase letters and leave out characters which are easily confused, like `0` and `O`.
+pub fn generate_email_token(token_size: u8, alphanumeric: bool) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+    if alphanumeric {
+        get_random_string(ALPHABET, token_size as usize)
+    } else {
+        get_random_string_numeric(token_size as usize)
+    }
+}
+
+/// Checks a token entered by the user against the one which was sent by email.
+/// Alphanumeric tokens are compared case-insensitively, since they're typed over by hand.
+pub fn check_email_token(issued: &str, provided: &str) -> bool {
+    ct_eq(issued, provided.trim().to_uppercase())
 }
 
 /// Generates a personal API key.
@@ -113,3 +139,29 @@ pub fn ct_eq<T: AsRef<[u8]>, U: AsRef<[u8]>>(a: T, b: U) -> bool {
     use subtle::ConstantTimeEq;
     a.as_ref().ct_eq(b.as_ref()).into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numeric_email_token() {
+        for size in [6, 12] {
+            let token = generate_email_token(size, false);
+            assert_eq!(token.len(), size as usize);
+            assert!(token.chars().all(|c| c.is_ascii_digit()));
+        }
+    }
+
+    #[test]
+    fn test_alphanumeric_email_token() {
+        let token = generate_email_token(10, true);
+        assert_eq!(token.len(), 10);
+        assert!(token.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()));
+        assert!(!token.contains(['0', 'O', '1', 'I']));
+
+        assert!(check_email_token(&token, &token.to_lowercase()));
+        assert!(check_email_token(&token, &format!(" {token}\n")));
+        assert!(!check_email_token(&token, &token[..9]));
+    }
+}
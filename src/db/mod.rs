@@ -405,6 +405,19 @@ pub async fn get_sql_server_version(conn: &mut DbConn) -> String {
     }
 }
 
+// `LENGTH()` is available on all supported databases, and is used to sum up the size of stored (encrypted) values.
+// The encrypted values only contain ASCII, so it doesn't matter that MySQL/MariaDB counts bytes instead of characters.
+diesel::define_sql_function! {
+    fn length(
+        x: diesel::sql_types::Nullable<diesel::sql_types::Text>
+    ) -> diesel::sql_types::Nullable<diesel::sql_types::Integer>;
+}
+
+/// Adds up the `SUM(LENGTH(...))` of several columns, a column without any value has a `NULL` sum.
+pub fn sum_lengths(lengths: &[Option<i64>]) -> i64 {
+    lengths.iter().flatten().sum()
+}
+
 /// Attempts to retrieve a single connection from the managed database pool. If
 /// no pool is currently managed, fails with an `InternalServerError` status. If
 /// no connections are available, fails with a `ServiceUnavailable` status.
@@ -490,3 +503,49 @@ mod postgresql_migrations {
         Ok(())
     }
 }
+
+/// A throwaway SQLite database for the tests which need to go through the models and endpoints
+#[cfg(all(test, sqlite, not(query_logger)))]
+pub mod test_db {
+    use std::future::Future;
+
+    use diesel::{Connection, RunQueryDsl};
+    use diesel_migrations::MigrationHarness;
+
+    use super::*;
+
+    /// Runs `test` with a pool of a new database which has all the migrations applied.
+    /// `db_run!` blocks in place, so this needs a multi-threaded runtime.
+    pub fn run<F, Fut>(test: F)
+    where
+        F: FnOnce(DbPool) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let path = std::env::temp_dir().join(format!("vaultwarden-test-{}.sqlite3", crate::util::get_uuid()));
+        let url = path.to_string_lossy().into_owned();
+
+        let mut connection = diesel::sqlite::SqliteConnection::establish(&url).expect("Failed to create test database");
+        diesel::sql_query("PRAGMA foreign_keys = OFF").execute(&mut connection).unwrap();
+        connection.run_pending_migrations(sqlite_migrations::MIGRATIONS).expect("Error running migrations");
+        drop(connection);
+
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let pool = Pool::builder()
+                .max_size(4)
+                .connection_customizer(Box::new(DbConnOptions {
+                    init_stmts: DbConnType::sqlite.default_init_stmts(),
+                }))
+                .build(ConnectionManager::new(&url))
+                .expect("Failed to create pool");
+            test(DbPool {
+                pool: Some(DbPoolInner::sqlite(pool)),
+                semaphore: Arc::new(Semaphore::new(4)),
+            })
+            .await;
+        });
+        drop(runtime);
+
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -1,8 +1,14 @@
-use super::{DeviceId, OrganizationId, UserId};
-use crate::{crypto::ct_eq, util::format_date};
+use super::{DeviceId, DeviceType, OrganizationId, UserId};
+use crate::{
+    crypto::{self, ct_eq},
+    util::format_date,
+    CONFIG,
+};
 use chrono::{NaiveDateTime, Utc};
+use data_encoding::BASE64;
 use derive_more::{AsRef, Deref, Display, From};
 use macros::UuidFromParam;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Verifier};
 use serde_json::Value;
 
 db_object! {
@@ -32,6 +38,8 @@ db_object! {
         pub response_date: Option<NaiveDateTime>,
 
         pub authentication_date: Option<NaiveDateTime>,
+
+        pub challenge_nonce: Option<String>,
     }
 }
 
@@ -63,9 +71,76 @@ impl AuthRequest {
             creation_date: now,
             response_date: None,
             authentication_date: None,
+
+            challenge_nonce: Some(crypto::generate_id::<16>()),
         }
     }
 
+    /// Clients reject requests which are older than this
+    pub const EXPIRATION_MINUTES: i64 = 5;
+
+    pub fn is_expired(&self) -> bool {
+        self.creation_date < Utc::now().naive_utc() - chrono::TimeDelta::try_minutes(Self::EXPIRATION_MINUTES).unwrap()
+    }
+
+    /// Replaces the access code of a pending request and restarts its expiration, the id and the public key stay the same.
+    /// Returns the new access code, or `None` when the current code doesn't match or the request was already answered.
+    pub fn refresh_access_code(&mut self, current_access_code: &str) -> Option<String> {
+        if self.approved.is_some() || !self.check_access_code(current_access_code) {
+            return None;
+        }
+
+        self.access_code = crypto::get_random_string_alphanum(25);
+        self.creation_date = Utc::now().naive_utc();
+        Some(self.access_code.clone())
+    }
+
+    /// Marks the request as approved by `device_id`, the requesting device can then login using `enc_key`.
+    pub fn approve(&mut self, device_id: DeviceId, enc_key: String, master_password_hash: Option<String>) {
+        self.approved = Some(true);
+        self.enc_key = Some(enc_key);
+        self.master_password_hash = master_password_hash;
+        self.response_device_id = Some(device_id);
+        self.response_date = Some(Utc::now().naive_utc());
+    }
+
+    /// The device which approved the request, only set for approved requests.
+    pub fn approving_device_id(&self) -> Option<&DeviceId> {
+        self.response_device_id.as_ref().filter(|_| self.approved == Some(true))
+    }
+
+    pub fn to_json(&self, origin: &str) -> Value {
+        self.to_json_with(origin, CONFIG.include_auth_request_master_password_hash())
+    }
+
+    fn to_json_with(&self, origin: &str, include_master_password_hash: bool) -> Value {
+        let mut json = json!({
+            "id": self.uuid,
+            "publicKey": self.public_key,
+            "requestDeviceType": DeviceType::from_i32(self.device_type).to_string(),
+            "requestIpAddress": self.request_ip,
+            "key": self.enc_key,
+            "masterPasswordHash": self.master_password_hash,
+            "creationDate": format_date(&self.creation_date),
+            "responseDate": self.response_date.as_ref().map(format_date),
+            "requestApproved": self.approved,
+            "responseDeviceId": self.approving_device_id(),
+            "origin": origin,
+            "object": "auth-request",
+        });
+        if !include_master_password_hash {
+            json.as_object_mut().unwrap().remove("masterPasswordHash");
+        }
+        json
+    }
+
+    /// Only the requesting device gets the challenge nonce, it has to sign it to login with the approved request
+    pub fn to_json_for_requester(&self, origin: &str) -> Value {
+        let mut json = self.to_json(origin);
+        json["challengeNonce"] = json!(self.challenge_nonce);
+        json
+    }
+
     pub fn to_json_for_pending_device(&self) -> Value {
         json!({
             "id": self.uuid,
@@ -112,6 +187,25 @@ impl AuthRequest {
         }
     }
 
+    /// An approved request can only be used for a single login, returns `false` when it was used already.
+    /// This is a single conditional update, so concurrent logins can't both redeem the same request.
+    pub async fn redeem(&mut self, conn: &mut DbConn) -> bool {
+        let now = Utc::now().naive_utc();
+        let redeemed = db_run! { conn: {
+            diesel::update(auth_requests::table)
+                .filter(auth_requests::uuid.eq(&self.uuid))
+                .filter(auth_requests::approved.eq(true))
+                .filter(auth_requests::authentication_date.is_null())
+                .set(auth_requests::authentication_date.eq(now))
+                .execute(conn)
+                .unwrap_or(0)
+        }} == 1;
+        if redeemed {
+            self.authentication_date = Some(now);
+        }
+        redeemed
+    }
+
     pub async fn find_by_uuid(uuid: &AuthRequestId, conn: &mut DbConn) -> Option<Self> {
         db_run! {conn: {
             auth_requests::table
@@ -176,8 +270,57 @@ impl AuthRequest {
         ct_eq(&self.access_code, access_code)
     }
 
+    /// Checks the signature of the challenge nonce, with which the requesting device proves it holds the private key
+    /// the user key was encrypted for. The signature is a base64 RSA PKCS#1 v1.5 SHA-256 signature over the nonce.
+    /// A missing signature, or a request without a nonce, is only accepted when it isn't `required`.
+    pub fn check_nonce_signature(&self, signature: Option<&str>, required: bool) -> bool {
+        let (Some(nonce), Some(signature)) = (self.challenge_nonce.as_deref(), signature) else {
+            return !required;
+        };
+        let (Ok(public_key), Ok(signature)) =
+            (BASE64.decode(self.public_key.as_bytes()), BASE64.decode(signature.as_bytes()))
+        else {
+            return false;
+        };
+        let Ok(pkey) = PKey::public_key_from_der(&public_key) else {
+            return false;
+        };
+
+        Verifier::new(MessageDigest::sha256(), &pkey)
+            .and_then(|mut verifier| {
+                verifier.update(nonce.as_bytes())?;
+                verifier.verify(&signature)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Checks the structure of the key sent along by the approving device, which should be RSA encrypted for the `public_key` of the request.
+    /// OAEP doesn't reveal the recipient of a ciphertext, so only the encryption type and the size of the ciphertext are compared
+    /// with the key, which catches symmetric or malformed keys, and keys encrypted for a key of another size.
+    pub fn check_key_envelope(&self, enc_key: &str) -> bool {
+        let Ok(public_key) = BASE64.decode(self.public_key.as_bytes()) else {
+            return false;
+        };
+        let Ok(rsa) = PKey::public_key_from_der(&public_key).and_then(|k| k.rsa()) else {
+            return false;
+        };
+
+        // EncString format: `<type>.<b64 data>[|<b64 mac>]`, types 3 and 4 are RSA-OAEP, 5 and 6 are their legacy HMAC variants
+        let Some((enc_type, data)) = enc_key.split_once('.') else {
+            return false;
+        };
+        if !matches!(enc_type, "3" | "4" | "5" | "6") {
+            return false;
+        }
+        let ciphertext = data.split('|').next().unwrap_or_default();
+        match BASE64.decode(ciphertext.as_bytes()) {
+            Ok(ciphertext) => ciphertext.len() == rsa.size() as usize,
+            Err(_) => false,
+        }
+    }
+
     pub async fn purge_expired_auth_requests(conn: &mut DbConn) {
-        let expiry_time = Utc::now().naive_utc() - chrono::TimeDelta::try_minutes(5).unwrap(); //after 5 minutes, clients reject the request
+        let expiry_time = Utc::now().naive_utc() - chrono::TimeDelta::try_minutes(Self::EXPIRATION_MINUTES).unwrap();
         for auth_request in Self::find_created_before(&expiry_time, conn).await {
             auth_request.delete(conn).await.ok();
         }
@@ -201,3 +344,193 @@ impl AuthRequest {
     UuidFromParam,
 )]
 pub struct AuthRequestId(String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::User;
+
+    fn test_auth_request() -> AuthRequest {
+        AuthRequest::new(
+            UserId::from(crate::util::get_uuid()),
+            DeviceId::from(crate::util::get_uuid()),
+            0,
+            String::from("127.0.0.1"),
+            String::from("access-code"),
+            String::from("public-key"),
+        )
+    }
+
+    #[test]
+    fn test_master_password_hash_can_be_omitted() {
+        let mut auth_request = test_auth_request();
+        auth_request.approve(
+            DeviceId::from(crate::util::get_uuid()),
+            String::from("enc-key"),
+            Some(String::from("hash")),
+        );
+
+        let json = auth_request.to_json_with("https://vault.example.com", true);
+        assert_eq!(json["masterPasswordHash"], "hash");
+
+        let json = auth_request.to_json_with("https://vault.example.com", false);
+        assert!(json.get("masterPasswordHash").is_none());
+        assert_eq!(json["key"], "enc-key");
+    }
+
+    #[test]
+    fn test_refresh_access_code() {
+        let mut auth_request = test_auth_request();
+        auth_request.creation_date -= chrono::TimeDelta::try_minutes(4).unwrap();
+
+        let new_code = auth_request.refresh_access_code("access-code").unwrap();
+        assert!(auth_request.check_access_code(&new_code));
+        assert!(!auth_request.is_expired());
+        assert!(auth_request.creation_date > Utc::now().naive_utc() - chrono::TimeDelta::try_minutes(1).unwrap());
+    }
+
+    #[test]
+    fn test_old_access_code_rejected_after_refresh() {
+        let mut auth_request = test_auth_request();
+        let uuid = auth_request.uuid.clone();
+
+        assert!(auth_request.refresh_access_code("wrong-code").is_none());
+        assert!(auth_request.check_access_code("access-code"));
+
+        let new_code = auth_request.refresh_access_code("access-code").unwrap();
+        assert!(!auth_request.check_access_code("access-code"));
+        assert!(auth_request.refresh_access_code("access-code").is_none());
+        assert_eq!(auth_request.uuid, uuid);
+
+        // Answered requests can't be refreshed anymore
+        auth_request.approved = Some(true);
+        assert!(auth_request.refresh_access_code(&new_code).is_none());
+    }
+
+    #[test]
+    fn test_response_device_set_after_approval() {
+        let mut auth_request = test_auth_request();
+        assert_eq!(auth_request.to_json("vault.example.com")["responseDeviceId"], Value::Null);
+
+        let approving_device = DeviceId::from(crate::util::get_uuid());
+        auth_request.approve(approving_device.clone(), String::from("key"), None);
+
+        let json = auth_request.to_json("vault.example.com");
+        assert_eq!(json["responseDeviceId"], approving_device.to_string());
+        assert_eq!(json["requestApproved"], true);
+        assert_ne!(json["responseDate"], Value::Null);
+    }
+
+    #[test]
+    fn test_response_device_hidden_when_not_approved() {
+        let mut auth_request = test_auth_request();
+        auth_request.response_device_id = Some(DeviceId::from(crate::util::get_uuid()));
+        auth_request.approved = Some(false);
+
+        assert!(auth_request.approving_device_id().is_none());
+    }
+
+    fn sign_nonce(rsa: &openssl::rsa::Rsa<openssl::pkey::Private>, nonce: &str) -> String {
+        let pkey = PKey::from_rsa(rsa.clone()).unwrap();
+        let mut signer = openssl::sign::Signer::new(MessageDigest::sha256(), &pkey).unwrap();
+        signer.update(nonce.as_bytes()).unwrap();
+        BASE64.encode(&signer.sign_to_vec().unwrap())
+    }
+
+    #[test]
+    fn test_challenge_nonce_signed_by_requesting_device() {
+        let (rsa, public_key) = rsa_public_key(2048);
+        let (other_rsa, _) = rsa_public_key(2048);
+        let mut auth_request = test_auth_request();
+        auth_request.public_key = public_key;
+        let nonce = auth_request.challenge_nonce.clone().unwrap();
+
+        assert!(auth_request.check_nonce_signature(Some(&sign_nonce(&rsa, &nonce)), true));
+        assert!(!auth_request.check_nonce_signature(Some(&sign_nonce(&rsa, "wrong")), false));
+        // Only the device which holds the private key of the request can sign the nonce
+        assert!(!auth_request.check_nonce_signature(Some(&sign_nonce(&other_rsa, &nonce)), true));
+    }
+
+    #[test]
+    fn test_challenge_nonce_only_returned_to_requester() {
+        let auth_request = test_auth_request();
+        assert!(auth_request.to_json("vault.example.com").get("challengeNonce").is_none());
+        assert_eq!(
+            auth_request.to_json_for_requester("vault.example.com")["challengeNonce"],
+            json!(auth_request.challenge_nonce)
+        );
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_approved_request_redeemed_once() {
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let mut user = User::new(String::from("user@example.ext"), None);
+            user.save(&mut conn).await.unwrap();
+
+            let mut auth_request = test_auth_request();
+            auth_request.user_uuid = user.uuid.clone();
+            auth_request.save(&mut conn).await.unwrap();
+            // Pending requests can't be used to login
+            assert!(!auth_request.redeem(&mut conn).await);
+
+            auth_request.approve(DeviceId::from(crate::util::get_uuid()), String::from("key"), None);
+            auth_request.save(&mut conn).await.unwrap();
+            assert!(auth_request.redeem(&mut conn).await);
+            assert!(auth_request.authentication_date.is_some());
+
+            let mut replayed = AuthRequest::find_by_uuid(&auth_request.uuid, &mut conn).await.unwrap();
+            assert!(!replayed.redeem(&mut conn).await);
+        });
+    }
+
+    fn rsa_public_key(bits: u32) -> (openssl::rsa::Rsa<openssl::pkey::Private>, String) {
+        let rsa = openssl::rsa::Rsa::generate(bits).unwrap();
+        let der = rsa.public_key_to_der().unwrap();
+        (rsa, BASE64.encode(&der))
+    }
+
+    fn rsa_envelope(rsa: &openssl::rsa::Rsa<openssl::pkey::Private>) -> String {
+        let mut buf = vec![0; rsa.size() as usize];
+        let len =
+            rsa.public_encrypt(&crypto::get_random_bytes::<64>(), &mut buf, openssl::rsa::Padding::PKCS1_OAEP).unwrap();
+        format!("4.{}", BASE64.encode(&buf[..len]))
+    }
+
+    #[test]
+    fn test_key_envelope_for_requesting_device() {
+        let (rsa, public_key) = rsa_public_key(2048);
+        let mut auth_request = test_auth_request();
+        auth_request.public_key = public_key;
+
+        assert!(auth_request.check_key_envelope(&rsa_envelope(&rsa)));
+    }
+
+    #[test]
+    fn test_key_envelope_mismatch_rejected() {
+        let (_, public_key) = rsa_public_key(2048);
+        let (other_rsa, _) = rsa_public_key(1024);
+        let mut auth_request = test_auth_request();
+        auth_request.public_key = public_key;
+
+        assert!(!auth_request.check_key_envelope(&rsa_envelope(&other_rsa)));
+        assert!(!auth_request.check_key_envelope("2.aXY=|Y3Q=|bWFj"));
+        assert!(!auth_request.check_key_envelope("enc-key"));
+
+        // Without a valid public key on the request nothing can be checked
+        assert!(!test_auth_request().check_key_envelope(&rsa_envelope(&other_rsa)));
+    }
+
+    #[test]
+    fn test_missing_nonce_signature_only_allowed_when_not_required() {
+        let mut auth_request = test_auth_request();
+        assert!(auth_request.check_nonce_signature(None, false));
+        assert!(!auth_request.check_nonce_signature(None, true));
+
+        // Requests created before nonces were stored
+        auth_request.challenge_nonce = None;
+        assert!(auth_request.check_nonce_signature(Some("c2lnbmF0dXJl"), false));
+        assert!(!auth_request.check_nonce_signature(Some("c2lnbmF0dXJl"), true));
+    }
+}
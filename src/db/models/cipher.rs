@@ -9,6 +9,7 @@ use super::{
     MembershipType, OrganizationId, User, UserId,
 };
 use crate::api::core::{CipherData, CipherSyncData, CipherSyncType};
+use crate::db::{length, sum_lengths};
 use macros::UuidFromParam;
 
 use std::borrow::Cow;
@@ -45,6 +46,8 @@ db_object! {
         pub password_history: Option<String>,
         pub deleted_at: Option<NaiveDateTime>,
         pub reprompt: Option<i32>,
+        // Account key version of the owner this cipher was last written with, `None` for organization ciphers
+        pub key_version: Option<i32>,
     }
 }
 
@@ -78,6 +81,7 @@ impl Cipher {
             password_history: None,
             deleted_at: None,
             reprompt: None,
+            key_version: None,
         }
     }
 
@@ -936,6 +940,147 @@ impl Cipher {
         }}
     }
 
+    /// Counts the personal ciphers of a user, and the stored length of their encrypted fields
+    pub async fn count_and_size_owned_by_user(user_uuid: &UserId, conn: &mut DbConn) -> (i64, i64) {
+        db_run! {conn: {
+            ciphers::table
+                .filter(
+                    ciphers::user_uuid.eq(user_uuid)
+                    .and(ciphers::organization_uuid.is_null())
+                )
+                .select((
+                    diesel::dsl::count_star(),
+                    diesel::dsl::sum(length(ciphers::name.nullable())),
+                    diesel::dsl::sum(length(ciphers::data.nullable())),
+                    diesel::dsl::sum(length(ciphers::key)),
+                    diesel::dsl::sum(length(ciphers::notes)),
+                    diesel::dsl::sum(length(ciphers::fields)),
+                    diesel::dsl::sum(length(ciphers::password_history)),
+                ))
+                .first::<(i64, Option<i64>, Option<i64>, Option<i64>, Option<i64>, Option<i64>, Option<i64>)>(conn)
+                .map(|(count, name, data, key, notes, fields, history)| {
+                    (count, sum_lengths(&[name, data, key, notes, fields, history]))
+                })
+                .unwrap_or((0, 0))
+        }}
+    }
+
+    /// Counts the organization ciphers visible to a user, and the stored length of their encrypted fields.
+    /// Uses the same access rules as `find_by_user_visible`, with sub-queries so every cipher is only counted once.
+    pub async fn count_and_size_of_orgs_visible_to_user(user_uuid: &UserId, conn: &mut DbConn) -> (i64, i64) {
+        if CONFIG.org_groups_enabled() {
+            db_run! {conn: {
+                // The memberships through which the user can see organization ciphers
+                let confirmed = users_organizations::table
+                    .filter(users_organizations::user_uuid.eq(user_uuid))
+                    .filter(users_organizations::status.eq(MembershipStatus::Confirmed as i32));
+
+                ciphers::table
+                    .filter(ciphers::organization_uuid.eq_any(
+                        confirmed.clone().select(users_organizations::org_uuid.nullable())
+                    ))
+                    .filter(
+                        ciphers::organization_uuid.eq_any( // access_all in org
+                            confirmed.clone()
+                                .filter(users_organizations::access_all.eq(true))
+                                .select(users_organizations::org_uuid.nullable())
+                        )
+                        .or(ciphers::organization_uuid.eq_any( // access_all via groups
+                            confirmed.clone()
+                                .filter(users_organizations::uuid.eq_any(
+                                    groups_users::table
+                                        .filter(groups_users::groups_uuid.eq_any(
+                                            groups::table.filter(groups::access_all.eq(true)).select(groups::uuid)
+                                        ))
+                                        .select(groups_users::users_organizations_uuid)
+                                ))
+                                .select(users_organizations::org_uuid.nullable())
+                        ))
+                        .or(ciphers::uuid.eq_any( // Access to collection
+                            ciphers_collections::table
+                                .filter(ciphers_collections::collection_uuid.eq_any(
+                                    users_collections::table
+                                        .filter(users_collections::user_uuid.eq(user_uuid))
+                                        .select(users_collections::collection_uuid)
+                                ))
+                                .select(ciphers_collections::cipher_uuid)
+                        ))
+                        .or(ciphers::uuid.eq_any( // Access to collection via groups
+                            ciphers_collections::table
+                                .filter(ciphers_collections::collection_uuid.eq_any(
+                                    collections_groups::table
+                                        .filter(collections_groups::groups_uuid.eq_any(
+                                            groups_users::table
+                                                .filter(groups_users::users_organizations_uuid.eq_any(
+                                                    confirmed.clone().select(users_organizations::uuid)
+                                                ))
+                                                .select(groups_users::groups_uuid)
+                                        ))
+                                        .select(collections_groups::collections_uuid)
+                                ))
+                                .select(ciphers_collections::cipher_uuid)
+                        ))
+                    )
+                    .select((
+                        diesel::dsl::count_star(),
+                        diesel::dsl::sum(length(ciphers::name.nullable())),
+                        diesel::dsl::sum(length(ciphers::data.nullable())),
+                        diesel::dsl::sum(length(ciphers::key)),
+                        diesel::dsl::sum(length(ciphers::notes)),
+                        diesel::dsl::sum(length(ciphers::fields)),
+                        diesel::dsl::sum(length(ciphers::password_history)),
+                    ))
+                    .first::<(i64, Option<i64>, Option<i64>, Option<i64>, Option<i64>, Option<i64>, Option<i64>)>(conn)
+                    .map(|(count, name, data, key, notes, fields, history)| {
+                        (count, sum_lengths(&[name, data, key, notes, fields, history]))
+                    })
+                    .unwrap_or((0, 0))
+            }}
+        } else {
+            db_run! {conn: {
+                // The memberships through which the user can see organization ciphers
+                let confirmed = users_organizations::table
+                    .filter(users_organizations::user_uuid.eq(user_uuid))
+                    .filter(users_organizations::status.eq(MembershipStatus::Confirmed as i32));
+
+                ciphers::table
+                    .filter(ciphers::organization_uuid.eq_any(
+                        confirmed.clone().select(users_organizations::org_uuid.nullable())
+                    ))
+                    .filter(
+                        ciphers::organization_uuid.eq_any( // access_all in org
+                            confirmed.clone()
+                                .filter(users_organizations::access_all.eq(true))
+                                .select(users_organizations::org_uuid.nullable())
+                        )
+                        .or(ciphers::uuid.eq_any( // Access to collection
+                            ciphers_collections::table
+                                .filter(ciphers_collections::collection_uuid.eq_any(
+                                    users_collections::table
+                                        .filter(users_collections::user_uuid.eq(user_uuid))
+                                        .select(users_collections::collection_uuid)
+                                ))
+                                .select(ciphers_collections::cipher_uuid)
+                        ))
+                    )
+                    .select((
+                        diesel::dsl::count_star(),
+                        diesel::dsl::sum(length(ciphers::name.nullable())),
+                        diesel::dsl::sum(length(ciphers::data.nullable())),
+                        diesel::dsl::sum(length(ciphers::key)),
+                        diesel::dsl::sum(length(ciphers::notes)),
+                        diesel::dsl::sum(length(ciphers::fields)),
+                        diesel::dsl::sum(length(ciphers::password_history)),
+                    ))
+                    .first::<(i64, Option<i64>, Option<i64>, Option<i64>, Option<i64>, Option<i64>, Option<i64>)>(conn)
+                    .map(|(count, name, data, key, notes, fields, history)| {
+                        (count, sum_lengths(&[name, data, key, notes, fields, history]))
+                    })
+                    .unwrap_or((0, 0))
+            }}
+        }
+    }
+
     pub async fn find_by_org(org_uuid: &OrganizationId, conn: &mut DbConn) -> Vec<Self> {
         db_run! {conn: {
             ciphers::table
@@ -5,7 +5,7 @@ use super::{
     CipherId, CollectionGroup, GroupUser, Membership, MembershipId, MembershipStatus, MembershipType, OrganizationId,
     User, UserId,
 };
-use crate::CONFIG;
+use crate::{db::length, CONFIG};
 use macros::UuidFromParam;
 
 db_object! {
@@ -293,6 +293,82 @@ impl Collection {
         }
     }
 
+    /// Counts the collections visible to a user, and the stored length of their encrypted names.
+    /// Uses the same access rules as `find_by_user_uuid`, with sub-queries so every collection is only counted once.
+    pub async fn count_and_size_by_user_uuid(user_uuid: &UserId, conn: &mut DbConn) -> (i64, i64) {
+        if CONFIG.org_groups_enabled() {
+            db_run! { conn: {
+                // The memberships through which the user can see collections
+                let confirmed = users_organizations::table
+                    .filter(users_organizations::user_uuid.eq(user_uuid))
+                    .filter(users_organizations::status.eq(MembershipStatus::Confirmed as i32));
+
+                collections::table
+                .filter(collections::org_uuid.eq_any(confirmed.clone().select(users_organizations::org_uuid)))
+                .filter(
+                    collections::uuid.eq_any( // Directly accessed collection
+                        users_collections::table
+                            .filter(users_collections::user_uuid.eq(user_uuid))
+                            .select(users_collections::collection_uuid)
+                    ).or(collections::org_uuid.eq_any( // access_all in Organization
+                        confirmed.clone()
+                            .filter(users_organizations::access_all.eq(true))
+                            .select(users_organizations::org_uuid)
+                    )).or(collections::org_uuid.eq_any( // access_all in groups
+                        confirmed.clone()
+                            .filter(users_organizations::uuid.eq_any(
+                                groups_users::table
+                                    .filter(groups_users::groups_uuid.eq_any(
+                                        groups::table.filter(groups::access_all.eq(true)).select(groups::uuid)
+                                    ))
+                                    .select(groups_users::users_organizations_uuid)
+                            ))
+                            .select(users_organizations::org_uuid)
+                    )).or(collections::uuid.eq_any( // access via groups
+                        collections_groups::table
+                            .filter(collections_groups::groups_uuid.eq_any(
+                                groups_users::table
+                                    .filter(groups_users::users_organizations_uuid.eq_any(
+                                        confirmed.clone().select(users_organizations::uuid)
+                                    ))
+                                    .select(groups_users::groups_uuid)
+                            ))
+                            .select(collections_groups::collections_uuid)
+                    ))
+                )
+                .select((diesel::dsl::count_star(), diesel::dsl::sum(length(collections::name.nullable()))))
+                .first::<(i64, Option<i64>)>(conn)
+                .map(|(count, name)| (count, name.unwrap_or(0)))
+                .unwrap_or((0, 0))
+            }}
+        } else {
+            db_run! { conn: {
+                // The memberships through which the user can see collections
+                let confirmed = users_organizations::table
+                    .filter(users_organizations::user_uuid.eq(user_uuid))
+                    .filter(users_organizations::status.eq(MembershipStatus::Confirmed as i32));
+
+                collections::table
+                .filter(collections::org_uuid.eq_any(confirmed.clone().select(users_organizations::org_uuid)))
+                .filter(
+                    collections::uuid.eq_any( // Directly accessed collection
+                        users_collections::table
+                            .filter(users_collections::user_uuid.eq(user_uuid))
+                            .select(users_collections::collection_uuid)
+                    ).or(collections::org_uuid.eq_any( // access_all in Organization
+                        confirmed.clone()
+                            .filter(users_organizations::access_all.eq(true))
+                            .select(users_organizations::org_uuid)
+                    ))
+                )
+                .select((diesel::dsl::count_star(), diesel::dsl::sum(length(collections::name.nullable()))))
+                .first::<(i64, Option<i64>)>(conn)
+                .map(|(count, name)| (count, name.unwrap_or(0)))
+                .unwrap_or((0, 0))
+            }}
+        }
+    }
+
     pub async fn find_by_organization_and_user_uuid(
         org_uuid: &OrganizationId,
         user_uuid: &UserId,
@@ -8,6 +8,7 @@ use super::{AuthRequest, UserId};
 use crate::{
     crypto,
     util::{format_date, get_uuid},
+    CONFIG,
 };
 use macros::{IdFromParam, UuidFromParam};
 
@@ -30,6 +31,22 @@ db_object! {
 
         pub refresh_token: String,
         pub twofactor_remember: Option<String>,
+
+        pub push_registration_success: Option<bool>,
+        pub push_registration_date: Option<NaiveDateTime>,
+        pub push_registration_error: Option<String>,
+
+        pub pending_approval: bool,
+        pub approval_public_key: Option<String>,
+        pub encrypted_user_key: Option<String>,
+
+        pub push_muted: i32, // Bitmask of muted PushCategory values
+
+        // Set when the device was removed, it's kept for a while so clients can tell a revoked device from an unknown one
+        pub deleted_at: Option<NaiveDateTime>,
+
+        // Key the device signs its requests with, see `SESSION_BINDING_DEVICE_TYPES`
+        pub session_public_key: Option<String>,
     }
 }
 
@@ -43,10 +60,121 @@ impl Device {
             "identifier": self.uuid,
             "creationDate": format_date(&self.created_at),
             "isTrusted": false,
+            "pushPreferences": self.push_preferences_json(),
             "object":"device"
         })
     }
 
+    /// Device names are compared ignoring case, when `DEVICE_NAMES_UNIQUE` is enabled they can't be used twice by a user
+    pub fn check_unique_name(name: &str, user_devices: &[Self]) -> EmptyResult {
+        if user_devices.iter().any(|d| d.name.to_lowercase() == name.to_lowercase()) {
+            err!(format!("A device with the name {name} already exists"))
+        }
+        Ok(())
+    }
+
+    /// Whether the device identifier belongs to one of the devices of a user, revoked devices aren't known anymore
+    pub fn is_known(user_devices: &[Self], device_id: &DeviceId) -> bool {
+        user_devices.iter().any(|d| &d.uuid == device_id)
+    }
+
+    /// Whether this is the device of the session making the request
+    pub fn is_current(&self, session_device: &DeviceId) -> bool {
+        &self.uuid == session_device
+    }
+
+    /// Metadata to recreate the device on another instance, tokens are left out as devices have to register again
+    pub fn to_export_json(&self) -> Value {
+        json!({
+            "identifier": self.uuid,
+            "name": self.name,
+            "type": self.atype,
+            "creationDate": format_date(&self.created_at),
+            "lastActiveDate": format_date(&self.updated_at),
+        })
+    }
+
+    /// Splits imported devices into the ones to create and the identifiers which are skipped,
+    /// because the user already has a device with that identifier or it's listed more than once.
+    pub fn split_import(import: Vec<DeviceImport>, user_devices: &[Self]) -> (Vec<DeviceImport>, Vec<DeviceId>) {
+        let mut to_create: Vec<DeviceImport> = Vec::new();
+        let mut skipped = Vec::new();
+        for device in import {
+            if Self::is_known(user_devices, &device.identifier)
+                || to_create.iter().any(|d| d.identifier == device.identifier)
+            {
+                skipped.push(device.identifier);
+            } else {
+                to_create.push(device);
+            }
+        }
+        (to_create, skipped)
+    }
+
+    fn push_preferences_json(&self) -> Value {
+        json!({
+            "ciphers": self.push_allowed(PushCategory::Ciphers),
+            "folders": self.push_allowed(PushCategory::Folders),
+            "sends": self.push_allowed(PushCategory::Sends),
+            "authRequests": self.push_allowed(PushCategory::AuthRequests),
+        })
+    }
+
+    pub fn push_allowed(&self, category: PushCategory) -> bool {
+        self.push_muted & category as i32 == 0
+    }
+
+    /// Whether this device wants push notifications for updates of this type.
+    /// Updates which don't belong to a category, like a logout, can't be muted.
+    pub fn wants_push(&self, ut: UpdateType) -> bool {
+        PushCategory::from_update_type(ut).is_none_or(|category| self.push_allowed(category))
+    }
+
+    pub fn set_push_allowed(&mut self, category: PushCategory, allowed: bool) {
+        if allowed {
+            self.push_muted &= !(category as i32);
+        } else {
+            self.push_muted |= category as i32;
+        }
+    }
+
+    /// Keeps track of the last push relay registration attempt, so it can be shown in the device list.
+    pub fn set_push_registration_result(&mut self, result: &EmptyResult) {
+        const MAX_ERROR_LENGTH: usize = 250;
+
+        self.push_registration_date = Some(Utc::now().naive_utc());
+        match result {
+            Ok(()) => {
+                self.push_registration_success = Some(true);
+                self.push_registration_error = None;
+            }
+            Err(e) => {
+                self.push_registration_success = Some(false);
+                self.push_registration_error = Some(e.to_string().chars().take(MAX_ERROR_LENGTH).collect());
+            }
+        }
+    }
+
+    /// Removes the push token and the push relay registration of the device, so it registers again the next time the app sends its token.
+    /// Returns the push uuid which still has to be unregistered from the push relay.
+    pub fn clear_push_token(&mut self) -> Option<PushId> {
+        self.push_token = None;
+        self.push_registration_success = None;
+        self.push_registration_date = None;
+        self.push_registration_error = None;
+        self.push_uuid.take()
+    }
+
+    fn push_registration_status(&self) -> Option<&'static str> {
+        self.push_registration_success.map(|success| {
+            if success {
+                "success"
+            } else {
+                "failed"
+            }
+        })
+    }
+
     pub fn refresh_twofactor_remember(&mut self) -> String {
         let twofactor_remember = crypto::encode_random_bytes::<180>(BASE64);
         self.twofactor_remember = Some(twofactor_remember.clone());
@@ -58,6 +186,74 @@ impl Device {
         self.twofactor_remember = None;
     }
 
+    pub fn check_twofactor_remember(&self, twofactor_remember: &str) -> bool {
+        self.twofactor_remember.as_ref().is_some_and(|code| crypto::ct_eq(code, twofactor_remember))
+    }
+
+    /// Marks the device as removed, it can't be used to login or receive push notifications anymore
+    pub fn soft_delete(&mut self) {
+        self.revoke_refresh_token();
+        self.twofactor_remember = None;
+        self.push_token = None;
+        self.deleted_at = Some(Utc::now().naive_utc());
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    /// Replaces the refresh token of this device, every refresh token issued before can't be used anymore.
+    /// Already issued access tokens stay valid until they expire.
+    pub fn revoke_refresh_token(&mut self) {
+        self.refresh_token = crypto::encode_random_bytes::<64>(BASE64URL);
+    }
+
+    pub fn check_refresh_token(&self, refresh_token: &str) -> bool {
+        crypto::ct_eq(&self.refresh_token, refresh_token)
+    }
+
+    /// Applies the decision of `approver` on this pending device.
+    /// Returns `false` when the device was denied, it should be removed then.
+    pub fn resolve_approval(
+        &mut self,
+        approver: &Device,
+        approved: bool,
+        encrypted_user_key: Option<String>,
+    ) -> ApiResult<bool> {
+        if !self.pending_approval {
+            err!("Device is not pending approval")
+        }
+        if approver.pending_approval || approver.uuid == self.uuid {
+            err!("Device can only be approved from another approved device")
+        }
+        if !approved {
+            return Ok(false);
+        }
+        // When the new device registered a key, the approving device has to provide the user key encrypted with it
+        if self.approval_public_key.is_some() && encrypted_user_key.is_none() {
+            err!("The encrypted user key for this device is missing")
+        }
+
+        self.pending_approval = false;
+        self.encrypted_user_key = encrypted_user_key;
+        Ok(true)
+    }
+
+    /// Returns true when this device may answer `auth_request`.
+    /// Any trusted device of the user can, except the requesting one. With `same_device_only`,
+    /// the device given in the response also has to be this device.
+    pub fn can_answer_auth_request(
+        &self,
+        auth_request: &AuthRequest,
+        device_identifier: &DeviceId,
+        same_device_only: bool,
+    ) -> bool {
+        !self.pending_approval
+            && self.user_uuid == auth_request.user_uuid
+            && self.uuid != auth_request.request_device_identifier
+            && (!same_device_only || self.uuid == *device_identifier)
+    }
+
     // This rely on the fact we only update the device after a successful login
     pub fn is_new(&self) -> bool {
         self.created_at == self.updated_at
@@ -76,13 +272,22 @@ impl Device {
     }
 }
 
+/// A device exported by `Device::to_export_json`, the dates are only informational and not imported
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceImport {
+    pub identifier: DeviceId,
+    pub name: String,
+    pub r#type: i32,
+}
+
 pub struct DeviceWithAuthRequest {
     pub device: Device,
     pub pending_auth_request: Option<AuthRequest>,
 }
 
 impl DeviceWithAuthRequest {
-    pub fn to_json(&self) -> Value {
+    pub fn to_json(&self, session_device: &DeviceId) -> Value {
         let auth_request = match &self.pending_auth_request {
             Some(auth_request) => auth_request.to_json_for_pending_device(),
             None => Value::Null,
@@ -97,6 +302,15 @@ impl DeviceWithAuthRequest {
             "isTrusted": false,
             "encryptedPublicKey": null,
             "encryptedUserKey": null,
+            // Vaultwarden specific, helps debugging push notifications
+            "pushTokenSet": self.device.push_token.is_some(),
+            "lastPushRegistrationStatus": self.device.push_registration_status(),
+            "lastPushRegistrationDate": self.device.push_registration_date.as_ref().map(format_date),
+            "lastPushRegistrationError": self.device.push_registration_error,
+            "pendingApproval": self.device.pending_approval,
+            "pushPreferences": self.device.push_preferences_json(),
+            "revokedDate": self.device.deleted_at.as_ref().map(format_date),
+            "current": self.device.is_current(session_device),
             "object": "device",
         })
     }
@@ -110,7 +324,7 @@ impl DeviceWithAuthRequest {
 }
 use crate::db::DbConn;
 
-use crate::api::{ApiResult, EmptyResult};
+use crate::api::{ApiResult, EmptyResult, UpdateType};
 use crate::error::MapResult;
 
 /// Database methods
@@ -123,6 +337,10 @@ impl Device {
         conn: &mut DbConn,
     ) -> ApiResult<Device> {
         let now = Utc::now().naive_utc();
+        let user_devices = Self::find_by_user(&user_uuid, conn).await;
+        if CONFIG.device_names_unique() {
+            Self::check_unique_name(&name, &user_devices)?;
+        }
 
         let device = Self {
             uuid,
@@ -137,6 +355,19 @@ impl Device {
             push_token: None,
             refresh_token: crypto::encode_random_bytes::<64>(BASE64URL),
             twofactor_remember: None,
+
+            push_registration_success: None,
+            push_registration_date: None,
+            push_registration_error: None,
+
+            // The first device of a user is always approved, otherwise nobody would be able to approve it
+            pending_approval: CONFIG.device_approval_required() && user_devices.iter().any(|d| !d.pending_approval),
+            approval_public_key: None,
+            encrypted_user_key: None,
+
+            push_muted: 0,
+            deleted_at: None,
+            session_public_key: None,
         };
 
         device.inner_save(conn).await.map(|()| device)
@@ -174,19 +405,36 @@ impl Device {
         }}
     }
 
+    pub async fn delete(self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(devices::table.filter(devices::uuid.eq(&self.uuid)).filter(devices::user_uuid.eq(&self.user_uuid)))
+                .execute(conn)
+                .map_res("Error removing device")
+        }}
+    }
+
     pub async fn find_by_uuid_and_user(uuid: &DeviceId, user_uuid: &UserId, conn: &mut DbConn) -> Option<Self> {
         db_run! { conn: {
             devices::table
                 .filter(devices::uuid.eq(uuid))
                 .filter(devices::user_uuid.eq(user_uuid))
+                .filter(devices::deleted_at.is_null())
                 .first::<DeviceDb>(conn)
                 .ok()
                 .from_db()
         }}
     }
 
-    pub async fn find_with_auth_request_by_user(user_uuid: &UserId, conn: &mut DbConn) -> Vec<DeviceWithAuthRequest> {
-        let devices = Self::find_by_user(user_uuid, conn).await;
+    pub async fn find_with_auth_request_by_user(
+        user_uuid: &UserId,
+        include_deleted: bool,
+        conn: &mut DbConn,
+    ) -> Vec<DeviceWithAuthRequest> {
+        let devices = if include_deleted {
+            Self::find_by_user_including_deleted(user_uuid, conn).await
+        } else {
+            Self::find_by_user(user_uuid, conn).await
+        };
         let mut result = Vec::new();
         for device in devices {
             let auth_request = AuthRequest::find_by_user_and_requested_device(user_uuid, &device.uuid, conn).await;
@@ -199,22 +447,54 @@ impl Device {
         db_run! { conn: {
             devices::table
                 .filter(devices::user_uuid.eq(user_uuid))
+                .filter(devices::deleted_at.is_null())
                 .load::<DeviceDb>(conn)
                 .expect("Error loading devices")
                 .from_db()
         }}
     }
 
+    pub async fn find_by_user_including_deleted(user_uuid: &UserId, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            devices::table
+                .filter(devices::user_uuid.eq(user_uuid))
+                .load::<DeviceDb>(conn)
+                .expect("Error loading devices")
+                .from_db()
+        }}
+    }
+
+    /// Removes the devices which were soft-deleted before the given date
+    pub async fn purge_deleted_before(dt: &NaiveDateTime, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(devices::table.filter(devices::deleted_at.lt(dt)))
+                .execute(conn)
+                .map_res("Error purging deleted devices")
+        }}
+    }
+
     pub async fn find_by_uuid(uuid: &DeviceId, conn: &mut DbConn) -> Option<Self> {
         db_run! { conn: {
             devices::table
                 .filter(devices::uuid.eq(uuid))
+                .filter(devices::deleted_at.is_null())
                 .first::<DeviceDb>(conn)
                 .ok()
                 .from_db()
         }}
     }
 
+    /// Removes the remembered 2FA of all devices of a user, they all need to pass the 2FA again on their next login
+    pub async fn clear_twofactor_remember_by_user(user_uuid: &UserId, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::update(devices::table)
+                .filter(devices::user_uuid.eq(user_uuid))
+                .set(devices::twofactor_remember.eq::<Option<String>>(None))
+                .execute(conn)
+                .map_res("Error removing remembered 2FA of devices")
+        }}
+    }
+
     pub async fn clear_push_token_by_uuid(uuid: &DeviceId, conn: &mut DbConn) -> EmptyResult {
         db_run! { conn: {
             diesel::update(devices::table)
@@ -228,6 +508,7 @@ impl Device {
         db_run! { conn: {
             devices::table
                 .filter(devices::refresh_token.eq(refresh_token))
+                .filter(devices::deleted_at.is_null())
                 .first::<DeviceDb>(conn)
                 .ok()
                 .from_db()
@@ -238,6 +519,7 @@ impl Device {
         db_run! { conn: {
             devices::table
                 .filter(devices::user_uuid.eq(user_uuid))
+                .filter(devices::deleted_at.is_null())
                 .order(devices::updated_at.desc())
                 .first::<DeviceDb>(conn)
                 .ok()
@@ -269,6 +551,32 @@ impl Device {
     }
 }
 
+/// Groups of push notifications which can be muted per device
+#[derive(Copy, Clone)]
+pub enum PushCategory {
+    Ciphers = 1,
+    Folders = 2,
+    Sends = 4,
+    AuthRequests = 8,
+}
+
+impl PushCategory {
+    pub fn from_update_type(ut: UpdateType) -> Option<Self> {
+        match ut {
+            UpdateType::SyncCipherUpdate
+            | UpdateType::SyncCipherCreate
+            | UpdateType::SyncLoginDelete
+            | UpdateType::SyncCiphers => Some(Self::Ciphers),
+            UpdateType::SyncFolderCreate | UpdateType::SyncFolderUpdate | UpdateType::SyncFolderDelete => {
+                Some(Self::Folders)
+            }
+            UpdateType::SyncSendCreate | UpdateType::SyncSendUpdate | UpdateType::SyncSendDelete => Some(Self::Sends),
+            UpdateType::AuthRequest | UpdateType::AuthRequestResponse => Some(Self::AuthRequests),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Display)]
 pub enum DeviceType {
     #[display("Android")]
@@ -366,3 +674,268 @@ pub struct DeviceId(String);
 
 #[derive(Clone, Debug, DieselNewType, Display, From, FromForm, Serialize, Deserialize, UuidFromParam)]
 pub struct PushId(pub String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_device() -> Device {
+        let now = Utc::now().naive_utc();
+        Device {
+            uuid: DeviceId::from(get_uuid()),
+            created_at: now,
+            updated_at: now,
+            user_uuid: UserId::from(get_uuid()),
+            name: String::from("test"),
+            atype: DeviceType::LinuxDesktop as i32,
+            push_uuid: None,
+            push_token: None,
+            refresh_token: crypto::encode_random_bytes::<64>(BASE64URL),
+            twofactor_remember: None,
+
+            push_registration_success: None,
+            push_registration_date: None,
+            push_registration_error: None,
+
+            pending_approval: false,
+            approval_public_key: None,
+            encrypted_user_key: None,
+
+            push_muted: 0,
+            deleted_at: None,
+            session_public_key: None,
+        }
+    }
+
+    #[test]
+    fn test_soft_deleted_device_tombstone() {
+        let mut device = test_device();
+        let refresh_token = device.refresh_token.clone();
+        device.push_token = Some(String::from("push-token"));
+        device.twofactor_remember = Some(String::from("remember"));
+        assert!(!device.is_deleted());
+
+        device.soft_delete();
+        assert!(device.is_deleted());
+        assert!(!device.check_refresh_token(&refresh_token));
+        assert!(device.push_token.is_none());
+        assert!(device.twofactor_remember.is_none());
+
+        let session_device = device.uuid.clone();
+        let json = DeviceWithAuthRequest::from(device, None).to_json(&session_device);
+        assert_ne!(json["revokedDate"], Value::Null);
+    }
+
+    #[test]
+    fn test_clear_push_tokens_of_all_devices() {
+        let mut devices: Vec<Device> = (0..3)
+            .map(|_| {
+                let mut device = test_device();
+                device.push_uuid = Some(PushId(get_uuid()));
+                device.push_token = Some(String::from("push-token"));
+                device.set_push_registration_result(&Ok(()));
+                device
+            })
+            .collect();
+        let registered: Vec<String> =
+            devices.iter().filter_map(|d| d.push_uuid.as_ref()).map(|p| p.to_string()).collect();
+
+        let unregistered: Vec<String> =
+            devices.iter_mut().filter_map(Device::clear_push_token).map(|p| p.to_string()).collect();
+        assert_eq!(unregistered, registered);
+        assert!(devices.iter().all(|d| d.push_token.is_none() && d.push_uuid.is_none()));
+        assert!(devices.iter().all(|d| d.push_registration_status().is_none()));
+    }
+
+    fn auth_request_for(device: &Device) -> AuthRequest {
+        AuthRequest::new(
+            device.user_uuid.clone(),
+            DeviceId::from(get_uuid()),
+            DeviceType::Android as i32,
+            String::from("127.0.0.1"),
+            String::from("access-code"),
+            String::from("public-key"),
+        )
+    }
+
+    #[test]
+    fn test_auth_request_answered_from_other_device() {
+        let phone = test_device();
+        let auth_request = auth_request_for(&phone);
+        let listed = DeviceId::from(get_uuid());
+
+        assert!(phone.can_answer_auth_request(&auth_request, &listed, false));
+        assert!(phone.can_answer_auth_request(&auth_request, &phone.uuid, false));
+
+        let mut pending = test_device();
+        pending.user_uuid = phone.user_uuid.clone();
+        pending.pending_approval = true;
+        assert!(!pending.can_answer_auth_request(&auth_request, &pending.uuid, false));
+
+        let other_user = test_device();
+        assert!(!other_user.can_answer_auth_request(&auth_request, &other_user.uuid, false));
+    }
+
+    #[test]
+    fn test_auth_request_answered_from_same_device_only() {
+        let phone = test_device();
+        let auth_request = auth_request_for(&phone);
+
+        assert!(phone.can_answer_auth_request(&auth_request, &phone.uuid, true));
+        assert!(!phone.can_answer_auth_request(&auth_request, &DeviceId::from(get_uuid()), true));
+
+        let mut requesting = test_device();
+        requesting.uuid = auth_request.request_device_identifier.clone();
+        requesting.user_uuid = phone.user_uuid.clone();
+        assert!(!requesting.can_answer_auth_request(&auth_request, &requesting.uuid, false));
+    }
+
+    #[test]
+    fn test_duplicate_device_name_rejected() {
+        let mut device = test_device();
+        device.name = String::from("Firefox");
+        let user_devices = [device];
+
+        assert!(Device::check_unique_name("firefox", &user_devices).is_err());
+        assert!(Device::check_unique_name("FIREFOX", &user_devices).is_err());
+        assert!(Device::check_unique_name("Chrome", &user_devices).is_ok());
+        assert!(Device::check_unique_name("firefox", &[]).is_ok());
+    }
+
+    #[test]
+    fn test_device_list_export_import_round_trip() {
+        let mut existing = test_device();
+        existing.push_token = Some(String::from("push-token"));
+        let mut new = test_device();
+        new.name = String::from("Firefox");
+        new.atype = DeviceType::FirefoxBrowser as i32;
+
+        let export: Vec<Value> = [&existing, &new].iter().map(|d| d.to_export_json()).collect();
+        assert!(export.iter().all(|d| d.get("pushToken").is_none() && d.get("refreshToken").is_none()));
+
+        let mut import: Vec<DeviceImport> = serde_json::from_value(Value::Array(export)).unwrap();
+        import.push(DeviceImport {
+            identifier: new.uuid.clone(),
+            name: String::from("Duplicate"),
+            r#type: 0,
+        });
+        let (to_create, skipped) = Device::split_import(import, std::slice::from_ref(&existing));
+
+        assert_eq!(to_create.len(), 1);
+        assert_eq!(to_create[0].identifier, new.uuid);
+        assert_eq!(to_create[0].name, new.name);
+        assert_eq!(to_create[0].r#type, new.atype);
+        assert_eq!(skipped, vec![existing.uuid.clone(), new.uuid.clone()]);
+    }
+
+    #[test]
+    fn test_cleared_twofactor_remember_requires_2fa() {
+        let mut device = test_device();
+        assert!(!device.check_twofactor_remember(""));
+
+        let remember = device.refresh_twofactor_remember();
+        assert!(device.check_twofactor_remember(&remember));
+        assert!(!device.check_twofactor_remember("other"));
+
+        device.delete_twofactor_remember();
+        assert!(device.twofactor_remember.is_none());
+        assert!(!device.check_twofactor_remember(&remember));
+    }
+
+    #[test]
+    fn test_only_session_device_is_current() {
+        let devices: Vec<DeviceWithAuthRequest> =
+            (0..3).map(|_| DeviceWithAuthRequest::from(test_device(), None)).collect();
+        let session_device = devices[1].device.uuid.clone();
+
+        let current: Vec<Value> =
+            devices.iter().map(|d| d.to_json(&session_device)).filter(|json| json["current"] == true).collect();
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0]["id"], session_device.to_string());
+    }
+
+    #[test]
+    fn test_muted_push_type_is_not_pushed() {
+        let mut device = test_device();
+        assert!(device.wants_push(UpdateType::SyncSendCreate));
+
+        device.set_push_allowed(PushCategory::Sends, false);
+        assert!(!device.wants_push(UpdateType::SyncSendCreate));
+        assert!(!device.wants_push(UpdateType::SyncSendDelete));
+        assert!(device.wants_push(UpdateType::SyncCipherUpdate));
+        // A logout is always pushed
+        assert!(device.wants_push(UpdateType::LogOut));
+
+        device.set_push_allowed(PushCategory::Sends, true);
+        assert!(device.wants_push(UpdateType::SyncSendCreate));
+    }
+
+    #[test]
+    fn test_revoked_refresh_token_is_rejected() {
+        let mut device = test_device();
+        let issued_refresh_token = device.refresh_token.clone();
+        assert!(device.check_refresh_token(&issued_refresh_token));
+
+        device.revoke_refresh_token();
+
+        // A refresh token issued before the revocation can't be used to get a new access token anymore
+        assert!(!device.check_refresh_token(&issued_refresh_token));
+        assert!(device.check_refresh_token(&device.refresh_token.clone()));
+    }
+
+    #[test]
+    fn test_failed_push_registration_is_recorded() {
+        let mut device = test_device();
+        assert_eq!(device.push_registration_status(), None);
+
+        let error = "x".repeat(1000);
+        device.set_push_registration_result(&Err(crate::error::Error::new(error, "")));
+
+        assert_eq!(device.push_registration_status(), Some("failed"));
+        assert!(device.push_registration_date.is_some());
+        assert_eq!(device.push_registration_error.as_ref().map(String::len), Some(250));
+
+        device.set_push_registration_result(&Ok(()));
+
+        assert_eq!(device.push_registration_status(), Some("success"));
+        assert_eq!(device.push_registration_error, None);
+    }
+
+    #[test]
+    fn test_pending_device_is_approved() {
+        let approver = test_device();
+        let mut device = test_device();
+        device.pending_approval = true;
+        device.approval_public_key = Some(String::from("public-key"));
+
+        // The new device registered a key, so the approval has to contain the user key
+        assert!(device.resolve_approval(&approver, true, None).is_err());
+        assert!(device.pending_approval);
+
+        assert!(device.resolve_approval(&approver, true, Some(String::from("4.key"))).unwrap());
+        assert!(!device.pending_approval);
+        assert_eq!(device.encrypted_user_key.as_deref(), Some("4.key"));
+
+        // Can't be approved twice
+        assert!(device.resolve_approval(&approver, true, None).is_err());
+    }
+
+    #[test]
+    fn test_pending_device_is_denied() {
+        let approver = test_device();
+        let mut device = test_device();
+        device.pending_approval = true;
+
+        assert!(!device.resolve_approval(&approver, false, None).unwrap());
+        assert!(device.encrypted_user_key.is_none());
+
+        // A pending device can't approve itself or other pending devices
+        let mut pending_approver = test_device();
+        pending_approver.pending_approval = true;
+        assert!(device.resolve_approval(&pending_approver, true, None).is_err());
+        let same_device = device.uuid.clone();
+        let mut self_approver = test_device();
+        self_approver.uuid = same_device;
+        assert!(device.resolve_approval(&self_approver, true, None).is_err());
+    }
+}
@@ -49,6 +49,28 @@ impl EmergencyAccess {
         }
     }
 
+    /// Moves a pending invite addressed to `old_email` over to `new_email`.
+    /// Returns `false` if the invite was not pending for `old_email` and nothing changed.
+    pub fn relink_grantee_email(&mut self, old_email: &str, new_email: &str) -> bool {
+        if self.status != EmergencyAccessStatus::Invited as i32 || self.email.as_deref() != Some(old_email) {
+            return false;
+        }
+        self.email = Some(new_email.to_string());
+        true
+    }
+
+    pub fn is_grantor(&self, user: &User) -> bool {
+        self.grantor_uuid == user.uuid
+    }
+
+    /// Pending invites for users which didn't have an account yet are only linked by email.
+    pub fn is_grantee(&self, user: &User) -> bool {
+        match &self.grantee_uuid {
+            Some(grantee_uuid) => grantee_uuid == &user.uuid,
+            None => self.status == EmergencyAccessStatus::Invited as i32 && self.email.as_deref() == Some(&user.email),
+        }
+    }
+
     pub fn get_type_as_str(&self) -> &'static str {
         if self.atype == EmergencyAccessType::View as i32 {
             "View"
@@ -334,6 +356,23 @@ impl EmergencyAccess {
         }}
     }
 
+    /// Used when a user changes their email, so pending invites keep pointing to them.
+    /// Returns the invites which were updated.
+    pub async fn relink_all_invited_by_grantee_email(
+        old_email: &str,
+        new_email: &str,
+        conn: &mut DbConn,
+    ) -> Result<Vec<Self>, crate::Error> {
+        let mut relinked = Vec::new();
+        for mut ea in Self::find_all_invited_by_grantee_email(old_email, conn).await {
+            if ea.relink_grantee_email(old_email, new_email) {
+                ea.save(conn).await?;
+                relinked.push(ea);
+            }
+        }
+        Ok(relinked)
+    }
+
     pub async fn find_all_by_grantor_uuid(grantor_uuid: &UserId, conn: &mut DbConn) -> Vec<Self> {
         db_run! { conn: {
             emergency_access::table
@@ -382,3 +421,83 @@ impl EmergencyAccess {
     UuidFromParam,
 )]
 pub struct EmergencyAccessId(String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_invite_follows_email_change() {
+        let grantor = UserId::from(crate::util::get_uuid());
+        let mut ea = EmergencyAccess::new(
+            grantor,
+            String::from("old@example.com"),
+            EmergencyAccessStatus::Invited as i32,
+            EmergencyAccessType::View as i32,
+            7,
+        );
+
+        assert!(!ea.relink_grantee_email("other@example.com", "new@example.com"));
+        assert_eq!(ea.email.as_deref(), Some("old@example.com"));
+
+        assert!(ea.relink_grantee_email("old@example.com", "new@example.com"));
+        assert_eq!(ea.email.as_deref(), Some("new@example.com"));
+    }
+
+    #[test]
+    fn test_grantor_perspective() {
+        let grantor = User::new(String::from("grantor@example.com"), None);
+        let grantee = User::new(String::from("grantee@example.com"), None);
+        let mut ea = EmergencyAccess::new(
+            grantor.uuid.clone(),
+            grantee.email.clone(),
+            EmergencyAccessStatus::Confirmed as i32,
+            EmergencyAccessType::View as i32,
+            7,
+        );
+        ea.grantee_uuid = Some(grantee.uuid.clone());
+
+        assert!(ea.is_grantor(&grantor));
+        assert!(!ea.is_grantee(&grantor));
+        assert!(!ea.is_grantor(&grantee));
+    }
+
+    #[test]
+    fn test_grantee_perspective() {
+        let grantor = User::new(String::from("grantor@example.com"), None);
+        let grantee = User::new(String::from("grantee@example.com"), None);
+        let other = User::new(String::from("other@example.com"), None);
+        let mut ea = EmergencyAccess::new(
+            grantor.uuid.clone(),
+            grantee.email.clone(),
+            EmergencyAccessStatus::Invited as i32,
+            EmergencyAccessType::Takeover as i32,
+            7,
+        );
+
+        // Invites are matched by email until they are accepted
+        assert!(ea.is_grantee(&grantee));
+        assert!(!ea.is_grantee(&other));
+
+        ea.status = EmergencyAccessStatus::Accepted as i32;
+        assert!(!ea.is_grantee(&grantee));
+        ea.grantee_uuid = Some(grantee.uuid.clone());
+        assert!(ea.is_grantee(&grantee));
+        assert!(!ea.is_grantee(&other));
+    }
+
+    #[test]
+    fn test_accepted_access_is_not_relinked() {
+        let grantor = UserId::from(crate::util::get_uuid());
+        let mut ea = EmergencyAccess::new(
+            grantor,
+            String::from("old@example.com"),
+            EmergencyAccessStatus::Accepted as i32,
+            EmergencyAccessType::Takeover as i32,
+            7,
+        );
+
+        assert!(!ea.relink_grantee_email("old@example.com", "new@example.com"));
+        assert_eq!(ea.email.as_deref(), Some("old@example.com"));
+    }
+}
@@ -52,6 +52,8 @@ pub enum EventType {
     // UserMigratedKeyToKeyConnector = 1009, // Not supported
     UserRequestedDeviceApproval = 1010,
     // UserTdeOffboardingPasswordSet = 1011, // Not supported
+    // Vaultwarden specific, not part of upstream
+    UserEmergencyAccessAutoAccepted = 1090,
 
     // Cipher
     CipherCreated = 1100,
@@ -311,6 +313,25 @@ impl Event {
         }}
     }
 
+    /// Personal events of a user (not bound to an organization), oldest first
+    pub async fn find_by_user_uuid(
+        user_uuid: &UserId,
+        start: &NaiveDateTime,
+        end: &NaiveDateTime,
+        conn: &mut DbConn,
+    ) -> Vec<Self> {
+        db_run! { conn: {
+            event::table
+                .filter(event::user_uuid.eq(user_uuid))
+                .filter(event::org_uuid.is_null())
+                .filter(event::event_date.between(start, end))
+                .order_by(event::event_date.asc())
+                .load::<EventDb>(conn)
+                .expect("Error filtering events")
+                .from_db()
+        }}
+    }
+
     pub async fn find_by_cipher_uuid(
         cipher_uuid: &CipherId,
         start: &NaiveDateTime,
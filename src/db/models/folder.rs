@@ -3,6 +3,7 @@ use derive_more::{AsRef, Deref, Display, From};
 use serde_json::Value;
 
 use super::{CipherId, User, UserId};
+use crate::db::length;
 use macros::UuidFromParam;
 
 db_object! {
@@ -142,6 +143,18 @@ impl Folder {
                 .from_db()
         }}
     }
+
+    /// Counts the folders of a user, and the stored length of their encrypted names
+    pub async fn count_and_size_by_user(user_uuid: &UserId, conn: &mut DbConn) -> (i64, i64) {
+        db_run! { conn: {
+            folders::table
+                .filter(folders::user_uuid.eq(user_uuid))
+                .select((diesel::dsl::count_star(), diesel::dsl::sum(length(folders::name.nullable()))))
+                .first::<(i64, Option<i64>)>(conn)
+                .map(|(count, name)| (count, name.unwrap_or(0)))
+                .unwrap_or((0, 0))
+        }}
+    }
 }
 
 impl FolderCipher {
@@ -0,0 +1,148 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use super::{User, UserId};
+use crate::{
+    api::EmptyResult,
+    db::DbConn,
+    error::MapResult,
+    util::{format_date, get_uuid},
+};
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = key_history)]
+    #[diesel(primary_key(uuid))]
+    pub struct KeyHistory {
+        pub uuid: String,
+        pub user_uuid: UserId,
+        pub version: i32,
+        pub change_type: i32,
+        pub changed_at: NaiveDateTime,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum KeyChangeType {
+    // The account key was rotated, all items were re-encrypted
+    Rotation = 0,
+    // An existing asymmetric keypair was replaced
+    Keypair = 1,
+}
+
+/// Local methods
+impl KeyHistory {
+    /// Number of key changes kept per user
+    pub const HISTORY_SIZE: usize = 50;
+
+    /// Creates the entry of a key change of `user`, its version is the account key version the change resulted in.
+    pub fn new(user: &User, change_type: KeyChangeType) -> Self {
+        Self {
+            uuid: get_uuid(),
+            user_uuid: user.uuid.clone(),
+            version: user.key_version,
+            change_type: change_type as i32,
+            changed_at: Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "version": self.version,
+            "changeType": self.change_type,
+            "changeDate": format_date(&self.changed_at),
+            "object": "keyChange",
+        })
+    }
+}
+
+/// Database methods
+impl KeyHistory {
+    /// Stores the change, and only keeps the `HISTORY_SIZE` most recent ones of the user.
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(key_history::table)
+                    .values(KeyHistoryDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving key history")
+            }
+            postgresql {
+                let value = KeyHistoryDb::to_db(self);
+                diesel::insert_into(key_history::table)
+                    .values(&value)
+                    .on_conflict(key_history::uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving key history")
+            }
+        }?;
+
+        let expired: Vec<String> = Self::find_by_user(&self.user_uuid, conn)
+            .await
+            .into_iter()
+            .skip(Self::HISTORY_SIZE)
+            .map(|h| h.uuid)
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        db_run! { conn: {
+            diesel::delete(key_history::table.filter(key_history::uuid.eq_any(expired)))
+                .execute(conn)
+                .map_res("Error pruning key history")
+        }}
+    }
+
+    /// Adds a key change to the history of a user, it has to be called after the user is saved with its new keys
+    pub async fn record(user: &User, change_type: KeyChangeType, conn: &mut DbConn) -> EmptyResult {
+        Self::new(user, change_type).save(conn).await
+    }
+
+    /// Returns the key changes of a user, most recent first.
+    pub async fn find_by_user(user_uuid: &UserId, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            key_history::table
+                .filter(key_history::user_uuid.eq(user_uuid))
+                .order((key_history::changed_at.desc(), key_history::version.desc()))
+                .load::<KeyHistoryDb>(conn)
+                .expect("Error loading key history")
+                .from_db()
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &UserId, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(key_history::table.filter(key_history::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting key history")
+        }}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_follows_account_key_version() {
+        let mut user = User::new(String::from("user@example.ext"), None);
+        user.key_version = 2;
+
+        // Replacing the keypair doesn't change the account key
+        let keypair = KeyHistory::new(&user, KeyChangeType::Keypair);
+        assert_eq!(keypair.version, 2);
+
+        user.key_version += 1;
+        let rotation = KeyHistory::new(&user, KeyChangeType::Rotation);
+        assert_eq!(rotation.version, 3);
+        assert_eq!(rotation.user_uuid, user.uuid);
+
+        let json = rotation.to_json();
+        assert_eq!(json["version"], 3);
+        assert_eq!(json["changeType"], KeyChangeType::Rotation as i32);
+        assert!(json.get("key").is_none());
+    }
+}
@@ -0,0 +1,174 @@
+use std::net::IpAddr;
+
+use chrono::{NaiveDateTime, Utc};
+
+use super::UserId;
+use crate::{api::EmptyResult, db::DbConn, error::MapResult, util::get_uuid};
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = login_locations)]
+    #[diesel(treat_none_as_null = true)]
+    #[diesel(primary_key(uuid))]
+    pub struct LoginLocation {
+        pub uuid: String,
+        pub user_uuid: UserId,
+        pub network: String,
+        pub country: Option<String>,
+        pub last_seen: NaiveDateTime,
+    }
+}
+
+/// Local methods
+impl LoginLocation {
+    /// Number of recent locations kept per user
+    pub const HISTORY_SIZE: usize = 10;
+
+    /// Logins are compared by network instead of by address, a /16 for IPv4 and a /32 for IPv6.
+    /// Most providers hand out addresses from the same range, so this mostly catches logins from another provider.
+    pub fn network_of(ip: &IpAddr) -> String {
+        match ip {
+            IpAddr::V4(ip) => {
+                let [a, b, ..] = ip.octets();
+                format!("{a}.{b}.0.0/16")
+            }
+            IpAddr::V6(ip) => {
+                let [a, b, ..] = ip.segments();
+                format!("{a:x}:{b:x}::/32")
+            }
+        }
+    }
+
+    /// Returns true when a login doesn't match any of the recent locations of a user.
+    /// When the country is known for the login and the history, it's compared instead of the network.
+    /// Without any history there is nothing to compare against.
+    pub fn is_anomaly(history: &[Self], ip: &IpAddr, country: Option<&str>) -> bool {
+        if history.is_empty() {
+            return false;
+        }
+
+        let known_countries: Vec<&str> = history.iter().filter_map(|l| l.country.as_deref()).collect();
+        match country {
+            Some(country) if !known_countries.is_empty() => !known_countries.contains(&country),
+            _ => {
+                let network = Self::network_of(ip);
+                !history.iter().any(|l| l.network == network)
+            }
+        }
+    }
+}
+
+/// Database methods
+impl LoginLocation {
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(login_locations::table)
+                    .values(LoginLocationDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving login location")
+            }
+            postgresql {
+                let value = LoginLocationDb::to_db(self);
+                diesel::insert_into(login_locations::table)
+                    .values(&value)
+                    .on_conflict(login_locations::uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving login location")
+            }
+        }
+    }
+
+    /// Returns the recent locations of a user, most recent first.
+    pub async fn find_by_user(user_uuid: &UserId, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            login_locations::table
+                .filter(login_locations::user_uuid.eq(user_uuid))
+                .order(login_locations::last_seen.desc())
+                .load::<LoginLocationDb>(conn)
+                .expect("Error loading login locations")
+                .from_db()
+        }}
+    }
+
+    /// Stores the location of a login, and only keeps the `HISTORY_SIZE` most recent ones of the user.
+    pub async fn record(
+        user_uuid: &UserId,
+        ip: &IpAddr,
+        country: Option<String>,
+        history: Vec<Self>,
+        conn: &mut DbConn,
+    ) -> EmptyResult {
+        let network = Self::network_of(ip);
+        let now = Utc::now().naive_utc();
+
+        let (mut current, others): (Vec<Self>, Vec<Self>) = history.into_iter().partition(|l| l.network == network);
+        let mut location = current.pop().unwrap_or_else(|| Self {
+            uuid: get_uuid(),
+            user_uuid: user_uuid.clone(),
+            network,
+            country: None,
+            last_seen: now,
+        });
+        location.country = country.or(location.country);
+        location.last_seen = now;
+        location.save(conn).await?;
+
+        let expired: Vec<String> =
+            current.into_iter().chain(others.into_iter().skip(Self::HISTORY_SIZE - 1)).map(|l| l.uuid).collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        db_run! { conn: {
+            diesel::delete(login_locations::table.filter(login_locations::uuid.eq_any(expired)))
+                .execute(conn)
+                .map_res("Error pruning login locations")
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &UserId, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(login_locations::table.filter(login_locations::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting login locations")
+        }}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(ip: &str, country: Option<&str>) -> LoginLocation {
+        LoginLocation {
+            uuid: get_uuid(),
+            user_uuid: UserId::from(get_uuid()),
+            network: LoginLocation::network_of(&ip.parse().unwrap()),
+            country: country.map(String::from),
+            last_seen: Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn test_login_from_known_network() {
+        let history = [location("203.0.113.7", None), location("2001:db8::1", None)];
+
+        assert!(!LoginLocation::is_anomaly(&history, &"203.0.200.1".parse().unwrap(), None));
+        assert!(!LoginLocation::is_anomaly(&history, &"2001:db8:ffff::1".parse().unwrap(), None));
+        assert!(LoginLocation::is_anomaly(&history, &"198.51.100.1".parse().unwrap(), None));
+        assert!(!LoginLocation::is_anomaly(&[], &"198.51.100.1".parse().unwrap(), None));
+    }
+
+    #[test]
+    fn test_login_country_is_preferred_over_network() {
+        let history = [location("203.0.113.7", Some("NL"))];
+
+        assert!(!LoginLocation::is_anomaly(&history, &"198.51.100.1".parse().unwrap(), Some("NL")));
+        assert!(LoginLocation::is_anomaly(&history, &"203.0.113.8".parse().unwrap(), Some("US")));
+        // Without a country for this login, fall back to the network
+        assert!(LoginLocation::is_anomaly(&history, &"198.51.100.1".parse().unwrap(), None));
+    }
+}
@@ -8,8 +8,12 @@ mod event;
 mod favorite;
 mod folder;
 mod group;
+mod key_history;
+mod login_location;
 mod org_policy;
 mod organization;
+mod password_history;
+mod revoked_session;
 mod send;
 mod sso_nonce;
 mod two_factor;
@@ -21,17 +25,21 @@ pub use self::attachment::{Attachment, AttachmentId};
 pub use self::auth_request::{AuthRequest, AuthRequestId};
 pub use self::cipher::{Cipher, CipherId, RepromptType};
 pub use self::collection::{Collection, CollectionCipher, CollectionId, CollectionUser};
-pub use self::device::{Device, DeviceId, DeviceType, PushId};
+pub use self::device::{Device, DeviceId, DeviceImport, DeviceType, PushCategory, PushId};
 pub use self::emergency_access::{EmergencyAccess, EmergencyAccessId, EmergencyAccessStatus, EmergencyAccessType};
 pub use self::event::{Event, EventType};
 pub use self::favorite::Favorite;
 pub use self::folder::{Folder, FolderCipher, FolderId};
 pub use self::group::{CollectionGroup, Group, GroupId, GroupUser};
+pub use self::key_history::{KeyChangeType, KeyHistory};
+pub use self::login_location::LoginLocation;
 pub use self::org_policy::{OrgPolicy, OrgPolicyErr, OrgPolicyId, OrgPolicyType};
 pub use self::organization::{
     Membership, MembershipId, MembershipStatus, MembershipType, OrgApiKeyId, Organization, OrganizationApiKey,
     OrganizationId,
 };
+pub use self::password_history::PasswordHistory;
+pub use self::revoked_session::RevokedSession;
 pub use self::send::{
     id::{SendFileId, SendId},
     Send, SendType,
@@ -40,4 +48,6 @@ pub use self::sso_nonce::SsoNonce;
 pub use self::two_factor::{TwoFactor, TwoFactorType};
 pub use self::two_factor_duo_context::TwoFactorDuoContext;
 pub use self::two_factor_incomplete::TwoFactorIncomplete;
-pub use self::user::{Invitation, SsoUser, User, UserId, UserKdfType, UserStampException};
+pub use self::user::{
+    Invitation, KdfThresholds, ReencryptedItems, SsoUser, User, UserId, UserKdfType, UserStampException,
+};
@@ -82,6 +82,12 @@ impl OrgPolicy {
         self.atype == policy_type as i32
     }
 
+    /// PIN unlock is only permitted when the server allows it and none of the organizations of the user removed it,
+    /// so a single organization forbidding it is enough.
+    pub fn is_pin_unlock_allowed(policies: &[Self], server_default: bool) -> bool {
+        server_default && !policies.iter().any(|p| p.enabled && p.has_type(OrgPolicyType::RemoveUnlockWithPin))
+    }
+
     pub fn to_json(&self) -> Value {
         let data_json: Value = serde_json::from_str(&self.data).unwrap_or(Value::Null);
         let mut policy = json!({
@@ -236,6 +242,27 @@ impl OrgPolicy {
         }}
     }
 
+    /// Returns the enabled policies of all organizations the user is an accepted or confirmed member of.
+    /// Policies of organizations where the user was revoked are not included.
+    pub async fn find_active_by_user(user_uuid: &UserId, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            org_policies::table
+                .inner_join(
+                    users_organizations::table.on(
+                        users_organizations::org_uuid.eq(org_policies::org_uuid)
+                            .and(users_organizations::user_uuid.eq(user_uuid)))
+                )
+                .filter(
+                    users_organizations::status.eq_any([MembershipStatus::Accepted as i32, MembershipStatus::Confirmed as i32])
+                )
+                .filter(org_policies::enabled.eq(true))
+                .select(org_policies::all_columns)
+                .load::<OrgPolicyDb>(conn)
+                .expect("Error loading org_policy")
+                .from_db()
+        }}
+    }
+
     pub async fn find_confirmed_by_user_and_active_policy(
         user_uuid: &UserId,
         policy_type: OrgPolicyType,
@@ -368,3 +395,26 @@ impl OrgPolicy {
 
 #[derive(Clone, Debug, AsRef, DieselNewType, From, FromForm, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct OrgPolicyId(String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(atype: OrgPolicyType, enabled: bool) -> OrgPolicy {
+        OrgPolicy::new(OrganizationId::from(crate::util::get_uuid()), atype, enabled, String::from("{}"))
+    }
+
+    #[test]
+    fn test_pin_unlock_forbidden_by_one_org() {
+        let policies = [
+            policy(OrgPolicyType::TwoFactorAuthentication, true),
+            policy(OrgPolicyType::RemoveUnlockWithPin, false),
+            policy(OrgPolicyType::RemoveUnlockWithPin, true),
+        ];
+
+        assert!(!OrgPolicy::is_pin_unlock_allowed(&policies, true));
+        assert!(OrgPolicy::is_pin_unlock_allowed(&policies[..2], true));
+        // The server default can't be relaxed by the organizations
+        assert!(!OrgPolicy::is_pin_unlock_allowed(&[], false));
+    }
+}
@@ -8,8 +8,8 @@ use std::{
 };
 
 use super::{
-    CipherId, Collection, CollectionGroup, CollectionId, CollectionUser, Group, GroupId, GroupUser, OrgPolicy,
-    OrgPolicyType, TwoFactor, User, UserId,
+    CipherId, Collection, CollectionGroup, CollectionId, CollectionUser, Group, GroupId, GroupUser, KdfThresholds,
+    OrgPolicy, OrgPolicyType, TwoFactor, User, UserId,
 };
 use crate::CONFIG;
 use macros::UuidFromParam;
@@ -25,6 +25,11 @@ db_object! {
         pub billing_email: String,
         pub private_key: Option<String>,
         pub public_key: Option<String>,
+        // Vaultwarden specific, the minimum KDF settings of users registering through an invite of the organization
+        pub kdf_min_pbkdf2_iterations: Option<i32>,
+        pub kdf_min_argon2_iterations: Option<i32>,
+        pub kdf_min_argon2_memory: Option<i32>,
+        pub kdf_min_argon2_parallelism: Option<i32>,
     }
 
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
@@ -44,6 +49,8 @@ db_object! {
         pub atype: i32,
         pub reset_password_key: Option<String>,
         pub external_id: Option<String>,
+        // JSON object with attributes the organization provides for the profile of the member, only editable by admins
+        pub profile_attributes: Option<String>,
     }
 
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
@@ -177,8 +184,59 @@ impl Organization {
             billing_email,
             private_key,
             public_key,
+            kdf_min_pbkdf2_iterations: None,
+            kdf_min_argon2_iterations: None,
+            kdf_min_argon2_memory: None,
+            kdf_min_argon2_parallelism: None,
         }
     }
+
+    /// The KDF minimums of the organization, unset ones don't restrict anything.
+    pub fn kdf_minimums(&self) -> KdfThresholds {
+        KdfThresholds {
+            pbkdf2_iterations: self.kdf_min_pbkdf2_iterations.unwrap_or(0),
+            argon2_iterations: self.kdf_min_argon2_iterations.unwrap_or(0),
+            argon2_memory: self.kdf_min_argon2_memory.unwrap_or(0),
+            argon2_parallelism: self.kdf_min_argon2_parallelism.unwrap_or(0),
+        }
+    }
+
+    /// Sets the KDF minimums, `None` removes a minimum.
+    /// The Argon2 settings are limited to what a client can register with, else nobody could join the organization.
+    pub fn set_kdf_minimums(
+        &mut self,
+        pbkdf2_iterations: Option<i32>,
+        argon2_iterations: Option<i32>,
+        argon2_memory: Option<i32>,
+        argon2_parallelism: Option<i32>,
+    ) -> EmptyResult {
+        if [pbkdf2_iterations, argon2_iterations, argon2_memory, argon2_parallelism].iter().flatten().any(|v| *v < 1) {
+            err!("KDF minimums must be at least 1")
+        }
+        if argon2_memory.is_some_and(|m| m > 1024) {
+            err!("The Argon2 memory minimum can't be more than 1024 MB")
+        }
+        if argon2_parallelism.is_some_and(|p| p > 16) {
+            err!("The Argon2 parallelism minimum can't be more than 16")
+        }
+
+        self.kdf_min_pbkdf2_iterations = pbkdf2_iterations;
+        self.kdf_min_argon2_iterations = argon2_iterations;
+        self.kdf_min_argon2_memory = argon2_memory;
+        self.kdf_min_argon2_parallelism = argon2_parallelism;
+        Ok(())
+    }
+
+    pub fn kdf_minimums_json(&self) -> Value {
+        json!({
+            "pbkdf2Iterations": self.kdf_min_pbkdf2_iterations,
+            "argon2Iterations": self.kdf_min_argon2_iterations,
+            "argon2Memory": self.kdf_min_argon2_memory,
+            "argon2Parallelism": self.kdf_min_argon2_parallelism,
+            "object": "kdfMinimums",
+        })
+    }
+
     // https://github.com/bitwarden/server/blob/9ebe16587175b1c0e9208f84397bb75d0d595510/src/Api/AdminConsole/Models/Response/Organizations/OrganizationResponseModel.cs
     pub fn to_json(&self) -> Value {
         json!({
@@ -251,9 +309,54 @@ impl Membership {
             atype: MembershipType::User as i32,
             reset_password_key: None,
             external_id: None,
+            profile_attributes: None,
         }
     }
 
+    /// Maximum number of profile attributes an organization can set per member
+    const MAX_PROFILE_ATTRIBUTES: usize = 20;
+
+    /// Stores the profile attributes of the organization for this member, an empty object removes them.
+    /// Only a flat object with short string values is accepted, the values are shown as-is by the clients.
+    pub fn set_profile_attributes(&mut self, attributes: &Value) -> EmptyResult {
+        let Some(attributes) = attributes.as_object() else {
+            err!("Profile attributes must be an object")
+        };
+        if attributes.len() > Self::MAX_PROFILE_ATTRIBUTES {
+            err!(format!("An organization can set at most {} profile attributes", Self::MAX_PROFILE_ATTRIBUTES))
+        }
+        for (name, value) in attributes {
+            if name.is_empty() || name.len() > 64 {
+                err!("Profile attribute names must be between 1 and 64 characters")
+            }
+            if !value.as_str().is_some_and(|v| v.len() <= 1000) {
+                err!(format!("Profile attribute `{name}` must be a string of at most 1000 characters"))
+            }
+        }
+
+        self.profile_attributes = if attributes.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(attributes)?)
+        };
+        Ok(())
+    }
+
+    pub fn profile_attributes(&self) -> Value {
+        self.profile_attributes.as_deref().and_then(|a| serde_json::from_str(a).ok()).unwrap_or_else(|| json!({}))
+    }
+
+    /// The profile attributes of the organizations a user is a confirmed member of, keyed by organization id.
+    /// They are kept apart from the personal profile fields, which always take precedence.
+    pub fn org_profile_attributes(memberships: &[Self]) -> Value {
+        let attributes: serde_json::Map<String, Value> = memberships
+            .iter()
+            .filter(|m| m.status == MembershipStatus::Confirmed as i32 && m.profile_attributes.is_some())
+            .map(|m| (m.org_uuid.to_string(), m.profile_attributes()))
+            .collect();
+        Value::Object(attributes)
+    }
+
     pub fn restore(&mut self) -> bool {
         if self.status < MembershipStatus::Invited as i32 {
             self.status += ACTIVATE_REVOKE_DIFF;
@@ -648,6 +751,7 @@ impl Membership {
             "name": if self.get_unrevoked_status() >= MembershipStatus::Accepted as i32 { Some(user.name) } else { None },
             "email": user.email,
             "externalId": self.external_id,
+            "profileAttributes": self.profile_attributes(),
             "avatarColor": user.avatar_color,
             "groups": groups,
             "collections": collections,
@@ -1270,6 +1374,45 @@ pub struct OrgApiKeyId(String);
 mod tests {
     use super::*;
 
+    fn confirmed_member(org_uuid: &OrganizationId) -> Membership {
+        let mut member = Membership::new(UserId::from(crate::util::get_uuid()), org_uuid.clone(), None);
+        member.status = MembershipStatus::Confirmed as i32;
+        member
+    }
+
+    #[test]
+    fn test_org_profile_attributes_for_members() {
+        let org = OrganizationId::from(crate::util::get_uuid());
+        let other_org = OrganizationId::from(crate::util::get_uuid());
+
+        let mut member = confirmed_member(&org);
+        member.set_profile_attributes(&json!({"department": "Finance", "displayName": "Doe, J."})).unwrap();
+        let plain_member = confirmed_member(&other_org);
+
+        let attributes = Membership::org_profile_attributes(&[member, plain_member]);
+        assert_eq!(attributes[org.to_string()]["department"], "Finance");
+        assert_eq!(attributes[org.to_string()]["displayName"], "Doe, J.");
+        // Organizations without attributes aren't listed
+        assert!(attributes.get(other_org.to_string()).is_none());
+    }
+
+    #[test]
+    fn test_org_profile_attributes_not_shown_to_non_members() {
+        let org = OrganizationId::from(crate::util::get_uuid());
+
+        let mut invited = confirmed_member(&org);
+        invited.set_profile_attributes(&json!({"department": "Finance"})).unwrap();
+        invited.status = MembershipStatus::Invited as i32;
+        assert_eq!(Membership::org_profile_attributes(&[invited]), json!({}));
+        assert_eq!(Membership::org_profile_attributes(&[]), json!({}));
+
+        let mut member = confirmed_member(&org);
+        assert!(member.set_profile_attributes(&json!(["Finance"])).is_err());
+        assert!(member.set_profile_attributes(&json!({"nested": {"a": 1}})).is_err());
+        member.set_profile_attributes(&json!({})).unwrap();
+        assert!(member.profile_attributes.is_none());
+    }
+
     #[test]
     #[allow(non_snake_case)]
     fn partial_cmp_MembershipType() {
@@ -0,0 +1,101 @@
+use chrono::{NaiveDateTime, Utc};
+
+use super::{User, UserId};
+use crate::{api::EmptyResult, crypto, db::DbConn, error::MapResult, util::get_uuid};
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = password_history)]
+    #[diesel(primary_key(uuid))]
+    pub struct PasswordHistory {
+        pub uuid: String,
+        pub user_uuid: UserId,
+        pub password_hash: Vec<u8>,
+        pub salt: Vec<u8>,
+        pub password_iterations: i32,
+        pub created_at: NaiveDateTime,
+    }
+}
+
+/// Local methods
+impl PasswordHistory {
+    /// Snapshot of the user's current master password hash, taken right before it gets replaced.
+    pub fn from_user(user: &User) -> Self {
+        Self {
+            uuid: get_uuid(),
+            user_uuid: user.uuid.clone(),
+            password_hash: user.password_hash.clone(),
+            salt: user.salt.clone(),
+            password_iterations: user.password_iterations,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn matches(&self, password: &str) -> bool {
+        crypto::verify_password_hash(
+            password.as_bytes(),
+            &self.salt,
+            &self.password_hash,
+            self.password_iterations as u32,
+        )
+    }
+}
+
+/// Database methods
+impl PasswordHistory {
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(password_history::table)
+                    .values(PasswordHistoryDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving password history")
+            }
+            postgresql {
+                let value = PasswordHistoryDb::to_db(self);
+                diesel::insert_into(password_history::table)
+                    .values(&value)
+                    .on_conflict(password_history::uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving password history")
+            }
+        }
+    }
+
+    /// Returns the stored entries of a user, newest first.
+    pub async fn find_by_user(user_uuid: &UserId, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            password_history::table
+                .filter(password_history::user_uuid.eq(user_uuid))
+                .order(password_history::created_at.desc())
+                .load::<PasswordHistoryDb>(conn)
+                .expect("Error loading password history")
+                .from_db()
+        }}
+    }
+
+    /// Only keep the `depth` most recent entries of a user.
+    pub async fn prune_by_user(user_uuid: &UserId, depth: usize, conn: &mut DbConn) -> EmptyResult {
+        let expired: Vec<String> =
+            Self::find_by_user(user_uuid, conn).await.into_iter().skip(depth).map(|h| h.uuid).collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        db_run! { conn: {
+            diesel::delete(password_history::table.filter(password_history::uuid.eq_any(expired)))
+                .execute(conn)
+                .map_res("Error pruning password history")
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &UserId, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(password_history::table.filter(password_history::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting password history")
+        }}
+    }
+}
@@ -0,0 +1,170 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use super::{DeviceId, UserId};
+use crate::{
+    api::EmptyResult,
+    db::DbConn,
+    error::MapResult,
+    util::{format_date, get_uuid},
+};
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[diesel(table_name = revoked_sessions)]
+    #[diesel(treat_none_as_null = true)]
+    #[diesel(primary_key(uuid))]
+    pub struct RevokedSession {
+        pub uuid: String,
+        pub user_uuid: UserId,
+        // When empty, the sessions of all devices were revoked
+        pub device_uuid: Option<DeviceId>,
+        // The device which triggered the revocation keeps its session
+        pub except_device_uuid: Option<DeviceId>,
+        pub revoked_at: NaiveDateTime,
+    }
+}
+
+/// Local methods
+impl RevokedSession {
+    /// Number of revocations kept per user
+    pub const HISTORY_SIZE: usize = 50;
+
+    /// Revocation of all sessions of a user, for example after a security stamp reset.
+    pub fn all_devices(user_uuid: UserId, except_device_uuid: Option<DeviceId>) -> Self {
+        Self {
+            uuid: get_uuid(),
+            user_uuid,
+            device_uuid: None,
+            except_device_uuid,
+            revoked_at: Utc::now().naive_utc(),
+        }
+    }
+
+    /// Revocation of the session of a single device, for example when it was logged out or deleted.
+    pub fn device(user_uuid: UserId, device_uuid: DeviceId) -> Self {
+        Self {
+            uuid: get_uuid(),
+            user_uuid,
+            device_uuid: Some(device_uuid),
+            except_device_uuid: None,
+            revoked_at: Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn applies_to(&self, device_uuid: &DeviceId) -> bool {
+        match &self.device_uuid {
+            Some(revoked) => revoked == device_uuid,
+            None => self.except_device_uuid.as_ref() != Some(device_uuid),
+        }
+    }
+
+    /// Returns true when this revocation ended the session of an access token of `device_uuid` issued at `issued_at`
+    pub fn revokes_token(&self, device_uuid: &DeviceId, issued_at: &NaiveDateTime) -> bool {
+        self.applies_to(device_uuid) && self.revoked_at >= *issued_at
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "deviceId": self.device_uuid,
+            "exceptDeviceId": self.except_device_uuid,
+            "revokedDate": format_date(&self.revoked_at),
+            "object": "revokedSession",
+        })
+    }
+}
+
+/// Database methods
+impl RevokedSession {
+    /// Stores the revocation, and only keeps the `HISTORY_SIZE` most recent ones of the user.
+    pub async fn save(&self, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(revoked_sessions::table)
+                    .values(RevokedSessionDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving revoked session")
+            }
+            postgresql {
+                let value = RevokedSessionDb::to_db(self);
+                diesel::insert_into(revoked_sessions::table)
+                    .values(&value)
+                    .on_conflict(revoked_sessions::uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving revoked session")
+            }
+        }?;
+
+        let expired: Vec<String> = Self::find_by_user(&self.user_uuid, conn)
+            .await
+            .into_iter()
+            .skip(Self::HISTORY_SIZE)
+            .map(|r| r.uuid)
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        db_run! { conn: {
+            diesel::delete(revoked_sessions::table.filter(revoked_sessions::uuid.eq_any(expired)))
+                .execute(conn)
+                .map_res("Error pruning revoked sessions")
+        }}
+    }
+
+    /// Returns the revocations of a user, most recent first.
+    pub async fn find_by_user(user_uuid: &UserId, conn: &mut DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            revoked_sessions::table
+                .filter(revoked_sessions::user_uuid.eq(user_uuid))
+                .order(revoked_sessions::revoked_at.desc())
+                .load::<RevokedSessionDb>(conn)
+                .expect("Error loading revoked sessions")
+                .from_db()
+        }}
+    }
+
+    pub async fn delete_all_by_user(user_uuid: &UserId, conn: &mut DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(revoked_sessions::table.filter(revoked_sessions::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting revoked sessions")
+        }}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_revoked_session_applies_to() {
+        let user = UserId::from(get_uuid());
+        let acting = DeviceId::from(get_uuid());
+        let other = DeviceId::from(get_uuid());
+
+        let all = RevokedSession::all_devices(user.clone(), Some(acting.clone()));
+        assert!(all.applies_to(&other));
+        assert!(!all.applies_to(&acting));
+        assert!(RevokedSession::all_devices(user.clone(), None).applies_to(&acting));
+
+        let single = RevokedSession::device(user, other.clone());
+        assert!(single.applies_to(&other));
+        assert!(!single.applies_to(&acting));
+    }
+
+    #[test]
+    fn test_revoked_session_revokes_older_tokens() {
+        let device = DeviceId::from(get_uuid());
+        let revocation = RevokedSession::device(UserId::from(get_uuid()), device.clone());
+
+        let before = revocation.revoked_at - chrono::TimeDelta::minutes(5);
+        let after = revocation.revoked_at + chrono::TimeDelta::minutes(5);
+        assert!(revocation.revokes_token(&device, &before));
+        // A new login after the revocation starts a new session
+        assert!(!revocation.revokes_token(&device, &after));
+        assert!(!revocation.revokes_token(&DeviceId::from(get_uuid()), &before));
+    }
+}
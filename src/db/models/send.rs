@@ -1,7 +1,13 @@
 use chrono::{NaiveDateTime, Utc};
 use serde_json::Value;
 
-use crate::{config::PathType, util::LowerCase, CONFIG};
+use crate::{
+    api::core::SendData,
+    config::PathType,
+    db::{length, sum_lengths},
+    util::LowerCase,
+    CONFIG,
+};
 
 use super::{OrganizationId, User, UserId};
 use id::SendId;
@@ -37,6 +43,8 @@ db_object! {
 
         pub disabled: bool,
         pub hide_email: Option<bool>,
+        // Account key version of the owner this send was last written with
+        pub key_version: Option<i32>,
     }
 }
 
@@ -75,6 +83,7 @@ impl Send {
 
             disabled: false,
             hide_email: None,
+            key_version: None,
         }
     }
 
@@ -103,6 +112,46 @@ impl Send {
         }
     }
 
+    /// Pre-validates a batch of sends, so that bulk operations like a key rotation can fail before anything is changed.
+    /// Uses the same limits as upstream, which only allows encrypted values of up to 1000 characters.
+    pub fn validate_send_data(send_data: &[SendData]) -> EmptyResult {
+        const MAX_ENCRYPTED_LENGTH: usize = 1000;
+
+        let mut validation_errors = serde_json::Map::new();
+        let mut check_length = |field: String, value: Option<&str>| {
+            if value.is_some_and(|v| v.len() > MAX_ENCRYPTED_LENGTH) {
+                let msg = format!(
+                    "The field {field} exceeds the maximum encrypted value length of {MAX_ENCRYPTED_LENGTH} characters."
+                );
+                validation_errors.insert(field, serde_json::to_value([msg]).unwrap());
+            }
+        };
+
+        for (index, send) in send_data.iter().enumerate() {
+            check_length(format!("Sends[{index}].Name"), Some(&send.name));
+            check_length(format!("Sends[{index}].Notes"), send.notes.as_deref());
+
+            if send.r#type == SendType::Text as i32 {
+                let text = send.text.as_ref().and_then(|t| t.get("text")).and_then(Value::as_str);
+                check_length(format!("Sends[{index}].Text.Text"), text);
+            } else if send.r#type == SendType::File as i32 {
+                let file_name = send.file.as_ref().and_then(|f| f.get("fileName")).and_then(Value::as_str);
+                check_length(format!("Sends[{index}].File.FileName"), file_name);
+            }
+        }
+
+        if !validation_errors.is_empty() {
+            let err_json = json!({
+                "message": "The model state is invalid.",
+                "validationErrors" : validation_errors,
+                "object": "error"
+            });
+            err_json!(err_json, "Send validation errors")
+        } else {
+            Ok(())
+        }
+    }
+
     pub async fn creator_identifier(&self, conn: &mut DbConn) -> Option<String> {
         if let Some(hide_email) = self.hide_email {
             if hide_email {
@@ -310,6 +359,24 @@ impl Send {
         }}
     }
 
+    /// Counts the sends of a user, and the stored length of their encrypted fields
+    pub async fn count_and_size_by_user(user_uuid: &UserId, conn: &mut DbConn) -> (i64, i64) {
+        db_run! {conn: {
+            sends::table
+                .filter(sends::user_uuid.eq(user_uuid))
+                .select((
+                    diesel::dsl::count_star(),
+                    diesel::dsl::sum(length(sends::name.nullable())),
+                    diesel::dsl::sum(length(sends::data.nullable())),
+                    diesel::dsl::sum(length(sends::akey.nullable())),
+                    diesel::dsl::sum(length(sends::notes)),
+                ))
+                .first::<(i64, Option<i64>, Option<i64>, Option<i64>, Option<i64>)>(conn)
+                .map(|(count, name, data, akey, notes)| (count, sum_lengths(&[name, data, akey, notes])))
+                .unwrap_or((0, 0))
+        }}
+    }
+
     pub async fn size_by_user(user_uuid: &UserId, conn: &mut DbConn) -> Option<i64> {
         let sends = Self::find_by_user(user_uuid, conn).await;
 
@@ -351,6 +418,38 @@ impl Send {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_data(name: &str, text: &str) -> SendData {
+        serde_json::from_value(json!({
+            "type": SendType::Text as i32,
+            "key": "2.key",
+            "deletionDate": "2030-01-01T00:00:00Z",
+            "disabled": false,
+            "name": name,
+            "text": { "text": text, "hidden": false },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_validate_send_data_accepts_regular_sends() {
+        let sends = [send_data("2.name", "2.text"), send_data("2.other", "2.text")];
+        assert!(Send::validate_send_data(&sends).is_ok());
+    }
+
+    #[test]
+    fn test_validate_send_data_rejects_oversized_send() {
+        let sends = [send_data("2.name", "2.text"), send_data("2.name", &"a".repeat(1001))];
+        assert!(Send::validate_send_data(&sends).is_err());
+
+        let sends = [send_data(&"a".repeat(1001), "2.text")];
+        assert!(Send::validate_send_data(&sends).is_err());
+    }
+}
+
 // separate namespace to avoid name collision with std::marker::Send
 pub mod id {
     use derive_more::{AsRef, Deref, Display, From};
@@ -3,7 +3,8 @@ use derive_more::{AsRef, Deref, Display, From};
 use serde_json::Value;
 
 use super::{
-    Cipher, Device, EmergencyAccess, Favorite, Folder, Membership, MembershipType, TwoFactor, TwoFactorIncomplete,
+    Attachment, Cipher, CipherId, Device, EmergencyAccess, EmergencyAccessStatus, Favorite, Folder, Membership,
+    MembershipType, OrgPolicy, OrganizationId, Send, TwoFactor, TwoFactorIncomplete,
 };
 use crate::{
     api::EmptyResult,
@@ -65,6 +66,31 @@ db_object! {
         pub avatar_color: Option<String>,
 
         pub external_id: Option<String>, // Todo: Needs to be removed in the future, this is not used anymore.
+
+        pub external_services: Option<bool>,
+        pub pending_approval: bool,
+        pub sso_only: bool,
+
+        // Per-user overrides of the global storage limits, in KB
+        pub attachment_limit: Option<i64>,
+        pub send_limit: Option<i64>,
+
+        // Fingerprint of the client certificate which has to be presented with the API key
+        pub api_key_cert_fingerprint: Option<String>,
+
+        // Incremented on every key rotation, the personal ciphers and sends are stamped with it when they are written
+        pub key_version: i32,
+
+        // Data residency region of the account, only set when residency regions are configured
+        pub region: Option<String>,
+
+        // Verified address which receives the account recovery mails instead of the login email
+        pub recovery_email: Option<String>,
+        pub recovery_email_new: Option<String>,
+        pub recovery_email_new_token: Option<String>,
+
+        // Whether the email had to be verified on signup, only those accounts are purged while unverified
+        pub signup_verification: bool,
     }
 
     #[derive(Identifiable, Queryable, Insertable)]
@@ -99,6 +125,60 @@ pub struct UserStampException {
     pub routes: Vec<String>,
     pub security_stamp: String,
     pub expire: i64,
+    // Set for the grace period after a security stamp reset, during which the previous stamp is accepted on every route
+    #[serde(default)]
+    pub any_route: bool,
+}
+
+impl UserStampException {
+    pub fn is_expired(&self) -> bool {
+        Utc::now().timestamp() > self.expire
+    }
+
+    pub fn allows_route(&self, route: &str) -> bool {
+        self.any_route || self.routes.iter().any(|r| r == route)
+    }
+}
+
+/// Minimum client side KDF settings, users below these thresholds are considered to use weak settings
+#[derive(Default)]
+pub struct KdfThresholds {
+    pub pbkdf2_iterations: i32,
+    pub argon2_iterations: i32,
+    pub argon2_memory: i32,
+    pub argon2_parallelism: i32,
+}
+
+impl KdfThresholds {
+    pub fn from_config() -> Self {
+        Self {
+            pbkdf2_iterations: CONFIG.kdf_min_pbkdf2_iterations(),
+            argon2_iterations: CONFIG.kdf_min_argon2_iterations(),
+            argon2_memory: CONFIG.kdf_min_argon2_memory(),
+            argon2_parallelism: CONFIG.kdf_min_argon2_parallelism(),
+        }
+    }
+
+    /// The highest of both thresholds, for every setting on its own
+    pub fn strictest(self, other: &Self) -> Self {
+        Self {
+            pbkdf2_iterations: self.pbkdf2_iterations.max(other.pbkdf2_iterations),
+            argon2_iterations: self.argon2_iterations.max(other.argon2_iterations),
+            argon2_memory: self.argon2_memory.max(other.argon2_memory),
+            argon2_parallelism: self.argon2_parallelism.max(other.argon2_parallelism),
+        }
+    }
+}
+
+/// The items of a user which is merged into another user, with their keys re-encrypted for the other user.
+/// Only the encrypted fields are taken over, everything else of the saved items stays as it is.
+#[derive(Default)]
+pub struct ReencryptedItems {
+    pub ciphers: Vec<Cipher>,
+    pub attachments: Vec<Attachment>,
+    pub folders: Vec<Folder>,
+    pub sends: Vec<Send>,
+    pub memberships: Vec<Membership>,
 }
 
 /// Local methods
@@ -151,6 +231,25 @@ impl User {
             avatar_color: None,
 
             external_id: None, // Todo: Needs to be removed in the future, this is not used anymore.
+
+            external_services: None,
+            pending_approval: false,
+            sso_only: false,
+
+            attachment_limit: None,
+            send_limit: None,
+
+            api_key_cert_fingerprint: None,
+
+            key_version: 0,
+
+            region: Some(CONFIG.residency_default_region()).filter(|r| !r.is_empty()),
+
+            recovery_email: None,
+            recovery_email_new: None,
+            recovery_email_new_token: None,
+
+            signup_verification: false,
         }
     }
 
@@ -163,6 +262,22 @@ impl User {
         )
     }
 
+    /// Whether optional third-party services (push relay, HIBP) may be used for this user.
+    /// When the user never set a preference, `EXTERNAL_SERVICES_DEFAULT` decides.
+    pub fn allows_external_services(&self) -> bool {
+        self.external_services.unwrap_or_else(|| CONFIG.external_services_default())
+    }
+
+    /// The attachment storage limit of this user in KB, falling back to the global limit when no override is set.
+    pub fn effective_attachment_limit(&self) -> Option<i64> {
+        self.attachment_limit.or_else(|| CONFIG.user_attachment_limit())
+    }
+
+    /// The send storage limit of this user in KB, falling back to the global limit when no override is set.
+    pub fn effective_send_limit(&self) -> Option<i64> {
+        self.send_limit.or_else(|| CONFIG.user_send_limit())
+    }
+
     pub fn check_valid_recovery_code(&self, recovery_code: &str) -> bool {
         if let Some(ref totp_recover) = self.totp_recover {
             crypto::ct_eq(recovery_code, totp_recover.to_lowercase())
@@ -175,6 +290,30 @@ impl User {
         matches!(self.api_key, Some(ref api_key) if crypto::ct_eq(api_key, key))
     }
 
+    /// Fingerprints are compared as lowercase hex, without the separators and `SHA256 Fingerprint=` prefix some proxies add
+    pub fn normalize_cert_fingerprint(fingerprint: &str) -> String {
+        let fingerprint = fingerprint.rsplit_once('=').map_or(fingerprint, |(_, hex)| hex);
+        fingerprint.chars().filter(char::is_ascii_hexdigit).collect::<String>().to_lowercase()
+    }
+
+    /// Without a stored fingerprint, the API key can be used without a client certificate
+    pub fn check_api_key_cert_fingerprint(&self, presented: Option<&str>) -> bool {
+        match (&self.api_key_cert_fingerprint, presented) {
+            (None, _) => true,
+            (Some(expected), Some(presented)) => crypto::ct_eq(expected, Self::normalize_cert_fingerprint(presented)),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Whether an item of the user was last written under another account key than the current one.
+    /// A rotation stamps every item it re-encrypts, so after the first one unstamped items were left behind as well.
+    pub fn is_stale_key_version(&self, item_key_version: Option<i32>) -> bool {
+        match item_key_version {
+            Some(version) => version != self.key_version,
+            None => self.key_version > 0,
+        }
+    }
+
     /// Set the password hash generated
     /// And resets the security_stamp. Based upon the allow_next_route the security_stamp will be different.
     ///
@@ -220,10 +359,28 @@ impl User {
     ///   After these 2 minutes this stamp will expire.
     ///
     pub fn set_stamp_exception(&mut self, route_exception: Vec<String>) {
+        self.set_stamp_exception_for(route_exception, 120);
+    }
+
+    /// Same as `set_stamp_exception`, but with a custom lifetime of the exception in seconds.
+    pub fn set_stamp_exception_for(&mut self, route_exception: Vec<String>, seconds: i64) {
         let stamp_exception = UserStampException {
             routes: route_exception,
             security_stamp: self.security_stamp.clone(),
-            expire: (Utc::now() + TimeDelta::try_minutes(2).unwrap()).timestamp(),
+            expire: (Utc::now() + TimeDelta::try_seconds(seconds).unwrap()).timestamp(),
+            any_route: false,
+        };
+        self.stamp_exception = Some(serde_json::to_string(&stamp_exception).unwrap_or_default());
+    }
+
+    /// Keeps the current security stamp valid on every route for the next `seconds`, has to be called before resetting the stamp.
+    /// Requests which were already in flight can complete, after the grace period only the new stamp is accepted.
+    pub fn set_stamp_grace_period(&mut self, seconds: i64) {
+        let stamp_exception = UserStampException {
+            routes: Vec::new(),
+            security_stamp: self.security_stamp.clone(),
+            expire: (Utc::now() + TimeDelta::try_seconds(seconds).unwrap()).timestamp(),
+            any_route: true,
         };
         self.stamp_exception = Some(serde_json::to_string(&stamp_exception).unwrap_or_default());
     }
@@ -232,17 +389,60 @@ impl User {
     pub fn reset_stamp_exception(&mut self) {
         self.stamp_exception = None;
     }
+
+    /// Items can only be moved to another user without re-encrypting them,
+    /// when both have the same keys and client side KDF settings.
+    pub fn has_same_keys(&self, other: &User) -> bool {
+        self.akey == other.akey
+            && self.private_key == other.private_key
+            && self.public_key == other.public_key
+            && self.client_kdf_type == other.client_kdf_type
+            && self.client_kdf_iter == other.client_kdf_iter
+            && self.client_kdf_memory == other.client_kdf_memory
+            && self.client_kdf_parallelism == other.client_kdf_parallelism
+    }
+
+    /// Returns true when the email had to be verified on signup but never was,
+    /// and the account was created and last sent a verification email before `cutoff`
+    pub fn is_stale_unverified(&self, cutoff: &NaiveDateTime) -> bool {
+        self.signup_verification
+            && self.verified_at.is_none()
+            && self.created_at < *cutoff
+            && self.last_verifying_at.is_none_or(|last_verifying_at| last_verifying_at < *cutoff)
+    }
+
+    /// The address account recovery mails, like the password hint and account deletion, are sent to.
+    /// A recovery email is only used once it was verified, until then it's the login email.
+    pub fn recovery_address(&self) -> &str {
+        self.recovery_email.as_deref().unwrap_or(&self.email)
+    }
+
+    /// Returns true when the client side KDF settings are below the thresholds, the same check as `find_weak_kdf`
+    pub fn has_weak_kdf(&self, thresholds: &KdfThresholds) -> bool {
+        match self.client_kdf_type {
+            t if t == UserKdfType::Pbkdf2 as i32 => self.client_kdf_iter < thresholds.pbkdf2_iterations,
+            t if t == UserKdfType::Argon2id as i32 => {
+                self.client_kdf_iter < thresholds.argon2_iterations
+                    || self.client_kdf_memory.is_none_or(|m| m < thresholds.argon2_memory)
+                    || self.client_kdf_parallelism.is_none_or(|p| p < thresholds.argon2_parallelism)
+            }
+            _ => false,
+        }
+    }
 }
 
 /// Database methods
 impl User {
     pub async fn to_json(&self, conn: &mut DbConn) -> Value {
+        let memberships = Membership::find_confirmed_by_user(&self.uuid, conn).await;
         let mut orgs_json = Vec::new();
-        for c in Membership::find_confirmed_by_user(&self.uuid, conn).await {
+        for c in &memberships {
             orgs_json.push(c.to_json(conn).await);
         }
 
         let twofactor_enabled = !TwoFactor::find_by_user(&self.uuid, conn).await.is_empty();
+        let org_count = Membership::count_accepted_and_confirmed_by_user(&self.uuid, conn).await;
+        let policies = OrgPolicy::find_active_by_user(&self.uuid, conn).await;
 
         // TODO: Might want to save the status field in the DB
         let status = if self.password_hash.is_empty() {
@@ -257,6 +457,10 @@ impl User {
             "name": self.name,
             "email": self.email,
             "emailVerified": !CONFIG.mail_enabled() || self.verified_at.is_some(),
+            "pendingEmail": self.email_new,
+            // Vaultwarden specific, the verified address which receives the account recovery mails
+            "recoveryEmail": self.recovery_email,
+            "pendingRecoveryEmail": self.recovery_email_new,
             "premium": true,
             "premiumFromOrganization": false,
             "culture": "en-US",
@@ -265,11 +469,19 @@ impl User {
             "privateKey": self.private_key,
             "securityStamp": self.security_stamp,
             "organizations": orgs_json,
+            // Vaultwarden specific, read-only attributes set by the organizations, keyed by organization id
+            "organizationProfileAttributes": Membership::org_profile_attributes(&memberships),
             "providers": [],
             "providerOrganizations": [],
             "forcePasswordReset": false,
             "avatarColor": self.avatar_color,
+            "externalServices": self.external_services,
+            "ssoOnly": self.sso_only,
+            "organizationCount": org_count,
+            "organizationLimit": Some(CONFIG.max_orgs_per_user()).filter(|l| *l != 0),
             "usesKeyConnector": false,
+            // Vaultwarden specific, clients should not offer to unlock with a PIN when this is false
+            "pinUnlockAllowed": OrgPolicy::is_pin_unlock_allowed(&policies, CONFIG.pin_unlock_allowed()),
             "creationDate": format_date(&self.created_at),
             "object": "profile",
         })
@@ -325,6 +537,10 @@ impl User {
         Device::delete_all_by_user(&self.uuid, conn).await?;
         TwoFactor::delete_all_by_user(&self.uuid, conn).await?;
         TwoFactorIncomplete::delete_all_by_user(&self.uuid, conn).await?;
+        super::PasswordHistory::delete_all_by_user(&self.uuid, conn).await?;
+        super::LoginLocation::delete_all_by_user(&self.uuid, conn).await?;
+        super::RevokedSession::delete_all_by_user(&self.uuid, conn).await?;
+        super::KeyHistory::delete_all_by_user(&self.uuid, conn).await?;
         Invitation::take(&self.email, conn).await; // Delete invitation if any
 
         db_run! {conn: {
@@ -334,6 +550,160 @@ impl User {
         }}
     }
 
+    /// Merges this user into `target`: the ciphers, folders, sends, favorites and memberships are moved over,
+    /// and this user is deleted afterwards, all in one transaction. Memberships of organizations the target is already
+    /// a member of, and favorites the target already has, are deleted together with this user.
+    /// Unless both users have the same keys, `reencrypted` needs to contain every item, encrypted for the target.
+    pub async fn merge_into(self, target: &User, reencrypted: ReencryptedItems, conn: &mut DbConn) -> EmptyResult {
+        for member in Membership::find_confirmed_by_user(&self.uuid, conn).await {
+            if member.atype == MembershipType::Owner
+                && Membership::find_by_user_and_org(&target.uuid, &member.org_uuid, conn).await.is_some()
+                && Membership::count_confirmed_by_org_and_type(&member.org_uuid, MembershipType::Owner, conn).await <= 1
+            {
+                err!("Can't delete last owner")
+            }
+        }
+
+        let source = &self.uuid;
+        let email = &self.email;
+        let key_version = target.key_version;
+        let target = &target.uuid;
+        db_run! {conn: {
+            conn.transaction::<_, diesel::result::Error, _>(|conn| {
+                for cipher in &reencrypted.ciphers {
+                    diesel::update(
+                        ciphers::table.filter(ciphers::uuid.eq(&cipher.uuid)).filter(ciphers::user_uuid.eq(source)),
+                    )
+                    .set((
+                        ciphers::key.eq(&cipher.key),
+                        ciphers::name.eq(&cipher.name),
+                        ciphers::notes.eq(&cipher.notes),
+                        ciphers::fields.eq(&cipher.fields),
+                        ciphers::data.eq(&cipher.data),
+                        ciphers::password_history.eq(&cipher.password_history),
+                    ))
+                    .execute(conn)?;
+                }
+                for attachment in &reencrypted.attachments {
+                    diesel::update(attachments::table.filter(attachments::id.eq(&attachment.id)))
+                        .set((attachments::akey.eq(&attachment.akey), attachments::file_name.eq(&attachment.file_name)))
+                        .execute(conn)?;
+                }
+                for folder in &reencrypted.folders {
+                    diesel::update(
+                        folders::table.filter(folders::uuid.eq(&folder.uuid)).filter(folders::user_uuid.eq(source)),
+                    )
+                    .set(folders::name.eq(&folder.name))
+                    .execute(conn)?;
+                }
+                for send in &reencrypted.sends {
+                    diesel::update(sends::table.filter(sends::uuid.eq(&send.uuid)).filter(sends::user_uuid.eq(source)))
+                        .set(sends::akey.eq(&send.akey))
+                        .execute(conn)?;
+                }
+                for member in &reencrypted.memberships {
+                    diesel::update(
+                        users_organizations::table
+                            .filter(users_organizations::uuid.eq(&member.uuid))
+                            .filter(users_organizations::user_uuid.eq(source)),
+                    )
+                    .set((
+                        users_organizations::akey.eq(&member.akey),
+                        users_organizations::reset_password_key.eq(&member.reset_password_key),
+                    ))
+                    .execute(conn)?;
+                }
+
+                // The moved items are encrypted with the keys of the target now
+                diesel::update(ciphers::table.filter(ciphers::user_uuid.eq(source)))
+                    .set((ciphers::user_uuid.eq(target), ciphers::key_version.eq(key_version)))
+                    .execute(conn)?;
+                diesel::update(folders::table.filter(folders::user_uuid.eq(source)))
+                    .set(folders::user_uuid.eq(target))
+                    .execute(conn)?;
+                diesel::update(sends::table.filter(sends::user_uuid.eq(source)))
+                    .set((sends::user_uuid.eq(target), sends::key_version.eq(key_version)))
+                    .execute(conn)?;
+
+                let target_favorites: Vec<CipherId> = favorites::table
+                    .filter(favorites::user_uuid.eq(target))
+                    .select(favorites::cipher_uuid)
+                    .load(conn)?;
+                diesel::update(
+                    favorites::table
+                        .filter(favorites::user_uuid.eq(source))
+                        .filter(favorites::cipher_uuid.ne_all(target_favorites)),
+                )
+                .set(favorites::user_uuid.eq(target))
+                .execute(conn)?;
+
+                // The collection access is stored per user, so it moves along with the membership
+                let target_orgs: Vec<OrganizationId> = users_organizations::table
+                    .filter(users_organizations::user_uuid.eq(target))
+                    .select(users_organizations::org_uuid)
+                    .load(conn)?;
+                diesel::update(
+                    users_collections::table
+                        .filter(users_collections::user_uuid.eq(source))
+                        .filter(users_collections::collection_uuid.eq_any(
+                            collections::table
+                                .filter(collections::org_uuid.ne_all(&target_orgs))
+                                .select(collections::uuid),
+                        )),
+                )
+                .set(users_collections::user_uuid.eq(target))
+                .execute(conn)?;
+                diesel::update(
+                    users_organizations::table
+                        .filter(users_organizations::user_uuid.eq(source))
+                        .filter(users_organizations::org_uuid.ne_all(&target_orgs)),
+                )
+                .set(users_organizations::user_uuid.eq(target))
+                .execute(conn)?;
+
+                // Everything which is left only belongs to the source user, the same as what `delete()` removes
+                diesel::delete(users_collections::table.filter(users_collections::user_uuid.eq(source))).execute(conn)?;
+                diesel::delete(
+                    groups_users::table.filter(
+                        groups_users::users_organizations_uuid.eq_any(
+                            users_organizations::table
+                                .filter(users_organizations::user_uuid.eq(source))
+                                .select(users_organizations::uuid),
+                        ),
+                    ),
+                )
+                .execute(conn)?;
+                diesel::delete(users_organizations::table.filter(users_organizations::user_uuid.eq(source)))
+                    .execute(conn)?;
+                diesel::delete(favorites::table.filter(favorites::user_uuid.eq(source))).execute(conn)?;
+                diesel::delete(
+                    emergency_access::table.filter(
+                        emergency_access::grantor_uuid
+                            .eq(source)
+                            .or(emergency_access::grantee_uuid.eq(source))
+                            .or(emergency_access::email
+                                .eq(email)
+                                .and(emergency_access::status.eq(EmergencyAccessStatus::Invited as i32))),
+                    ),
+                )
+                .execute(conn)?;
+                diesel::delete(devices::table.filter(devices::user_uuid.eq(source))).execute(conn)?;
+                diesel::delete(twofactor::table.filter(twofactor::user_uuid.eq(source))).execute(conn)?;
+                diesel::delete(twofactor_incomplete::table.filter(twofactor_incomplete::user_uuid.eq(source)))
+                    .execute(conn)?;
+                diesel::delete(password_history::table.filter(password_history::user_uuid.eq(source))).execute(conn)?;
+                diesel::delete(login_locations::table.filter(login_locations::user_uuid.eq(source))).execute(conn)?;
+                diesel::delete(revoked_sessions::table.filter(revoked_sessions::user_uuid.eq(source))).execute(conn)?;
+                diesel::delete(key_history::table.filter(key_history::user_uuid.eq(source))).execute(conn)?;
+                diesel::delete(invitations::table.filter(invitations::email.eq(email))).execute(conn)?;
+                diesel::delete(users::table.filter(users::uuid.eq(source))).execute(conn)?;
+
+                Ok(())
+            })
+            .map_res("Error merging the user into the other user")
+        }}
+    }
+
     pub async fn update_uuid_revision(uuid: &UserId, conn: &mut DbConn) {
         if let Err(e) = Self::_update_revision(uuid, &Utc::now().naive_utc(), conn).await {
             warn!("Failed to update revision for {uuid}: {e:#?}");
@@ -381,6 +751,47 @@ impl User {
         }}
     }
 
+    /// Returns the accounts which had to verify their email on signup, but never did
+    pub async fn find_unverified_created_before(cutoff: &NaiveDateTime, conn: &mut DbConn) -> Vec<Self> {
+        db_run! {conn: {
+            users::table
+                .filter(users::signup_verification.eq(true))
+                .filter(users::verified_at.is_null())
+                .filter(users::created_at.lt(cutoff))
+                .load::<UserDb>(conn)
+                .expect("Error loading unverified users")
+                .from_db()
+        }}
+    }
+
+    /// Returns the users with client side KDF settings below the thresholds, ordered by email
+    pub async fn find_weak_kdf(thresholds: &KdfThresholds, limit: i64, offset: i64, conn: &mut DbConn) -> Vec<Self> {
+        db_run! {conn: {
+            users::table
+                .filter(
+                    users::client_kdf_type
+                        .eq(UserKdfType::Pbkdf2 as i32)
+                        .and(users::client_kdf_iter.lt(thresholds.pbkdf2_iterations))
+                        .nullable()
+                        .or(users::client_kdf_type.eq(UserKdfType::Argon2id as i32).nullable().and(
+                            users::client_kdf_iter
+                                .lt(thresholds.argon2_iterations)
+                                .nullable()
+                                .or(users::client_kdf_memory.is_null().nullable())
+                                .or(users::client_kdf_memory.lt(thresholds.argon2_memory))
+                                .or(users::client_kdf_parallelism.is_null().nullable())
+                                .or(users::client_kdf_parallelism.lt(thresholds.argon2_parallelism)),
+                        )),
+                )
+                .order(users::email.asc())
+                .limit(limit)
+                .offset(offset)
+                .load::<UserDb>(conn)
+                .expect("Error loading users")
+                .from_db()
+        }}
+    }
+
     pub async fn find_by_uuid(uuid: &UserId, conn: &mut DbConn) -> Option<Self> {
         db_run! {conn: {
             users::table.filter(users::uuid.eq(uuid)).first::<UserDb>(conn).ok().from_db()
@@ -412,6 +823,17 @@ impl User {
         }}
     }
 
+    pub async fn find_pending_approval(conn: &mut DbConn) -> Vec<Self> {
+        db_run! {conn: {
+            users::table
+                .filter(users::pending_approval.eq(true))
+                .order(users::created_at.asc())
+                .load::<UserDb>(conn)
+                .expect("Error loading users pending approval")
+                .from_db()
+        }}
+    }
+
     pub async fn last_active(&self, conn: &mut DbConn) -> Option<NaiveDateTime> {
         match Device::find_latest_active_by_user(&self.uuid, conn).await {
             Some(device) => Some(device.updated_at),
@@ -553,3 +975,167 @@ impl SsoUser {
         }}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamp_exception(user: &User) -> UserStampException {
+        serde_json::from_str(user.stamp_exception.as_deref().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_recovery_mails_sent_to_verified_recovery_email() {
+        let mut user = User::new(String::from("user@example.com"), None);
+        assert_eq!(user.recovery_address(), "user@example.com");
+
+        // A pending recovery email isn't used before it's verified
+        user.recovery_email_new = Some(String::from("recovery@example.org"));
+        assert_eq!(user.recovery_address(), "user@example.com");
+
+        user.recovery_email = user.recovery_email_new.take();
+        assert_eq!(user.recovery_address(), "recovery@example.org");
+    }
+
+    #[test]
+    fn test_previous_stamp_accepted_within_grace_period() {
+        let mut user = User::new(String::from("user@example.com"), None);
+        let previous_stamp = user.security_stamp.clone();
+        user.set_stamp_grace_period(30);
+        user.reset_security_stamp();
+
+        let exception = stamp_exception(&user);
+        assert_ne!(user.security_stamp, previous_stamp);
+        assert_eq!(exception.security_stamp, previous_stamp);
+        assert!(!exception.is_expired());
+        assert!(exception.allows_route("get_sync"));
+        assert!(exception.allows_route("post_ciphers"));
+    }
+
+    #[test]
+    fn test_previous_stamp_rejected_after_grace_period() {
+        let mut user = User::new(String::from("user@example.com"), None);
+        user.set_stamp_grace_period(-1);
+        user.reset_security_stamp();
+        assert!(stamp_exception(&user).is_expired());
+
+        // Route exceptions stored before the grace period existed still only allow their own routes
+        user.stamp_exception = Some(String::from(r#"{"routes":["post_login"],"security_stamp":"stamp","expire":0}"#));
+        let exception = stamp_exception(&user);
+        assert!(exception.allows_route("post_login"));
+        assert!(!exception.allows_route("get_sync"));
+    }
+
+    #[test]
+    fn test_api_key_cert_fingerprint_matches() {
+        let mut user = User::new(String::from("user@example.com"), None);
+        // Without a stored fingerprint there is nothing to check
+        assert!(user.check_api_key_cert_fingerprint(None));
+
+        user.api_key_cert_fingerprint = Some(User::normalize_cert_fingerprint("AB:CD:EF:01:23"));
+        assert!(user.check_api_key_cert_fingerprint(Some("abcdef0123")));
+        assert!(user.check_api_key_cert_fingerprint(Some("SHA256 Fingerprint=AB:CD:EF:01:23")));
+    }
+
+    #[test]
+    fn test_api_key_cert_fingerprint_mismatch() {
+        let mut user = User::new(String::from("user@example.com"), None);
+        user.api_key_cert_fingerprint = Some(String::from("abcdef0123"));
+
+        assert!(!user.check_api_key_cert_fingerprint(Some("abcdef0124")));
+        assert!(!user.check_api_key_cert_fingerprint(Some("")));
+        assert!(!user.check_api_key_cert_fingerprint(None));
+    }
+
+    #[test]
+    fn test_only_stale_unverified_accounts_are_purged() {
+        let cutoff = Utc::now().naive_utc() - TimeDelta::try_days(30).unwrap();
+        let stale_date = cutoff - TimeDelta::try_days(1).unwrap();
+
+        let mut verified = User::new(String::from("verified@example.com"), None);
+        verified.created_at = stale_date;
+        verified.verified_at = Some(stale_date);
+
+        let mut unverified = User::new(String::from("unverified@example.com"), None);
+        unverified.created_at = stale_date;
+        unverified.signup_verification = true;
+
+        // Created before signups had to verify their email
+        let mut legacy = User::new(String::from("legacy@example.com"), None);
+        legacy.created_at = stale_date;
+
+        let users = [verified, unverified, legacy];
+        let purged: Vec<&str> =
+            users.iter().filter(|u| u.is_stale_unverified(&cutoff)).map(|u| u.email.as_str()).collect();
+        assert_eq!(purged, vec!["unverified@example.com"]);
+    }
+
+    #[test]
+    fn test_recent_verification_request_is_kept() {
+        let cutoff = Utc::now().naive_utc() - TimeDelta::try_days(30).unwrap();
+
+        let mut user = User::new(String::from("unverified@example.com"), None);
+        user.created_at = cutoff - TimeDelta::try_days(1).unwrap();
+        user.signup_verification = true;
+        user.last_verifying_at = Some(Utc::now().naive_utc());
+        assert!(!user.is_stale_unverified(&cutoff));
+
+        // Accounts created within the grace period are always kept
+        let new_user = User::new(String::from("new@example.com"), None);
+        assert!(!new_user.is_stale_unverified(&cutoff));
+    }
+
+    #[cfg(sqlite)]
+    #[test]
+    fn test_merge_moves_reencrypted_items_and_deletes_source() {
+        use crate::db::models::{MembershipStatus, Organization};
+
+        crate::db::test_db::run(|pool| async move {
+            let mut conn = pool.get().await.unwrap();
+            let mut target = User::new(String::from("user@example.com"), None);
+            target.key_version = 3;
+            target.save(&mut conn).await.unwrap();
+            let mut source = User::new(String::from("user+alias@example.com"), None);
+            source.save(&mut conn).await.unwrap();
+
+            let mut cipher = Cipher::new(1, String::from("2.source-name"));
+            cipher.user_uuid = Some(source.uuid.clone());
+            cipher.save(&mut conn).await.unwrap();
+            let mut folder = Folder::new(source.uuid.clone(), String::from("2.source-folder"));
+            folder.save(&mut conn).await.unwrap();
+            let org = Organization::new(String::from("Org"), String::from("org@example.com"), None, None);
+            org.save(&mut conn).await.unwrap();
+            let mut member = Membership::new(source.uuid.clone(), org.uuid.clone(), None);
+            member.status = MembershipStatus::Confirmed as i32;
+            member.akey = String::from("4.source-org-key");
+            member.reset_password_key = Some(String::from("4.source-user-key"));
+            member.save(&mut conn).await.unwrap();
+
+            let mut reencrypted = ReencryptedItems::default();
+            let mut cipher = Cipher::find_by_uuid(&cipher.uuid, &mut conn).await.unwrap();
+            cipher.name = String::from("2.target-name");
+            reencrypted.ciphers.push(cipher);
+            let mut folder = Folder::find_by_uuid_and_user(&folder.uuid, &source.uuid, &mut conn).await.unwrap();
+            folder.name = String::from("2.target-folder");
+            reencrypted.folders.push(folder);
+            member.akey = String::from("4.target-org-key");
+            member.reset_password_key = None;
+            reencrypted.memberships.push(member);
+
+            let (source_id, cipher_id, folder_id) =
+                (source.uuid.clone(), reencrypted.ciphers[0].uuid.clone(), reencrypted.folders[0].uuid.clone());
+            source.merge_into(&target, reencrypted, &mut conn).await.unwrap();
+
+            assert!(User::find_by_uuid(&source_id, &mut conn).await.is_none());
+            let cipher = Cipher::find_by_uuid(&cipher_id, &mut conn).await.unwrap();
+            assert_eq!(cipher.user_uuid.as_ref(), Some(&target.uuid));
+            assert_eq!(cipher.name, "2.target-name");
+            assert_eq!(cipher.key_version, Some(3));
+            let folder = Folder::find_by_uuid_and_user(&folder_id, &target.uuid, &mut conn).await.unwrap();
+            assert_eq!(folder.name, "2.target-folder");
+            let member = Membership::find_by_user_and_org(&target.uuid, &org.uuid, &mut conn).await.unwrap();
+            assert_eq!(member.akey, "4.target-org-key");
+            assert!(member.reset_password_key.is_none());
+        });
+    }
+}
@@ -24,6 +24,7 @@ table! {
         password_history -> Nullable<Text>,
         deleted_at -> Nullable<Timestamp>,
         reprompt -> Nullable<Integer>,
+        key_version -> Nullable<Integer>,
     }
 }
 
@@ -55,6 +56,15 @@ table! {
         push_token -> Nullable<Text>,
         refresh_token -> Text,
         twofactor_remember -> Nullable<Text>,
+        push_registration_success -> Nullable<Bool>,
+        push_registration_date -> Nullable<Timestamp>,
+        push_registration_error -> Nullable<Text>,
+        pending_approval -> Bool,
+        approval_public_key -> Nullable<Text>,
+        encrypted_user_key -> Nullable<Text>,
+        push_muted -> Integer,
+        deleted_at -> Nullable<Timestamp>,
+        session_public_key -> Nullable<Text>,
     }
 }
 
@@ -126,6 +136,10 @@ table! {
         billing_email -> Text,
         private_key -> Nullable<Text>,
         public_key -> Nullable<Text>,
+        kdf_min_pbkdf2_iterations -> Nullable<Integer>,
+        kdf_min_argon2_iterations -> Nullable<Integer>,
+        kdf_min_argon2_memory -> Nullable<Integer>,
+        kdf_min_argon2_parallelism -> Nullable<Integer>,
     }
 }
 
@@ -150,6 +164,7 @@ table! {
         deletion_date -> Timestamp,
         disabled -> Bool,
         hide_email -> Nullable<Bool>,
+        key_version -> Nullable<Integer>,
     }
 }
 
@@ -217,6 +232,18 @@ table! {
         api_key -> Nullable<Text>,
         avatar_color -> Nullable<Text>,
         external_id -> Nullable<Text>,
+        external_services -> Nullable<Bool>,
+        pending_approval -> Bool,
+        sso_only -> Bool,
+        attachment_limit -> Nullable<BigInt>,
+        send_limit -> Nullable<BigInt>,
+        api_key_cert_fingerprint -> Nullable<Text>,
+        key_version -> Integer,
+        region -> Nullable<Text>,
+        recovery_email -> Nullable<Text>,
+        recovery_email_new -> Nullable<Text>,
+        recovery_email_new_token -> Nullable<Text>,
+        signup_verification -> Bool,
     }
 }
 
@@ -242,6 +269,7 @@ table! {
         atype -> Integer,
         reset_password_key -> Nullable<Text>,
         external_id -> Nullable<Text>,
+        profile_attributes -> Nullable<Text>,
     }
 }
 
@@ -255,6 +283,47 @@ table! {
     }
 }
 
+table! {
+    password_history (uuid) {
+        uuid -> Text,
+        user_uuid -> Text,
+        password_hash -> Binary,
+        salt -> Binary,
+        password_iterations -> Integer,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    login_locations (uuid) {
+        uuid -> Text,
+        user_uuid -> Text,
+        network -> Text,
+        country -> Nullable<Text>,
+        last_seen -> Timestamp,
+    }
+}
+
+table! {
+    revoked_sessions (uuid) {
+        uuid -> Text,
+        user_uuid -> Text,
+        device_uuid -> Nullable<Text>,
+        except_device_uuid -> Nullable<Text>,
+        revoked_at -> Timestamp,
+    }
+}
+
+table! {
+    key_history (uuid) {
+        uuid -> Text,
+        user_uuid -> Text,
+        version -> Integer,
+        change_type -> Integer,
+        changed_at -> Timestamp,
+    }
+}
+
 table! {
     sso_nonce (state) {
         state -> Text,
@@ -335,6 +404,7 @@ table! {
         creation_date -> Timestamp,
         response_date -> Nullable<Timestamp>,
         authentication_date -> Nullable<Timestamp>,
+        challenge_nonce -> Nullable<Text>,
     }
 }
 
@@ -367,6 +437,10 @@ joinable!(collections_groups -> groups (groups_uuid));
 joinable!(event -> users_organizations (uuid));
 joinable!(auth_requests -> users (user_uuid));
 joinable!(sso_users -> users (user_uuid));
+joinable!(password_history -> users (user_uuid));
+joinable!(login_locations -> users (user_uuid));
+joinable!(revoked_sessions -> users (user_uuid));
+joinable!(key_history -> users (user_uuid));
 
 allow_tables_to_appear_in_same_query!(
     attachments,
@@ -392,4 +466,8 @@ allow_tables_to_appear_in_same_query!(
     collections_groups,
     event,
     auth_requests,
+    password_history,
+    login_locations,
+    revoked_sessions,
+    key_history,
 );
@@ -234,6 +234,30 @@ pub async fn send_welcome(address: &str) -> EmptyResult {
     send_email(address, &subject, body_html, body_text).await
 }
 
+pub async fn send_registration_pending_approval(address: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/registration_pending_approval",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
+pub async fn send_registration_approved(address: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/registration_approved",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
 pub async fn send_welcome_must_verify(address: &str, user_id: &UserId) -> EmptyResult {
     let claims = generate_verify_email_claims(user_id.clone());
     let verify_email_token = encode_jwt(&claims);
@@ -290,6 +314,7 @@ pub async fn send_invite(
         org_id.clone(),
         member_id.clone(),
         invited_by_email,
+        user.region.clone(),
     );
     let invite_token = encode_jwt(&claims);
     let mut query = url::Url::parse("https://query.builder").unwrap();
@@ -396,6 +421,19 @@ pub async fn send_emergency_access_invite_confirmed(address: &str, grantor_name:
     send_email(address, &subject, body_html, body_text).await
 }
 
+pub async fn send_emergency_access_grantor_key_rotated(address: &str, grantor_name: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/emergency_access_grantor_key_rotated",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "grantor_name": grantor_name,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
 pub async fn send_emergency_access_recovery_approved(address: &str, grantor_name: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
         "email/emergency_access_recovery_approved",
@@ -490,6 +528,27 @@ pub async fn send_invite_accepted(new_user_email: &str, address: &str, org_name:
     send_email(address, &subject, body_html, body_text).await
 }
 
+pub async fn send_invite_registered(
+    address: &str,
+    new_user_email: &str,
+    org_name: &str,
+    dt: &NaiveDateTime,
+) -> EmptyResult {
+    let fmt = "%A, %B %_d, %Y at %r %Z";
+    let (subject, body_html, body_text) = get_text(
+        "email/invite_registered",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "email": new_user_email,
+            "org_name": org_name,
+            "datetime": crate::util::format_naive_datetime_local(dt, fmt),
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
 pub async fn send_invite_confirmed(address: &str, org_name: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
         "email/invite_confirmed",
@@ -522,6 +581,32 @@ pub async fn send_new_device_logged_in(address: &str, ip: &str, dt: &NaiveDateTi
     send_email(address, &subject, body_html, body_text).await
 }
 
+pub async fn send_login_anomaly(
+    address: &str,
+    ip: &str,
+    country: Option<&str>,
+    dt: &NaiveDateTime,
+    device: &Device,
+) -> EmptyResult {
+    use crate::util::upcase_first;
+
+    let fmt = "%A, %B %_d, %Y at %r %Z";
+    let (subject, body_html, body_text) = get_text(
+        "email/login_anomaly",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "ip": ip,
+            "country": country,
+            "device_name": upcase_first(&device.name),
+            "device_type": DeviceType::from_i32(device.atype).to_string(),
+            "datetime": crate::util::format_naive_datetime_local(dt, fmt),
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
 pub async fn send_incomplete_2fa_login(
     address: &str,
     ip: &str,
@@ -574,6 +659,19 @@ pub async fn send_change_email(address: &str, token: &str) -> EmptyResult {
     send_email(address, &subject, body_html, body_text).await
 }
 
+pub async fn send_recovery_email(address: &str, token: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/recovery_email",
+        json!({
+            "url": CONFIG.domain(),
+            "img_src": CONFIG._smtp_img_src(),
+            "token": token,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text).await
+}
+
 pub async fn send_change_email_existing(address: &str, acting_address: &str) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
         "email/change_email_existing",
@@ -697,6 +697,20 @@ fn schedule_jobs(pool: db::DbPool) {
                 }));
             }
 
+            // Delete accounts which never verified their email.
+            if !CONFIG.purge_unverified_accounts_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.purge_unverified_accounts_schedule().parse().unwrap(), || {
+                    runtime.spawn(api::purge_unverified_accounts(pool.clone()));
+                }));
+            }
+
+            // Remove the soft-deleted devices once they are past the tombstone period.
+            if !CONFIG.purge_device_tombstones_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.purge_device_tombstones_schedule().parse().unwrap(), || {
+                    runtime.spawn(api::purge_device_tombstones(pool.clone()));
+                }));
+            }
+
             // Clean unused, expired Duo authentication contexts.
             if !CONFIG.duo_context_purge_schedule().is_empty() && CONFIG._enable_duo() && !CONFIG.duo_use_iframe() {
                 sched.add(Job::new(CONFIG.duo_context_purge_schedule().parse().unwrap(), || {
@@ -1,9 +1,17 @@
+use dashmap::DashMap;
 use once_cell::sync::Lazy;
-use std::{net::IpAddr, num::NonZeroU32, time::Duration};
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    num::NonZeroU32,
+    time::{Duration, Instant},
+};
 
 use governor::{clock::DefaultClock, state::keyed::DashMapStateStore, Quota, RateLimiter};
 
-use crate::{Error, CONFIG};
+use crate::{
+    db::models::{AuthRequestId, UserId},
+    Error, CONFIG,
+};
 
 type Limiter<T = IpAddr> = RateLimiter<T, DashMapStateStore<T>, DefaultClock>;
 
@@ -19,6 +27,72 @@ static LIMITER_ADMIN: Lazy<Limiter> = Lazy::new(|| {
     RateLimiter::keyed(Quota::with_period(seconds).expect("Non-zero admin ratelimit seconds").allow_burst(burst))
 });
 
+static LIMITER_SIGNUP_IP: Lazy<Option<Limiter>> =
+    Lazy::new(|| new_limiter(CONFIG.signups_ratelimit_seconds(), CONFIG.signups_ratelimit_max_burst()));
+
+static LIMITER_SIGNUP_DOMAIN: Lazy<Option<Limiter<String>>> =
+    Lazy::new(|| new_limiter(CONFIG.signups_domain_ratelimit_seconds(), CONFIG.signups_domain_ratelimit_max_burst()));
+
+static LIMITER_SIGNUP_NAME: Lazy<Option<Limiter<String>>> =
+    Lazy::new(|| new_limiter(CONFIG.signups_name_ratelimit_seconds(), CONFIG.signups_name_ratelimit_max_burst()));
+
+static LIMITER_USER_EMAIL: Lazy<Option<Limiter<UserId>>> =
+    Lazy::new(|| new_limiter(CONFIG.user_email_ratelimit_seconds(), CONFIG.user_email_ratelimit_max_burst()));
+
+static LIMITER_PUBLIC_KEY_LOOKUP: Lazy<Limiter> = Lazy::new(|| {
+    let seconds = Duration::from_secs(CONFIG.public_key_lookup_ratelimit_seconds());
+    let burst =
+        NonZeroU32::new(CONFIG.public_key_lookup_ratelimit_max_burst()).expect("Non-zero public key ratelimit burst");
+    RateLimiter::keyed(Quota::with_period(seconds).expect("Non-zero public key ratelimit seconds").allow_burst(burst))
+});
+
+static LIMITER_EMAIL_VALIDATE: Lazy<Limiter<UserId>> = Lazy::new(|| {
+    let seconds = Duration::from_secs(CONFIG.email_validate_ratelimit_seconds());
+    let burst =
+        NonZeroU32::new(CONFIG.email_validate_ratelimit_max_burst()).expect("Non-zero email validate ratelimit burst");
+    RateLimiter::keyed(
+        Quota::with_period(seconds).expect("Non-zero email validate ratelimit seconds").allow_burst(burst),
+    )
+});
+
+static LIMITER_AUTH_REQUEST_POLL: Lazy<Limiter<AuthRequestId>> = Lazy::new(|| {
+    let seconds = Duration::from_secs(CONFIG.auth_request_poll_ratelimit_seconds());
+    let burst = NonZeroU32::new(CONFIG.auth_request_poll_ratelimit_max_burst())
+        .expect("Non-zero auth request poll ratelimit burst");
+    RateLimiter::keyed(
+        Quota::with_period(seconds).expect("Non-zero auth request poll ratelimit seconds").allow_burst(burst),
+    )
+});
+
+/// Creates a keyed limiter, or `None` when it is disabled with a burst size of 0
+fn new_limiter<T: std::hash::Hash + Eq + Clone>(seconds: u64, burst: u32) -> Option<Limiter<T>> {
+    let burst = NonZeroU32::new(burst)?;
+    let quota = Quota::with_period(Duration::from_secs(seconds)).expect("Non-zero ratelimit seconds");
+    Some(RateLimiter::keyed(quota.allow_burst(burst)))
+}
+
+static LIMITER_VERIFY_PASSWORD: Lazy<AttemptLimiter> = Lazy::new(|| {
+    AttemptLimiter::new(
+        CONFIG.verify_password_max_attempts(),
+        Duration::from_secs(CONFIG.verify_password_lockout_seconds()),
+        MAX_LOCKOUT,
+        MAX_LOCKOUT,
+    )
+});
+
+static LIMITER_EMAIL_CHANGE_TOKEN: Lazy<AttemptLimiter> = Lazy::new(|| {
+    let expiration = Duration::from_secs(CONFIG.email_expiration_time());
+    let attempts = u32::try_from(CONFIG.email_attempts_limit()).unwrap_or(u32::MAX);
+    AttemptLimiter::new(attempts, expiration, expiration, expiration)
+});
+
+// A request only lives for a few minutes, unless its code is refreshed, so failures are kept as long as the lockouts
+static LIMITER_AUTH_REQUEST_CODE: Lazy<AttemptLimiter> =
+    Lazy::new(|| AttemptLimiter::new(CONFIG.auth_request_max_failed_codes(), MAX_LOCKOUT, MAX_LOCKOUT, MAX_LOCKOUT));
+
+/// Upper limit of the exponential backoff, this is also the time after which failed attempts are forgotten
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+
 pub fn check_limit_login(ip: &IpAddr) -> Result<(), Error> {
     match LIMITER_LOGIN.check_key(ip) {
         Ok(_) => Ok(()),
@@ -36,3 +110,408 @@ pub fn check_limit_admin(ip: &IpAddr) -> Result<(), Error> {
         }
     }
 }
+
+pub fn check_limit_public_key_lookup(ip: &IpAddr) -> Result<(), Error> {
+    match LIMITER_PUBLIC_KEY_LOOKUP.check_key(ip) {
+        Ok(_) => Ok(()),
+        Err(_e) => {
+            err_code!("Too many public key requests", 429);
+        }
+    }
+}
+
+pub fn check_limit_email_validate(user_id: &UserId) -> Result<(), Error> {
+    match LIMITER_EMAIL_VALIDATE.check_key(user_id) {
+        Ok(_) => Ok(()),
+        Err(_e) => {
+            err_code!("Too many email validation requests", 429);
+        }
+    }
+}
+
+/// Fetches of the response to a login with device request are limited per request, regardless of the client.
+/// The error is the same as for an unknown request or a wrong access code, to not reveal anything about the request.
+pub fn check_limit_auth_request_poll(auth_request_id: &AuthRequestId) -> Result<(), Error> {
+    // Prevent the state from growing indefinitely with random ids, by removing the ones back at their full burst
+    if LIMITER_AUTH_REQUEST_POLL.len() >= 10_000 {
+        LIMITER_AUTH_REQUEST_POLL.retain_recent();
+    }
+    match LIMITER_AUTH_REQUEST_POLL.check_key(auth_request_id) {
+        Ok(_) => Ok(()),
+        Err(_e) => {
+            err!("AuthRequest doesn't exist", format!("Too many response requests for auth request {auth_request_id}"));
+        }
+    }
+}
+
+/// IPv6 clients usually get a whole /64 assigned, so registrations are limited per /64 network instead of per address
+fn signup_ip_key(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => *ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ipv4) => IpAddr::V4(ipv4),
+            None => {
+                let [a, b, c, d, ..] = ip.segments();
+                IpAddr::V6(Ipv6Addr::new(a, b, c, d, 0, 0, 0, 0))
+            }
+        },
+    }
+}
+
+fn check_limit_signup_with(
+    ip_limiter: Option<&Limiter>,
+    domain_limiter: Option<&Limiter<String>>,
+    ip: &IpAddr,
+    email: &str,
+) -> Result<(), Error> {
+    if ip_limiter.is_some_and(|limiter| limiter.check_key(&signup_ip_key(ip)).is_err()) {
+        err_code!("Too many registration requests", 429);
+    }
+
+    let domain = email.rsplit_once('@').map(|(_, domain)| domain.to_lowercase());
+    if let (Some(limiter), Some(domain)) = (domain_limiter, domain) {
+        if limiter.check_key(&domain).is_err() {
+            err_code!("Too many registration requests", format!("Too many registrations for domain {domain}"), 429);
+        }
+    }
+    Ok(())
+}
+
+pub fn check_limit_signup(ip: &IpAddr, email: &str) -> Result<(), Error> {
+    check_limit_signup_with(LIMITER_SIGNUP_IP.as_ref(), LIMITER_SIGNUP_DOMAIN.as_ref(), ip, email)
+}
+
+/// Spam registrations often reuse the same name, so it's compared ignoring case and surrounding whitespace
+fn check_limit_signup_name_with(name_limiter: Option<&Limiter<String>>, name: &str) -> Result<(), Error> {
+    let name = name.trim().to_lowercase();
+    if name.is_empty() {
+        return Ok(());
+    }
+    if name_limiter.is_some_and(|limiter| limiter.check_key(&name).is_err()) {
+        err_code!("Too many registration requests", format!("Too many registrations with the name {name}"), 429);
+    }
+    Ok(())
+}
+
+pub fn check_limit_signup_name(name: &str) -> Result<(), Error> {
+    check_limit_signup_name_with(LIMITER_SIGNUP_NAME.as_ref(), name)
+}
+
+fn allow_user_email_with(limiter: Option<&Limiter<UserId>>, user_id: &UserId) -> bool {
+    let Some(limiter) = limiter else {
+        return true;
+    };
+    // Prevent the state from growing indefinitely by removing the users which are back at their full burst once in a while
+    if limiter.len() >= 10_000 {
+        limiter.retain_recent();
+    }
+    limiter.check_key(user_id).is_ok()
+}
+
+/// Emails triggered by the actions of a user are limited per user, so an account can't be used to flood a mailbox.
+/// Returns false when the email shouldn't be sent. The action itself still succeeds, to not reveal the limit.
+pub fn allow_user_email(user_id: &UserId) -> bool {
+    let allowed = allow_user_email_with(LIMITER_USER_EMAIL.as_ref(), user_id);
+    if !allowed {
+        warn!("Too many emails for user {user_id}, not sending this one");
+    }
+    allowed
+}
+
+pub fn check_limit_verify_password(user_id: &UserId) -> Result<(), Error> {
+    match LIMITER_VERIFY_PASSWORD.check(user_id, Instant::now()) {
+        None => Ok(()),
+        Some(remaining) => {
+            err_code!(
+                format!("Too many failed password verifications. Try again in {} seconds", remaining.as_secs().max(1)),
+                429
+            );
+        }
+    }
+}
+
+pub fn is_verify_password_locked(user_id: &UserId) -> bool {
+    LIMITER_VERIFY_PASSWORD.check(user_id, Instant::now()).is_some()
+}
+
+/// Registers a failed password verification for this user, returns the lockout duration if the user is now locked out
+pub fn failed_verify_password(user_id: &UserId) -> Option<Duration> {
+    LIMITER_VERIFY_PASSWORD.failure(user_id, Instant::now())
+}
+
+pub fn reset_verify_password(user_id: &UserId) {
+    LIMITER_VERIFY_PASSWORD.success(user_id);
+}
+
+/// Registers a wrong email change token for this user, returns true when the limit of `EMAIL_ATTEMPTS_LIMIT` is reached
+pub fn failed_email_change_token(user_id: &UserId) -> bool {
+    let limit_reached = LIMITER_EMAIL_CHANGE_TOKEN.failure(user_id, Instant::now()).is_some();
+    if limit_reached {
+        LIMITER_EMAIL_CHANGE_TOKEN.success(user_id);
+    }
+    limit_reached
+}
+
+pub fn reset_email_change_token(user_id: &UserId) {
+    LIMITER_EMAIL_CHANGE_TOKEN.success(user_id);
+}
+
+fn failed_auth_request_code_with(limiter: &AttemptLimiter, auth_request_id: &AuthRequestId) -> bool {
+    let limit_reached = limiter.failure(auth_request_id, Instant::now()).is_some();
+    if limit_reached {
+        limiter.success(auth_request_id);
+    }
+    limit_reached
+}
+
+/// Registers a wrong access code, device or IP address for this login with device request,
+/// returns true when the limit of `AUTH_REQUEST_MAX_FAILED_CODES` is reached and the request should be removed.
+/// Successful fetches don't reset the count, otherwise the polling of the requesting device would allow endless guesses.
+pub fn failed_auth_request_code(auth_request_id: &AuthRequestId) -> bool {
+    failed_auth_request_code_with(&LIMITER_AUTH_REQUEST_CODE, auth_request_id)
+}
+
+/// Keeps track of failed attempts per key in memory.
+/// Once the number of failures reaches the threshold, the key is locked out with an exponential backoff
+/// starting at `base_lockout`, capped at `max_lockout`. Entries are forgotten after `ttl` without new failures.
+pub struct AttemptLimiter {
+    attempts: DashMap<String, FailedAttempts>,
+    threshold: u32,
+    base_lockout: Duration,
+    max_lockout: Duration,
+    ttl: Duration,
+}
+
+struct FailedAttempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl FailedAttempts {
+    fn is_expired(&self, now: Instant, ttl: Duration) -> bool {
+        let last = self.locked_until.map_or(self.last_failure, |until| until.max(self.last_failure));
+        now.saturating_duration_since(last) >= ttl
+    }
+}
+
+impl AttemptLimiter {
+    pub fn new(threshold: u32, base_lockout: Duration, max_lockout: Duration, ttl: Duration) -> Self {
+        Self {
+            attempts: DashMap::new(),
+            threshold: threshold.max(1),
+            base_lockout,
+            max_lockout,
+            ttl,
+        }
+    }
+
+    /// Returns the remaining lockout time if the key is currently locked out
+    pub fn check(&self, key: &str, now: Instant) -> Option<Duration> {
+        let mut expired = false;
+        let remaining = self.attempts.get(key).and_then(|attempts| {
+            if attempts.is_expired(now, self.ttl) {
+                expired = true;
+                return None;
+            }
+            attempts.locked_until.filter(|until| *until > now).map(|until| until - now)
+        });
+
+        if expired {
+            self.attempts.remove(key);
+        }
+        remaining
+    }
+
+    /// Registers a failed attempt, returns the lockout duration if the key is now locked out
+    pub fn failure(&self, key: &str, now: Instant) -> Option<Duration> {
+        // Prevent the map from growing indefinitely by removing the expired entries once in a while
+        if self.attempts.len() >= 10_000 {
+            self.attempts.retain(|_, attempts| !attempts.is_expired(now, self.ttl));
+        }
+
+        let mut attempts = self.attempts.entry(key.to_string()).or_insert(FailedAttempts {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+
+        if attempts.is_expired(now, self.ttl) {
+            attempts.failures = 0;
+            attempts.locked_until = None;
+        }
+        attempts.failures += 1;
+        attempts.last_failure = now;
+
+        if attempts.failures < self.threshold {
+            return None;
+        }
+
+        let exponent = (attempts.failures - self.threshold).min(16);
+        let lockout = self.base_lockout.saturating_mul(1 << exponent).min(self.max_lockout);
+        attempts.locked_until = Some(now + lockout);
+        Some(lockout)
+    }
+
+    /// Forgets all failed attempts of this key
+    pub fn success(&self, key: &str) {
+        self.attempts.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attempt_limiter_backoff_after_threshold() {
+        let limiter =
+            AttemptLimiter::new(3, Duration::from_secs(10), Duration::from_secs(60), Duration::from_secs(3600));
+        let now = Instant::now();
+
+        // Below the threshold there is no lockout
+        assert_eq!(limiter.failure("user", now), None);
+        assert_eq!(limiter.failure("user", now), None);
+        assert_eq!(limiter.check("user", now), None);
+
+        // Reaching the threshold locks out with the base duration, and every further failure doubles it
+        assert_eq!(limiter.failure("user", now), Some(Duration::from_secs(10)));
+        assert_eq!(limiter.check("user", now), Some(Duration::from_secs(10)));
+        assert_eq!(limiter.failure("user", now), Some(Duration::from_secs(20)));
+        assert_eq!(limiter.failure("user", now), Some(Duration::from_secs(40)));
+        assert_eq!(limiter.failure("user", now), Some(Duration::from_secs(60)));
+
+        // Other keys are not affected
+        assert_eq!(limiter.check("other", now), None);
+
+        // The lockout ends after the backoff
+        assert_eq!(limiter.check("user", now + Duration::from_secs(61)), None);
+    }
+
+    #[test]
+    fn test_auth_request_removed_after_failed_codes() {
+        let limiter = AttemptLimiter::new(3, MAX_LOCKOUT, MAX_LOCKOUT, MAX_LOCKOUT);
+        let auth_request_id = AuthRequestId::from(crate::util::get_uuid());
+        let other_id = AuthRequestId::from(crate::util::get_uuid());
+
+        assert!(!failed_auth_request_code_with(&limiter, &auth_request_id));
+        assert!(!failed_auth_request_code_with(&limiter, &auth_request_id));
+        assert!(!failed_auth_request_code_with(&limiter, &other_id));
+        assert!(failed_auth_request_code_with(&limiter, &auth_request_id));
+
+        // The count starts over for the next request, which can't have the same id
+        assert!(!failed_auth_request_code_with(&limiter, &auth_request_id));
+        assert!(!failed_auth_request_code_with(&limiter, &other_id));
+        assert!(failed_auth_request_code_with(&limiter, &other_id));
+    }
+
+    #[test]
+    fn test_signup_burst_from_one_ip() {
+        let ip_limiter = new_limiter(3600, 3);
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+
+        for i in 0..3 {
+            let email = format!("bot{i}@example.com");
+            assert!(check_limit_signup_with(ip_limiter.as_ref(), None, &ip, &email).is_ok());
+        }
+        assert!(check_limit_signup_with(ip_limiter.as_ref(), None, &ip, "bot3@example.com").is_err());
+
+        // Other addresses are not affected
+        let other: IpAddr = "198.51.100.1".parse().unwrap();
+        assert!(check_limit_signup_with(ip_limiter.as_ref(), None, &other, "user@example.com").is_ok());
+
+        // A disabled limiter never refuses
+        let disabled = new_limiter(3600, 0);
+        assert!(disabled.is_none());
+        for _ in 0..10 {
+            assert!(check_limit_signup_with(disabled.as_ref(), None, &ip, "user@example.com").is_ok());
+        }
+    }
+
+    #[test]
+    fn test_signup_burst_from_one_ipv6_network() {
+        let ip_limiter = new_limiter(3600, 2);
+
+        // Addresses from the same /64 share the limit
+        for ip in ["2001:db8:1:2::1", "2001:db8:1:2::2"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert!(check_limit_signup_with(ip_limiter.as_ref(), None, &ip, "user@example.com").is_ok());
+        }
+        let ip: IpAddr = "2001:db8:1:2:ffff::3".parse().unwrap();
+        assert!(check_limit_signup_with(ip_limiter.as_ref(), None, &ip, "user@example.com").is_err());
+
+        let ip: IpAddr = "2001:db8:1:3::1".parse().unwrap();
+        assert!(check_limit_signup_with(ip_limiter.as_ref(), None, &ip, "user@example.com").is_ok());
+
+        // IPv4-mapped addresses are limited like the IPv4 address itself
+        let mapped: IpAddr = "::ffff:203.0.113.7".parse().unwrap();
+        assert_eq!(signup_ip_key(&mapped), "203.0.113.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_signup_burst_for_one_domain() {
+        let domain_limiter = new_limiter(3600, 2);
+
+        for i in 0..2 {
+            let ip: IpAddr = format!("203.0.113.{i}").parse().unwrap();
+            let email = format!("bot{i}@Example.com");
+            assert!(check_limit_signup_with(None, domain_limiter.as_ref(), &ip, &email).is_ok());
+        }
+        let ip: IpAddr = "203.0.113.100".parse().unwrap();
+        assert!(check_limit_signup_with(None, domain_limiter.as_ref(), &ip, "bot2@example.com").is_err());
+        assert!(check_limit_signup_with(None, domain_limiter.as_ref(), &ip, "user@example.org").is_ok());
+    }
+
+    #[test]
+    fn test_signup_burst_with_one_name() {
+        let name_limiter = new_limiter(600, 2);
+
+        assert!(check_limit_signup_name_with(name_limiter.as_ref(), "Spam Bot").is_ok());
+        assert!(check_limit_signup_name_with(name_limiter.as_ref(), "spam bot ").is_ok());
+        assert!(check_limit_signup_name_with(name_limiter.as_ref(), "SPAM BOT").is_err());
+
+        // Other names are not affected, and neither are empty names
+        assert!(check_limit_signup_name_with(name_limiter.as_ref(), "Alice").is_ok());
+        for _ in 0..5 {
+            assert!(check_limit_signup_name_with(name_limiter.as_ref(), "").is_ok());
+            assert!(check_limit_signup_name_with(None, "Spam Bot").is_ok());
+        }
+    }
+
+    #[test]
+    fn test_user_email_burst_suppressed() {
+        let limiter = new_limiter(300, 3);
+        let user = UserId::from(crate::util::get_uuid());
+
+        for _ in 0..3 {
+            assert!(allow_user_email_with(limiter.as_ref(), &user));
+        }
+        // The next email in the same window is dropped
+        assert!(!allow_user_email_with(limiter.as_ref(), &user));
+        assert!(!allow_user_email_with(limiter.as_ref(), &user));
+
+        // Other users are not affected, and a disabled limiter never drops emails
+        assert!(allow_user_email_with(limiter.as_ref(), &UserId::from(crate::util::get_uuid())));
+        for _ in 0..10 {
+            assert!(allow_user_email_with(None, &user));
+        }
+    }
+
+    #[test]
+    fn test_attempt_limiter_reset() {
+        let limiter = AttemptLimiter::new(2, Duration::from_secs(10), Duration::from_secs(60), Duration::from_secs(30));
+        let now = Instant::now();
+
+        limiter.failure("user", now);
+        assert_eq!(limiter.failure("user", now), Some(Duration::from_secs(10)));
+
+        // A successful attempt resets the counter
+        limiter.success("user");
+        assert_eq!(limiter.check("user", now), None);
+        assert_eq!(limiter.failure("user", now), None);
+
+        // Failures are forgotten after the ttl
+        let later = now + Duration::from_secs(31);
+        assert_eq!(limiter.failure("user", later), None);
+    }
+}
@@ -118,6 +118,64 @@ function enableUser(event) {
     }
 }
 
+function approveUser(event) {
+    event.preventDefault();
+    event.stopPropagation();
+    const id = event.target.parentNode.dataset.vwUserUuid;
+    const email = event.target.parentNode.dataset.vwUserEmail;
+    if (!id || !email) {
+        alert("Required parameters not found!");
+        return false;
+    }
+    const confirmed = confirm(`Are you sure you want to approve user "${email}"?`);
+    if (confirmed) {
+        _post(`${BASE_URL}/admin/users/${id}/approve`,
+            "User approved successfully",
+            "Error approving user"
+        );
+    }
+}
+
+function rejectUser(event) {
+    event.preventDefault();
+    event.stopPropagation();
+    const id = event.target.parentNode.dataset.vwUserUuid;
+    const email = event.target.parentNode.dataset.vwUserEmail;
+    if (!id || !email) {
+        alert("Required parameters not found!");
+        return false;
+    }
+    const confirmed = confirm(`Are you sure you want to reject user "${email}"? This will delete the account.`);
+    if (confirmed) {
+        _post(`${BASE_URL}/admin/users/${id}/reject`,
+            "User rejected successfully",
+            "Error rejecting user"
+        );
+    }
+}
+
+function setSsoOnly(event, ssoOnly) {
+    event.preventDefault();
+    event.stopPropagation();
+    const id = event.target.parentNode.dataset.vwUserUuid;
+    const email = event.target.parentNode.dataset.vwUserEmail;
+    if (!id || !email) {
+        alert("Required parameters not found!");
+        return false;
+    }
+    const question = ssoOnly
+        ? `Are you sure you want to require SSO login for user "${email}"? The master password can then only be used to unlock the vault.`
+        : `Are you sure you want to allow password login for user "${email}"?`;
+    const confirmed = confirm(question);
+    if (confirmed) {
+        _post(`${BASE_URL}/admin/users/${id}/sso-only`,
+            "SSO login requirement updated successfully",
+            "Error updating the SSO login requirement",
+            JSON.stringify({ "sso_only": ssoOnly })
+        );
+    }
+}
+
 function updateRevisions(event) {
     event.preventDefault();
     event.stopPropagation();
@@ -277,6 +335,18 @@ function initUserTable() {
     document.querySelectorAll("button[vw-enable-user]").forEach(btn => {
         btn.addEventListener("click", enableUser);
     });
+    document.querySelectorAll("button[vw-approve-user]").forEach(btn => {
+        btn.addEventListener("click", approveUser);
+    });
+    document.querySelectorAll("button[vw-reject-user]").forEach(btn => {
+        btn.addEventListener("click", rejectUser);
+    });
+    document.querySelectorAll("button[vw-require-sso-login]").forEach(btn => {
+        btn.addEventListener("click", event => setSsoOnly(event, true));
+    });
+    document.querySelectorAll("button[vw-allow-password-login]").forEach(btn => {
+        btn.addEventListener("click", event => setSsoOnly(event, false));
+    });
     document.querySelectorAll("button[vw-resend-user-invite]").forEach(btn => {
         btn.addEventListener("click", resendUserInvite);
     });
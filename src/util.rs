@@ -246,6 +246,45 @@ impl<'r, R: 'r + Responder<'r, 'static> + Send> Responder<'r, 'static> for Cache
     }
 }
 
+/// Random key for the `ETag` values, so they don't reveal a plain hash of the response.
+/// The tags change after a restart, which only causes one extra download per client.
+static ETAG_NONCE: once_cell::sync::Lazy<String> = once_cell::sync::Lazy::new(crate::crypto::generate_id::<32>);
+
+/// Adds an `ETag` to a JSON response and responds with `304 Not Modified` when it matches the `If-None-Match` header.
+/// The tag is derived from the whole response, so it changes whenever any of the returned fields change.
+pub struct Tagged {
+    value: serde_json::Value,
+    etag: String,
+}
+
+impl Tagged {
+    pub fn new(value: serde_json::Value) -> Self {
+        let etag = format!("\"{}\"", crate::crypto::hmac_sha256_sign(&ETAG_NONCE, &value.to_string()));
+        Self {
+            value,
+            etag,
+        }
+    }
+
+    fn is_not_modified(&self, if_none_match: Option<&str>) -> bool {
+        if_none_match.is_some_and(|tags| {
+            tags.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == self.etag)
+        })
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Tagged {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        if self.is_not_modified(request.headers().get_one("If-None-Match")) {
+            return Response::build().status(Status::NotModified).raw_header("ETag", self.etag).ok();
+        }
+
+        let mut res = rocket::serde::json::Json(self.value).respond_to(request)?;
+        res.set_raw_header("ETag", self.etag);
+        Ok(res)
+    }
+}
+
 // Log all the routes from the main paths list, and the attachments endpoint
 // Effectively ignores, any static file route, and the alive endpoint
 const LOGGED_ROUTES: [&str; 7] = ["/api", "/admin", "/identity", "/icons", "/attachments", "/events", "/notifications"];
@@ -695,6 +734,14 @@ where
     }
 }
 
+/// Returns a random delay of `base_ms`, with up to `jitter_ms` added or removed.
+pub fn randomized_delay(base_ms: u64, jitter_ms: u64) -> Duration {
+    use rand::Rng;
+
+    let jitter_ms = jitter_ms.min(base_ms);
+    Duration::from_millis(rand::rng().random_range(base_ms - jitter_ms..=base_ms + jitter_ms))
+}
+
 pub async fn retry_db<F, T, E>(mut func: F, max_tries: u32) -> Result<T, E>
 where
     F: FnMut() -> Result<T, E>,
@@ -912,4 +959,28 @@ mod tests {
             }
         });
     }
+
+    #[get("/profile")]
+    fn tagged_profile() -> Tagged {
+        Tagged::new(json!({"name": "User", "email": "user@example.com"}))
+    }
+
+    #[test]
+    fn test_profile_etag() {
+        use rocket::local::blocking::Client;
+
+        let client = Client::untracked(rocket::build().mount("/", routes![tagged_profile])).unwrap();
+        let response = client.get("/profile").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+
+        let response = client.get("/profile").header(Header::new("If-None-Match", etag.clone())).dispatch();
+        assert_eq!(response.status(), Status::NotModified);
+        assert!(response.into_string().unwrap_or_default().is_empty());
+
+        let response = client.get("/profile").header(Header::new("If-None-Match", "\"stale\"")).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("ETag"), Some(etag.as_str()));
+        assert!(response.into_string().unwrap().contains("user@example.com"));
+    }
 }